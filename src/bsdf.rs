@@ -0,0 +1,645 @@
+use crate::scene::PulseMaterial;
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+// CPU reference of the layered BSDF in utilities.wgsl. It builds the table of directional albedos the BSDF needs to
+// conserve energy, and the white furnace test at the bottom checks that the whole model neither loses nor gains any.
+// GGX is sampled from its visible normals, and another test checks that the density of those samples integrates to one.
+//
+// Layers from top to bottom, where each one lets through what it doesn't reflect by scaling everything below it with
+// one minus its directional albedo:
+// - Clearcoat, a rough dielectric with an IOR of 1.5.
+// - Sheen, the "Charlie" distribution from Estevez and Kulla 2017 with the visibility term of Neubelt and Pettineo 2013.
+// - The base, anisotropic GGX specular over Lambertian diffuse, or only specular for metals.
+//
+// Single scattering GGX loses energy on rough surfaces, which is added back with the multiple scattering term from
+// Turquin 2019, "Practical multiple scattering compensation for microfacet models".
+//
+// Directions are in the shading frame, with the anisotropy direction along x and the normal along z.
+
+// Size of `PulseEnergyTable` along each of its dimensions, matches the constants in utilities.wgsl.
+pub const PULSE_ENERGY_TABLE_COS_THETA_SIZE: usize = 16;
+pub const PULSE_ENERGY_TABLE_AZIMUTH_SIZE: usize = 5;
+pub const PULSE_ENERGY_TABLE_ROUGHNESS_SIZE: usize = 16;
+// Lower bounds of the roughnesses, to keep the distributions finite. Match the constants in utilities.wgsl.
+pub const MIN_GGX_ALPHA: f32 = 1e-3;
+pub const MIN_SHEEN_ALPHA: f32 = 0.05;
+// Reflectance at normal incidence of the clearcoat, an IOR of 1.5.
+pub const CLEARCOAT_F0: f32 = 0.04;
+
+const ENERGY_TABLE_SAMPLES: u32 = 256;
+// Sheen entries are few, but sampling the cosine instead of the sheen lobe is noisy.
+const SHEEN_ENERGY_TABLE_SAMPLES: u32 = 4096;
+// Light from exactly the horizon is never reflected, which would leave nothing to compensate.
+const MIN_ENERGY_TABLE_COS_THETA: f32 = 0.01;
+
+// Directional albedos of single scattering GGX, over the cosine of the view angle, its azimuth from the anisotropy
+// direction between zero and a quarter turn, and the perceptual roughness along and across the anisotropy direction.
+// Every dimension covers its range from the first to the last entry, the cosine by its square root since albedos change
+// fastest towards the horizon. Anisotropic GGX loses very different amounts of
+// energy depending on where it's viewed from, which no single isotropic roughness can match.
+//
+// Each entry holds:
+// - x and y: the GGX albedo, split into `F0 * x + y` for Schlick's Fresnel like in Karis 2013
+// - z: the albedo of sheen with a white color, over the cosine and the roughness along the anisotropy direction only
+//
+// Stored on the GPU as a 3D texture with the cosine along x, the roughness along the anisotropy direction along y and
+// the roughness across it along z, followed by the next azimuth.
+#[derive(Clone, Debug)]
+pub struct PulseEnergyTable(pub Vec<Vec4>);
+
+impl PulseEnergyTable {
+    pub fn new() -> Self {
+        let cos_theta_size = PULSE_ENERGY_TABLE_COS_THETA_SIZE;
+        let azimuth_size = PULSE_ENERGY_TABLE_AZIMUTH_SIZE;
+        let roughness_size = PULSE_ENERGY_TABLE_ROUGHNESS_SIZE;
+        let mut entries: Vec<Vec4> = vec![];
+        for azimuth in 0..azimuth_size {
+            let phi = azimuth as f32 / (azimuth_size - 1) as f32 * PI / 2.0;
+            for y in 0..roughness_size {
+                for x in 0..roughness_size {
+                    let roughness = Vec2::new(x as f32, y as f32) / (roughness_size - 1) as f32;
+                    for c in 0..cos_theta_size {
+                        let cos_theta = (c as f32 / (cos_theta_size - 1) as f32)
+                            .powi(2)
+                            .max(MIN_ENERGY_TABLE_COS_THETA);
+                        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                        let wo = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                        let (scale, bias) = ggx_albedo(wo, roughness * roughness);
+                        // Only depends on the cosine and one roughness, but is stored alongside so a single texture
+                        // holds everything.
+                        let sheen = if azimuth == 0 && y == 0 {
+                            sheen_albedo(cos_theta, roughness.x)
+                        } else {
+                            entries[x * cos_theta_size + c].z
+                        };
+                        entries.push(Vec4::new(scale, bias, sheen, 0.0));
+                    }
+                }
+            }
+        }
+        Self(entries)
+    }
+
+    // Size of the 3D texture the table is stored in.
+    pub fn extent() -> UVec3 {
+        UVec3::new(
+            PULSE_ENERGY_TABLE_COS_THETA_SIZE as u32,
+            PULSE_ENERGY_TABLE_ROUGHNESS_SIZE as u32,
+            (PULSE_ENERGY_TABLE_ROUGHNESS_SIZE * PULSE_ENERGY_TABLE_AZIMUTH_SIZE) as u32,
+        )
+    }
+
+    fn entry(
+        &self,
+        cos_theta: usize,
+        azimuth: usize,
+        roughness_x: usize,
+        roughness_y: usize,
+    ) -> Vec4 {
+        let roughness_size = PULSE_ENERGY_TABLE_ROUGHNESS_SIZE;
+        let index = ((azimuth * roughness_size + roughness_y) * roughness_size + roughness_x)
+            * PULSE_ENERGY_TABLE_COS_THETA_SIZE
+            + cos_theta;
+        self.0[index]
+    }
+
+    // Albedo of GGX with the roughnesses `alpha` seen from `wo`, as `F0 * x + y`. Same as `load_ggx_albedo` in
+    // utilities.wgsl.
+    pub fn ggx(&self, wo: Vec3, alpha: Vec2) -> Vec2 {
+        let cos_theta = table_coordinate(wo.z.max(0.0).sqrt(), PULSE_ENERGY_TABLE_COS_THETA_SIZE);
+        let phi = wo.y.abs().atan2(wo.x.abs()) / (PI / 2.0);
+        let azimuth = table_coordinate(phi, PULSE_ENERGY_TABLE_AZIMUTH_SIZE);
+        let roughness_x = table_coordinate(alpha.x.sqrt(), PULSE_ENERGY_TABLE_ROUGHNESS_SIZE);
+        let roughness_y = table_coordinate(alpha.y.sqrt(), PULSE_ENERGY_TABLE_ROUGHNESS_SIZE);
+
+        // Linear along all four dimensions.
+        let mut albedo = Vec2::ZERO;
+        for corner in 0..16 {
+            let pick = |(i, t): (usize, f32), bit: usize| {
+                if corner & bit == 0 {
+                    (i, 1.0 - t)
+                } else {
+                    (i + 1, t)
+                }
+            };
+            let (c, wc) = pick(cos_theta, 1);
+            let (a, wa) = pick(azimuth, 2);
+            let (x, wx) = pick(roughness_x, 4);
+            let (y, wy) = pick(roughness_y, 8);
+            albedo += self.entry(c, a, x, y).xy() * wc * wa * wx * wy;
+        }
+        albedo
+    }
+
+    // Albedo of sheen with a white color seen from `cos_theta`. Same as `load_sheen_albedo` in utilities.wgsl.
+    pub fn sheen(&self, cos_theta: f32, perceptual_roughness: f32) -> f32 {
+        let (c, tc) =
+            table_coordinate(cos_theta.max(0.0).sqrt(), PULSE_ENERGY_TABLE_COS_THETA_SIZE);
+        let (x, tx) = table_coordinate(perceptual_roughness, PULSE_ENERGY_TABLE_ROUGHNESS_SIZE);
+        let bottom = lerp(self.entry(c, 0, x, 0).z, self.entry(c + 1, 0, x, 0).z, tc);
+        let top = lerp(
+            self.entry(c, 0, x + 1, 0).z,
+            self.entry(c + 1, 0, x + 1, 0).z,
+            tc,
+        );
+        lerp(bottom, top, tx)
+    }
+}
+
+impl Default for PulseEnergyTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Index of the entry before `v`, between zero and one, and how far `v` is towards the next one.
+fn table_coordinate(v: f32, size: usize) -> (usize, f32) {
+    let p = v.clamp(0.0, 1.0) * (size - 1) as f32;
+    let i = (p as usize).min(size - 2);
+    (i, p - i as f32)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn hammersley(i: u32, n: u32) -> Vec2 {
+    Vec2::new(
+        (i as f32 + 0.5) / n as f32,
+        i.reverse_bits() as f32 / 4294967296.0,
+    )
+}
+
+fn ggx_albedo(wo: Vec3, alpha: Vec2) -> (f32, f32) {
+    let alpha = alpha.max(Vec2::splat(MIN_GGX_ALPHA));
+    let (mut scale, mut bias) = (0.0, 0.0);
+    for i in 0..ENERGY_TABLE_SAMPLES {
+        let m = sample_ggx_vndf(wo, alpha, hammersley(i, ENERGY_TABLE_SAMPLES));
+        let wi = reflect(wo, m);
+        if wi.z <= 0.0 {
+            continue;
+        }
+        let weight = ggx_reflection_weight(wo, wi, alpha);
+        let fresnel = (1.0 - wo.dot(m)).max(0.0).powi(5);
+        scale += weight * (1.0 - fresnel);
+        bias += weight * fresnel;
+    }
+    (
+        scale / ENERGY_TABLE_SAMPLES as f32,
+        bias / ENERGY_TABLE_SAMPLES as f32,
+    )
+}
+
+fn sheen_albedo(cos_theta: f32, perceptual_roughness: f32) -> f32 {
+    let alpha = sheen_alpha(perceptual_roughness);
+    let wo = Vec3::new((1.0 - cos_theta * cos_theta).sqrt(), 0.0, cos_theta);
+    let mut albedo = 0.0;
+    for i in 0..SHEEN_ENERGY_TABLE_SAMPLES {
+        let wi = sample_cosine_hemisphere(hammersley(i, SHEEN_ENERGY_TABLE_SAMPLES));
+        albedo += sheen_reflection_weight(wo, wi, alpha);
+    }
+    albedo / SHEEN_ENERGY_TABLE_SAMPLES as f32
+}
+
+// Reflects `wo` about `m`, both pointing away from the surface.
+fn reflect(wo: Vec3, m: Vec3) -> Vec3 {
+    2.0 * wo.dot(m) * m - wo
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 {
+    f0 + (1.0 - f0) * (1.0 - cos_theta).max(0.0).powi(5)
+}
+
+// Roughness along the anisotropy direction and across it, the same mapping as `KHR_materials_anisotropy`.
+pub fn ggx_alpha(perceptual_roughness: f32, anisotropy_strength: f32) -> Vec2 {
+    let alpha = (perceptual_roughness * perceptual_roughness).max(MIN_GGX_ALPHA);
+    let strength = anisotropy_strength.clamp(0.0, 1.0);
+    Vec2::new(alpha + (1.0 - alpha) * strength * strength, alpha)
+}
+
+fn clearcoat_alpha(material: &PulseMaterial) -> Vec2 {
+    ggx_alpha(material.clearcoat_perceptual_roughness, 0.0)
+}
+
+pub fn ggx_d(m: Vec3, alpha: Vec2) -> f32 {
+    let s = (m.x / alpha.x).powi(2) + (m.y / alpha.y).powi(2) + m.z * m.z;
+    1.0 / (PI * alpha.x * alpha.y * s * s)
+}
+
+// Smith's Λ, so that G1 = 1 / (1 + Λ).
+pub fn ggx_lambda(w: Vec3, alpha: Vec2) -> f32 {
+    let tan2_theta = ((alpha.x * w.x).powi(2) + (alpha.y * w.y).powi(2)) / (w.z * w.z);
+    0.5 * ((1.0 + tan2_theta).sqrt() - 1.0)
+}
+
+// Height correlated masking and shadowing.
+pub fn ggx_g2(wo: Vec3, wi: Vec3, alpha: Vec2) -> f32 {
+    1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+}
+
+// Microfacet normal visible from `wo`, with a pdf of `G1(wo) * D(m) * dot(wo, m) / wo.z`. Samples a spherical cap in
+// the configuration stretched to unit roughness, from Dupuy and Benyoub 2023, "Sampling Visible GGX Normals with
+// Spherical Caps". Same as `sample_ggx_vndf` in utilities.wgsl.
+pub fn sample_ggx_vndf(wo: Vec3, alpha: Vec2, u: Vec2) -> Vec3 {
+    let wo_std = Vec3::new(wo.x * alpha.x, wo.y * alpha.y, wo.z).normalize();
+    let phi = 2.0 * PI * u.x;
+    let z = (1.0 - u.y) * (1.0 + wo_std.z) - wo_std.z;
+    let sin_theta = (1.0 - z * z).clamp(0.0, 1.0).sqrt();
+    let m_std = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), z) + wo_std;
+    Vec3::new(m_std.x * alpha.x, m_std.y * alpha.y, m_std.z).normalize()
+}
+
+// Density of `wi` reflected about a microfacet normal from `sample_ggx_vndf`, over solid angle. Directions below the
+// surface are included, since they can be sampled too. Same as `ggx_vndf_pdf` in utilities.wgsl.
+pub fn ggx_vndf_pdf(wo: Vec3, wi: Vec3, alpha: Vec2) -> f32 {
+    let m = wo + wi;
+    if m.length_squared() == 0.0 {
+        return 0.0;
+    }
+    let m = m.normalize();
+    if m.z <= 0.0 || wo.dot(m) <= 0.0 {
+        return 0.0;
+    }
+    // Visible normal density, times the Jacobian of the reflection `1 / (4 * dot(wo, m))`.
+    ggx_d(m, alpha) / (4.0 * wo.z * (1.0 + ggx_lambda(wo, alpha)))
+}
+
+// Single scattering GGX reflection over its pdf times the cosine, without Fresnel, for `wi` sampled by reflecting `wo`
+// about a normal from `sample_ggx_vndf`. D cancels out, leaving `G2 / G1(wo)`.
+fn ggx_reflection_weight(wo: Vec3, wi: Vec3, alpha: Vec2) -> f32 {
+    (1.0 + ggx_lambda(wo, alpha)) * ggx_g2(wo, wi, alpha)
+}
+
+fn sample_cosine_hemisphere(u: Vec2) -> Vec3 {
+    let r = u.x.sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u.x).max(0.0).sqrt())
+}
+
+fn sheen_alpha(perceptual_roughness: f32) -> f32 {
+    (perceptual_roughness * perceptual_roughness).max(MIN_SHEEN_ALPHA)
+}
+
+pub fn sheen_d(m: Vec3, alpha: f32) -> f32 {
+    let sin_theta = (1.0 - m.z * m.z).max(0.0).sqrt();
+    (2.0 + 1.0 / alpha) * sin_theta.powf(1.0 / alpha) / (2.0 * PI)
+}
+
+pub fn sheen_v(wo: Vec3, wi: Vec3) -> f32 {
+    1.0 / (4.0 * (wi.z + wo.z - wi.z * wo.z))
+}
+
+// Sheen with a white color over the pdf of a cosine weighted `wi`, times the cosine.
+fn sheen_reflection_weight(wo: Vec3, wi: Vec3, alpha: f32) -> f32 {
+    let h = (wo + wi).normalize();
+    sheen_d(h, alpha) * sheen_v(wo, wi) * PI
+}
+
+// How much of the energy arriving from `wo` each layer reflects. `coat` and `sheen` are the probabilities of sampling
+// those layers, which is also what they take away from the layers below.
+pub struct PulseLayerAlbedos {
+    pub coat: f32,
+    pub coat_albedo: f32,
+    pub coat_compensation: f32,
+    pub sheen: f32,
+    pub specular_f0: Vec3,
+    pub specular: Vec3,
+    pub specular_compensation: Vec3,
+    pub diffuse: Vec3,
+}
+
+// Same as `layer_albedos` in utilities.wgsl.
+pub fn layer_albedos(
+    material: &PulseMaterial,
+    table: &PulseEnergyTable,
+    wo: Vec3,
+) -> PulseLayerAlbedos {
+    let coat_table = table.ggx(wo, clearcoat_alpha(material));
+    let coat_single = coat_table.x + coat_table.y;
+    let coat_compensation = 1.0 + CLEARCOAT_F0 * (1.0 - coat_single) / coat_single.max(1e-4);
+    let coat_albedo = (CLEARCOAT_F0 * coat_table.x + coat_table.y) * coat_compensation;
+
+    let sheen = material.sheen_color.max_element().clamp(0.0, 1.0)
+        * table.sheen(wo.z, material.sheen_perceptual_roughness);
+
+    let alpha = ggx_alpha(material.perceptual_roughness, material.anisotropy_strength);
+    let base_color = material.base_color.xyz();
+    let dielectric_f0 = Vec3::splat(0.16 * material.reflectance * material.reflectance);
+    let specular_f0 = dielectric_f0.lerp(base_color, material.metallic);
+    let specular_table = table.ggx(wo, alpha);
+    let specular_single = specular_table.x + specular_table.y;
+    let specular_compensation =
+        1.0 + specular_f0 * (1.0 - specular_single) / specular_single.max(1e-4);
+    let specular = (specular_f0 * specular_table.x + specular_table.y) * specular_compensation;
+    let diffuse = (1.0 - specular) * base_color * (1.0 - material.metallic);
+
+    PulseLayerAlbedos {
+        coat: material.clearcoat.clamp(0.0, 1.0) * coat_albedo,
+        coat_albedo,
+        coat_compensation,
+        sheen,
+        specular_f0,
+        specular,
+        specular_compensation,
+        diffuse,
+    }
+}
+
+// A sampled incident direction and the BSDF times the cosine over the pdf of sampling it. A zero weight means the
+// path was absorbed.
+pub struct PulseBsdfSample {
+    pub wi: Vec3,
+    pub weight: Vec3,
+}
+
+// Same as `importance_sample_layered_bsdf` in utilities.wgsl. Picks a layer with the probability of the energy it
+// reflects, then samples only that layer.
+pub fn sample_layered_bsdf(
+    material: &PulseMaterial,
+    table: &PulseEnergyTable,
+    wo: Vec3,
+    rng: &mut impl FnMut() -> f32,
+) -> PulseBsdfSample {
+    let absorbed = PulseBsdfSample {
+        wi: wo,
+        weight: Vec3::ZERO,
+    };
+    if wo.z <= 0.0 {
+        return absorbed;
+    }
+    let layers = layer_albedos(material, table, wo);
+
+    if rng() < layers.coat {
+        let alpha = clearcoat_alpha(material);
+        let m = sample_ggx_vndf(wo, alpha, Vec2::new(rng(), rng()));
+        let wi = reflect(wo, m);
+        if wi.z <= 0.0 {
+            return absorbed;
+        }
+        let fresnel = fresnel_schlick(wo.dot(m), Vec3::splat(CLEARCOAT_F0));
+        let weight = fresnel * ggx_reflection_weight(wo, wi, alpha) * layers.coat_compensation
+            / layers.coat_albedo;
+        return PulseBsdfSample { wi, weight };
+    }
+
+    if rng() < layers.sheen {
+        let wi = sample_cosine_hemisphere(Vec2::new(rng(), rng()));
+        let alpha = sheen_alpha(material.sheen_perceptual_roughness);
+        let weight = material.sheen_color * sheen_reflection_weight(wo, wi, alpha) / layers.sheen;
+        return PulseBsdfSample { wi, weight };
+    }
+
+    sample_base(material, &layers, wo, rng)
+}
+
+// Same as `importance_sample_ggx_vndf` in utilities.wgsl.
+fn sample_base(
+    material: &PulseMaterial,
+    layers: &PulseLayerAlbedos,
+    wo: Vec3,
+    rng: &mut impl FnMut() -> f32,
+) -> PulseBsdfSample {
+    let specular = layers.specular.max_element();
+    let specular_probability = specular / (specular + layers.diffuse.max_element()).max(1e-6);
+    if rng() < specular_probability {
+        let alpha = ggx_alpha(material.perceptual_roughness, material.anisotropy_strength);
+        let m = sample_ggx_vndf(wo, alpha, Vec2::new(rng(), rng()));
+        let wi = reflect(wo, m);
+        if wi.z <= 0.0 {
+            return PulseBsdfSample {
+                wi,
+                weight: Vec3::ZERO,
+            };
+        }
+        let fresnel = fresnel_schlick(wo.dot(m), layers.specular_f0);
+        let weight = fresnel * ggx_reflection_weight(wo, wi, alpha) * layers.specular_compensation
+            / specular_probability;
+        return PulseBsdfSample { wi, weight };
+    }
+
+    let wi = sample_cosine_hemisphere(Vec2::new(rng(), rng()));
+    PulseBsdfSample {
+        wi,
+        weight: layers.diffuse / (1.0 - specular_probability),
+    }
+}
+
+// The BSDF times the cosine of `wi`, same as `eval_layered_bsdf` in utilities.wgsl.
+pub fn eval_layered_bsdf(
+    material: &PulseMaterial,
+    table: &PulseEnergyTable,
+    wo: Vec3,
+    wi: Vec3,
+) -> Vec3 {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let layers = layer_albedos(material, table, wo);
+    let h = (wo + wi).normalize();
+
+    let coat_alpha = clearcoat_alpha(material);
+    let coat = fresnel_schlick(wo.dot(h), Vec3::splat(CLEARCOAT_F0))
+        * ggx_d(h, coat_alpha)
+        * ggx_g2(wo, wi, coat_alpha)
+        / (4.0 * wo.z)
+        * layers.coat_compensation;
+
+    let sheen_alpha = sheen_alpha(material.sheen_perceptual_roughness);
+    let sheen = material.sheen_color * sheen_d(h, sheen_alpha) * sheen_v(wo, wi) * wi.z;
+
+    let alpha = ggx_alpha(material.perceptual_roughness, material.anisotropy_strength);
+    let specular =
+        fresnel_schlick(wo.dot(h), layers.specular_f0) * ggx_d(h, alpha) * ggx_g2(wo, wi, alpha)
+            / (4.0 * wo.z)
+            * layers.specular_compensation;
+    let diffuse = layers.diffuse * wi.z / PI;
+
+    let below_coat = 1.0 - layers.coat;
+    let below_sheen = 1.0 - layers.sheen;
+    material.clearcoat.clamp(0.0, 1.0) * coat
+        + below_coat * (sheen + below_sheen * (specular + diffuse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::textures::PulseMaterialTexture;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Fraction of the light arriving from `wo` that is reflected, estimated with `sample_count` samples of the BSDF.
+    fn directional_albedo(
+        material: &PulseMaterial,
+        table: &PulseEnergyTable,
+        wo: Vec3,
+        sample_count: u32,
+        seed: u64,
+    ) -> Vec3 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut next = || rng.gen::<f32>();
+        let mut albedo = Vec3::ZERO;
+        for _ in 0..sample_count {
+            albedo += sample_layered_bsdf(material, table, wo, &mut next).weight;
+        }
+        albedo / sample_count as f32
+    }
+
+    // A material that reflects everything that reaches it in every layer, so any energy gained or lost is an error of the
+    // model. Starts out as a white dielectric.
+    fn white_furnace_material() -> PulseMaterial {
+        PulseMaterial {
+            base_color: Vec4::ONE,
+            emissive: Vec4::ZERO,
+            perceptual_roughness: 0.5,
+            reflectance: 0.5,
+            metallic: 0.0,
+            flags: 0,
+            specular_transmission: 0.0,
+            diffuse_transmission: 0.0,
+            ior: 1.5,
+            thickness: 0.0,
+            attenuation_color: Vec3::ONE,
+            attenuation_distance: f32::MAX,
+            clearcoat: 0.0,
+            clearcoat_perceptual_roughness: 0.5,
+            anisotropy_strength: 0.0,
+            anisotropy_rotation: 0.0,
+            sheen_color: Vec3::ZERO,
+            sheen_perceptual_roughness: 0.5,
+            base_color_texture: PulseMaterialTexture::NONE,
+            emissive_texture: PulseMaterialTexture::NONE,
+            metallic_roughness_texture: PulseMaterialTexture::NONE,
+            occlusion_texture: PulseMaterialTexture::NONE,
+            normal_map_texture: PulseMaterialTexture::NONE,
+        }
+    }
+
+    // Checks that white materials reflect all light arriving from any angle, for every combination of layers and a range of
+    // roughnesses. Also checks that `eval_layered_bsdf` integrates to the same albedo as the samples of
+    // `sample_layered_bsdf`, so both agree on the BSDF.
+    //
+    // Interpolating the energy table keeps the error within 0.03 down to light arriving at a cosine of 0.05, it's largest
+    // for smooth and strongly anisotropic surfaces close to the horizon.
+    #[test]
+    fn white_furnace() {
+        let table = PulseEnergyTable::new();
+        let tolerance = 0.03;
+        let mut failures = vec![];
+        let roughnesses: [f32; 5] = [0.05, 0.25, 0.5, 0.75, 1.0];
+        let cos_thetas: [f32; 5] = [0.05, 0.25, 0.5, 0.75, 1.0];
+        // Azimuths from the anisotropy direction.
+        let phis = [0.0, PI / 6.0, PI / 3.0, PI / 2.0];
+
+        let mut materials = vec![];
+        for roughness in roughnesses {
+            for metallic in [0.0, 1.0] {
+                for (clearcoat, sheen, anisotropy) in [
+                    (0.0, 0.0, 0.0),
+                    (1.0, 0.0, 0.0),
+                    (0.0, 1.0, 0.0),
+                    (0.0, 0.0, 0.8),
+                    (1.0, 1.0, 0.8),
+                ] {
+                    let mut material = white_furnace_material();
+                    material.perceptual_roughness = roughness;
+                    material.metallic = metallic;
+                    material.clearcoat = clearcoat;
+                    material.clearcoat_perceptual_roughness = roughness;
+                    material.sheen_color = Vec3::splat(sheen);
+                    material.sheen_perceptual_roughness = roughness;
+                    material.anisotropy_strength = anisotropy;
+                    let name = format!(
+                        "roughness {}, metallic {}, clearcoat {}, sheen {}, anisotropy {}",
+                        roughness, metallic, clearcoat, sheen, anisotropy
+                    );
+                    materials.push((name, material));
+                }
+            }
+        }
+
+        for (seed, (name, material)) in materials.iter().enumerate() {
+            for (cos_theta, phi) in cos_thetas
+                .into_iter()
+                .flat_map(|cos_theta| phis.map(|phi| (cos_theta, phi)))
+            {
+                // Isotropic materials look the same from every azimuth.
+                if material.anisotropy_strength == 0.0 && phi != 0.0 {
+                    continue;
+                }
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let wo = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                let albedo = directional_albedo(material, &table, wo, 1 << 16, seed as u64);
+                if (albedo - 1.0).abs().max_element() > tolerance {
+                    failures.push(format!(
+                        "{}: sampled albedo {} at cos theta {}, azimuth {}",
+                        name, albedo, cos_theta, phi
+                    ));
+                }
+
+                // Sharp lobes are too noisy to integrate without importance sampling.
+                if material.perceptual_roughness < 0.5 {
+                    continue;
+                }
+                let mut rng = StdRng::seed_from_u64(seed as u64);
+                let sample_count = 1 << 16;
+                let mut evaluated = Vec3::ZERO;
+                for _ in 0..sample_count {
+                    let wi = sample_cosine_hemisphere(Vec2::new(rng.gen(), rng.gen()));
+                    evaluated += eval_layered_bsdf(material, &table, wo, wi) * PI / wi.z.max(1e-6);
+                }
+                evaluated /= sample_count as f32;
+                if (evaluated - albedo).abs().max_element() > tolerance {
+                    failures.push(format!(
+                        "{}: evaluated albedo {} but sampled {} at cos theta {}, azimuth {}",
+                        name, evaluated, albedo, cos_theta, phi
+                    ));
+                }
+            }
+        }
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    // Checks that `ggx_vndf_pdf` integrates to one over the sphere, for a range of roughnesses, anisotropies and view
+    // directions. Integrated with the midpoint rule on a grid of equal area cells, which can't resolve the sharpest lobes,
+    // so smooth surfaces are left out.
+    #[test]
+    fn ggx_vndf_pdf_integrates_to_one() {
+        let tolerance = 0.01;
+        let mut failures = vec![];
+        let (z_cells, phi_cells) = (512, 1024);
+        let cell_area = 4.0 * PI / (z_cells * phi_cells) as f32;
+
+        for perceptual_roughness in [0.35, 0.5, 1.0] {
+            for anisotropy_strength in [0.0, 0.8] {
+                let alpha = ggx_alpha(perceptual_roughness, anisotropy_strength);
+                for cos_theta in [0.05f32, 0.5, 1.0] {
+                    for phi in [0.0, PI / 3.0] {
+                        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                        let wo = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+
+                        let mut integral = 0.0f64;
+                        for i in 0..z_cells {
+                            let z = -1.0 + 2.0 * (i as f32 + 0.5) / z_cells as f32;
+                            let r = (1.0 - z * z).sqrt();
+                            for j in 0..phi_cells {
+                                let phi = 2.0 * PI * (j as f32 + 0.5) / phi_cells as f32;
+                                let wi = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                                integral += (ggx_vndf_pdf(wo, wi, alpha) * cell_area) as f64;
+                            }
+                        }
+
+                        if (integral as f32 - 1.0).abs() > tolerance {
+                            failures.push(format!(
+                                "roughness {}, anisotropy {}: pdf integrates to {} at cos theta {}, azimuth {}",
+                                perceptual_roughness, anisotropy_strength, integral, cos_theta, phi
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -1,14 +1,17 @@
 use bevy::{prelude::*, render::RenderApp};
 
+pub mod bsdf;
 // pub mod diagnostics;
 pub mod path_tracer;
 pub mod pulse;
 pub mod scene;
+pub mod support;
 pub mod upscaling;
 pub mod utilities;
 
 // use diagnostics::*;
 use scene::*;
+use support::*;
 
 pub struct PulsePlugin;
 
@@ -18,20 +21,10 @@ impl Plugin for PulsePlugin {
     }
 
     fn finish(&self, app: &mut App) {
-        let _render_app = app.sub_app_mut(RenderApp);
+        let render_app = app.sub_app_mut(RenderApp);
 
-        // TODO: Implement this properly. Not sure if I use any non-standard features though
-        // let required_features = WgpuFeatures::TIMESTAMP_QUERY;
-        // match render_app.world.get_resource::<RenderDevice>() {
-        //     Some(render_device) => {
-        //         if !render_device.features().contains(required_features) {
-        //             error!("All required wgpu features are not supported");
-        //             return;
-        //         }
-        //     }
-        //     _ => {
-        //         warn!("RenderDevice not found");
-        //     }
-        // }
+        // Also available in the main world, eg. to not add Pulse cameras when it's unsupported.
+        let support = pulse_support(render_app);
+        app.insert_resource(support);
     }
 }
@@ -3,6 +3,7 @@ use std::sync::{
     Arc,
 };
 
+use crate::support::pulse_support;
 use bevy::{
     asset::load_internal_asset,
     core_pipeline::core_3d,
@@ -56,6 +57,9 @@ impl Plugin for PulsePathTracerPlugin {
 
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
+        if !pulse_support(render_app).is_supported() {
+            return;
+        }
 
         render_app
             .add_render_graph_node::<ViewNodeRunner<PulsePathTracerNode>>(
@@ -95,6 +99,8 @@ pub struct PulsePathTracerUniform {
     pub width: u32,
     pub height: u32,
     pub accumulation_count: u32,
+    // Render layers of the camera, only instances on these layers are hit by camera and GI rays.
+    pub render_layers: u32,
 }
 
 #[derive(Component, Default, Clone, ExtractComponent)]
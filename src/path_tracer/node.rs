@@ -12,7 +12,7 @@ use bevy::{
         render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
         render_resource::*,
         renderer::{RenderContext, RenderDevice, RenderQueue},
-        view::{ViewTarget, ViewUniformOffset, ViewUniforms},
+        view::{RenderLayers, ViewTarget, ViewUniformOffset, ViewUniforms},
     },
 };
 
@@ -33,6 +33,7 @@ impl ViewNode for PulsePathTracerNode {
         &'static PulsePathTracerPipeline,
         &'static ViewUniformOffset,
         &'static ViewPrepassTextures,
+        Option<&'static RenderLayers>,
     );
 
     fn update(&mut self, _world: &mut World) {}
@@ -41,7 +42,7 @@ impl ViewNode for PulsePathTracerNode {
         &self,
         _graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
-        (path_tracer, render_target, pipeline, view_offset, prepass_textures): QueryItem<
+        (path_tracer, render_target, pipeline, view_offset, prepass_textures, render_layers): QueryItem<
             Self::ViewQuery,
         >,
         world: &World,
@@ -64,6 +65,7 @@ impl ViewNode for PulsePathTracerNode {
                 width: render_target.width,
                 height: render_target.height,
                 accumulation_count: path_tracer.accumulation_count,
+                render_layers: render_layers.copied().unwrap_or_default().bits(),
             },
             Some("pulse_path_tracer_uniform_buffer"),
             device,
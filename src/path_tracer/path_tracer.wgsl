@@ -3,6 +3,7 @@
     pbr_deferred_types,
     rgb9e5,
     utils,
+    mesh_types::MESH_FLAGS_SHADOW_RECEIVER_BIT,
 }
 #import bevy_render::view::View
 #import pulse::{
@@ -13,12 +14,17 @@
         rand_f_pair,
         rand_range_u,
         sample_cosine_hemisphere,
-        importance_sample_ggx_d,
+        importance_sample_bsdf,
         transform_direction,
         trace_ray,
         trace_shadow_ray,
         distance_sq,
         sample_direct_light,
+        instance_receives_shadows,
+        load_surface_hit,
+        volume_transmittance,
+        RAY_CAMERA,
+        RAY_GI,
     }, 
     scene::{
         types::{
@@ -30,14 +36,12 @@
         bindings::{
             instances,
             triangle_indices,
-            triangle_data,
             materials,
             light_emission_strength_cdf,
             light_triangle_area_cdfs,
             light_mesh_areas,
             light_indices,
             scene_uniform,
-            primitives,
         }
     },
 }
@@ -46,6 +50,7 @@ struct PathTracerUniform {
     width: u32,
     height: u32,
     accumulation_count: u32,
+    render_layers: u32,
 }
 
 @group(1) @binding(0) var<uniform> view: View;
@@ -79,26 +84,26 @@ fn path_trace(@builtin(global_invocation_id) id: vec3<u32>) {
 
     let max_depth: u32 = 5u;
     for (var depth: u32 = 0u; depth < max_depth; depth += 1u) {
-        trace_ray(&ray);
+        let ray_type = select(RAY_GI, RAY_CAMERA, depth == 0u);
+        trace_ray(&ray, ray_type, path_tracer_uniform.render_layers);
         if ray.record.t >= t_far  {
             // Miss
             // color += throughput * vec3<f32>(0.03, 0.03, 0.03);
             break;
         } else {
             // Hit
-            let instance = instances[ray.record.instance_index];
-            let t_idx = triangle_indices[instance.index_offset + ray.record.triangle_index];
-            let t = triangle_data[instance.triangle_offset + t_idx];
-            let w = 1.0 - (ray.record.u + ray.record.v);
-            let normal = w * t.n_first + ray.record.u * t.n_second + ray.record.v * t.n_third;
-            let world_normal = normalize(transform_direction(instance.object_world, normal));
-            let world_hit_position = ray.origin + ray.record.t * ray.dir;
-
-            let material_index = instance.material_index;
-            let material = materials[material_index];
+            let hit = load_surface_hit(ray);
+            let world_normal = hit.normal;
+            let world_hit_position = hit.position;
+            // Without direct light sampling every bounce gathers direct light too, so occlusion isn't applied here.
+            let material = hit.material;
+            // Leaving a closed mesh means the ray travelled through its volume.
+            if !hit.front_face {
+                throughput *= volume_transmittance(material, ray.record.t);
+            }
 
             color += throughput * material.emissive.xyz;
-            let sample = importance_sample_ggx_d(world_normal, -ray.dir, material, &rng_state);
+            let sample = importance_sample_bsdf(hit, -ray.dir, &rng_state);
             throughput *= sample.reflectance;
 
             let p = max(max(throughput.r, throughput.g), throughput.b);
@@ -108,7 +113,9 @@ fn path_trace(@builtin(global_invocation_id) id: vec3<u32>) {
             throughput *= 1.0 / p;
 
             ray.dir = normalize(sample.wi);
-            ray.origin = world_hit_position + 0.001 * world_normal;
+            // Transmitted rays continue on the other side of the surface.
+            let offset_normal = select(-world_normal, world_normal, dot(ray.dir, world_normal) >= 0.0);
+            ray.origin = world_hit_position + 0.001 * offset_normal;
             ray.record = RayHitRecord(t_far, 0u, 0u, 0.0, 0.0);
         }
     }
@@ -148,33 +155,30 @@ fn path_traceBBB(@builtin(global_invocation_id) id: vec3<u32>) {
     let t_far = 1e30;
     ray.record = RayHitRecord(t_far, 0u, 0u, 0.0, 0.0);
 
-    let direct_light = sample_direct_light(pbr_input.world_position.xyz, pbr_input.world_normal, pbr_input.material.base_color.xyz, &rng_state);
+    let receives_shadows = (pbr_input.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u;
+    let direct_light = sample_direct_light(pbr_input.world_position.xyz, pbr_input.world_normal, pbr_input.material.base_color.xyz, receives_shadows, &rng_state);
     var color = pbr_input.material.emissive.xyz + direct_light;
     var throughput = pbr_input.material.base_color.xyz;
 
     let max_depth: u32 = 5u;
     for (var depth: u32 = 0u; depth < max_depth; depth += 1u) {
-        trace_ray(&ray);
+        trace_ray(&ray, RAY_GI, path_tracer_uniform.render_layers);
         if ray.record.t >= t_far  {
             // Miss
             // color += throughput * vec3<f32>(0.03, 0.03, 0.03);
             break;
         } else {
             // Hit
-            let instance = instances[ray.record.instance_index];
-            let t_idx = triangle_indices[instance.index_offset + ray.record.triangle_index];
-            let t = triangle_data[instance.triangle_offset + t_idx];
-            let w = 1.0 - (ray.record.u + ray.record.v);
-            let normal = w * t.n_first + ray.record.u * t.n_second + ray.record.v * t.n_third;
-            let world_normal = normalize(transform_direction(instance.object_world, normal));
-            let world_hit_position = ray.origin + ray.record.t * ray.dir;
-
-            let material_index = instance.material_index;
-            let material = materials[material_index];
-
-            let direct_light = sample_direct_light(world_hit_position, world_normal, material.base_color.xyz, &rng_state);
+            let hit = load_surface_hit(ray);
+            let world_normal = hit.normal;
+            let world_hit_position = hit.position;
+            let material = hit.material;
+
+            let receives_shadows = instance_receives_shadows(ray.record.instance_index);
+            let direct_light = sample_direct_light(world_hit_position, world_normal, material.base_color.xyz, receives_shadows, &rng_state);
             color += throughput * direct_light;
-            throughput *= material.base_color.xyz;
+            // Occlusion only darkens the indirect light gathered by the next bounce.
+            throughput *= material.base_color.xyz * hit.occlusion;
 
             let p = max(max(throughput.r, throughput.g), throughput.b);
             if rand_f(&rng_state) > p { 
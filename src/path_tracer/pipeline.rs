@@ -1,5 +1,10 @@
 use super::{PulsePathTracerCamera, PULSE_PATH_TRACER_SHADER_HANDLE};
-use crate::scene::PulseSceneBindGroupLayout;
+use crate::scene::{
+    buffers::PulseBufferLimits,
+    vertices::{PulseVertexEncoding, PulseVertexLayout},
+    wide_bvh::PulseBlasLayout,
+    PulseBvhSettings, PulseSceneBindGroupLayout,
+};
 use bevy::{
     core_pipeline::{
         prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
@@ -26,6 +31,7 @@ pub struct PulsePathTracerPipeline {
 #[derive(Resource)]
 pub struct PulsePathTracerLayout {
     pub scene_layout: BindGroupLayout,
+    pub buffer_limits: PulseBufferLimits,
     pub view_layout: BindGroupLayout,
 }
 
@@ -94,18 +100,28 @@ impl FromWorld for PulsePathTracerLayout {
         );
 
         let scene_layout = world.resource::<PulseSceneBindGroupLayout>().0.clone();
+        let buffer_limits = *world.resource::<PulseBufferLimits>();
 
         Self {
             scene_layout,
+            buffer_limits,
             view_layout,
         }
     }
 }
 
 impl SpecializedComputePipeline for PulsePathTracerLayout {
-    type Key = MeshPipelineKey;
-
-    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+    type Key = (
+        MeshPipelineKey,
+        PulseBlasLayout,
+        PulseVertexLayout,
+        PulseVertexEncoding,
+    );
+
+    fn specialize(
+        &self,
+        (key, blas_layout, vertex_layout, vertex_encoding): Self::Key,
+    ) -> ComputePipelineDescriptor {
         // NOTE: These shader defs aren't used by Pulse but are needed for various shader functions, eg. for unpacking the deferred texture.
         let mut shader_defs = Vec::new();
 
@@ -184,6 +200,11 @@ impl SpecializedComputePipeline for PulsePathTracerLayout {
         #[cfg(all(feature = "webgl", target_arch = "wasm32", not(feature = "webgpu")))]
         shader_defs.push("SIXTEEN_BYTE_ALIGNMENT".into());
 
+        shader_defs.extend(blas_layout.shader_defs());
+        shader_defs.extend(vertex_layout.shader_defs());
+        shader_defs.extend(vertex_encoding.shader_defs());
+        shader_defs.extend(self.buffer_limits.shader_defs());
+
         ComputePipelineDescriptor {
             label: Some("pulse_path_tracer_pipeline".into()),
             layout: vec![self.scene_layout.clone(), self.view_layout.clone()],
@@ -220,6 +241,7 @@ pub fn prepare_path_tracer_pipelines(
     layout: Res<PulsePathTracerLayout>,
     cache: Res<PipelineCache>,
     images: Res<RenderAssets<Image>>,
+    bvh_settings: Res<PulseBvhSettings>,
 ) {
     for (
         entity,
@@ -301,7 +323,16 @@ pub fn prepare_path_tracer_pipelines(
             }
         }
 
-        let id = pipelines.specialize(&cache, &layout, mesh_view_key);
+        let id = pipelines.specialize(
+            &cache,
+            &layout,
+            (
+                mesh_view_key,
+                bvh_settings.blas_layout,
+                bvh_settings.vertex_layout,
+                bvh_settings.vertex_encoding,
+            ),
+        );
         commands
             .entity(entity)
             .insert(PulsePathTracerPipeline { id });
@@ -5,6 +5,7 @@
     pbr_deferred_functions::pbr_input_from_deferred_gbuffer,
     pbr_deferred_types::unpack_unorm3x4_plus_unorm_20_,
     mesh_view_bindings::{depth_prepass_texture, deferred_prepass_texture, view},
+    mesh_types::MESH_FLAGS_SHADOW_RECEIVER_BIT,
 }
 #import bevy_render::view::View
 #import pulse::{
@@ -18,6 +19,13 @@
         trace_ray,
         trace_shadow_ray,
         distance_sq,
+        instance_receives_shadows,
+        load_primitive,
+        load_surface_hit,
+        light_emission,
+        sample_triangle_barycentrics,
+        RAY_GI,
+        RAY_SHADOW,
     }, 
     scene::{
         types::{
@@ -29,14 +37,12 @@
         bindings::{
             instances,
             triangle_indices,
-            triangle_data,
             materials,
             light_emission_strength_cdf,
             light_triangle_area_cdfs,
             light_mesh_areas,
             light_indices,
             scene_uniform,
-            primitives,
         }
     },
 }
@@ -44,6 +50,7 @@
 struct PulseUniform {
     width: u32,
     height: u32,
+    render_layers: u32,
 }
 
 // TODO: Use constant value for ray origin offset
@@ -78,33 +85,30 @@ fn gi(@builtin(global_invocation_id) id: vec3<u32>) {
         let t_far = 1e30;
         ray.record = RayHitRecord(t_far, 0u, 0u, 0.0, 0.0);
 
-        let direct_light = sample_direct_light(pbr_input.world_position.xyz, pbr_input.world_normal, pbr_input.material.base_color.xyz, &rng_state);
+        let receives_shadows = (pbr_input.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u;
+        let direct_light = sample_direct_light(pbr_input.world_position.xyz, pbr_input.world_normal, pbr_input.material.base_color.xyz, receives_shadows, &rng_state);
         var color = pbr_input.material.emissive.xyz + direct_light;
         var throughput = pbr_input.material.base_color.xyz;
 
         let max_depth: u32 = 5u;
         for (var depth: u32 = 0u; depth < max_depth; depth += 1u) {
-            trace_ray(&ray);
+            trace_ray(&ray, RAY_GI, pulse_uniform.render_layers);
             if ray.record.t >= t_far  {
                 // Miss
                 // color += throughput * vec3<f32>(0.03, 0.03, 0.03);
                 break;
             } else {
                 // Hit
-                let instance = instances[ray.record.instance_index];
-                let t_idx = triangle_indices[instance.index_offset + ray.record.triangle_index];
-                let t = triangle_data[instance.triangle_offset + t_idx];
-                let w = 1.0 - (ray.record.u + ray.record.v);
-                let normal = w * t.n_first + ray.record.u * t.n_second + ray.record.v * t.n_third;
-                let world_normal = normalize(transform_direction(instance.object_world, normal));
-                let world_hit_position = ray.origin + ray.record.t * ray.dir;
-
-                let material_index = instance.material_index;
-                let material = materials[material_index];
-
-                let direct_light = sample_direct_light(world_hit_position, world_normal, material.base_color.xyz, &rng_state);
+                let hit = load_surface_hit(ray);
+                let world_normal = hit.normal;
+                let world_hit_position = hit.position;
+                let material = hit.material;
+
+                let receives_shadows = instance_receives_shadows(ray.record.instance_index);
+                let direct_light = sample_direct_light(world_hit_position, world_normal, material.base_color.xyz, receives_shadows, &rng_state);
                 color += throughput * direct_light;
-                throughput *= material.base_color.xyz;
+                // Occlusion only darkens the indirect light gathered by the next bounce.
+                throughput *= material.base_color.xyz * hit.occlusion;
 
                 let p = max(max(throughput.r, throughput.g), throughput.b);
                 if rand_f(&rng_state) > p { 
@@ -135,7 +139,8 @@ fn gi(@builtin(global_invocation_id) id: vec3<u32>) {
 }
 
 // `p0`/`n0`/`base_color` are position/normal/color of point from where to sample
-fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, rng_state: ptr<function, u32>) -> vec3f {
+// Lights are never occluded if `receives_shadows` is false.
+fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, receives_shadows: bool, rng_state: ptr<function, u32>) -> vec3f {
     // let light_index = sample_light_emission_strength_cdf(rand_f(rng_state));
     let light_index = rand_range_u(scene_uniform.light_count, rng_state);
     let light_data_index = light_indices[light_index];
@@ -146,17 +151,20 @@ fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, rng_state: ptr<f
     let e1 = rand_f(rng_state);
     let e2 = rand_f(rng_state);
     let primitive_index = sample_light_triangle_area_cdf(e0, light_data_index.cdf_offset, mesh_instance.triangle_count);
-    let primitive = primitives[mesh_instance.triangle_offset + primitive_index];
+    let primitive = load_primitive(mesh_instance.triangle_offset + primitive_index);
 
     let pl_obj = sample_triangle_uniformly(e1, e2, primitive.p_first, primitive.p_second, primitive.p_third);
     let pl = pulse::utils::transform_position(mesh_instance.object_world, pl_obj);
+    let emission = light_emission(mesh_instance, primitive_index, sample_triangle_barycentrics(e1, e2));
 
     let to_light = pl - p0;
     var shadow_ray = Ray();
     shadow_ray.origin = p0 + 0.001 * n0;
     shadow_ray.dir = normalize(to_light);
     shadow_ray.record = RayHitRecord(1e30, 0u, 0u, 0.0, 0.0);
-    trace_ray(&shadow_ray);
+    if receives_shadows {
+        trace_ray(&shadow_ray, RAY_SHADOW, 0u);
+    }
 
     if shadow_ray.record.t < length(pl - shadow_ray.origin) - 0.005 {
         return vec3f(0.0);
@@ -190,8 +198,7 @@ fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, rng_state: ptr<f
     let cos_theta_receiver = dot(shadow_ray.dir, n0);
     let cos_theta_emitter = dot(-shadow_ray.dir, nl);
     let brdf = base_color * pulse::utils::INV_PI;
-    let light_material = materials[mesh_instance.material_index];
-    let direct_light = brdf * light_material.emissive.xyz * cos_theta_receiver * cos_theta_emitter / pdf / distance_sq(n0, nl);
+    let direct_light = brdf * emission * cos_theta_receiver * cos_theta_emitter / pdf / distance_sq(n0, nl);
     return max(direct_light, vec3f(0.0));
 }
 
@@ -1,4 +1,7 @@
-use crate::upscaling::{PulseUpscalingLabel, PulseUpscalingNode, PulseUpscalingPlugin};
+use crate::{
+    support::pulse_support,
+    upscaling::{PulseUpscalingLabel, PulseUpscalingNode, PulseUpscalingPlugin},
+};
 use bevy::{
     asset::load_internal_asset,
     core_pipeline::core_3d,
@@ -37,6 +40,10 @@ impl Plugin for PulseRealtimePlugin {
 
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
+        if !pulse_support(render_app).is_supported() {
+            return;
+        }
+
         render_app
             .init_resource::<PulseGILayout>()
             .init_resource::<SpecializedComputePipelines<PulseGILayout>>()
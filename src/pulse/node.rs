@@ -7,7 +7,7 @@ use bevy::{
         render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
         render_resource::*,
         renderer::{RenderContext, RenderQueue},
-        view::ViewUniformOffset,
+        view::{RenderLayers, ViewUniformOffset},
     },
 };
 
@@ -31,6 +31,7 @@ impl ViewNode for PulseNode {
         &'static ViewLightsUniformOffset,
         &'static ViewFogUniformOffset,
         &'static MeshViewBindGroup,
+        Option<&'static RenderLayers>,
     );
 
     fn update(&mut self, _world: &mut World) {}
@@ -48,6 +49,7 @@ impl ViewNode for PulseNode {
             view_lights_offset,
             view_fog_offset,
             mesh_view_bind_group,
+            render_layers,
         ): QueryItem<Self::ViewQuery>,
         world: &World,
     ) -> Result<(), NodeRunError> {
@@ -65,6 +67,7 @@ impl ViewNode for PulseNode {
         let uniform = PulseUniform {
             width: gi_render_target.width,
             height: gi_render_target.height,
+            render_layers: render_layers.copied().unwrap_or_default().bits(),
         };
 
         let uniform_buffer = create_uniform_buffer(
@@ -136,5 +139,7 @@ impl FromWorld for PulseNode {
 pub struct PulseUniform {
     width: u32,
     height: u32,
+    // Render layers of the camera, only instances on these layers are hit by GI rays.
+    render_layers: u32,
     // sample_count: u32,
 }
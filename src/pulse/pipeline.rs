@@ -1,5 +1,10 @@
 use super::{PulseCamera, PULSE_GI_SHADER_HANDLE};
-use crate::scene::PulseSceneBindGroupLayout;
+use crate::scene::{
+    buffers::PulseBufferLimits,
+    vertices::{PulseVertexEncoding, PulseVertexLayout},
+    wide_bvh::PulseBlasLayout,
+    PulseBvhSettings, PulseSceneBindGroupLayout,
+};
 use bevy::{
     core_pipeline::{
         prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
@@ -24,6 +29,7 @@ pub struct PulseGIPipeline {
 pub struct PulseGILayout {
     pub mesh_pipeline: MeshPipeline,
     pub scene_layout: BindGroupLayout,
+    pub buffer_limits: PulseBufferLimits,
     pub view_layout: BindGroupLayout,
 }
 
@@ -71,19 +77,29 @@ impl FromWorld for PulseGILayout {
 
         let mesh_pipeline = world.resource::<MeshPipeline>().clone();
         let scene_layout = world.resource::<PulseSceneBindGroupLayout>().0.clone();
+        let buffer_limits = *world.resource::<PulseBufferLimits>();
 
         Self {
             mesh_pipeline,
             scene_layout,
+            buffer_limits,
             view_layout,
         }
     }
 }
 
 impl SpecializedComputePipeline for PulseGILayout {
-    type Key = MeshPipelineKey;
-
-    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+    type Key = (
+        MeshPipelineKey,
+        PulseBlasLayout,
+        PulseVertexLayout,
+        PulseVertexEncoding,
+    );
+
+    fn specialize(
+        &self,
+        (key, blas_layout, vertex_layout, vertex_encoding): Self::Key,
+    ) -> ComputePipelineDescriptor {
         // NOTE: These shader defs aren't used by Pulse but are needed for various shader functions, eg. for unpacking the deferred texture.
         let mut shader_defs = Vec::new();
 
@@ -162,6 +178,11 @@ impl SpecializedComputePipeline for PulseGILayout {
         #[cfg(all(feature = "webgl", target_arch = "wasm32", not(feature = "webgpu")))]
         shader_defs.push("SIXTEEN_BYTE_ALIGNMENT".into());
 
+        shader_defs.extend(blas_layout.shader_defs());
+        shader_defs.extend(vertex_layout.shader_defs());
+        shader_defs.extend(vertex_encoding.shader_defs());
+        shader_defs.extend(self.buffer_limits.shader_defs());
+
         ComputePipelineDescriptor {
             label: Some("pulse_pipeline".into()),
             layout: vec![
@@ -202,6 +223,7 @@ pub fn prepare_gi_pipelines(
     layout: Res<PulseGILayout>,
     cache: Res<PipelineCache>,
     images: Res<RenderAssets<Image>>,
+    bvh_settings: Res<PulseBvhSettings>,
 ) {
     for (
         entity,
@@ -283,7 +305,16 @@ pub fn prepare_gi_pipelines(
             }
         }
 
-        let id = pipelines.specialize(&cache, &layout, mesh_view_key);
+        let id = pipelines.specialize(
+            &cache,
+            &layout,
+            (
+                mesh_view_key,
+                bvh_settings.blas_layout,
+                bvh_settings.vertex_layout,
+                bvh_settings.vertex_encoding,
+            ),
+        );
         commands.entity(entity).insert(PulseGIPipeline { id });
     }
 }
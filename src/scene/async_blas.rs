@@ -0,0 +1,179 @@
+use super::{blas::*, PulseBvhBuildSettings, PulseMesh, PulsePrimitive, PulseTriangleData};
+use bevy::{
+    prelude::*,
+    render::extract_resource::ExtractResource,
+    tasks::{block_on, poll_once, Task},
+    utils::HashMap,
+};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+// Large meshes have their BLAS built on the `AsyncComputeTaskPool` over several frames instead of blocking the render
+// schedule. A mesh is only added to `PulseMeshes`, and with that `PulseMeshIndices`, once its build is done. Until
+// then it's traced as `placeholder`. Meshes that are rebuilt, eg. after changing `PulseBvhSettings`, keep their old
+// BLAS in the meantime. Builds still in flight when the settings change are queued again once they finish.
+#[derive(Resource, ExtractResource, Clone, Debug)]
+pub struct PulseAsyncBlasSettings {
+    pub enabled: bool,
+    // Meshes with fewer triangles are built right away, since they are quick enough to not cause a hitch.
+    pub min_triangle_count: usize,
+    pub placeholder: PulseBlasPlaceholder,
+}
+
+impl Default for PulseAsyncBlasSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_triangle_count: 4096,
+            placeholder: PulseBlasPlaceholder::None,
+        }
+    }
+}
+
+impl PulseAsyncBlasSettings {
+    pub fn should_build_async(&self, triangle_count: usize) -> bool {
+        self.enabled && triangle_count >= self.min_triangle_count
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PulseBlasPlaceholder {
+    // Instances of the mesh aren't traced at all.
+    #[default]
+    None,
+    // Instances of the mesh are traced as its axis aligned bounding box.
+    BoundingBox,
+}
+
+// Number of BLAS builds still in flight. Shared between the main and render world.
+#[derive(Resource, Clone, Default)]
+pub struct PulseSceneBuildStatus(Arc<AtomicU32>);
+
+impl PulseSceneBuildStatus {
+    pub fn pending_blas_count(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.pending_blas_count() == 0
+    }
+
+    fn set_pending_blas_count(&self, count: u32) {
+        self.0.store(count, Ordering::Relaxed);
+    }
+}
+
+// Sent in the main world once all pending BLAS builds have finished.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PulseSceneBuilt;
+
+pub fn send_scene_built_events(
+    status: Res<PulseSceneBuildStatus>,
+    mut was_building: Local<bool>,
+    mut events: EventWriter<PulseSceneBuilt>,
+) {
+    let building = !status.is_built();
+    if *was_building && !building {
+        events.send(PulseSceneBuilt);
+    }
+    *was_building = building;
+}
+
+pub struct PulsePendingBlasBuild {
+    pub task: Task<PulseMesh>,
+    // Whether `PulseMeshes` holds a placeholder for the mesh, rather than nothing or an older version of it.
+    pub has_placeholder: bool,
+    // What the build was started with, so it can be queued again if the settings changed in the meantime.
+    pub builder: PulseBlasBuilder,
+    pub settings: PulseBvhBuildSettings,
+}
+
+pub struct PulseFinishedBlasBuild {
+    pub id: AssetId<Mesh>,
+    pub mesh: PulseMesh,
+    pub builder: PulseBlasBuilder,
+    pub settings: PulseBvhBuildSettings,
+}
+
+#[derive(Resource, Default)]
+pub struct PulsePendingBlasBuilds(pub HashMap<AssetId<Mesh>, PulsePendingBlasBuild>);
+
+impl PulsePendingBlasBuilds {
+    // Removes finished builds and returns their meshes.
+    pub fn take_finished(&mut self) -> Vec<PulseFinishedBlasBuild> {
+        let mut finished = vec![];
+        self.0
+            .retain(|id, build| match block_on(poll_once(&mut build.task)) {
+                Some(mesh) => {
+                    finished.push(PulseFinishedBlasBuild {
+                        id: *id,
+                        mesh,
+                        builder: build.builder,
+                        settings: build.settings,
+                    });
+                    false
+                }
+                None => true,
+            });
+        finished
+    }
+
+    pub fn publish_status(&self, status: &PulseSceneBuildStatus) {
+        status.set_pending_blas_count(self.0.len() as u32);
+    }
+}
+
+// Twelve triangles covering the bounds of `primitives`, with flat normals facing outwards.
+pub fn bounding_box_placeholder(
+    primitives: &[PulsePrimitive],
+    settings: &PulseBvhBuildSettings,
+) -> PulseMesh {
+    let mut min = Vec3::MAX;
+    let mut max = Vec3::MIN;
+    for p in primitives.iter().flat_map(|p| p.positions) {
+        min = min.min(p);
+        max = max.max(p);
+    }
+    if primitives.is_empty() {
+        min = Vec3::ZERO;
+        max = Vec3::ZERO;
+    }
+
+    let corner = |i: u32| Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min);
+    // Two counter-clockwise triangles per face, seen from outside.
+    const FACES: [([u32; 4], Vec3); 6] = [
+        ([0, 4, 6, 2], Vec3::NEG_X),
+        ([1, 3, 7, 5], Vec3::X),
+        ([0, 1, 5, 4], Vec3::NEG_Y),
+        ([2, 6, 7, 3], Vec3::Y),
+        ([0, 2, 3, 1], Vec3::NEG_Z),
+        ([4, 5, 7, 6], Vec3::Z),
+    ];
+    let mut box_primitives = vec![];
+    let mut triangle_data = vec![];
+    let mut indices = vec![];
+    for (quad, normal) in FACES.iter() {
+        for triangle in [[quad[0], quad[1], quad[2]], [quad[0], quad[2], quad[3]]] {
+            box_primitives.push(PulsePrimitive {
+                positions: triangle.map(corner),
+            });
+            triangle_data.push(PulseTriangleData {
+                normals: [*normal; 3],
+                tangents: [Vec4::ZERO; 3],
+                uvs: [Vec2::ZERO; 3],
+            });
+            indices.extend(triangle);
+        }
+    }
+
+    let bvh = build_blas(&box_primitives, settings);
+    PulseMesh {
+        primitives: box_primitives,
+        triangle_data,
+        built_sah_cost: bvh.sah_cost(),
+        bvh,
+        indices,
+    }
+}
@@ -10,10 +10,32 @@
     Material,
     LightDataIndex,
 }
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+#import pulse::scene::types::PackedTriangleData
+#endif
+
+// Element types of the paged bindings, which depend on `PulseVertexLayout` and `PulseVertexEncoding`.
+#ifdef PULSE_INDEXED_VERTICES
+// Three floats per vertex, or a single u32 for compact normals. Triangles index them through `vertex_indices`.
+alias PrimitiveElement = f32;
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+alias TriangleDataElement = u32;
+#else
+alias TriangleDataElement = f32;
+#endif
+#else
+alias PrimitiveElement = Primitive;
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+alias TriangleDataElement = PackedTriangleData;
+#else
+alias TriangleDataElement = TriangleData;
+#endif
+#endif
 
 @group(0) @binding(0) var<uniform> scene_uniform: SceneUniform;
-@group(0) @binding(1) var<storage> primitives: array<Primitive>;
-@group(0) @binding(2) var<storage> triangle_data: array<TriangleData>;
+// Vertex positions and normals with `PulseVertexLayout::Indexed`.
+@group(0) @binding(1) var<storage> primitives: array<PrimitiveElement>;
+@group(0) @binding(2) var<storage> triangle_data: array<TriangleDataElement>;
 @group(0) @binding(3) var<storage> triangle_indices: array<u32>;
 @group(0) @binding(4) var<storage> blas_nodes: array<BLASNode>;
 @group(0) @binding(5) var<storage> tlas_nodes: array<TLASNode>;
@@ -26,4 +48,104 @@
 @group(0) @binding(10) var<storage> light_triangle_area_cdfs: array<f32>;
 @group(0) @binding(11) var<storage> light_mesh_areas: array<f32>;
 @group(0) @binding(12) var<storage> light_indices: array<LightDataIndex>;
+#ifdef PULSE_INDEXED_VERTICES
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+@group(0) @binding(13) var<storage> vertex_uvs: array<u32>;
+#else
+@group(0) @binding(13) var<storage> vertex_uvs: array<vec2<f32>>;
+#endif
+@group(0) @binding(14) var<storage> vertex_indices: array<u32>;
+#endif
+// Layers of packed material textures, see `PulseTextureAtlas`.
+@group(0) @binding(24) var texture_atlas: texture_2d_array<f32>;
+@group(0) @binding(25) var texture_atlas_sampler: sampler;
+// Directional albedos of the layered BSDF, see `PulseEnergyTable`.
+@group(0) @binding(26) var energy_table: texture_3d<f32>;
+
+// Extra pages of `primitives`, `triangle_data` and `blas_nodes` for scenes that don't fit into a single binding, see
+// `page_binding` in buffers.rs.
+#if PULSE_BUFFER_PAGES > 1
+@group(0) @binding(15) var<storage> primitives_1: array<PrimitiveElement>;
+@group(0) @binding(18) var<storage> triangle_data_1: array<TriangleDataElement>;
+@group(0) @binding(21) var<storage> blas_nodes_1: array<BLASNode>;
+#endif
+#if PULSE_BUFFER_PAGES > 2
+@group(0) @binding(16) var<storage> primitives_2: array<PrimitiveElement>;
+@group(0) @binding(19) var<storage> triangle_data_2: array<TriangleDataElement>;
+@group(0) @binding(22) var<storage> blas_nodes_2: array<BLASNode>;
+#endif
+#if PULSE_BUFFER_PAGES > 3
+@group(0) @binding(17) var<storage> primitives_3: array<PrimitiveElement>;
+@group(0) @binding(20) var<storage> triangle_data_3: array<TriangleDataElement>;
+@group(0) @binding(23) var<storage> blas_nodes_3: array<BLASNode>;
+#endif
+
+// Element `i` of the paged bindings, counted over all pages.
+fn load_primitive_element(i: u32) -> PrimitiveElement {
+#if PULSE_BUFFER_PAGES > 1
+    let page = i / scene_uniform.primitive_page_size;
+    let j = i - page * scene_uniform.primitive_page_size;
+    if page == 1u {
+        return primitives_1[j];
+    }
+#if PULSE_BUFFER_PAGES > 2
+    if page == 2u {
+        return primitives_2[j];
+    }
+#endif
+#if PULSE_BUFFER_PAGES > 3
+    if page == 3u {
+        return primitives_3[j];
+    }
+#endif
+    return primitives[j];
+#else
+    return primitives[i];
+#endif
+}
 
+fn load_triangle_data_element(i: u32) -> TriangleDataElement {
+#if PULSE_BUFFER_PAGES > 1
+    let page = i / scene_uniform.triangle_data_page_size;
+    let j = i - page * scene_uniform.triangle_data_page_size;
+    if page == 1u {
+        return triangle_data_1[j];
+    }
+#if PULSE_BUFFER_PAGES > 2
+    if page == 2u {
+        return triangle_data_2[j];
+    }
+#endif
+#if PULSE_BUFFER_PAGES > 3
+    if page == 3u {
+        return triangle_data_3[j];
+    }
+#endif
+    return triangle_data[j];
+#else
+    return triangle_data[i];
+#endif
+}
+
+fn load_blas_node(i: u32) -> BLASNode {
+#if PULSE_BUFFER_PAGES > 1
+    let page = i / scene_uniform.blas_node_page_size;
+    let j = i - page * scene_uniform.blas_node_page_size;
+    if page == 1u {
+        return blas_nodes_1[j];
+    }
+#if PULSE_BUFFER_PAGES > 2
+    if page == 2u {
+        return blas_nodes_2[j];
+    }
+#endif
+#if PULSE_BUFFER_PAGES > 3
+    if page == 3u {
+        return blas_nodes_3[j];
+    }
+#endif
+    return blas_nodes[j];
+#else
+    return blas_nodes[i];
+#endif
+}
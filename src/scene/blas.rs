@@ -1,8 +1,12 @@
-use super::PulsePrimitive;
+use super::{sbvh::build_sbvh, PulseBvhBuildSettings, PulsePrimitive};
 use crate::utilities::*;
-use bevy::{prelude::*, render::render_resource::ShaderType};
+use bevy::{
+    prelude::*,
+    render::render_resource::ShaderType,
+    tasks::{ComputeTaskPool, TaskPool},
+};
 
-#[derive(Default, ShaderType, Clone, Debug)]
+#[derive(Default, ShaderType, Clone, Debug, PartialEq)]
 pub struct PulseBLASNode {
     pub aabb_min: Vec3,
     // Index to child a or to first triangle.
@@ -12,55 +16,87 @@ pub struct PulseBLASNode {
     pub tri_count: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PulseBlasBuilder {
+    // Binned SAH on triangle centroids. Fast to build.
+    Binned,
+    // Spatial split BVH. Slower to build but gives tighter nodes for meshes with long thin triangles.
+    // `max_duplication` is the fraction of extra triangle references allowed, eg. 0.3 for at most 30% more.
+    SpatialSplit { max_duplication: f32 },
+}
+
+impl Default for PulseBlasBuilder {
+    fn default() -> Self {
+        Self::Binned
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Blas {
     pub nodes: Vec<PulseBLASNode>,
     pub tri_indices: Vec<u32>,
 }
 
-#[derive(Default, Copy, Clone)]
+#[derive(Copy, Clone)]
 pub struct AABB {
     pub min: Vec3,
     pub max: Vec3,
 }
 
+impl Default for AABB {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
 impl AABB {
+    // Inverted bounds that any grow operation will overwrite.
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::MAX,
+            max: Vec3::MIN,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.min.cmple(self.max).all()
+    }
+
+    pub fn intersection(&self, other: &AABB) -> AABB {
+        AABB {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
     pub fn grow_position(&mut self, p: Vec3) {
         self.min = self.min.min(p);
         self.max = self.max.max(p);
     }
 
     pub fn grow_aabb(&mut self, aabb: AABB) {
+        if !aabb.is_valid() {
+            return;
+        }
         self.grow_position(aabb.min);
         self.grow_position(aabb.max);
     }
 
     pub fn area(&self) -> f32 {
+        if !self.is_valid() {
+            return 0.0;
+        }
         let e = self.max - self.min;
         e.x * e.y + e.y * e.z + e.z * e.x
     }
 }
 
-pub fn build_blas(prims: &Vec<PulsePrimitive>) -> Blas {
-    let mut tri_indices: Vec<usize> = vec![];
-    let mut centroids: Vec<Vec3> = vec![];
-    for i in 0..prims.len() {
-        let mut bounds_min = Vec3::MAX;
-        let mut bounds_max = Vec3::MIN;
-
-        bounds_min = bounds_min.min(prims[i].positions[0]);
-        bounds_min = bounds_min.min(prims[i].positions[1]);
-        bounds_min = bounds_min.min(prims[i].positions[2]);
-
-        bounds_max = bounds_max.max(prims[i].positions[0]);
-        bounds_max = bounds_max.max(prims[i].positions[1]);
-        bounds_max = bounds_max.max(prims[i].positions[2]);
+// Nodes with at least this many triangles are split using tasks on the `ComputeTaskPool`. Below that the overhead
+// of spawning tasks outweighs the gain.
+const PARALLEL_BUILD_THRESHOLD: u32 = 4096;
 
-        let center = bounds_min + 0.5 * (bounds_max - bounds_min);
-        centroids.push(center);
-
-        tri_indices.push(i);
-    }
+pub fn build_blas(prims: &Vec<PulsePrimitive>, settings: &PulseBvhBuildSettings) -> Blas {
+    let (mut tri_indices, centroids) = prepare_build_data(prims);
     // for i in 0..prims.len() {
     //     centroids
     //         .push((prims[i].positions[0] + prims[i].positions[1] + prims[i].positions[2]) * 0.3333);
@@ -74,7 +110,7 @@ pub fn build_blas(prims: &Vec<PulsePrimitive>) -> Blas {
     calculate_node_aabb(&mut root, prims, &tri_indices);
     nodes.push(root);
 
-    subdivide(0, &mut nodes, prims, &centroids, &mut tri_indices);
+    subdivide(0, &mut nodes, prims, &centroids, &mut tri_indices, settings);
 
     // Ugly fix. Should already use u32
     let tri_indices = tri_indices.iter().map(|i| *i as u32).collect::<Vec<u32>>();
@@ -86,42 +122,99 @@ pub fn build_blas(prims: &Vec<PulsePrimitive>) -> Blas {
     Blas { nodes, tri_indices }
 }
 
+pub fn build_blas_with_builder(
+    prims: &Vec<PulsePrimitive>,
+    builder: PulseBlasBuilder,
+    settings: &PulseBvhBuildSettings,
+) -> Blas {
+    match builder {
+        PulseBlasBuilder::Binned => build_blas_parallel(prims, settings),
+        PulseBlasBuilder::SpatialSplit { max_duplication } => {
+            build_sbvh(prims, max_duplication, settings)
+        }
+    }
+}
+
+// Same as `build_blas` but large nodes are binned per axis and have their two child subtrees built as separate tasks
+// on the `ComputeTaskPool`. Produces the exact same `Blas` as the serial build.
+pub fn build_blas_parallel(prims: &Vec<PulsePrimitive>, settings: &PulseBvhBuildSettings) -> Blas {
+    let (mut tri_indices, centroids) = prepare_build_data(prims);
+
+    let mut root = PulseBLASNode::default();
+    root.a_or_first_tri = 0;
+    root.tri_count = tri_indices.len() as u32;
+    calculate_node_aabb(&mut root, prims, &tri_indices);
+
+    let mut descendants =
+        subdivide_parallel(&mut root, prims, &centroids, &mut tri_indices, settings);
+    // Everything below the root is placed right after it. An unsplit root is a leaf, even if it has no triangles.
+    if !descendants.is_empty() {
+        offset_child_indices(&mut root, 1);
+        offset_child_indices_all(&mut descendants, 1);
+    }
+
+    let mut nodes = Vec::with_capacity(descendants.len() + 1);
+    nodes.push(root);
+    nodes.append(&mut descendants);
+
+    let tri_indices = tri_indices.iter().map(|i| *i as u32).collect::<Vec<u32>>();
+
+    Blas { nodes, tri_indices }
+}
+
+// Returns (tri_indices, centroids)
+fn prepare_build_data(prims: &Vec<PulsePrimitive>) -> (Vec<usize>, Vec<Vec3>) {
+    let mut tri_indices: Vec<usize> = vec![];
+    let mut centroids: Vec<Vec3> = vec![];
+    for i in 0..prims.len() {
+        let mut bounds_min = Vec3::MAX;
+        let mut bounds_max = Vec3::MIN;
+
+        bounds_min = bounds_min.min(prims[i].positions[0]);
+        bounds_min = bounds_min.min(prims[i].positions[1]);
+        bounds_min = bounds_min.min(prims[i].positions[2]);
+
+        bounds_max = bounds_max.max(prims[i].positions[0]);
+        bounds_max = bounds_max.max(prims[i].positions[1]);
+        bounds_max = bounds_max.max(prims[i].positions[2]);
+
+        let center = bounds_min + 0.5 * (bounds_max - bounds_min);
+        centroids.push(center);
+
+        tri_indices.push(i);
+    }
+
+    (tri_indices, centroids)
+}
+
 pub fn subdivide(
     node_idx: usize,
     nodes: &mut Vec<PulseBLASNode>,
     prims: &Vec<PulsePrimitive>,
     centroids: &Vec<Vec3>,
     tri_indices: &mut Vec<usize>,
+    settings: &PulseBvhBuildSettings,
 ) {
-    if nodes[node_idx].tri_count <= 8 {
+    if nodes[node_idx].tri_count <= settings.max_leaf_size {
         return;
     }
 
-    let (axis, split_position, _split_cost) =
-        find_best_split_plane(&nodes[node_idx], prims, centroids, tri_indices);
+    let first = nodes[node_idx].a_or_first_tri as usize;
+    let count = nodes[node_idx].tri_count as usize;
+    let node_tri_indices = &mut tri_indices[first..(first + count)];
 
-    // let no_split_cost = calculate_node_cost(&nodes[node_idx]);
-    // if split_cost >= no_split_cost {
-    //     // warn!(
-    //     //     "aborting. split: {}, no_split: {}, ",
-    //     //     split_cost,
-    //     //     no_split_cost
-    //     // );
-    //     return;
-    // }
+    let (axis, split_position, split_cost) =
+        find_best_split_plane(prims, centroids, node_tri_indices, settings);
 
-    let mut i = nodes[node_idx].a_or_first_tri;
-    let mut j = i + nodes[node_idx].tri_count - 1;
-    while i <= j {
-        if centroids[tri_indices[i as usize]][axis] < split_position {
-            i += 1;
-        } else {
-            swap(tri_indices, i as usize, j as usize);
-            j -= 1;
-        }
+    if !settings.is_split_worth_it(
+        calculate_node_area(&nodes[node_idx]),
+        nodes[node_idx].tri_count,
+        split_cost,
+    ) {
+        return;
     }
 
-    let a_count = i - nodes[node_idx].a_or_first_tri;
+    let a_count = partition(node_tri_indices, centroids, axis, split_position);
     // Don't split the nodes[node_idx] if either one of it's children contain no primitives.
     if a_count == 0 || a_count == nodes[node_idx].tri_count {
         return;
@@ -135,7 +228,7 @@ pub fn subdivide(
     nodes.push(child_a);
 
     let mut child_b = PulseBLASNode::default();
-    child_b.a_or_first_tri = i;
+    child_b.a_or_first_tri = nodes[node_idx].a_or_first_tri + a_count;
     child_b.tri_count = nodes[node_idx].tri_count - a_count;
     calculate_node_aabb(&mut child_b, prims, tri_indices);
     nodes.push(child_b);
@@ -150,6 +243,7 @@ pub fn subdivide(
         prims,
         centroids,
         tri_indices,
+        settings,
     );
     subdivide(
         nodes[node_idx].a_or_first_tri as usize + 1,
@@ -157,105 +251,337 @@ pub fn subdivide(
         prims,
         centroids,
         tri_indices,
+        settings,
     );
 }
 
+// Splits `node` and returns all nodes below it in the same order as `subdivide` would push them: both children first,
+// then the subtree of child a, then the subtree of child b. Child indices of the returned nodes (and of `node`) are
+// relative to the start of the returned vec and have to be offset by the caller.
+// `tri_indices` only contains the triangles of `node`, while `a_or_first_tri` is still an index into the full array.
+fn subdivide_parallel(
+    node: &mut PulseBLASNode,
+    prims: &Vec<PulsePrimitive>,
+    centroids: &Vec<Vec3>,
+    tri_indices: &mut [usize],
+    settings: &PulseBvhBuildSettings,
+) -> Vec<PulseBLASNode> {
+    if node.tri_count <= settings.max_leaf_size {
+        return vec![];
+    }
+
+    let (axis, split_position, split_cost) = if node.tri_count >= PARALLEL_BUILD_THRESHOLD {
+        find_best_split_plane_parallel(prims, centroids, tri_indices, settings)
+    } else {
+        find_best_split_plane(prims, centroids, tri_indices, settings)
+    };
+
+    if !settings.is_split_worth_it(calculate_node_area(node), node.tri_count, split_cost) {
+        return vec![];
+    }
+
+    let a_count = partition(tri_indices, centroids, axis, split_position);
+    // Don't split the node if either one of it's children contain no primitives.
+    if a_count == 0 || a_count == node.tri_count {
+        return vec![];
+    }
+
+    let (tri_indices_a, tri_indices_b) = tri_indices.split_at_mut(a_count as usize);
+
+    let mut child_a = PulseBLASNode::default();
+    child_a.a_or_first_tri = node.a_or_first_tri;
+    child_a.tri_count = a_count;
+    calculate_node_aabb_local(&mut child_a, prims, tri_indices_a);
+
+    let mut child_b = PulseBLASNode::default();
+    child_b.a_or_first_tri = node.a_or_first_tri + a_count;
+    child_b.tri_count = node.tri_count - a_count;
+    calculate_node_aabb_local(&mut child_b, prims, tri_indices_b);
+
+    let tri_count = node.tri_count;
+    // Node is not a leaf anymore. Its children are at the start of the returned vec.
+    node.a_or_first_tri = 0;
+    node.tri_count = 0;
+
+    let (mut descendants_a, mut descendants_b) = if tri_count >= PARALLEL_BUILD_THRESHOLD {
+        let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        let mut results = pool.scope(|s| {
+            let child_a = &mut child_a;
+            let child_b = &mut child_b;
+            s.spawn(async move {
+                subdivide_parallel(child_a, prims, centroids, tri_indices_a, settings)
+            });
+            s.spawn(async move {
+                subdivide_parallel(child_b, prims, centroids, tri_indices_b, settings)
+            });
+        });
+        let descendants_b = results.pop().unwrap();
+        let descendants_a = results.pop().unwrap();
+        (descendants_a, descendants_b)
+    } else {
+        (
+            subdivide_parallel(&mut child_a, prims, centroids, tri_indices_a, settings),
+            subdivide_parallel(&mut child_b, prims, centroids, tri_indices_b, settings),
+        )
+    };
+
+    // Layout: [child_a, child_b, descendants_a.., descendants_b..]
+    let offset_a = 2;
+    let offset_b = offset_a + descendants_a.len() as u32;
+    offset_child_indices(&mut child_a, offset_a);
+    offset_child_indices_all(&mut descendants_a, offset_a);
+    offset_child_indices(&mut child_b, offset_b);
+    offset_child_indices_all(&mut descendants_b, offset_b);
+
+    let mut nodes = Vec::with_capacity(2 + descendants_a.len() + descendants_b.len());
+    nodes.push(child_a);
+    nodes.push(child_b);
+    nodes.append(&mut descendants_a);
+    nodes.append(&mut descendants_b);
+    nodes
+}
+
+// Only interior nodes point to other nodes. Leaf nodes point into `tri_indices` and are left untouched.
+fn offset_child_indices(node: &mut PulseBLASNode, offset: u32) {
+    if node.tri_count == 0 {
+        node.a_or_first_tri += offset;
+    }
+}
+
+fn offset_child_indices_all(nodes: &mut [PulseBLASNode], offset: u32) {
+    for node in nodes.iter_mut() {
+        offset_child_indices(node, offset);
+    }
+}
+
+// Moves all triangles with a centroid below `split_position` on `axis` to the front of `tri_indices`.
+// Returns the number of triangles that were moved there.
+fn partition(
+    tri_indices: &mut [usize],
+    centroids: &Vec<Vec3>,
+    axis: usize,
+    split_position: f32,
+) -> u32 {
+    let mut i = 0;
+    let mut j = tri_indices.len() as i64 - 1;
+    while i as i64 <= j {
+        if centroids[tri_indices[i]][axis] < split_position {
+            i += 1;
+        } else {
+            swap(tri_indices, i, j as usize);
+            j -= 1;
+        }
+    }
+    i as u32
+}
+
+impl Blas {
+    // Surface area heuristic cost of the whole tree. Useful for comparing the quality of different builds.
+    pub fn sah_cost(&self) -> f32 {
+        if self.nodes.is_empty() || self.tri_indices.is_empty() {
+            return 0.0;
+        }
+
+        let root_area = calculate_node_area(&self.nodes[0]);
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+
+        let mut cost = 0.0;
+        for node in self.nodes.iter() {
+            let relative_area = calculate_node_area(node) / root_area;
+            if node.tri_count > 0 {
+                cost += relative_area * node.tri_count as f32;
+            } else {
+                // Interior nodes cost one box test per child.
+                cost += relative_area * 2.0;
+            }
+        }
+        cost
+    }
+}
+
 #[derive(Default, Copy, Clone)]
 struct Bin {
     bounds: AABB,
     tri_count: u32,
 }
 
+// Recomputes node bounds for moved triangles while keeping the tree topology, which is much cheaper than
+// a rebuild. Only valid if `prims` has the same triangles, in the same order, as the tree was built with.
+pub fn refit_blas(bvh: &mut Blas, prims: &Vec<PulsePrimitive>) {
+    // Children are always stored after their parent, so walking backwards visits them first.
+    for node_idx in (0..bvh.nodes.len()).rev() {
+        let node = &bvh.nodes[node_idx];
+        let mut aabb = AABB::empty();
+        if node.tri_count > 0 || bvh.nodes.len() == 1 {
+            for i in 0..node.tri_count {
+                let tri_index = bvh.tri_indices[(node.a_or_first_tri + i) as usize] as usize;
+                for p in prims[tri_index].positions.iter() {
+                    aabb.grow_position(*p);
+                }
+            }
+        } else {
+            let a = &bvh.nodes[node.a_or_first_tri as usize];
+            let b = &bvh.nodes[node.a_or_first_tri as usize + 1];
+            aabb.grow_aabb(AABB {
+                min: a.aabb_min,
+                max: a.aabb_max,
+            });
+            aabb.grow_aabb(AABB {
+                min: b.aabb_min,
+                max: b.aabb_max,
+            });
+        }
+        let node = &mut bvh.nodes[node_idx];
+        node.aabb_min = aabb.min;
+        node.aabb_max = aabb.max;
+    }
+}
+
 // Returns (axis, position, cost)
+// `tri_indices` only contains the triangles of the node being split.
 fn find_best_split_plane(
-    node: &PulseBLASNode,
     prims: &Vec<PulsePrimitive>,
     centroids: &Vec<Vec3>,
-    tri_indices: &Vec<usize>,
+    tri_indices: &[usize],
+    settings: &PulseBvhBuildSettings,
 ) -> (usize, f32, f32) {
     let mut best_axis = 0;
     let mut best_position = 0.0;
     let mut best_cost = 1e30;
     for axis in 0..3 {
-        let mut bounds_min: f32 = 1e30;
-        let mut bounds_max: f32 = -1e30;
-        for i in 0..node.tri_count {
-            bounds_min =
-                bounds_min.min(centroids[tri_indices[(node.a_or_first_tri + i) as usize]][axis]);
-            bounds_max =
-                bounds_max.max(centroids[tri_indices[(node.a_or_first_tri + i) as usize]][axis]);
-        }
-        if bounds_min == bounds_max {
+        let Some((position, cost)) =
+            find_best_split_plane_on_axis(axis, prims, centroids, tri_indices, settings)
+        else {
             continue;
+        };
+        if cost < best_cost {
+            best_axis = axis;
+            best_position = position;
+            best_cost = cost;
         }
+    }
 
-        // Create bins
-        const BIN_COUNT: usize = 20;
-        let mut bins: [Bin; BIN_COUNT] = [Bin::default(); BIN_COUNT];
-        let bin_size_inv = BIN_COUNT as f32 / (bounds_max - bounds_min);
-        for i in 0..node.tri_count {
-            let triangle = &prims[tri_indices[(node.a_or_first_tri + i) as usize]];
-            let bin_idx = (BIN_COUNT - 1).min(
-                ((centroids[tri_indices[(node.a_or_first_tri + i) as usize]][axis] - bounds_min)
-                    * bin_size_inv) as usize,
-            );
-            bins[bin_idx].tri_count += 1;
-            bins[bin_idx].bounds.grow_position(triangle.positions[0]);
-            bins[bin_idx].bounds.grow_position(triangle.positions[1]);
-            bins[bin_idx].bounds.grow_position(triangle.positions[2]);
-        }
+    (best_axis, best_position, best_cost)
+}
 
-        // Calculate bin data
-        let mut area_a = [0.0; BIN_COUNT - 1];
-        let mut area_b = [0.0; BIN_COUNT - 1];
-        let mut count_a = [0u32; BIN_COUNT - 1];
-        let mut count_b = [0u32; BIN_COUNT - 1];
-        let mut box_a = AABB::default();
-        let mut box_b = AABB::default();
-        let mut sum_a = 0;
-        let mut sum_b = 0;
-        for i in 0..(BIN_COUNT - 1) {
-            sum_a += bins[i].tri_count;
-            count_a[i] = sum_a;
-            box_a.grow_aabb(bins[i].bounds);
-            area_a[i] = box_a.area();
-
-            sum_b += bins[BIN_COUNT - 1 - i].tri_count;
-            count_b[BIN_COUNT - 2 - i] = sum_b;
-            box_b.grow_aabb(bins[BIN_COUNT - 1 - i].bounds);
-            area_b[BIN_COUNT - 2 - i] = box_b.area();
+// Same as `find_best_split_plane` but bins each axis in a separate task.
+// Axes are compared in the same order as the serial version so ties resolve identically.
+fn find_best_split_plane_parallel(
+    prims: &Vec<PulsePrimitive>,
+    centroids: &Vec<Vec3>,
+    tri_indices: &[usize],
+    settings: &PulseBvhBuildSettings,
+) -> (usize, f32, f32) {
+    let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+    let axis_results = pool.scope(|s| {
+        for axis in 0..3 {
+            s.spawn(async move {
+                find_best_split_plane_on_axis(axis, prims, centroids, tri_indices, settings)
+            });
         }
+    });
 
-        let bin_size = (bounds_max - bounds_min) / BIN_COUNT as f32;
-        for i in 0..(BIN_COUNT - 1) {
-            let plane_cost = count_a[i] as f32 * area_a[i] + count_b[i] as f32 * area_b[i];
-            if plane_cost < best_cost {
-                best_axis = axis;
-                best_position = bounds_min + bin_size * (i + 1) as f32;
-                best_cost = plane_cost;
-            }
+    let mut best_axis = 0;
+    let mut best_position = 0.0;
+    let mut best_cost = 1e30;
+    for (axis, result) in axis_results.into_iter().enumerate() {
+        let Some((position, cost)) = result else {
+            continue;
+        };
+        if cost < best_cost {
+            best_axis = axis;
+            best_position = position;
+            best_cost = cost;
         }
-
-        // let num_steps = 10;
-        // let step_size = (bounds_max - bounds_min) / num_steps as f32;
-        // for step in 1..num_steps {
-        //     let candidate_position = bounds_min + step as f32 * step_size;
-        //     let cost = evaluate_sah(node, axis, candidate_position, tris, centroids, tri_indices);
-        //     if cost < best_cost {
-        //         best_position = candidate_position;
-        //         best_axis = axis;
-        //         best_cost = cost;
-        //     }
-        // }
     }
 
     (best_axis, best_position, best_cost)
 }
 
-fn _calculate_node_cost(node: &PulseBLASNode) -> f32 {
+// Returns (position, cost) of the best split plane on `axis`, or `None` if all centroids lie in the same plane.
+fn find_best_split_plane_on_axis(
+    axis: usize,
+    prims: &Vec<PulsePrimitive>,
+    centroids: &Vec<Vec3>,
+    tri_indices: &[usize],
+    settings: &PulseBvhBuildSettings,
+) -> Option<(f32, f32)> {
+    let mut bounds_min: f32 = 1e30;
+    let mut bounds_max: f32 = -1e30;
+    for tri_index in tri_indices.iter() {
+        bounds_min = bounds_min.min(centroids[*tri_index][axis]);
+        bounds_max = bounds_max.max(centroids[*tri_index][axis]);
+    }
+    if bounds_min == bounds_max {
+        return None;
+    }
+
+    // Create bins
+    let bin_count = settings.bin_count.max(2);
+    let mut bins = vec![Bin::default(); bin_count];
+    let bin_size_inv = bin_count as f32 / (bounds_max - bounds_min);
+    for tri_index in tri_indices.iter() {
+        let triangle = &prims[*tri_index];
+        let bin_idx = (bin_count - 1)
+            .min(((centroids[*tri_index][axis] - bounds_min) * bin_size_inv) as usize);
+        bins[bin_idx].tri_count += 1;
+        bins[bin_idx].bounds.grow_position(triangle.positions[0]);
+        bins[bin_idx].bounds.grow_position(triangle.positions[1]);
+        bins[bin_idx].bounds.grow_position(triangle.positions[2]);
+    }
+
+    // Calculate bin data
+    let mut area_a = vec![0.0; bin_count - 1];
+    let mut area_b = vec![0.0; bin_count - 1];
+    let mut count_a = vec![0u32; bin_count - 1];
+    let mut count_b = vec![0u32; bin_count - 1];
+    let mut box_a = AABB::default();
+    let mut box_b = AABB::default();
+    let mut sum_a = 0;
+    let mut sum_b = 0;
+    for i in 0..(bin_count - 1) {
+        sum_a += bins[i].tri_count;
+        count_a[i] = sum_a;
+        box_a.grow_aabb(bins[i].bounds);
+        area_a[i] = box_a.area();
+
+        sum_b += bins[bin_count - 1 - i].tri_count;
+        count_b[bin_count - 2 - i] = sum_b;
+        box_b.grow_aabb(bins[bin_count - 1 - i].bounds);
+        area_b[bin_count - 2 - i] = box_b.area();
+    }
+
+    let mut best_position = 0.0;
+    let mut best_cost = 1e30;
+    let bin_size = (bounds_max - bounds_min) / bin_count as f32;
+    for i in 0..(bin_count - 1) {
+        let plane_cost = count_a[i] as f32 * area_a[i] + count_b[i] as f32 * area_b[i];
+        if plane_cost < best_cost {
+            best_position = bounds_min + bin_size * (i + 1) as f32;
+            best_cost = plane_cost;
+        }
+    }
+
+    // let num_steps = 10;
+    // let step_size = (bounds_max - bounds_min) / num_steps as f32;
+    // for step in 1..num_steps {
+    //     let candidate_position = bounds_min + step as f32 * step_size;
+    //     let cost = evaluate_sah(node, axis, candidate_position, tris, centroids, tri_indices);
+    //     if cost < best_cost {
+    //         best_position = candidate_position;
+    //         best_axis = axis;
+    //         best_cost = cost;
+    //     }
+    // }
+
+    Some((best_position, best_cost))
+}
+
+fn calculate_node_area(node: &PulseBLASNode) -> f32 {
     let e = node.aabb_max - node.aabb_min;
-    let area = e.x * e.y + e.y * e.z + e.z * e.x;
-    node.tri_count as f32 * area
+    e.x * e.y + e.y * e.z + e.z * e.x
 }
 
 fn _evaluate_sah(
@@ -312,3 +638,98 @@ fn calculate_node_aabb(
         node.aabb_max = node.aabb_max.max(prims[tri_index].positions[2]);
     }
 }
+
+// Same as `calculate_node_aabb` but `tri_indices` only contains the triangles of `node`.
+fn calculate_node_aabb_local(
+    node: &mut PulseBLASNode,
+    prims: &Vec<PulsePrimitive>,
+    tri_indices: &[usize],
+) {
+    node.aabb_min = Vec3::MAX;
+    node.aabb_max = Vec3::MIN;
+    for tri_index in tri_indices.iter() {
+        node.aabb_min = node.aabb_min.min(prims[*tri_index].positions[0]);
+        node.aabb_min = node.aabb_min.min(prims[*tri_index].positions[1]);
+        node.aabb_min = node.aabb_min.min(prims[*tri_index].positions[2]);
+
+        node.aabb_max = node.aabb_max.max(prims[*tri_index].positions[0]);
+        node.aabb_max = node.aabb_max.max(prims[*tri_index].positions[1]);
+        node.aabb_max = node.aabb_max.max(prims[*tri_index].positions[2]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_meshes::*, PulseBvhSettings};
+    use super::*;
+
+    #[test]
+    fn parallel_build_matches_serial_build() {
+        let settings = PulseBvhSettings::default().blas;
+        let mut largest = 0;
+        for file in BUNDLED_GLB_FILES {
+            for (name, prims) in load_glb_primitives(file) {
+                let serial = build_blas(&prims, &settings);
+                let parallel = build_blas_parallel(&prims, &settings);
+                assert!(serial.nodes == parallel.nodes, "{}: nodes differ", name);
+                assert!(
+                    serial.tri_indices == parallel.tri_indices,
+                    "{}: triangle indices differ",
+                    name
+                );
+                assert_eq!(serial.sah_cost(), parallel.sah_cost(), "{}", name);
+                largest = largest.max(prims.len());
+            }
+        }
+        // Otherwise the parallel paths were never taken.
+        assert!(largest >= PARALLEL_BUILD_THRESHOLD as usize);
+    }
+
+    // Every leaf has to bound its triangles exactly and every interior node its children.
+    fn assert_tight_bounds(prims: &[PulsePrimitive], bvh: &Blas) {
+        for node in bvh.nodes.iter() {
+            let mut aabb = AABB::empty();
+            let first = node.a_or_first_tri as usize;
+            if node.tri_count > 0 {
+                for tri_index in &bvh.tri_indices[first..first + node.tri_count as usize] {
+                    for p in prims[*tri_index as usize].positions {
+                        aabb.grow_position(p);
+                    }
+                }
+            } else {
+                for child in &bvh.nodes[first..first + 2] {
+                    aabb.grow_position(child.aabb_min);
+                    aabb.grow_position(child.aabb_max);
+                }
+            }
+            assert_eq!(node.aabb_min, aabb.min);
+            assert_eq!(node.aabb_max, aabb.max);
+        }
+    }
+
+    #[test]
+    fn refit_matches_moved_triangles() {
+        let settings = PulseBvhSettings::default().blas;
+        let (_, mut prims) = load_glb_primitives("monkey_smooth.glb").remove(0);
+        let mut bvh = build_blas(&prims, &settings);
+        let built = bvh.nodes.clone();
+
+        // Nothing moved, so nothing changes.
+        refit_blas(&mut bvh, &prims);
+        assert!(bvh.nodes == built);
+
+        for prim in prims.iter_mut() {
+            for p in prim.positions.iter_mut() {
+                *p += Vec3::new(0.0, (p.x * 4.0).sin() * 0.3, p.y * p.y * 0.5);
+            }
+        }
+        let tri_indices = bvh.tri_indices.clone();
+        refit_blas(&mut bvh, &prims);
+        assert_eq!(bvh.tri_indices, tri_indices);
+        for (node, built) in bvh.nodes.iter().zip(built.iter()) {
+            assert_eq!(node.a_or_first_tri, built.a_or_first_tri);
+            assert_eq!(node.tri_count, built.tri_count);
+        }
+        assert_tight_bounds(&prims, &bvh);
+    }
+}
@@ -0,0 +1,337 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            encase::{internal::WriteInto, StorageBuffer},
+            BindingResource, Buffer, BufferDescriptor, BufferUsages, ShaderDefVal, ShaderSize,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+use std::ops::Range;
+
+// Uploads are diffed in blocks of this many bytes. Must be a multiple of `wgpu::COPY_BUFFER_ALIGNMENT`.
+const DIFF_BLOCK_SIZE: usize = 256;
+const MIN_CAPACITY: u64 = 256;
+
+// Most pages a paged buffer can be split into, each with its own binding.
+pub const PULSE_MAX_BUFFER_PAGES: u32 = 4;
+// Primitives, triangle data and BLAS nodes.
+pub const PULSE_PAGED_BUFFER_COUNT: u32 = 3;
+// Storage buffers in the scene bind group layout, without extra pages.
+pub const PULSE_SCENE_STORAGE_BUFFERS: u32 = 14;
+// Storage buffers in Bevy's mesh view layout, which the GI pipeline binds next to the scene: point lights, cluster
+// light index lists and cluster offsets and counts.
+const MESH_VIEW_STORAGE_BUFFERS: u32 = 3;
+// Storage buffers a Pulse pipeline binds without extra pages. Devices with fewer per shader stage can't run Pulse, the
+// rest go to extra pages.
+pub const PULSE_PIPELINE_STORAGE_BUFFERS: u32 =
+    PULSE_SCENE_STORAGE_BUFFERS + MESH_VIEW_STORAGE_BUFFERS;
+// Extra pages of the paged buffers are bound starting here, `PULSE_MAX_BUFFER_PAGES - 1` bindings per buffer.
+const FIRST_PAGE_BINDING: u32 = 15;
+
+// Binding of `page` of the paged buffer with index `buffer`. Page 0 is the buffer's regular binding.
+pub fn page_binding(buffer: u32, page: u32) -> u32 {
+    FIRST_PAGE_BINDING + buffer * (PULSE_MAX_BUFFER_PAGES - 1) + page - 1
+}
+
+// Read from the device when the render app is finished. Insert it into the render app before that to override it,
+// eg. to test paging with a smaller `max_binding_size`.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct PulseBufferLimits {
+    // Largest storage buffer that can be created and bound.
+    pub max_binding_size: u64,
+    // Pages each paged buffer is split into, depending on how many storage buffers a shader can use.
+    pub page_count: u32,
+}
+
+impl FromWorld for PulseBufferLimits {
+    fn from_world(world: &mut World) -> Self {
+        let limits = world.resource::<RenderDevice>().limits();
+        let spare_storage_buffers = limits
+            .max_storage_buffers_per_shader_stage
+            .saturating_sub(PULSE_PIPELINE_STORAGE_BUFFERS);
+        Self {
+            max_binding_size: (limits.max_storage_buffer_binding_size as u64)
+                .min(limits.max_buffer_size),
+            page_count: (1 + spare_storage_buffers / PULSE_PAGED_BUFFER_COUNT)
+                .min(PULSE_MAX_BUFFER_PAGES),
+        }
+    }
+}
+
+impl PulseBufferLimits {
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        vec![ShaderDefVal::UInt(
+            "PULSE_BUFFER_PAGES".into(),
+            self.page_count,
+        )]
+    }
+}
+
+// Data that didn't fit into a scene buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PulseBufferOverflow {
+    pub buffer: &'static str,
+    pub size: u64,
+    pub max_size: u64,
+}
+
+// Storage buffer that is kept alive between frames. Only the ranges that differ from the previous upload are written,
+// and the buffer is only reallocated when the data outgrows it.
+pub struct PulseSceneBuffer {
+    label: &'static str,
+    max_size: u64,
+    buffer: Option<Buffer>,
+    // Copy of what is currently on the GPU, to find dirty ranges.
+    uploaded: Vec<u8>,
+    overflow: Option<PulseBufferOverflow>,
+}
+
+impl PulseSceneBuffer {
+    pub fn new(label: &'static str, limits: &PulseBufferLimits) -> Self {
+        Self {
+            label,
+            max_size: limits.max_binding_size,
+            buffer: None,
+            uploaded: vec![],
+            overflow: None,
+        }
+    }
+
+    // Returns true if the buffer was reallocated, in which case bind groups using it have to be recreated.
+    pub fn write<T: ShaderSize + WriteInto>(
+        &mut self,
+        data: &Vec<T>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> bool {
+        let bytes = encode(data);
+        if bytes.len() as u64 > self.max_size {
+            self.overflow = Some(PulseBufferOverflow {
+                buffer: self.label,
+                size: bytes.len() as u64,
+                max_size: self.max_size,
+            });
+            return false;
+        }
+        self.overflow = None;
+        self.write_bytes(bytes, render_device, render_queue)
+    }
+
+    fn write_bytes(
+        &mut self,
+        bytes: Vec<u8>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> bool {
+        let capacity = self.buffer.as_ref().map(|b| b.size()).unwrap_or(0);
+        if self.buffer.is_none() || bytes.len() as u64 > capacity {
+            // Grow by doubling so a slowly growing scene doesn't reallocate every frame, but never past what can be bound.
+            let size = (bytes.len() as u64)
+                .next_power_of_two()
+                .max(MIN_CAPACITY)
+                .min(self.max_size);
+            let buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some(self.label),
+                size,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            render_queue.write_buffer(&buffer, 0, &bytes);
+            self.buffer = Some(buffer);
+            self.uploaded = bytes;
+            return true;
+        }
+
+        let buffer = self.buffer.as_ref().unwrap();
+        let mut dirty_start = None;
+        for block_start in (0..bytes.len()).step_by(DIFF_BLOCK_SIZE) {
+            let block_end = (block_start + DIFF_BLOCK_SIZE).min(bytes.len());
+            let is_dirty =
+                self.uploaded.get(block_start..block_end) != Some(&bytes[block_start..block_end]);
+            match (is_dirty, dirty_start) {
+                (true, None) => dirty_start = Some(block_start),
+                (false, Some(start)) => {
+                    render_queue.write_buffer(buffer, start as u64, &bytes[start..block_start]);
+                    dirty_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = dirty_start {
+            render_queue.write_buffer(buffer, start as u64, &bytes[start..]);
+        }
+        self.uploaded = bytes;
+        false
+    }
+
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        self.buffer.as_ref().map(|b| b.as_entire_binding())
+    }
+
+    // Size of the GPU allocation, which can be larger than the data in it.
+    pub fn byte_size(&self) -> u64 {
+        self.buffer.as_ref().map(|b| b.size()).unwrap_or(0)
+    }
+
+    // Set if the last write didn't fit, in which case the buffer still holds older data.
+    pub fn overflow(&self) -> Option<PulseBufferOverflow> {
+        self.overflow
+    }
+}
+
+fn encode<T: ShaderSize + WriteInto>(data: &Vec<T>) -> Vec<u8> {
+    let mut bytes = StorageBuffer::new(Vec::<u8>::new());
+    bytes.write(data).unwrap();
+    bytes.into_inner()
+}
+
+// Splits `len` bytes of elements into pages of at most `max_page_size` bytes, without splitting an element. Returns
+// how many elements fit into a page and the byte range of every page that is needed.
+fn split_pages(len: usize, stride: u64, max_page_size: u64) -> (u32, Vec<Range<usize>>) {
+    let elements_per_page = (max_page_size / stride).min(u32::MAX as u64);
+    let page_size = (elements_per_page * stride) as usize;
+    let pages = (0..len.div_ceil(page_size))
+        .map(|i| i * page_size..((i + 1) * page_size).min(len))
+        .collect();
+    (elements_per_page as u32, pages)
+}
+
+// Scene buffer that is split into `PulseBufferLimits::page_count` buffers when its data doesn't fit into a single
+// binding. Shaders find the page of an element through `elements_per_page`, which is passed in the scene uniform.
+pub struct PulsePagedSceneBuffer {
+    label: &'static str,
+    max_page_size: u64,
+    pages: Vec<PulseSceneBuffer>,
+    elements_per_page: u32,
+    overflow: Option<PulseBufferOverflow>,
+}
+
+impl PulsePagedSceneBuffer {
+    pub fn new(label: &'static str, limits: &PulseBufferLimits) -> Self {
+        Self {
+            label,
+            max_page_size: limits.max_binding_size,
+            pages: (0..limits.page_count)
+                .map(|_| PulseSceneBuffer::new(label, limits))
+                .collect(),
+            elements_per_page: u32::MAX,
+            overflow: None,
+        }
+    }
+
+    // Returns true if any page was reallocated, in which case bind groups using it have to be recreated.
+    pub fn write<T: ShaderSize + WriteInto>(
+        &mut self,
+        data: &Vec<T>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> bool {
+        // Pages are split between elements, so the stride of runtime sized arrays is used rather than the size.
+        let stride = T::METADATA.alignment().round_up(T::SHADER_SIZE.get());
+        self.write_with_stride(data, stride, render_device, render_queue)
+    }
+
+    // Same as `write`, for data where one shader element spans several of `data`'s, like wide BVH nodes stored as
+    // words. `stride` is the size in bytes of a shader element.
+    pub fn write_with_stride<T: ShaderSize + WriteInto>(
+        &mut self,
+        data: &Vec<T>,
+        stride: u64,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> bool {
+        let bytes = encode(data);
+        let (elements_per_page, pages) = split_pages(bytes.len(), stride, self.max_page_size);
+        if pages.len() > self.pages.len() {
+            self.overflow = Some(PulseBufferOverflow {
+                buffer: self.label,
+                size: bytes.len() as u64,
+                max_size: self.pages.len() as u64 * elements_per_page as u64 * stride,
+            });
+            return false;
+        }
+        self.overflow = None;
+
+        let mut reallocated = false;
+        self.elements_per_page = elements_per_page;
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            // Unused pages are still bound, so they keep a small allocation.
+            let range = pages.get(i).cloned().unwrap_or(bytes.len()..bytes.len());
+            reallocated |= page.write_bytes(bytes[range].to_vec(), render_device, render_queue);
+        }
+        reallocated
+    }
+
+    // One binding per page, in page order.
+    pub fn bindings(&self) -> Vec<BindingResource<'_>> {
+        self.pages
+            .iter()
+            .filter_map(|page| page.binding())
+            .collect()
+    }
+
+    pub fn elements_per_page(&self) -> u32 {
+        self.elements_per_page
+    }
+
+    pub fn byte_size(&self) -> u64 {
+        self.pages.iter().map(|page| page.byte_size()).sum()
+    }
+
+    pub fn overflow(&self) -> Option<PulseBufferOverflow> {
+        self.overflow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{blas::PulseBLASNode, wide_bvh::wide_node_words};
+    use super::*;
+
+    // Checks that element `i` is found at `page * elements_per_page + j` for every element, like the shaders do.
+    fn assert_pages_find_elements(bytes: &[u8], stride: u64, max_page_size: u64) {
+        let stride_bytes = stride as usize;
+        let element_count = bytes.len() / stride_bytes;
+        let (elements_per_page, pages) = split_pages(bytes.len(), stride, max_page_size);
+        assert!(pages.len() > 1, "stride {stride} fits into a single page");
+        for page in &pages {
+            assert!(page.len() as u64 <= max_page_size);
+            assert_eq!(page.len() % stride_bytes, 0);
+        }
+        for i in 0..element_count {
+            let page = i / elements_per_page as usize;
+            let j = i - page * elements_per_page as usize;
+            let start = pages[page].start + j * stride_bytes;
+            assert_eq!(
+                &bytes[start..start + stride_bytes],
+                &bytes[i * stride_bytes..(i + 1) * stride_bytes],
+                "element {i} with stride {stride}"
+            );
+            assert!(start + stride_bytes <= pages[page].end);
+        }
+    }
+
+    #[test]
+    fn pages_split_between_elements() {
+        // Not a multiple of any of the strides.
+        let max_page_size = 1000;
+
+        let nodes = (0..100)
+            .map(|i| PulseBLASNode {
+                a_or_first_tri: i,
+                tri_count: i + 1,
+                ..default()
+            })
+            .collect::<Vec<_>>();
+        let stride = PulseBLASNode::SHADER_SIZE.get();
+        assert_pages_find_elements(&encode(&nodes), stride, max_page_size);
+
+        for width in [4, 8] {
+            let node_words = wide_node_words(width);
+            let words = (0..(100 * node_words) as u32).collect::<Vec<u32>>();
+            assert_pages_find_elements(&encode(&words), node_words as u64 * 4, max_page_size);
+        }
+    }
+}
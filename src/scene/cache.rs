@@ -0,0 +1,478 @@
+use super::{blas::*, PulseBvhBuildSettings, PulseTriangleData};
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// On-disk cache for BLASes and triangle data, so they don't have to be rebuilt on every launch.
+// Entries are keyed by a hash of the mesh content and the settings used to build the BLAS.
+//
+// File layout (little endian):
+//   magic: [u8; 8], version: u32, key: u64, primitive count: u32, node count: u32, tri index count: u32,
+//   payload checksum: u64, payload: nodes, tri indices, triangle data
+
+const CACHE_MAGIC: [u8; 8] = *b"PULSEBVH";
+const CACHE_VERSION: u32 = 2;
+const CACHE_EXTENSION: &str = "pulsebvh";
+const HEADER_SIZE: usize = 8 + 4 + 8 + 4 + 4 + 4 + 8;
+
+pub const NODE_SIZE: usize = 8 * 4;
+pub const TRI_INDEX_SIZE: usize = 4;
+pub const TRIANGLE_DATA_SIZE: usize = (3 * 3 + 3 * 4 + 3 * 2) * 4;
+
+// The cache is disabled when `directory` is `None`.
+#[derive(Resource, ExtractResource, Clone, Default, Debug)]
+pub struct PulseBvhCacheSettings {
+    pub directory: Option<PathBuf>,
+}
+
+pub struct CachedBlas {
+    pub bvh: Blas,
+    pub triangle_data: Vec<PulseTriangleData>,
+}
+
+// 64-bit FNV-1a. Used instead of `DefaultHasher` since cache keys have to be stable between builds.
+pub struct ContentHasher(u64);
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.write(&v.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, v: f32) {
+        self.write(&v.to_le_bytes());
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Hashes every mesh attribute that ends up in a cache entry together with the settings the BLAS is built with.
+pub fn mesh_cache_key(
+    positions: &[Vec3],
+    normals: &[Vec3],
+    tangents: &[Vec4],
+    uvs: &[Vec2],
+    indices: &[u32],
+    builder: PulseBlasBuilder,
+    settings: &PulseBvhBuildSettings,
+) -> u64 {
+    let mut hasher = ContentHasher::new();
+    hasher.write_u32(CACHE_VERSION);
+
+    hasher.write_u32(positions.len() as u32);
+    for p in positions.iter() {
+        hasher.write_f32(p.x);
+        hasher.write_f32(p.y);
+        hasher.write_f32(p.z);
+    }
+    hasher.write_u32(normals.len() as u32);
+    for n in normals.iter() {
+        hasher.write_f32(n.x);
+        hasher.write_f32(n.y);
+        hasher.write_f32(n.z);
+    }
+    hasher.write_u32(tangents.len() as u32);
+    for t in tangents.iter() {
+        hasher.write_f32(t.x);
+        hasher.write_f32(t.y);
+        hasher.write_f32(t.z);
+        hasher.write_f32(t.w);
+    }
+    hasher.write_u32(uvs.len() as u32);
+    for uv in uvs.iter() {
+        hasher.write_f32(uv.x);
+        hasher.write_f32(uv.y);
+    }
+    hasher.write_u32(indices.len() as u32);
+    for i in indices.iter() {
+        hasher.write_u32(*i);
+    }
+
+    write_blas_settings(&mut hasher, builder, settings);
+
+    hasher.finish()
+}
+
+// Identifies the settings a BLAS was built with, independent of the mesh.
+pub fn blas_settings_key(builder: PulseBlasBuilder, settings: &PulseBvhBuildSettings) -> u64 {
+    let mut hasher = ContentHasher::new();
+    write_blas_settings(&mut hasher, builder, settings);
+    hasher.finish()
+}
+
+fn write_blas_settings(
+    hasher: &mut ContentHasher,
+    builder: PulseBlasBuilder,
+    settings: &PulseBvhBuildSettings,
+) {
+    match builder {
+        PulseBlasBuilder::Binned => hasher.write_u32(0),
+        PulseBlasBuilder::SpatialSplit { max_duplication } => {
+            hasher.write_u32(1);
+            hasher.write_f32(max_duplication);
+        }
+    }
+    hasher.write_u32(settings.max_leaf_size);
+    hasher.write_u32(settings.bin_count as u32);
+    hasher.write_f32(settings.traversal_cost);
+    hasher.write_f32(settings.intersection_cost);
+    hasher.write_u32(settings.sah_termination as u32);
+}
+
+fn cache_entry_path(directory: &Path, key: u64) -> PathBuf {
+    directory.join(format!("{:016x}.{}", key, CACHE_EXTENSION))
+}
+
+// Returns `None` if there is no valid entry for `key`. Stale or corrupt entries are logged and ignored.
+pub fn load_cached_blas(directory: &Path, key: u64, primitive_count: usize) -> Option<CachedBlas> {
+    let path = cache_entry_path(directory, key);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read BVH cache entry {:?}: {}", path, e);
+            return None;
+        }
+    };
+
+    match decode_entry(&bytes, key, primitive_count) {
+        Ok(cached) => Some(cached),
+        Err(reason) => {
+            warn!(
+                "Ignoring invalid BVH cache entry {:?}: {}. Rebuilding.",
+                path, reason
+            );
+            None
+        }
+    }
+}
+
+pub fn store_cached_blas(
+    directory: &Path,
+    key: u64,
+    bvh: &Blas,
+    triangle_data: &[PulseTriangleData],
+) {
+    let bytes = encode_entry(key, bvh, triangle_data);
+    let path = cache_entry_path(directory, key);
+    // Write to a temporary file first so a crash can't leave a half written entry behind.
+    let tmp_path = path.with_extension(format!("{}.tmp", CACHE_EXTENSION));
+    let result = fs::create_dir_all(directory)
+        .and_then(|_| fs::write(&tmp_path, &bytes))
+        .and_then(|_| fs::rename(&tmp_path, &path));
+    if let Err(e) = result {
+        warn!("Failed to write BVH cache entry {:?}: {}", path, e);
+    }
+}
+
+fn encode_entry(key: u64, bvh: &Blas, triangle_data: &[PulseTriangleData]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(
+        bvh.nodes.len() * NODE_SIZE
+            + bvh.tri_indices.len() * TRI_INDEX_SIZE
+            + triangle_data.len() * TRIANGLE_DATA_SIZE,
+    );
+    let write_vec3 = |payload: &mut Vec<u8>, v: Vec3| {
+        payload.extend_from_slice(&v.x.to_le_bytes());
+        payload.extend_from_slice(&v.y.to_le_bytes());
+        payload.extend_from_slice(&v.z.to_le_bytes());
+    };
+    for node in bvh.nodes.iter() {
+        write_vec3(&mut payload, node.aabb_min);
+        payload.extend_from_slice(&node.a_or_first_tri.to_le_bytes());
+        write_vec3(&mut payload, node.aabb_max);
+        payload.extend_from_slice(&node.tri_count.to_le_bytes());
+    }
+    for i in bvh.tri_indices.iter() {
+        payload.extend_from_slice(&i.to_le_bytes());
+    }
+    for t in triangle_data.iter() {
+        for n in t.normals.iter() {
+            write_vec3(&mut payload, *n);
+        }
+        for tangent in t.tangents.iter() {
+            write_vec3(&mut payload, tangent.xyz());
+            payload.extend_from_slice(&tangent.w.to_le_bytes());
+        }
+        for uv in t.uvs.iter() {
+            payload.extend_from_slice(&uv.x.to_le_bytes());
+            payload.extend_from_slice(&uv.y.to_le_bytes());
+        }
+    }
+
+    let mut checksum = ContentHasher::new();
+    checksum.write(&payload);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&CACHE_MAGIC);
+    bytes.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&key.to_le_bytes());
+    bytes.extend_from_slice(&(triangle_data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(bvh.nodes.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(bvh.tri_indices.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum.finish().to_le_bytes());
+    bytes.append(&mut payload);
+    bytes
+}
+
+// Callers check that enough bytes are left, reading past the end panics.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    pub fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.bytes[self.offset..(self.offset + N)]);
+        self.offset += N;
+        out
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    pub fn f32(&mut self) -> f32 {
+        f32::from_le_bytes(self.take())
+    }
+
+    pub fn vec2(&mut self) -> Vec2 {
+        Vec2::new(self.f32(), self.f32())
+    }
+
+    pub fn vec3(&mut self) -> Vec3 {
+        Vec3::new(self.f32(), self.f32(), self.f32())
+    }
+
+    pub fn vec4(&mut self) -> Vec4 {
+        Vec4::new(self.f32(), self.f32(), self.f32(), self.f32())
+    }
+}
+
+// Returns the reason an entry was rejected on failure.
+fn decode_entry(bytes: &[u8], key: u64, primitive_count: usize) -> Result<CachedBlas, String> {
+    if bytes.len() < HEADER_SIZE {
+        return Err("truncated header".into());
+    }
+
+    let mut reader = Reader::new(bytes);
+    if reader.take::<8>() != CACHE_MAGIC {
+        return Err("bad magic".into());
+    }
+    let version = reader.u32();
+    if version != CACHE_VERSION {
+        return Err(format!("version {} != {}", version, CACHE_VERSION));
+    }
+    if reader.u64() != key {
+        return Err("key mismatch".into());
+    }
+    let triangle_count = reader.u32() as usize;
+    let node_count = reader.u32() as usize;
+    let tri_index_count = reader.u32() as usize;
+    let checksum = reader.u64();
+
+    if triangle_count != primitive_count {
+        return Err(format!(
+            "triangle count {} != {}",
+            triangle_count, primitive_count
+        ));
+    }
+    let payload_size = node_count * NODE_SIZE
+        + tri_index_count * TRI_INDEX_SIZE
+        + triangle_count * TRIANGLE_DATA_SIZE;
+    if bytes.len() != HEADER_SIZE + payload_size {
+        return Err("size mismatch".into());
+    }
+    let mut hasher = ContentHasher::new();
+    hasher.write(&bytes[HEADER_SIZE..]);
+    if hasher.finish() != checksum {
+        return Err("checksum mismatch".into());
+    }
+
+    let mut nodes = Vec::with_capacity(node_count);
+    for _ in 0..node_count {
+        nodes.push(PulseBLASNode {
+            aabb_min: reader.vec3(),
+            a_or_first_tri: reader.u32(),
+            aabb_max: reader.vec3(),
+            tri_count: reader.u32(),
+        });
+    }
+    let mut tri_indices = Vec::with_capacity(tri_index_count);
+    for _ in 0..tri_index_count {
+        tri_indices.push(reader.u32());
+    }
+    let mut triangle_data = Vec::with_capacity(triangle_count);
+    for _ in 0..triangle_count {
+        triangle_data.push(PulseTriangleData {
+            normals: [reader.vec3(), reader.vec3(), reader.vec3()],
+            tangents: [reader.vec4(), reader.vec4(), reader.vec4()],
+            uvs: [reader.vec2(), reader.vec2(), reader.vec2()],
+        });
+    }
+
+    let bvh = Blas { nodes, tri_indices };
+    validate_blas(&bvh, primitive_count)?;
+
+    Ok(CachedBlas { bvh, triangle_data })
+}
+
+// Makes sure a BLAS read from disk can't send traversal out of bounds or into a cycle.
+pub fn validate_blas(bvh: &Blas, primitive_count: usize) -> Result<(), String> {
+    let Blas { nodes, tri_indices } = bvh;
+    if nodes.is_empty() {
+        return Err("no root node".into());
+    }
+    for (node_index, node) in nodes.iter().enumerate() {
+        // A mesh without triangles has a single empty leaf as root.
+        let in_bounds = if node.tri_count > 0 || nodes.len() == 1 {
+            (node.a_or_first_tri as usize + node.tri_count as usize) <= tri_indices.len()
+        } else {
+            // Children always come after their parent, otherwise a corrupt tree could make traversal loop forever.
+            node.a_or_first_tri as usize > node_index
+                && (node.a_or_first_tri as usize + 1) < nodes.len()
+        };
+        if !in_bounds {
+            return Err("node index out of bounds".into());
+        }
+    }
+    if tri_indices.iter().any(|i| *i as usize >= primitive_count) {
+        return Err("triangle index out of bounds".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{PulseBvhSettings, PulsePrimitive};
+    use super::*;
+
+    // A grid of triangles, big enough for a BLAS with a few levels.
+    fn test_mesh() -> (Blas, Vec<PulseTriangleData>) {
+        let mut primitives = vec![];
+        for y in 0..8 {
+            for x in 0..8 {
+                let p = Vec3::new(x as f32, y as f32, (x * y) as f32 * 0.1);
+                primitives.push(PulsePrimitive {
+                    positions: [p, p + Vec3::X, p + Vec3::Y],
+                });
+            }
+        }
+        let triangle_data = (0..primitives.len())
+            .map(|i| PulseTriangleData {
+                normals: [Vec3::Z; 3],
+                tangents: [Vec4::new(1.0, 0.0, 0.0, -1.0); 3],
+                uvs: [Vec2::splat(i as f32), Vec2::X, Vec2::Y],
+            })
+            .collect();
+        let bvh = build_blas(&primitives, &PulseBvhSettings::default().blas);
+        (bvh, triangle_data)
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let (bvh, triangle_data) = test_mesh();
+        let bytes = encode_entry(7, &bvh, &triangle_data);
+        let cached = decode_entry(&bytes, 7, triangle_data.len()).unwrap();
+        assert!(cached.bvh.nodes == bvh.nodes);
+        assert_eq!(cached.bvh.tri_indices, bvh.tri_indices);
+        for (a, b) in cached.triangle_data.iter().zip(triangle_data.iter()) {
+            assert_eq!(a.normals, b.normals);
+            assert_eq!(a.tangents, b.tangents);
+            assert_eq!(a.uvs, b.uvs);
+        }
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        let (bvh, triangle_data) = test_mesh();
+        let count = triangle_data.len();
+        let bytes = encode_entry(7, &bvh, &triangle_data);
+
+        assert!(decode_entry(&bytes, 8, count).is_err());
+        assert!(decode_entry(&bytes, 7, count + 1).is_err());
+        assert!(decode_entry(&bytes[..HEADER_SIZE - 1], 7, count).is_err());
+        assert!(decode_entry(&bytes[..bytes.len() - 1], 7, count).is_err());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[8..12].copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+        assert!(decode_entry(&wrong_version, 7, count).is_err());
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decode_entry(&corrupt, 7, count).err().unwrap(),
+            "checksum mismatch"
+        );
+    }
+
+    #[test]
+    fn cyclic_blas_is_rejected() {
+        let (bvh, triangle_data) = test_mesh();
+        assert!(validate_blas(&bvh, triangle_data.len()).is_ok());
+
+        // Interior nodes pointing at themselves or an ancestor, with a valid checksum.
+        let interior = (1..bvh.nodes.len())
+            .find(|i| bvh.nodes[*i].tri_count == 0)
+            .unwrap();
+        for child in [interior as u32, 0] {
+            let mut nodes = bvh.nodes.clone();
+            nodes[interior].a_or_first_tri = child;
+            let cyclic = Blas {
+                nodes,
+                tri_indices: bvh.tri_indices.clone(),
+            };
+            let bytes = encode_entry(7, &cyclic, &triangle_data);
+            assert!(decode_entry(&bytes, 7, triangle_data.len()).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_files_fall_back_to_building() {
+        let directory =
+            std::env::temp_dir().join(format!("pulse_bvh_cache_{}", std::process::id()));
+        let (bvh, triangle_data) = test_mesh();
+        store_cached_blas(&directory, 7, &bvh, &triangle_data);
+        assert!(load_cached_blas(&directory, 7, triangle_data.len()).is_some());
+        assert!(load_cached_blas(&directory, 8, triangle_data.len()).is_none());
+
+        let path = cache_entry_path(&directory, 7);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(load_cached_blas(&directory, 7, triangle_data.len()).is_none());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
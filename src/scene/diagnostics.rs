@@ -0,0 +1,131 @@
+use super::buffers::PulseBufferOverflow;
+use bevy::{
+    prelude::*,
+    render::render_resource::{PrimitiveTopology, TextureFormat},
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+// Something in the scene that can't be ray traced, sent in the main world so apps can show it in their UI. Every
+// problem is reported once when it appears, or again if its reason changes.
+#[derive(Event, Clone, Debug, PartialEq)]
+pub enum PulseSceneDiagnosticEvent {
+    MeshSkipped {
+        mesh: AssetId<Mesh>,
+        reason: PulseMeshSkipReason,
+    },
+    InstanceSkipped {
+        entity: Entity,
+        reason: PulseInstanceSkipReason,
+    },
+    // Materials using the texture fall back to their constant factors.
+    TextureSkipped {
+        image: AssetId<Image>,
+        reason: PulseTextureSkipReason,
+    },
+    // There are instances, but none of them has an emissive material.
+    NoLights,
+    // The scene can't be rendered until it fits again.
+    BufferOverflow(PulseBufferOverflow),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PulseMeshSkipReason {
+    MissingPositions,
+    UnsupportedTopology(PrimitiveTopology),
+    // Not a whole number of triangles, or indices past the last vertex.
+    InvalidIndices,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PulseInstanceSkipReason {
+    // The mesh was skipped, or is still being loaded or built.
+    MeshNotPrepared,
+    MaterialNotPrepared,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PulseTextureSkipReason {
+    // Only uncompressed 8 bit formats can be copied into the texture atlas.
+    UnsupportedFormat(TextureFormat),
+    // All layers of the texture atlas are in use.
+    AtlasFull,
+}
+
+impl fmt::Display for PulseMeshSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPositions => write!(f, "no float3 vertex positions"),
+            Self::UnsupportedTopology(topology) => {
+                write!(f, "unsupported primitive topology {:?}", topology)
+            }
+            Self::InvalidIndices => write!(f, "invalid indices"),
+        }
+    }
+}
+
+impl fmt::Display for PulseInstanceSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MeshNotPrepared => write!(f, "mesh isn't prepared"),
+            Self::MaterialNotPrepared => write!(f, "material isn't prepared"),
+        }
+    }
+}
+
+impl fmt::Display for PulseTextureSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedFormat(format) => write!(f, "unsupported format {:?}", format),
+            Self::AtlasFull => write!(f, "the texture atlas is full"),
+        }
+    }
+}
+
+impl fmt::Display for PulseSceneDiagnosticEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MeshSkipped { mesh, reason } => write!(f, "Skipping mesh {:?}: {}.", mesh, reason),
+            Self::InstanceSkipped { entity, reason } => {
+                write!(f, "Skipping instance {:?}: {}.", entity, reason)
+            }
+            Self::TextureSkipped { image, reason } => {
+                write!(f, "Skipping texture {:?}: {}.", image, reason)
+            }
+            Self::NoLights => write!(f, "The scene has no emissive instances."),
+            Self::BufferOverflow(overflow) => write!(
+                f,
+                "Scene buffer {} needs {} bytes, but the device can only bind {}. The scene can't be rendered.",
+                overflow.buffer, overflow.size, overflow.max_size
+            ),
+        }
+    }
+}
+
+// Diagnostics reported by the render world that haven't been sent as events yet. Shared between the main and render
+// world.
+#[derive(Resource, Clone, Default)]
+pub struct PulseSceneDiagnostics(Arc<Mutex<Vec<PulseSceneDiagnosticEvent>>>);
+
+impl PulseSceneDiagnostics {
+    pub fn report(&self, event: PulseSceneDiagnosticEvent) {
+        match event {
+            PulseSceneDiagnosticEvent::BufferOverflow(_) => error!("{}", event),
+            _ => warn!("{}", event),
+        }
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn take(&self) -> Vec<PulseSceneDiagnosticEvent> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+pub fn send_scene_diagnostic_events(
+    diagnostics: Res<PulseSceneDiagnostics>,
+    mut events: EventWriter<PulseSceneDiagnosticEvent>,
+) {
+    events.send_batch(diagnostics.take());
+}
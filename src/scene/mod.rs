@@ -1,24 +1,58 @@
-use crate::utilities::*;
+use crate::{support::*, utilities::*};
 use bevy::{
     asset::load_internal_asset,
     diagnostic::Diagnostics,
+    pbr::{NotShadowCaster, NotShadowReceiver},
     prelude::*,
     render::{
         extract_resource::{ExtractResource, ExtractResourcePlugin},
-        mesh::{Indices, VertexAttributeValues},
-        render_asset::RenderAssets,
+        mesh::{
+            morph::MeshMorphWeights,
+            skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+            Indices, VertexAttributeValues,
+        },
+        render_asset::{RenderAssetUsages, RenderAssets},
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
+        view::RenderLayers,
         Extract, Render, RenderApp, RenderSet,
     },
-    utils::HashMap,
+    tasks::AsyncComputeTaskPool,
+    utils::{HashMap, HashSet},
+};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
 };
 // use std::time::Instant;
 
+pub mod async_blas;
+use async_blas::*;
 pub mod blas;
 use blas::*;
+pub mod buffers;
+use buffers::*;
+pub mod cache;
+use cache::*;
+pub mod diagnostics;
+use diagnostics::*;
+pub mod pulsemesh;
+use pulsemesh::*;
+pub mod raycast;
+use raycast::*;
+pub mod sbvh;
+pub mod skinning;
+use skinning::*;
+pub mod textures;
+use textures::*;
+#[cfg(test)]
+mod test_meshes;
 pub mod tlas;
 use tlas::*;
+pub mod vertices;
+use vertices::*;
+pub mod wide_bvh;
+use wide_bvh::*;
 
 pub const PULSE_SCENE_BINDINGS_SHADER_HANDLE: Handle<Shader> =
     Handle::weak_from_u128(187737725855836603431472235313437654946);
@@ -64,15 +98,57 @@ impl Plugin for PulseScenePlugin {
             .init_resource::<BlueNoiseImageHandle>()
             .add_plugins(ExtractResourcePlugin::<BlueNoiseImageHandles>::default())
             .add_plugins(ExtractResourcePlugin::<BlueNoiseImageHandle>::default())
-            .add_systems(Startup, load_blue_noise_image);
+            .init_resource::<PulseBlasBuilderSettings>()
+            .add_plugins(ExtractResourcePlugin::<PulseBlasBuilderSettings>::default())
+            .init_resource::<PulseBvhSettings>()
+            .add_plugins(ExtractResourcePlugin::<PulseBvhSettings>::default())
+            .init_resource::<PulseBvhCacheSettings>()
+            .add_plugins(ExtractResourcePlugin::<PulseBvhCacheSettings>::default())
+            .init_resource::<PulseAsyncBlasSettings>()
+            .add_plugins(ExtractResourcePlugin::<PulseAsyncBlasSettings>::default())
+            .init_resource::<PulseTextureAtlasSettings>()
+            .add_plugins(ExtractResourcePlugin::<PulseTextureAtlasSettings>::default())
+            .init_resource::<PulseMaterialLayerSettings>()
+            .add_plugins(ExtractResourcePlugin::<PulseMaterialLayerSettings>::default())
+            .init_resource::<PulseVisibilitySettings>()
+            .init_resource::<PulseRaycast>()
+            .init_resource::<PulseSceneBuildStatus>()
+            .add_event::<PulseSceneBuilt>()
+            .init_resource::<PulseSceneDiagnostics>()
+            .add_event::<PulseSceneDiagnosticEvent>()
+            .init_resource::<PulseSceneBufferUsage>()
+            .init_asset::<PulseMeshFile>()
+            .init_asset_loader::<PulseMeshLoader>()
+            .register_asset_processor(PulseGltfMeshProcessor::new(
+                PulseGltfMeshTransformer,
+                PulseMeshSaver,
+            ))
+            .add_systems(Startup, load_blue_noise_image)
+            .add_systems(
+                Update,
+                (send_scene_built_events, send_scene_diagnostic_events),
+            );
+
+        // Shared with the render world, which publishes a new snapshot whenever the scene changes.
+        let raycast = app.world.resource::<PulseRaycast>().clone();
+        let build_status = app.world.resource::<PulseSceneBuildStatus>().clone();
+        let diagnostics = app.world.resource::<PulseSceneDiagnostics>().clone();
+        let buffer_usage = app.world.resource::<PulseSceneBufferUsage>().clone();
 
         let render_app = app.sub_app_mut(RenderApp);
         render_app
+            .insert_resource(raycast)
+            .insert_resource(build_status)
+            .insert_resource(diagnostics)
+            .insert_resource(buffer_usage)
             .add_systems(
                 ExtractSchedule,
                 (
                     extract_material_assets,
+                    extract_texture_images,
                     extract_mesh_assets,
+                    extract_prebuilt_meshes,
+                    extract_morph_target_images,
                     extract_mesh_material_instances,
                 ),
             )
@@ -81,15 +157,21 @@ impl Plugin for PulseScenePlugin {
                 (
                     (
                         prepare_extracted_mesh_assets,
+                        prepare_deformable_mesh_assets,
+                        prepare_deformed_instances,
                         prepare_mesh_data,
                         prepare_mesh_instances,
+                        update_raycast_scene,
                         prepare_extracted_material_assets,
+                        prepare_texture_images,
+                        prepare_texture_atlas,
                         prepare_material_data,
                         prepare_blue_noise_texture,
                     ),
                     queue_scene_bind_group,
                 )
                     .chain()
+                    .run_if(pulse_supported)
                     .in_set(RenderSet::Prepare),
             );
 
@@ -108,14 +190,22 @@ impl Plugin for PulseScenePlugin {
         render_app
             .init_resource::<ExtractedMeshAssets>()
             .init_resource::<PulseMeshes>()
+            .init_resource::<PulsePrebuiltMeshes>()
+            .init_resource::<PulsePendingBlasBuilds>()
             .init_resource::<ExtractedMeshMaterialInstances>()
             .init_resource::<PulseMeshIndices>()
+            .init_resource::<ExtractedMorphTargetImages>()
+            .init_resource::<PulseDeformableMeshes>()
+            .init_resource::<PulseDeformedInstances>()
+            .init_resource::<PulseDeformedMeshIndices>()
             .init_resource::<PulseMeshInstances>()
             .init_resource::<PulseLightData>()
             .init_resource::<PulsePreparedMeshAssetData>()
             .init_resource::<PulseSceneTLAS>()
             .init_resource::<ExtractedMaterialAssets>()
             .init_resource::<PulseMaterials>()
+            .init_resource::<ExtractedTextureImages>()
+            .init_resource::<PulseTextureImages>()
             .init_resource::<PulseMaterialIndices>()
             .init_resource::<PulsePreparedMaterialAssetData>()
             .init_resource::<PulseCanRender>()
@@ -124,7 +214,17 @@ impl Plugin for PulseScenePlugin {
 
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
+        if !pulse_support(render_app).is_supported() {
+            return;
+        }
+
+        if !render_app.world.contains_resource::<PulseBufferLimits>() {
+            render_app.init_resource::<PulseBufferLimits>();
+        }
         render_app
+            .init_resource::<PulseSceneBuffers>()
+            .init_resource::<PulseTextureAtlas>()
+            .init_resource::<PulseEnergyTableTexture>()
             .init_resource::<PulseSceneBindGroup>()
             .init_resource::<PulseSceneBindGroupLayout>();
     }
@@ -237,6 +337,11 @@ fn extract_material_assets(
     extracted.removed = removed;
 }
 
+// Stored in `PulseMaterial::flags` and matches the constants in utilities.wgsl.
+pub const PULSE_MATERIAL_TWO_COMPONENT_NORMAL_MAP: u32 = 1 << 0;
+pub const PULSE_MATERIAL_FLIP_NORMAL_MAP_Y: u32 = 1 << 1;
+
+// Textures are multiplied with the constant factors, the same as for `StandardMaterial`.
 #[derive(ShaderType, Clone)]
 pub struct PulseMaterial {
     pub base_color: Vec4,
@@ -244,10 +349,35 @@ pub struct PulseMaterial {
     pub perceptual_roughness: f32,
     pub reflectance: f32,
     pub metallic: f32,
+    pub flags: u32,
+    pub specular_transmission: f32,
+    pub diffuse_transmission: f32,
+    pub ior: f32,
+    // Zero for thin-walled materials, which light passes through without being refracted or absorbed.
+    pub thickness: f32,
+    // Beer-Lambert absorption inside the volume, light keeps `attenuation_color` after `attenuation_distance`.
+    pub attenuation_color: Vec3,
+    pub attenuation_distance: f32,
+    // Layers that `StandardMaterial` doesn't have, set through `PulseMaterialLayerSettings`.
+    pub clearcoat: f32,
+    pub clearcoat_perceptual_roughness: f32,
+    pub anisotropy_strength: f32,
+    // Radians counterclockwise from the tangent.
+    pub anisotropy_rotation: f32,
+    pub sheen_color: Vec3,
+    pub sheen_perceptual_roughness: f32,
+    pub base_color_texture: PulseMaterialTexture,
+    pub emissive_texture: PulseMaterialTexture,
+    pub metallic_roughness_texture: PulseMaterialTexture,
+    pub occlusion_texture: PulseMaterialTexture,
+    pub normal_map_texture: PulseMaterialTexture,
 }
 
+// Textures are resolved to their place in the atlas in `prepare_material_data`, since it can be repacked.
 #[derive(Resource, Default, Deref, DerefMut)]
-struct PulseMaterials(pub HashMap<AssetId<StandardMaterial>, PulseMaterial>);
+pub struct PulseMaterials(
+    pub HashMap<AssetId<StandardMaterial>, (PulseMaterial, PulseMaterialTextureIds)>,
+);
 
 fn prepare_extracted_material_assets(
     extracted: Res<ExtractedMaterialAssets>,
@@ -260,9 +390,35 @@ fn prepare_extracted_material_assets(
             perceptual_roughness: material.perceptual_roughness,
             reflectance: material.reflectance,
             metallic: material.metallic,
+            flags: if material.flip_normal_map_y {
+                PULSE_MATERIAL_FLIP_NORMAL_MAP_Y
+            } else {
+                0
+            },
+            specular_transmission: material.specular_transmission,
+            diffuse_transmission: material.diffuse_transmission,
+            ior: material.ior,
+            thickness: material.thickness,
+            attenuation_color: Vec4::from(material.attenuation_color.as_linear_rgba_f32()).xyz(),
+            // Infinite by default, which shaders can't rely on.
+            attenuation_distance: material.attenuation_distance.min(f32::MAX),
+            clearcoat: 0.0,
+            clearcoat_perceptual_roughness: 0.0,
+            anisotropy_strength: 0.0,
+            anisotropy_rotation: 0.0,
+            sheen_color: Vec3::ZERO,
+            sheen_perceptual_roughness: 0.0,
+            base_color_texture: PulseMaterialTexture::NONE,
+            emissive_texture: PulseMaterialTexture::NONE,
+            metallic_roughness_texture: PulseMaterialTexture::NONE,
+            occlusion_texture: PulseMaterialTexture::NONE,
+            normal_map_texture: PulseMaterialTexture::NONE,
         };
 
-        materials.insert(id.clone(), pulse_material);
+        materials.insert(
+            id.clone(),
+            (pulse_material, PulseMaterialTextureIds::new(material)),
+        );
     }
 
     for id in extracted.removed.iter() {
@@ -270,6 +426,38 @@ fn prepare_extracted_material_assets(
     }
 }
 
+// Clearcoat, sheen and anisotropy of a material, see the layered BSDF in bsdf.rs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PulseMaterialLayers {
+    pub clearcoat: f32,
+    pub clearcoat_perceptual_roughness: f32,
+    // Stretches the specular highlight along the tangent, rotated by `anisotropy_rotation` radians.
+    pub anisotropy_strength: f32,
+    pub anisotropy_rotation: f32,
+    // Black for no sheen.
+    pub sheen_color: Color,
+    pub sheen_perceptual_roughness: f32,
+}
+
+impl Default for PulseMaterialLayers {
+    fn default() -> Self {
+        Self {
+            clearcoat: 0.0,
+            clearcoat_perceptual_roughness: 0.5,
+            anisotropy_strength: 0.0,
+            anisotropy_rotation: 0.0,
+            sheen_color: Color::BLACK,
+            sheen_perceptual_roughness: 0.3,
+        }
+    }
+}
+
+// Layers of materials, those without an entry have none.
+#[derive(Resource, Default, ExtractResource, Clone)]
+pub struct PulseMaterialLayerSettings {
+    pub per_material: HashMap<AssetId<StandardMaterial>, PulseMaterialLayers>,
+}
+
 #[derive(Resource, Default, Deref, DerefMut)]
 struct PulsePreparedMaterialAssetData(pub Vec<PulseMaterial>);
 
@@ -279,25 +467,54 @@ struct PulseMaterialIndices(pub HashMap<AssetId<StandardMaterial>, u32>);
 fn prepare_material_data(
     materials: Res<PulseMaterials>,
     extracted: Res<ExtractedMaterialAssets>,
+    atlas: Res<PulseTextureAtlas>,
+    layer_settings: Res<PulseMaterialLayerSettings>,
     mut material_data: ResMut<PulsePreparedMaterialAssetData>,
     mut material_indices: ResMut<PulseMaterialIndices>,
 ) {
     // Abort if material data is the same as last frame's.
-    if extracted.empty() {
+    if extracted.empty() && !atlas.is_changed() && !layer_settings.is_changed() {
         return;
     }
 
     *material_data = PulsePreparedMaterialAssetData::default();
     *material_indices = PulseMaterialIndices::default();
-    for (id, material) in materials.0.iter() {
+    for (id, (material, textures)) in materials.0.iter() {
         let index = material_data.len() as u32;
         material_indices.insert(id.clone(), index);
-        material_data.push(material.clone());
+
+        // Textures that aren't loaded yet or didn't fit into the atlas are left out.
+        let texture = |id| {
+            atlas
+                .get(id)
+                .map_or(PulseMaterialTexture::NONE, |entry| entry.texture)
+        };
+        let mut material = material.clone();
+        material.base_color_texture = texture(textures.base_color);
+        material.emissive_texture = texture(textures.emissive);
+        material.metallic_roughness_texture = texture(textures.metallic_roughness);
+        material.occlusion_texture = texture(textures.occlusion);
+        material.normal_map_texture = texture(textures.normal_map);
+        if atlas
+            .get(textures.normal_map)
+            .is_some_and(|entry| entry.two_component)
+        {
+            material.flags |= PULSE_MATERIAL_TWO_COMPONENT_NORMAL_MAP;
+        }
+        if let Some(layers) = layer_settings.per_material.get(id) {
+            material.clearcoat = layers.clearcoat;
+            material.clearcoat_perceptual_roughness = layers.clearcoat_perceptual_roughness;
+            material.anisotropy_strength = layers.anisotropy_strength;
+            material.anisotropy_rotation = layers.anisotropy_rotation;
+            material.sheen_color = Vec4::from(layers.sheen_color.as_linear_rgba_f32()).xyz();
+            material.sheen_perceptual_roughness = layers.sheen_perceptual_roughness;
+        }
+        material_data.push(material);
     }
 }
 
 #[derive(Resource, Default)]
-struct ExtractedMeshAssets {
+pub struct ExtractedMeshAssets {
     pub new_or_modified: Vec<(AssetId<Mesh>, Mesh)>,
     pub removed: Vec<AssetId<Mesh>>,
 }
@@ -338,6 +555,8 @@ fn extract_mesh_assets(
 #[derive(Default, ShaderType, Clone, Debug)]
 pub struct PulseTriangleData {
     pub normals: [Vec3; 3],
+    // Zero if the mesh has no tangents.
+    pub tangents: [Vec4; 3],
     pub uvs: [Vec2; 3],
 }
 
@@ -359,105 +578,586 @@ impl PulsePrimitive {
     }
 }
 
+#[derive(Clone)]
 pub struct PulseMesh {
     pub primitives: Vec<PulsePrimitive>,
     pub triangle_data: Vec<PulseTriangleData>,
     pub bvh: Blas,
+    // Kept to detect whether a modified mesh can be refit instead of rebuilt.
+    pub indices: Vec<u32>,
+    // SAH cost right after the last full build, used to decide when a refit tree has degraded too much.
+    pub built_sah_cost: f32,
 }
 
+// Shared with the snapshots of `PulseRaycast`. Modifying a mesh that a snapshot still uses copies it first.
 #[derive(Resource, Default)]
-pub struct PulseMeshes(pub HashMap<AssetId<Mesh>, PulseMesh>);
+pub struct PulseMeshes(pub HashMap<AssetId<Mesh>, Arc<PulseMesh>>);
+
+// Selects the BLAS builder for mesh assets. Entries in `per_mesh` take precedence over `default`.
+// Changing it rebuilds every BLAS.
+#[derive(Resource, Default, ExtractResource, Clone)]
+pub struct PulseBlasBuilderSettings {
+    pub default: PulseBlasBuilder,
+    pub per_mesh: HashMap<AssetId<Mesh>, PulseBlasBuilder>,
+}
+
+impl PulseBlasBuilderSettings {
+    pub fn builder_for(&self, id: &AssetId<Mesh>) -> PulseBlasBuilder {
+        self.per_mesh.get(id).copied().unwrap_or(self.default)
+    }
+}
+
+// Build parameters for a single kind of acceleration structure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PulseBvhBuildSettings {
+    // Nodes with at most this many triangles/instances are never split.
+    pub max_leaf_size: u32,
+    // Number of bins per axis when searching for the best split plane.
+    pub bin_count: usize,
+    // Cost of visiting a node, relative to `intersection_cost`.
+    pub traversal_cost: f32,
+    // Cost of intersecting a single triangle/instance.
+    pub intersection_cost: f32,
+    // Stop splitting when the SAH cost of the best split is higher than keeping the node as a leaf.
+    pub sah_termination: bool,
+    // A refit tree is rebuilt once its SAH cost exceeds the cost after the last full build times this ratio.
+    pub refit_rebuild_ratio: f32,
+}
+
+impl PulseBvhBuildSettings {
+    // `split_cost` is the sum of child areas weighted by their primitive counts, as returned when finding a split plane.
+    pub fn is_split_worth_it(&self, node_area: f32, count: u32, split_cost: f32) -> bool {
+        if !self.sah_termination {
+            return true;
+        }
+        let leaf_cost = self.intersection_cost * count as f32 * node_area;
+        let split_cost = self.traversal_cost * node_area + self.intersection_cost * split_cost;
+        split_cost < leaf_cost
+    }
+}
+
+// Changing `blas` rebuilds every BLAS. The TLAS is rebuilt with the current `tlas` settings.
+// `blas_layout` selects how BLASes are stored on the GPU. The TLAS is always binary.
+// `vertex_layout` selects how the triangles they point to are stored, and `vertex_encoding` how their normals and UVs are.
+// Changing any of these three only re-uploads the scene, the trees themselves are kept.
+#[derive(Resource, ExtractResource, Clone, Debug, PartialEq)]
+pub struct PulseBvhSettings {
+    pub blas: PulseBvhBuildSettings,
+    pub tlas: PulseBvhBuildSettings,
+    pub blas_layout: PulseBlasLayout,
+    pub vertex_layout: PulseVertexLayout,
+    pub vertex_encoding: PulseVertexEncoding,
+}
+
+impl Default for PulseBvhSettings {
+    fn default() -> Self {
+        Self {
+            blas: PulseBvhBuildSettings {
+                max_leaf_size: 8,
+                bin_count: 20,
+                traversal_cost: 1.0,
+                intersection_cost: 1.0,
+                sah_termination: false,
+                refit_rebuild_ratio: 1.5,
+            },
+            tlas: PulseBvhBuildSettings {
+                max_leaf_size: 1,
+                bin_count: 2,
+                traversal_cost: 1.0,
+                intersection_cost: 1.0,
+                sah_termination: false,
+                refit_rebuild_ratio: 1.5,
+            },
+            blas_layout: PulseBlasLayout::Binary,
+            vertex_layout: PulseVertexLayout::PerTriangle,
+            vertex_encoding: PulseVertexEncoding::Full,
+        }
+    }
+}
 
 // Prepare triangle data and build bvh for new/modified mesh assets and remove when not used anymore.
 fn prepare_extracted_mesh_assets(
     extracted: Res<ExtractedMeshAssets>,
+    builder_settings: Res<PulseBlasBuilderSettings>,
+    bvh_settings: Res<PulseBvhSettings>,
+    cache_settings: Res<PulseBvhCacheSettings>,
+    async_settings: Res<PulseAsyncBlasSettings>,
+    build_status: Res<PulseSceneBuildStatus>,
+    prebuilt: Res<PulsePrebuiltMeshes>,
+    scene_diagnostics: Res<PulseSceneDiagnostics>,
+    mut pending: ResMut<PulsePendingBlasBuilds>,
     mut meshes: ResMut<PulseMeshes>,
+    mut built_with: Local<Option<PulseBvhBuildSettings>>,
 ) {
+    // Rebuild everything that was built with old settings. The other fields of `PulseBvhSettings` only change how the
+    // trees are stored on the GPU.
+    let blas_settings_changed = *built_with != Some(bvh_settings.blas);
+    *built_with = Some(bvh_settings.blas);
+    if builder_settings.is_changed() || blas_settings_changed {
+        for (id, mesh) in meshes.0.iter_mut() {
+            // Builds in flight are queued again once they finish, see below.
+            if pending.0.contains_key(id) {
+                continue;
+            }
+            let builder = builder_settings.builder_for(id);
+            rebuild_blas(
+                *id,
+                Arc::make_mut(mesh),
+                builder,
+                &bvh_settings.blas,
+                &async_settings,
+                &mut pending,
+            );
+        }
+    }
+
     for (id, mesh) in extracted.new_or_modified.iter() {
-        let positions = mesh
-            .attribute(Mesh::ATTRIBUTE_POSITION)
-            .and_then(VertexAttributeValues::as_float3)
-            .unwrap()
-            .iter()
-            .map(|p| Vec3::from_array(*p))
-            .collect::<Vec<Vec3>>();
-        let normals = mesh
-            .attribute(Mesh::ATTRIBUTE_NORMAL)
-            .and_then(VertexAttributeValues::as_float3)
-            .unwrap()
-            .iter()
-            .map(|n| Vec3::from_array(*n))
-            .collect::<Vec<Vec3>>();
-        let uvs = mesh
-            .attribute(Mesh::ATTRIBUTE_UV_0)
-            .and_then(|attribute| match attribute {
-                VertexAttributeValues::Float32x2(value) => Some(value),
-                _ => None,
-            })
-            .unwrap()
-            .iter()
-            .map(|uv| Vec2::from_array(*uv))
-            .collect::<Vec<Vec2>>();
-
-        let indices: Vec<u32> = match mesh.indices() {
-            Some(Indices::U16(values)) => values.iter().map(|v| *v as u32).collect::<Vec<u32>>(),
-            Some(Indices::U32(values)) => values.clone(),
-            None => {
-                error!("Cannot load mesh with no index buffer.");
-                return;
+        // A build that was started for an older version of the mesh, or for new settings, is out of date. Either way
+        // the tree it was going to replace can't be refit, it might have been built with older settings.
+        let mut can_refit = true;
+        if let Some(build) = pending.0.remove(id) {
+            if build.has_placeholder {
+                meshes.0.remove(id);
+            }
+            can_refit = false;
+        }
+
+        let mut data = match read_mesh_vertex_data(mesh) {
+            Ok(data) => data,
+            Err(reason) => {
+                scene_diagnostics
+                    .report(PulseSceneDiagnosticEvent::MeshSkipped { mesh: *id, reason });
+                continue;
             }
         };
-        let mut triangle_data = vec![];
-        let mut primitives = vec![];
-        for i_0 in 0..(indices.len() / 3) {
-            let i_0 = i_0 * 3;
-            let v_0 = indices[i_0] as usize;
-            let v_1 = indices[i_0 + 1] as usize;
-            let v_2 = indices[i_0 + 2] as usize;
-            primitives.push(PulsePrimitive {
-                positions: [positions[v_0], positions[v_1], positions[v_2]],
-            });
-            triangle_data.push(PulseTriangleData {
-                normals: [normals[v_0], normals[v_1], normals[v_2]],
-                uvs: [uvs[v_0], uvs[v_1], uvs[v_2]],
-            })
+        let builder = builder_settings.builder_for(id);
+
+        // Meshes loaded from a processed `.pulsemesh` file come with their BLAS.
+        if !meshes.0.contains_key(id) {
+            if let Some(mesh) = prebuilt.get(
+                id,
+                &data.positions,
+                &data.indices,
+                builder,
+                &bvh_settings.blas,
+            ) {
+                meshes.0.insert(*id, Arc::new(mesh));
+                continue;
+            }
         }
 
-        // let blas_time_begin = Instant::now();
-        let bvh = build_blas(&primitives);
-        // info!(
-        //     "Built BLAS for mesh id:{:?} with triangle count {:?} in {:.3?}",
-        //     id,
-        //     primitives.len(),
-        //     blas_time_begin.elapsed(),
-        // );
+        // Vertices moved but the triangles are the same, so the existing tree topology is still valid.
+        if let Some(existing) = meshes.0.get_mut(id).filter(|_| can_refit) {
+            if existing.indices == data.indices {
+                generate_missing_tangents(&mut data);
+                // A new mesh rather than modifying the old one, which raycast snapshots might still use.
+                let mut mesh = PulseMesh {
+                    primitives: build_primitives(&data.positions, &data.indices),
+                    triangle_data: build_triangle_data(
+                        &data.positions,
+                        &data.normals,
+                        &data.tangents,
+                        &data.uvs,
+                        &data.indices,
+                    ),
+                    bvh: existing.bvh.clone(),
+                    indices: data.indices,
+                    built_sah_cost: existing.built_sah_cost,
+                };
+                refit_blas(&mut mesh.bvh, &mesh.primitives);
+                if mesh.bvh.sah_cost() > mesh.built_sah_cost * bvh_settings.blas.refit_rebuild_ratio
+                {
+                    mesh.bvh =
+                        build_blas_with_builder(&mesh.primitives, builder, &bvh_settings.blas);
+                    mesh.built_sah_cost = mesh.bvh.sah_cost();
+                }
+                *existing = Arc::new(mesh);
+                continue;
+            }
+        }
 
-        meshes.0.insert(
-            id.clone(),
-            PulseMesh {
-                primitives,
-                triangle_data,
-                bvh,
+        let cache_directory = cache_settings.directory.clone();
+        let settings = bvh_settings.blas;
+        if !async_settings.should_build_async(data.indices.len() / 3) {
+            let mesh = build_mesh(data, builder, &settings, cache_directory.as_deref());
+            meshes.0.insert(*id, Arc::new(mesh));
+            continue;
+        }
+
+        // An older version of the mesh is traced until the new one is done, otherwise the placeholder.
+        let mut has_placeholder = false;
+        if !meshes.0.contains_key(id)
+            && async_settings.placeholder == PulseBlasPlaceholder::BoundingBox
+        {
+            let primitives = build_primitives(&data.positions, &data.indices);
+            meshes.0.insert(
+                *id,
+                Arc::new(bounding_box_placeholder(&primitives, &settings)),
+            );
+            has_placeholder = true;
+        }
+        let task = AsyncComputeTaskPool::get()
+            .spawn(async move { build_mesh(data, builder, &settings, cache_directory.as_deref()) });
+        pending.0.insert(
+            *id,
+            PulsePendingBlasBuild {
+                task,
+                has_placeholder,
+                builder,
+                settings,
             },
         );
     }
 
     for id in extracted.removed.iter() {
+        pending.0.remove(id);
         meshes.0.remove(id);
     }
+
+    // Builds that were started with settings that changed since are traced as they are until they're rebuilt.
+    for build in pending.take_finished() {
+        let builder = builder_settings.builder_for(&build.id);
+        let mesh = meshes
+            .0
+            .entry(build.id)
+            .insert(Arc::new(build.mesh))
+            .into_mut();
+        if build.builder != builder || build.settings != bvh_settings.blas {
+            rebuild_blas(
+                build.id,
+                Arc::make_mut(mesh),
+                builder,
+                &bvh_settings.blas,
+                &async_settings,
+                &mut pending,
+            );
+        }
+    }
+    pending.publish_status(&build_status);
+}
+
+// Rebuilds the BLAS of an already prepared mesh, on the `AsyncComputeTaskPool` if it's large. The old BLAS is kept
+// until the new one is done.
+fn rebuild_blas(
+    id: AssetId<Mesh>,
+    mesh: &mut PulseMesh,
+    builder: PulseBlasBuilder,
+    settings: &PulseBvhBuildSettings,
+    async_settings: &PulseAsyncBlasSettings,
+    pending: &mut PulsePendingBlasBuilds,
+) {
+    if !async_settings.should_build_async(mesh.primitives.len()) {
+        mesh.bvh = build_blas_with_builder(&mesh.primitives, builder, settings);
+        mesh.built_sah_cost = mesh.bvh.sah_cost();
+        return;
+    }
+
+    let primitives = mesh.primitives.clone();
+    let triangle_data = mesh.triangle_data.clone();
+    let indices = mesh.indices.clone();
+    let settings = *settings;
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        let bvh = build_blas_with_builder(&primitives, builder, &settings);
+        PulseMesh {
+            primitives,
+            triangle_data,
+            built_sah_cost: bvh.sah_cost(),
+            bvh,
+            indices,
+        }
+    });
+    pending.0.insert(
+        id,
+        PulsePendingBlasBuild {
+            task,
+            has_placeholder: false,
+            builder,
+            settings,
+        },
+    );
+}
+
+// Builds the BLAS of a mesh, or loads it from the cache in `cache_directory`.
+fn build_mesh(
+    mut data: MeshVertexData,
+    builder: PulseBlasBuilder,
+    settings: &PulseBvhBuildSettings,
+    cache_directory: Option<&Path>,
+) -> PulseMesh {
+    let primitives = build_primitives(&data.positions, &data.indices);
+
+    let cache_key = cache_directory.map(|directory| {
+        let key = mesh_cache_key(
+            &data.positions,
+            &data.normals,
+            &data.tangents,
+            &data.uvs,
+            &data.indices,
+            builder,
+            settings,
+        );
+        (directory, key)
+    });
+    if let Some(cached) =
+        cache_key.and_then(|(directory, key)| load_cached_blas(directory, key, primitives.len()))
+    {
+        return PulseMesh {
+            primitives,
+            triangle_data: cached.triangle_data,
+            built_sah_cost: cached.bvh.sah_cost(),
+            bvh: cached.bvh,
+            indices: data.indices,
+        };
+    }
+
+    // Cached triangle data already has its generated tangents.
+    generate_missing_tangents(&mut data);
+    let MeshVertexData {
+        positions,
+        normals,
+        tangents,
+        uvs,
+        indices,
+    } = data;
+    let triangle_data = build_triangle_data(&positions, &normals, &tangents, &uvs, &indices);
+
+    // let blas_time_begin = Instant::now();
+    let bvh = build_blas_with_builder(&primitives, builder, settings);
+    // info!(
+    //     "Built BLAS with triangle count {:?} in {:.3?}",
+    //     primitives.len(),
+    //     blas_time_begin.elapsed(),
+    // );
+
+    if let Some((directory, key)) = cache_key {
+        store_cached_blas(directory, key, &bvh, &triangle_data);
+    }
+
+    PulseMesh {
+        primitives,
+        triangle_data,
+        built_sah_cost: bvh.sah_cost(),
+        bvh,
+        indices,
+    }
+}
+
+struct MeshVertexData {
+    positions: Vec<Vec3>,
+    // Empty if the mesh has no normals, in which case flat normals are used.
+    normals: Vec<Vec3>,
+    // Empty if the mesh has no tangents, until `generate_missing_tangents`.
+    tangents: Vec<Vec4>,
+    uvs: Vec<Vec2>,
+    // Always a triangle list, even for non-indexed meshes and triangle strips.
+    indices: Vec<u32>,
+}
+
+fn read_mesh_vertex_data(mesh: &Mesh) -> Result<MeshVertexData, PulseMeshSkipReason> {
+    let Some(positions) = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(VertexAttributeValues::as_float3)
+    else {
+        return Err(PulseMeshSkipReason::MissingPositions);
+    };
+    let positions = positions
+        .iter()
+        .map(|p| Vec3::from_array(*p))
+        .collect::<Vec<Vec3>>();
+
+    let normals = match mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(VertexAttributeValues::as_float3)
+    {
+        Some(normals) if normals.len() == positions.len() => normals
+            .iter()
+            .map(|n| Vec3::from_array(*n))
+            .collect::<Vec<Vec3>>(),
+        _ => vec![],
+    };
+    let tangents = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+        Some(VertexAttributeValues::Float32x4(tangents)) if tangents.len() == positions.len() => {
+            tangents
+                .iter()
+                .map(|t| Vec4::from_array(*t))
+                .collect::<Vec<Vec4>>()
+        }
+        _ => vec![],
+    };
+    let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(uvs)) if uvs.len() == positions.len() => uvs
+            .iter()
+            .map(|uv| Vec2::from_array(*uv))
+            .collect::<Vec<Vec2>>(),
+        _ => vec![Vec2::ZERO; positions.len()],
+    };
+
+    // Strip restart indices are kept as `u32::MAX` regardless of the index format.
+    let vertices: Vec<u32> = match mesh.indices() {
+        Some(Indices::U16(values)) => values
+            .iter()
+            .map(|v| if *v == u16::MAX { u32::MAX } else { *v as u32 })
+            .collect::<Vec<u32>>(),
+        Some(Indices::U32(values)) => values.clone(),
+        None => (0..positions.len() as u32).collect(),
+    };
+
+    let indices = match mesh.primitive_topology() {
+        PrimitiveTopology::TriangleList => vertices,
+        PrimitiveTopology::TriangleStrip => triangle_strip_to_list(&vertices),
+        topology => return Err(PulseMeshSkipReason::UnsupportedTopology(topology)),
+    };
+
+    if indices.len() % 3 != 0 || indices.iter().any(|i| *i as usize >= positions.len()) {
+        return Err(PulseMeshSkipReason::InvalidIndices);
+    }
+
+    Ok(MeshVertexData {
+        positions,
+        normals,
+        tangents,
+        uvs,
+        indices,
+    })
+}
+
+// Generates tangents with MikkTSpace for meshes that have normals and UVs but no tangents, the same as
+// `Mesh::generate_tangents` would. Goes through a temporary `Mesh` since that's how Bevy exposes MikkTSpace. Meshes
+// without normals or UVs, or where generation fails, are left without tangents.
+fn generate_missing_tangents(data: &mut MeshVertexData) {
+    if !data.tangents.is_empty()
+        || data.normals.is_empty()
+        || data.uvs.iter().all(|uv| *uv == data.uvs[0])
+    {
+        return;
+    }
+
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    );
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        data.positions
+            .iter()
+            .map(|p| p.to_array())
+            .collect::<Vec<[f32; 3]>>(),
+    );
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        data.normals
+            .iter()
+            .map(|n| n.to_array())
+            .collect::<Vec<[f32; 3]>>(),
+    );
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_UV_0,
+        data.uvs
+            .iter()
+            .map(|uv| uv.to_array())
+            .collect::<Vec<[f32; 2]>>(),
+    );
+    mesh.insert_indices(Indices::U32(data.indices.clone()));
+    if mesh.generate_tangents().is_err() {
+        return;
+    }
+    if let Some(VertexAttributeValues::Float32x4(tangents)) =
+        mesh.attribute(Mesh::ATTRIBUTE_TANGENT)
+    {
+        data.tangents = tangents.iter().map(|t| Vec4::from_array(*t)).collect();
+    }
+}
+
+// Every other triangle in a strip is flipped to keep the winding consistent.
+fn triangle_strip_to_list(strip: &[u32]) -> Vec<u32> {
+    let mut indices = vec![];
+    for run in strip.split(|i| *i == u32::MAX) {
+        for (i, window) in run.windows(3).enumerate() {
+            let [a, b, c] = [window[0], window[1], window[2]];
+            // Degenerate triangles are used to stitch strips together.
+            if a == b || b == c || a == c {
+                continue;
+            }
+            if i % 2 == 0 {
+                indices.extend([a, b, c]);
+            } else {
+                indices.extend([b, a, c]);
+            }
+        }
+    }
+    indices
+}
+
+fn build_primitives(positions: &[Vec3], indices: &[u32]) -> Vec<PulsePrimitive> {
+    let mut primitives = vec![];
+    for i_0 in 0..(indices.len() / 3) {
+        let i_0 = i_0 * 3;
+        let v_0 = indices[i_0] as usize;
+        let v_1 = indices[i_0 + 1] as usize;
+        let v_2 = indices[i_0 + 2] as usize;
+        primitives.push(PulsePrimitive {
+            positions: [positions[v_0], positions[v_1], positions[v_2]],
+        });
+    }
+    primitives
+}
+
+// Falls back to flat normals when `normals` is empty, and zero tangents when `tangents` is.
+fn build_triangle_data(
+    positions: &[Vec3],
+    normals: &[Vec3],
+    tangents: &[Vec4],
+    uvs: &[Vec2],
+    indices: &[u32],
+) -> Vec<PulseTriangleData> {
+    let mut triangle_data = vec![];
+    for i_0 in 0..(indices.len() / 3) {
+        let i_0 = i_0 * 3;
+        let v_0 = indices[i_0] as usize;
+        let v_1 = indices[i_0 + 1] as usize;
+        let v_2 = indices[i_0 + 2] as usize;
+        let normals = if normals.is_empty() {
+            let n = (positions[v_1] - positions[v_0])
+                .cross(positions[v_2] - positions[v_0])
+                .normalize_or_zero();
+            [n, n, n]
+        } else {
+            [normals[v_0], normals[v_1], normals[v_2]]
+        };
+        let tangents = if tangents.is_empty() {
+            [Vec4::ZERO; 3]
+        } else {
+            [tangents[v_0], tangents[v_1], tangents[v_2]]
+        };
+        triangle_data.push(PulseTriangleData {
+            normals,
+            tangents,
+            uvs: [uvs[v_0], uvs[v_1], uvs[v_2]],
+        })
+    }
+    triangle_data
 }
 
 #[derive(Resource, Default)]
 pub struct PulsePreparedMeshAssetData {
+    // Only one of `primitives`/`triangle_data` and `vertices` is filled, depending on `vertex_layout`.
+    // `packed_triangle_data` replaces `triangle_data` with `PulseVertexEncoding::Compact`.
     pub primitives: Vec<PulsePrimitive>,
     pub triangle_data: Vec<PulseTriangleData>,
+    pub packed_triangle_data: Vec<PulsePackedTriangleData>,
+    pub vertices: PulseIndexedVertices,
+    pub vertex_layout: PulseVertexLayout,
+    pub vertex_encoding: PulseVertexEncoding,
     pub indices: Vec<u32>,
+    // Only one of `nodes` and `wide_nodes` is filled, depending on `blas_layout`.
     pub nodes: Vec<PulseBLASNode>,
+    pub wide_nodes: Vec<u32>,
+    pub blas_layout: PulseBlasLayout,
     pub materials: Vec<PulseMaterial>,
 }
 
 // Index into buffers in `PulsePreparedMeshAssetData`.
-#[derive(ShaderType, Copy, Clone, Debug)]
+#[derive(ShaderType, Copy, Clone, Debug, Default)]
 pub struct PulseMeshIndex {
     pub triangle_offset: u32,
     pub triangle_count: u32,
@@ -470,58 +1170,207 @@ pub struct PulseMeshIndices(pub HashMap<AssetId<Mesh>, PulseMeshIndex>);
 
 fn prepare_mesh_data(
     meshes: Res<PulseMeshes>,
-    extracted: Res<ExtractedMeshAssets>,
+    deformed_instances: Res<PulseDeformedInstances>,
     mut prepared_mesh_data: ResMut<PulsePreparedMeshAssetData>,
     mut mesh_indices: ResMut<PulseMeshIndices>,
+    mut deformed_mesh_indices: ResMut<PulseDeformedMeshIndices>,
+    bvh_settings: Res<PulseBvhSettings>,
 ) {
     // Abort if mesh data is the same as last frame's.
-    if extracted.empty() {
+    if !meshes.is_changed() && !deformed_instances.is_changed() && !bvh_settings.is_changed() {
         return;
     }
 
-    *prepared_mesh_data = PulsePreparedMeshAssetData::default();
+    *prepared_mesh_data = PulsePreparedMeshAssetData {
+        blas_layout: bvh_settings.blas_layout,
+        vertex_layout: bvh_settings.vertex_layout,
+        vertex_encoding: bvh_settings.vertex_encoding,
+        ..default()
+    };
     *mesh_indices = PulseMeshIndices::default();
+    *deformed_mesh_indices = PulseDeformedMeshIndices::default();
     for (id, mesh) in meshes.0.iter() {
-        let triangle_offset = prepared_mesh_data.primitives.len() as u32;
-        let index_offset = prepared_mesh_data.indices.len() as u32;
-        let node_offset = prepared_mesh_data.nodes.len() as u32;
+        let mesh_index = append_mesh_data(&mut prepared_mesh_data, mesh);
+        mesh_indices.0.insert(id.clone(), mesh_index);
+    }
+    for (entity, instance) in deformed_instances.0.iter() {
+        let mesh_index = append_mesh_data(&mut prepared_mesh_data, &instance.mesh);
+        deformed_mesh_indices.0.insert(*entity, mesh_index);
+    }
+    if prepared_mesh_data.vertex_encoding == PulseVertexEncoding::Compact {
+        prepared_mesh_data.vertices.pack();
+    }
+}
 
-        mesh_indices.0.insert(
-            id.clone(),
-            PulseMeshIndex {
-                triangle_offset,
-                triangle_count: mesh.primitives.len() as u32,
-                index_offset,
-                node_offset,
-            },
-        );
+fn append_mesh_data(
+    prepared_mesh_data: &mut PulsePreparedMeshAssetData,
+    mesh: &PulseMesh,
+) -> PulseMeshIndex {
+    let mut mesh_index = PulseMeshIndex {
+        triangle_offset: 0,
+        triangle_count: mesh.primitives.len() as u32,
+        index_offset: prepared_mesh_data.indices.len() as u32,
+        node_offset: 0,
+    };
 
-        prepared_mesh_data
-            .primitives
-            .extend(mesh.primitives.clone());
-        prepared_mesh_data
-            .triangle_data
-            .extend(mesh.triangle_data.clone());
-        prepared_mesh_data
-            .indices
-            .extend(mesh.bvh.tri_indices.clone());
-        prepared_mesh_data.nodes.extend(mesh.bvh.nodes.clone());
+    match prepared_mesh_data.vertex_layout {
+        PulseVertexLayout::PerTriangle => {
+            mesh_index.triangle_offset = prepared_mesh_data.primitives.len() as u32;
+            prepared_mesh_data
+                .primitives
+                .extend(mesh.primitives.clone());
+            match prepared_mesh_data.vertex_encoding {
+                PulseVertexEncoding::Full => prepared_mesh_data
+                    .triangle_data
+                    .extend(mesh.triangle_data.clone()),
+                PulseVertexEncoding::Compact => prepared_mesh_data
+                    .packed_triangle_data
+                    .extend(mesh.triangle_data.iter().map(PulsePackedTriangleData::from)),
+            }
+        }
+        PulseVertexLayout::Indexed => {
+            mesh_index.triangle_offset = (prepared_mesh_data.vertices.indices.len() / 3) as u32;
+            prepared_mesh_data.vertices.append(mesh);
+        }
+    }
+    prepared_mesh_data
+        .indices
+        .extend(mesh.bvh.tri_indices.clone());
+    match prepared_mesh_data.blas_layout.width() {
+        Some(width) => {
+            mesh_index.node_offset =
+                (prepared_mesh_data.wide_nodes.len() / wide_node_words(width)) as u32;
+            prepared_mesh_data
+                .wide_nodes
+                .extend(collapse_blas(&mesh.bvh, width));
+        }
+        None => {
+            mesh_index.node_offset = prepared_mesh_data.nodes.len() as u32;
+            prepared_mesh_data.nodes.extend(mesh.bvh.nodes.clone());
+        }
+    }
+
+    mesh_index
+}
+
+// Which ray types can hit an instance, plus whether it receives shadows. Stored in `PulseMeshInstance::visibility_flags`
+// and matches the constants in utilities.wgsl.
+pub const PULSE_VISIBLE_TO_CAMERA_RAYS: u32 = 1 << 0;
+pub const PULSE_VISIBLE_TO_GI_RAYS: u32 = 1 << 1;
+pub const PULSE_VISIBLE_TO_SHADOW_RAYS: u32 = 1 << 2;
+pub const PULSE_RECEIVES_SHADOWS: u32 = 1 << 3;
+
+// Camera and GI rays only hit instances that share a render layer with the camera. Shadow rays ignore render layers,
+// the same as Bevy's shadow maps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PulseInstanceVisibility {
+    pub render_layers: u32,
+    pub flags: u32,
+}
+
+impl PulseInstanceVisibility {
+    pub fn new(
+        render_layers: Option<&RenderLayers>,
+        not_shadow_caster: bool,
+        not_shadow_receiver: bool,
+    ) -> Self {
+        let mut flags = PULSE_VISIBLE_TO_CAMERA_RAYS | PULSE_VISIBLE_TO_GI_RAYS;
+        if !not_shadow_caster {
+            flags |= PULSE_VISIBLE_TO_SHADOW_RAYS;
+        }
+        if !not_shadow_receiver {
+            flags |= PULSE_RECEIVES_SHADOWS;
+        }
+        Self {
+            render_layers: render_layers.copied().unwrap_or_default().bits(),
+            flags,
+        }
     }
 }
 
+// Entities are left out of the scene when their `InheritedVisibility` is false, which covers `Visibility::Hidden` on
+// them or any parent. `ViewVisibility` is only respected if `use_view_visibility` is set, since it's also the result of
+// frustum culling: entities outside the view would stop showing up in reflections and casting shadows into it.
+// Read from the main world while extracting.
+#[derive(Resource, Default, Clone, Debug)]
+pub struct PulseVisibilitySettings {
+    pub use_view_visibility: bool,
+}
+
+// The deformation is `None` for entities that aren't skinned or morphed.
 #[derive(Resource, Default)]
 pub struct ExtractedMeshMaterialInstances(
-    pub Vec<(Handle<Mesh>, Handle<StandardMaterial>, GlobalTransform)>,
+    pub  Vec<(
+        Entity,
+        Handle<Mesh>,
+        Handle<StandardMaterial>,
+        GlobalTransform,
+        Option<PulseDeformation>,
+        PulseInstanceVisibility,
+    )>,
 );
 
 pub fn extract_mesh_material_instances(
-    query: Extract<Query<(&Handle<Mesh>, &Handle<StandardMaterial>, &GlobalTransform)>>,
+    query: Extract<
+        Query<(
+            Entity,
+            &Handle<Mesh>,
+            &Handle<StandardMaterial>,
+            &GlobalTransform,
+            Option<&SkinnedMesh>,
+            Option<&MeshMorphWeights>,
+            &InheritedVisibility,
+            &ViewVisibility,
+            Option<&RenderLayers>,
+            Has<NotShadowCaster>,
+            Has<NotShadowReceiver>,
+        )>,
+    >,
+    joints: Extract<Query<&GlobalTransform>>,
+    inverse_bindposes: Extract<Res<Assets<SkinnedMeshInverseBindposes>>>,
+    visibility_settings: Extract<Res<PulseVisibilitySettings>>,
     mut extracted: ResMut<ExtractedMeshMaterialInstances>,
 ) {
     extracted.0 = query
         .iter()
-        .map(|(mesh, material, transform)| (mesh.clone(), material.clone(), transform.clone()))
-        .collect::<Vec<(Handle<Mesh>, Handle<StandardMaterial>, GlobalTransform)>>();
+        .filter(
+            |(_, _, _, _, _, _, inherited_visibility, view_visibility, ..)| {
+                inherited_visibility.get()
+                    && (!visibility_settings.use_view_visibility || view_visibility.get())
+            },
+        )
+        .map(
+            |(
+                entity,
+                mesh,
+                material,
+                transform,
+                skinned_mesh,
+                morph_weights,
+                _,
+                _,
+                render_layers,
+                not_shadow_caster,
+                not_shadow_receiver,
+            )| {
+                let deformation =
+                    extract_deformation(skinned_mesh, morph_weights, &joints, &inverse_bindposes);
+                let visibility = PulseInstanceVisibility::new(
+                    render_layers,
+                    not_shadow_caster,
+                    not_shadow_receiver,
+                );
+                (
+                    entity,
+                    mesh.clone(),
+                    material.clone(),
+                    transform.clone(),
+                    deformation,
+                    visibility,
+                )
+            },
+        )
+        .collect();
 }
 
 #[derive(ShaderType, Copy, Clone, Debug)]
@@ -530,6 +1379,22 @@ pub struct PulseMeshInstance {
     pub transform_inv: Mat4,
     pub mesh_index: PulseMeshIndex,
     pub material_index: u32,
+    pub render_layers: u32,
+    pub visibility_flags: u32,
+}
+
+impl PulseMeshInstance {
+    // Fills the slot of a removed entity until a new one takes it. Not visible to any ray.
+    pub fn tombstone() -> Self {
+        Self {
+            transform: Mat4::IDENTITY,
+            transform_inv: Mat4::IDENTITY,
+            mesh_index: PulseMeshIndex::default(),
+            material_index: 0,
+            render_layers: 0,
+            visibility_flags: 0,
+        }
+    }
 }
 
 pub struct PulsePrimitiveMeshInstance {
@@ -538,11 +1403,46 @@ pub struct PulsePrimitiveMeshInstance {
     pub center: Vec3,
 }
 
+impl PulsePrimitiveMeshInstance {
+    // Inverted bounds, left out of the TLAS.
+    pub fn tombstone() -> Self {
+        Self {
+            bounds_min: Vec3::MAX,
+            bounds_max: Vec3::MIN,
+            center: Vec3::ZERO,
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.bounds_min.cmpgt(self.bounds_max).any()
+    }
+}
+
 #[derive(Resource, Default)]
 pub struct PulseSceneTLAS(pub PulseTLAS);
 
+// An entity keeps its instance index for as long as it's prepared, see `stable_instance_order`. The slots of removed
+// entities hold `PulseMeshInstance::tombstone` until a new entity takes them.
 #[derive(Resource, Default, Debug)]
-pub struct PulseMeshInstances(pub Vec<PulseMeshInstance>);
+pub struct PulseMeshInstances {
+    pub instances: Vec<PulseMeshInstance>,
+    // Entity that produced each instance, in the same order. `None` for tombstones.
+    pub entities: Vec<Option<Entity>>,
+    pub indices: HashMap<Entity, u32>,
+}
+
+impl PulseMeshInstances {
+    pub fn index_of(&self, entity: Entity) -> Option<u32> {
+        self.indices.get(&entity).copied()
+    }
+
+    pub fn entity(&self, instance_index: u32) -> Option<Entity> {
+        self.entities
+            .get(instance_index as usize)
+            .copied()
+            .flatten()
+    }
+}
 
 #[derive(ShaderType, Copy, Clone, Debug)]
 pub struct PulseLightDataIndex {
@@ -558,20 +1458,121 @@ pub struct PulseLightData {
     pub light_data_indices: Vec<PulseLightDataIndex>,
 }
 
+// What `prepare_mesh_instances` saw last time it ran, used to skip work for instances that didn't change.
+#[derive(Default)]
+struct PreparedInstancesState {
+    keys: Vec<(
+        Entity,
+        AssetId<Mesh>,
+        AssetId<StandardMaterial>,
+        PulseInstanceVisibility,
+    )>,
+    transforms: Vec<GlobalTransform>,
+    // SAH cost of the TLAS right after its last full build.
+    built_sah_cost: f32,
+    // Already reported as diagnostics, so they are only sent again when something changes.
+    skipped: HashMap<Entity, PulseInstanceSkipReason>,
+    no_lights: bool,
+}
+
 fn prepare_mesh_instances(
     extracted: Res<ExtractedMeshMaterialInstances>,
+    meshes: Res<PulseMeshes>,
     mesh_indices: Res<PulseMeshIndices>,
+    deformed_instances: Res<PulseDeformedInstances>,
+    deformed_mesh_indices: Res<PulseDeformedMeshIndices>,
     mesh_data: Res<PulsePreparedMeshAssetData>,
     mut light_data: ResMut<PulseLightData>,
     material_data: Res<PulsePreparedMaterialAssetData>,
     material_indices: Res<PulseMaterialIndices>,
+    bvh_settings: Res<PulseBvhSettings>,
     mut mesh_instances: ResMut<PulseMeshInstances>,
     mut tlas: ResMut<PulseSceneTLAS>,
+    scene_diagnostics: Res<PulseSceneDiagnostics>,
+    mut state: Local<PreparedInstancesState>,
     // mut diagnostics: Diagnostics,
 ) {
     // let instance_prepare_start_time = Instant::now();
 
-    mesh_instances.0 = vec![];
+    let keys = extracted
+        .0
+        .iter()
+        .map(|(entity, mesh, material, _, _, visibility)| {
+            (*entity, mesh.id(), material.id(), *visibility)
+        })
+        .collect::<Vec<_>>();
+    let transforms = extracted
+        .0
+        .iter()
+        .map(|(_, _, _, transform, _, _)| *transform)
+        .collect::<Vec<GlobalTransform>>();
+
+    // Light data doesn't depend on transforms so it's only recreated when the instances themselves change.
+    let rebuild_lights = keys != state.keys || mesh_data.is_changed() || material_data.is_changed();
+    if !rebuild_lights
+        && transforms == state.transforms
+        && !bvh_settings.is_changed()
+        && !tlas.0.nodes.is_empty()
+    {
+        return;
+    }
+    state.keys = keys;
+    state.transforms = transforms;
+
+    // Resolve mesh and material of every instance first, those that aren't prepared yet are left out.
+    let mut resolved = HashMap::new();
+    let mut current = vec![];
+    let mut skipped = HashMap::new();
+    for (i, (entity, mesh_handle, material_handle, _, deformation, _)) in
+        extracted.0.iter().enumerate()
+    {
+        let (Handle::Weak(mesh_id), Handle::Weak(material_id)) =
+            (mesh_handle.clone_weak(), material_handle.clone_weak())
+        else {
+            continue;
+        };
+        // Deformed entities use their own copy of the mesh, or the rest pose until it has been prepared.
+        let deformed_mesh_index = deformation
+            .as_ref()
+            .and_then(|_| deformed_mesh_indices.0.get(entity));
+        let (mesh_index, material_index) = match (
+            deformed_mesh_index.or_else(|| mesh_indices.0.get(&mesh_id)),
+            material_indices.0.get(&material_id),
+        ) {
+            (Some(mesh_index), Some(&material_index)) => (mesh_index, material_index),
+            (None, _) => {
+                skipped.insert(*entity, PulseInstanceSkipReason::MeshNotPrepared);
+                continue;
+            }
+            (_, None) => {
+                skipped.insert(*entity, PulseInstanceSkipReason::MaterialNotPrepared);
+                continue;
+            }
+        };
+        resolved.insert(
+            *entity,
+            (
+                i,
+                mesh_id,
+                *mesh_index,
+                material_index,
+                deformed_mesh_index.is_some(),
+            ),
+        );
+        current.push(*entity);
+    }
+    for (entity, reason) in skipped.iter() {
+        if state.skipped.get(entity) != Some(reason) {
+            scene_diagnostics.report(PulseSceneDiagnosticEvent::InstanceSkipped {
+                entity: *entity,
+                reason: *reason,
+            });
+        }
+    }
+    state.skipped = skipped;
+    let prepared = stable_instance_order(&mesh_instances.entities, &current);
+
+    mesh_instances.instances = vec![];
     let mut instance_primitives: Vec<PulsePrimitiveMeshInstance> = vec![]; // Used for TLAS creation.
 
     // Create a cdf based on triangle size for every emissive mesh instance and store consecutively in `cdf_buffer`.
@@ -580,49 +1581,56 @@ fn prepare_mesh_instances(
     let mut light_data_indices = vec![];
     let mut light_emission_strengths = vec![];
 
-    for (mesh_handle, material_handle, transform) in &extracted.0 {
-        let (Handle::Weak(mesh_id), Handle::Weak(material_id)) =
-            (mesh_handle.clone_weak(), material_handle.clone_weak())
-        else {
+    for entity in prepared.iter() {
+        let Some(entity) = entity else {
+            mesh_instances
+                .instances
+                .push(PulseMeshInstance::tombstone());
+            instance_primitives.push(PulsePrimitiveMeshInstance::tombstone());
             continue;
         };
-        let (Some(mesh_index), Some(&material_index)) = (
-            mesh_indices.0.get(&mesh_id),
-            material_indices.0.get(&material_id),
-        ) else {
-            continue;
+        let (extracted_index, mesh_id, mesh_index, material_index, deformed) = resolved[entity];
+        let (_, _, _, transform, _, visibility) = &extracted.0[extracted_index];
+        let mesh = if deformed {
+            &deformed_instances.0[entity].mesh
+        } else {
+            &meshes.0[&mesh_id]
+        };
+        let world_space = deformed
+            && deformed_instances
+                .0
+                .get(entity)
+                .is_some_and(|instance| instance.world_space);
+        let transform = if world_space {
+            Mat4::IDENTITY
+        } else {
+            transform.compute_matrix()
         };
-        let transform = transform.compute_matrix();
         let transform_inv = transform.inverse();
-        mesh_instances.0.push(PulseMeshInstance {
+        mesh_instances.instances.push(PulseMeshInstance {
             transform,
             transform_inv,
-            mesh_index: mesh_index.clone(),
+            mesh_index,
             material_index,
+            render_layers: visibility.render_layers,
+            visibility_flags: visibility.flags,
         });
 
         let material = material_data.0[material_index as usize].clone();
-        if material.emissive.xyz().length() > 0.0001 {
+        if rebuild_lights && material.emissive.xyz().length() > 0.0001 {
             light_emission_strengths.push(material.emissive.xyz().length_squared());
 
-            // Primitives of CURRENT mesh
-            let mut primitives: Vec<PulsePrimitive> = Vec::new();
-            primitives.extend_from_slice(
-                &mesh_data.primitives[mesh_index.triangle_offset as usize
-                    ..(mesh_index.triangle_offset + mesh_index.triangle_count) as usize],
-            );
-
-            let (mut cdf, total_area) = create_triangle_area_cdf(&primitives);
+            let (mut cdf, total_area) = create_triangle_area_cdf(&mesh.primitives);
             light_data_indices.push(PulseLightDataIndex {
                 cdf_offset: cdfs.len() as u32,
-                mesh_instance_index: mesh_instances.0.len() as u32 - 1u32,
+                mesh_instance_index: mesh_instances.instances.len() as u32 - 1u32,
             });
             cdfs.append(&mut cdf);
             light_mesh_areas.push(total_area);
         }
 
-        // Calculate world space bounds.
-        let root_node = &mesh_data.nodes[mesh_index.node_offset as usize];
+        // Calculate world space bounds. Taken from the binary BVH since the GPU copy might be in the wide layout.
+        let root_node = &mesh.bvh.nodes[0];
         let b_min = root_node.aabb_min;
         let b_max = root_node.aabb_max;
         let mut b_min_world = Vec3::MAX;
@@ -680,22 +1688,79 @@ fn prepare_mesh_instances(
         });
     }
 
-    light_data.emission_strength_cdf = create_emission_strength_cdf(&light_emission_strengths);
-    light_data.triangle_cdfs = cdfs;
-    light_data.light_mesh_areas = light_mesh_areas;
-    light_data.light_data_indices = light_data_indices;
+    if rebuild_lights {
+        light_data.emission_strength_cdf = create_emission_strength_cdf(&light_emission_strengths);
+        light_data.triangle_cdfs = cdfs;
+        light_data.light_mesh_areas = light_mesh_areas;
+        light_data.light_data_indices = light_data_indices;
+
+        let no_lights = !resolved.is_empty() && light_data.light_data_indices.is_empty();
+        if no_lights && !state.no_lights {
+            scene_diagnostics.report(PulseSceneDiagnosticEvent::NoLights);
+        }
+        state.no_lights = no_lights;
+    }
 
     // diagnostics.add_measurement(INSTANCE_PREPARE_TIME, || {
     //     instance_prepare_start_time.elapsed().as_secs_f64() * 1000.0
     // });
 
     // let tlas_time_begin = Instant::now();
-    tlas.0 = build_tlas(&instance_primitives);
+    // The same instances in the same order only moved, so the old tree can be refit unless it degraded too much.
+    let can_refit = prepared == mesh_instances.entities
+        && !bvh_settings.is_changed()
+        && !tlas.0.nodes.is_empty();
+    let mut rebuild_tlas = !can_refit;
+    if can_refit {
+        refit_tlas(&mut tlas.0, &instance_primitives);
+        rebuild_tlas =
+            tlas.0.sah_cost() > state.built_sah_cost * bvh_settings.tlas.refit_rebuild_ratio;
+    }
+    if rebuild_tlas {
+        tlas.0 = build_tlas(&instance_primitives, &bvh_settings.tlas);
+        state.built_sah_cost = tlas.0.sah_cost();
+    }
+    mesh_instances.indices = prepared
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entity)| entity.map(|entity| (entity, i as u32)))
+        .collect();
+    mesh_instances.entities = prepared;
     // diagnostics.add_measurement(TLAS_BUILD_TIME, || {
     //     tlas_time_begin.elapsed().as_secs_f64() * 1000.0
     // });
 }
 
+// Orders `current` so that entities that were already prepared keep their index. New entities fill the slots of
+// removed ones first, slots that stay empty are `None` and hold a tombstone. Only empty slots at the end are dropped,
+// since that doesn't move any other entity.
+fn stable_instance_order(previous: &[Option<Entity>], current: &[Entity]) -> Vec<Option<Entity>> {
+    let current_set = current.iter().copied().collect::<HashSet<Entity>>();
+    let previous_set = previous
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<HashSet<Entity>>();
+    let mut added = current
+        .iter()
+        .filter(|e| !previous_set.contains(*e))
+        .copied();
+
+    let mut order = previous
+        .iter()
+        .map(|e| {
+            e.filter(|e| current_set.contains(e))
+                .or_else(|| added.next())
+        })
+        .collect::<Vec<Option<Entity>>>();
+    order.extend(added.map(Some));
+
+    while order.last().is_some_and(|e| e.is_none()) {
+        order.pop();
+    }
+    order
+}
+
 // Returns (cdf, total area)
 fn create_triangle_area_cdf(primitives: &Vec<PulsePrimitive>) -> (Vec<f32>, f32) {
     let mut areas = vec![];
@@ -747,144 +1812,207 @@ pub struct PulseSceneBindGroupLayout(pub BindGroupLayout);
 impl FromWorld for PulseSceneBindGroupLayout {
     fn from_world(world: &mut World) -> Self {
         let device = world.resource::<RenderDevice>();
-        Self(device.create_bind_group_layout(
-            Some("pulse_scene_bind_group_layout"),
-            &[
-                // Uniform
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+        let limits = world.resource::<PulseBufferLimits>();
+        let mut entries = vec![
+            // Uniform
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Primitives
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Primitives
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Triangle data
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Triangle data
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Triangle indices
-                BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Triangle indices
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // BLAS nodes
-                BindGroupLayoutEntry {
-                    binding: 4,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // BLAS nodes
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // TLAS nodes
-                BindGroupLayoutEntry {
-                    binding: 5,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // TLAS nodes
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Instance indices
-                BindGroupLayoutEntry {
-                    binding: 6,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Instance indices
+            BindGroupLayoutEntry {
+                binding: 6,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Instances
-                BindGroupLayoutEntry {
-                    binding: 7,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Instances
+            BindGroupLayoutEntry {
+                binding: 7,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Materials
-                BindGroupLayoutEntry {
-                    binding: 8,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Materials
+            BindGroupLayoutEntry {
+                binding: 8,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Light emission strength CDF
-                BindGroupLayoutEntry {
-                    binding: 9,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Light emission strength CDF
+            BindGroupLayoutEntry {
+                binding: 9,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Light triangle area CDFs
-                BindGroupLayoutEntry {
-                    binding: 10,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Light triangle area CDFs
+            BindGroupLayoutEntry {
+                binding: 10,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Light mesh areas
-                BindGroupLayoutEntry {
-                    binding: 11,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+                count: None,
+            },
+            // Light mesh areas
+            BindGroupLayoutEntry {
+                binding: 11,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // Light indices
-                BindGroupLayoutEntry {
-                    binding: 12,
+                count: None,
+            },
+            // Light indices
+            BindGroupLayoutEntry {
+                binding: 12,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // Vertex UVs, only used by the indexed vertex layout
+            BindGroupLayoutEntry {
+                binding: 13,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // Vertex indices, only used by the indexed vertex layout
+            BindGroupLayoutEntry {
+                binding: 14,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+        // Material texture atlas and its sampler
+        entries.push(BindGroupLayoutEntry {
+            binding: PULSE_TEXTURE_ATLAS_BINDING,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            count: None,
+        });
+        entries.push(BindGroupLayoutEntry {
+            binding: PULSE_TEXTURE_ATLAS_SAMPLER_BINDING,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Sampler(SamplerBindingType::Filtering),
+            count: None,
+        });
+        // Directional albedos of the layered BSDF
+        entries.push(BindGroupLayoutEntry {
+            binding: PULSE_ENERGY_TABLE_BINDING,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D3,
+                multisampled: false,
+            },
+            count: None,
+        });
+        // Extra pages of primitives, triangle data and BLAS nodes
+        for buffer in 0..PULSE_PAGED_BUFFER_COUNT {
+            for page in 1..limits.page_count {
+                entries.push(BindGroupLayoutEntry {
+                    binding: page_binding(buffer, page),
                     visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
@@ -892,9 +2020,11 @@ impl FromWorld for PulseSceneBindGroupLayout {
                         min_binding_size: None,
                     },
                     count: None,
-                },
-            ],
-        ))
+                });
+            }
+        }
+
+        Self(device.create_bind_group_layout(Some("pulse_scene_bind_group_layout"), &entries))
     }
 }
 
@@ -902,189 +2032,469 @@ impl FromWorld for PulseSceneBindGroupLayout {
 pub struct PulseSceneUniform {
     pub instance_count: u32,
     pub light_count: u32,
+    // Elements per page of the paged buffers, see `PulsePagedSceneBuffer`.
+    pub primitive_page_size: u32,
+    pub triangle_data_page_size: u32,
+    pub blas_node_page_size: u32,
 }
 
 #[derive(Resource, Default)]
 pub struct PulseSceneBindGroup(pub Option<BindGroup>);
 
-// TODO: Don't run every frame
+// Persistent GPU buffers backing `PulseSceneBindGroup`.
+#[derive(Resource)]
+pub struct PulseSceneBuffers {
+    uniform: UniformBuffer<PulseSceneUniform>,
+    primitives: PulsePagedSceneBuffer,
+    triangle_data: PulsePagedSceneBuffer,
+    triangle_indices: PulseSceneBuffer,
+    blas_nodes: PulsePagedSceneBuffer,
+    tlas_nodes: PulseSceneBuffer,
+    instance_indices: PulseSceneBuffer,
+    instances: PulseSceneBuffer,
+    materials: PulseSceneBuffer,
+    light_emission_strength_cdf: PulseSceneBuffer,
+    light_triangle_area_cdfs: PulseSceneBuffer,
+    light_mesh_areas: PulseSceneBuffer,
+    light_indices: PulseSceneBuffer,
+    vertex_uvs: PulseSceneBuffer,
+    vertex_indices: PulseSceneBuffer,
+    // Set until everything has been uploaded once, regardless of what changed since.
+    needs_full_upload: bool,
+}
+
+impl FromWorld for PulseSceneBuffers {
+    fn from_world(world: &mut World) -> Self {
+        let limits = world.resource::<PulseBufferLimits>();
+        let mut uniform = UniformBuffer::default();
+        uniform.set_label(Some("pulse_scene_uniform"));
+        Self {
+            uniform,
+            primitives: PulsePagedSceneBuffer::new("pulse_primitive_buffer", limits),
+            triangle_data: PulsePagedSceneBuffer::new("pulse_triangle_data_buffer", limits),
+            triangle_indices: PulseSceneBuffer::new("pulse_triangle_index_buffer", limits),
+            blas_nodes: PulsePagedSceneBuffer::new("pulse_blas_node_buffer", limits),
+            tlas_nodes: PulseSceneBuffer::new("pulse_tlas_node_buffer", limits),
+            instance_indices: PulseSceneBuffer::new("pulse_instance_index_buffer", limits),
+            instances: PulseSceneBuffer::new("pulse_instance_buffer", limits),
+            materials: PulseSceneBuffer::new("pulse_material_buffer", limits),
+            light_emission_strength_cdf: PulseSceneBuffer::new(
+                "pulse_light_strength_cdf_buffer",
+                limits,
+            ),
+            light_triangle_area_cdfs: PulseSceneBuffer::new("pulse_light_area_cdf_buffer", limits),
+            light_mesh_areas: PulseSceneBuffer::new("pulse_light_area_buffer", limits),
+            light_indices: PulseSceneBuffer::new("pulse_light_index_buffer", limits),
+            vertex_uvs: PulseSceneBuffer::new("pulse_vertex_uv_buffer", limits),
+            vertex_indices: PulseSceneBuffer::new("pulse_vertex_index_buffer", limits),
+            needs_full_upload: true,
+        }
+    }
+}
+
+impl PulseSceneBuffers {
+    fn overflows(&self) -> Vec<PulseBufferOverflow> {
+        [
+            self.primitives.overflow(),
+            self.triangle_data.overflow(),
+            self.triangle_indices.overflow(),
+            self.blas_nodes.overflow(),
+            self.tlas_nodes.overflow(),
+            self.instance_indices.overflow(),
+            self.instances.overflow(),
+            self.materials.overflow(),
+            self.light_emission_strength_cdf.overflow(),
+            self.light_triangle_area_cdfs.overflow(),
+            self.light_mesh_areas.overflow(),
+            self.light_indices.overflow(),
+            self.vertex_uvs.overflow(),
+            self.vertex_indices.overflow(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+// Size in bytes of every GPU scene buffer, including unused capacity. Paged buffers count all of their pages.
+#[derive(Default, Clone, Debug)]
+pub struct PulseSceneBufferSizes {
+    pub uniform: u64,
+    pub primitives: u64,
+    pub triangle_data: u64,
+    pub triangle_indices: u64,
+    pub blas_nodes: u64,
+    pub tlas_nodes: u64,
+    pub instance_indices: u64,
+    pub instances: u64,
+    pub materials: u64,
+    pub light_emission_strength_cdf: u64,
+    pub light_triangle_area_cdfs: u64,
+    pub light_mesh_areas: u64,
+    pub light_indices: u64,
+    pub vertex_uvs: u64,
+    pub vertex_indices: u64,
+    pub texture_atlas: u64,
+}
+
+impl PulseSceneBufferSizes {
+    pub fn total(&self) -> u64 {
+        self.uniform
+            + self.primitives
+            + self.triangle_data
+            + self.triangle_indices
+            + self.blas_nodes
+            + self.tlas_nodes
+            + self.instance_indices
+            + self.instances
+            + self.materials
+            + self.light_emission_strength_cdf
+            + self.light_triangle_area_cdfs
+            + self.light_mesh_areas
+            + self.light_indices
+            + self.vertex_uvs
+            + self.vertex_indices
+            + self.texture_atlas
+    }
+}
+
+// Latest `PulseSceneBufferSizes`, updated by the render world whenever a scene buffer is reallocated. Shared between the
+// main and render world.
+#[derive(Resource, Clone, Default)]
+pub struct PulseSceneBufferUsage(Arc<Mutex<PulseSceneBufferSizes>>);
+
+impl PulseSceneBufferUsage {
+    pub fn sizes(&self) -> PulseSceneBufferSizes {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn publish(&self, sizes: PulseSceneBufferSizes) {
+        *self.0.lock().unwrap() = sizes;
+    }
+}
+
+// Only uploads scene data that changed and only recreates the bind group when a buffer was reallocated.
 fn queue_scene_bind_group(
     mesh_data: Res<PulsePreparedMeshAssetData>,
     material_data: Res<PulsePreparedMaterialAssetData>,
     instances: Res<PulseMeshInstances>,
     light_data: Res<PulseLightData>,
     tlas: Res<PulseSceneTLAS>,
+    atlas: Res<PulseTextureAtlas>,
+    energy_table: Res<PulseEnergyTableTexture>,
+    mut buffers: ResMut<PulseSceneBuffers>,
+    buffer_usage: Res<PulseSceneBufferUsage>,
     mut bind_group: ResMut<PulseSceneBindGroup>,
     layout: Res<PulseSceneBindGroupLayout>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     can_render: Res<PulseCanRender>,
+    scene_diagnostics: Res<PulseSceneDiagnostics>,
+    mut reported_overflows: Local<Vec<PulseBufferOverflow>>,
 ) {
     if !can_render.0 {
         return;
     }
 
-    let uniform = PulseSceneUniform {
-        instance_count: instances.0.len() as u32,
-        light_count: light_data.light_data_indices.len() as u32,
-    };
+    let buffers = buffers.as_mut();
+    let first_upload = buffers.needs_full_upload;
+    buffers.needs_full_upload = false;
+    // The atlas texture is recreated whenever it's repacked.
+    let mut reallocated = first_upload || atlas.is_changed();
+
+    if first_upload || mesh_data.is_changed() {
+        // The indexed vertex layout reuses the primitive and triangle data bindings for vertex positions, and normals
+        // and tangents.
+        match mesh_data.vertex_layout {
+            PulseVertexLayout::PerTriangle => {
+                reallocated |=
+                    buffers
+                        .primitives
+                        .write(&mesh_data.primitives, &render_device, &render_queue);
+                reallocated |= match mesh_data.vertex_encoding {
+                    PulseVertexEncoding::Full => buffers.triangle_data.write(
+                        &mesh_data.triangle_data,
+                        &render_device,
+                        &render_queue,
+                    ),
+                    PulseVertexEncoding::Compact => buffers.triangle_data.write(
+                        &mesh_data.packed_triangle_data,
+                        &render_device,
+                        &render_queue,
+                    ),
+                };
+            }
+            PulseVertexLayout::Indexed => {
+                let vertices = &mesh_data.vertices;
+                reallocated |=
+                    buffers
+                        .primitives
+                        .write(&vertices.positions, &render_device, &render_queue);
+                reallocated |= match mesh_data.vertex_encoding {
+                    PulseVertexEncoding::Full => buffers.triangle_data.write(
+                        &vertices.tangent_frames,
+                        &render_device,
+                        &render_queue,
+                    ),
+                    PulseVertexEncoding::Compact => buffers.triangle_data.write(
+                        &vertices.packed_tangent_frames,
+                        &render_device,
+                        &render_queue,
+                    ),
+                };
+            }
+        }
+        // Written in both layouts so stale data doesn't stay alive after switching back.
+        reallocated |= match mesh_data.vertex_encoding {
+            PulseVertexEncoding::Full => {
+                buffers
+                    .vertex_uvs
+                    .write(&mesh_data.vertices.uvs, &render_device, &render_queue)
+            }
+            PulseVertexEncoding::Compact => buffers.vertex_uvs.write(
+                &mesh_data.vertices.packed_uvs,
+                &render_device,
+                &render_queue,
+            ),
+        };
+        reallocated |= buffers.vertex_indices.write(
+            &mesh_data.vertices.indices,
+            &render_device,
+            &render_queue,
+        );
+        reallocated |=
+            buffers
+                .triangle_indices
+                .write(&mesh_data.indices, &render_device, &render_queue);
+        reallocated |= match mesh_data.blas_layout.width() {
+            // Stored as words, but paged by whole nodes.
+            Some(width) => buffers.blas_nodes.write_with_stride(
+                &mesh_data.wide_nodes,
+                wide_node_words(width) as u64 * 4,
+                &render_device,
+                &render_queue,
+            ),
+            None => buffers
+                .blas_nodes
+                .write(&mesh_data.nodes, &render_device, &render_queue),
+        };
+    }
 
-    let uniform_buffer = create_uniform_buffer(
-        uniform,
-        Some("pulse_scene_uniform"),
-        &render_device,
-        &render_queue,
-    );
+    if first_upload || tlas.is_changed() {
+        reallocated |= buffers
+            .tlas_nodes
+            .write(&tlas.0.nodes, &render_device, &render_queue);
+        reallocated |=
+            buffers
+                .instance_indices
+                .write(&tlas.0.instance_indices, &render_device, &render_queue);
+    }
 
-    let primitive_buffer = create_storage_buffer(
-        mesh_data.primitives.clone(),
-        Some("pulse_primitive_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    if first_upload || instances.is_changed() {
+        reallocated |= buffers
+            .instances
+            .write(&instances.instances, &render_device, &render_queue);
+    }
 
-    let triangle_data_buffer = create_storage_buffer(
-        mesh_data.triangle_data.clone(),
-        Some("pulse_triangle_data_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    if first_upload || material_data.is_changed() {
+        reallocated |= buffers
+            .materials
+            .write(&material_data.0, &render_device, &render_queue);
+    }
 
-    let triangle_index_buffer = create_storage_buffer(
-        mesh_data.indices.clone(),
-        Some("pulse_triangle_index_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    if first_upload || light_data.is_changed() {
+        reallocated |= buffers.light_emission_strength_cdf.write(
+            &light_data.emission_strength_cdf,
+            &render_device,
+            &render_queue,
+        );
+        reallocated |= buffers.light_triangle_area_cdfs.write(
+            &light_data.triangle_cdfs,
+            &render_device,
+            &render_queue,
+        );
+        reallocated |= buffers.light_mesh_areas.write(
+            &light_data.light_mesh_areas,
+            &render_device,
+            &render_queue,
+        );
+        reallocated |= buffers.light_indices.write(
+            &light_data.light_data_indices,
+            &render_device,
+            &render_queue,
+        );
+    }
 
-    let blas_node_buffer = create_storage_buffer(
-        mesh_data.nodes.clone(),
-        Some("pulse_blas_node_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    // Written last since the page sizes depend on the mesh data that was just uploaded.
+    if first_upload || instances.is_changed() || light_data.is_changed() || mesh_data.is_changed() {
+        let uniform_buffer_id = buffers.uniform.buffer().map(|b| b.id());
+        buffers.uniform.set(PulseSceneUniform {
+            instance_count: instances.instances.len() as u32,
+            light_count: light_data.light_data_indices.len() as u32,
+            primitive_page_size: buffers.primitives.elements_per_page(),
+            triangle_data_page_size: buffers.triangle_data.elements_per_page(),
+            blas_node_page_size: buffers.blas_nodes.elements_per_page(),
+        });
+        buffers.uniform.write_buffer(&render_device, &render_queue);
+        reallocated |= buffers.uniform.buffer().map(|b| b.id()) != uniform_buffer_id;
+    }
 
-    let tlas_node_buffer = create_storage_buffer(
-        tlas.0.nodes.clone(),
-        Some("pulse_tlas_node_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    // Only buffers that just started overflowing are reported, not every time their data grows further.
+    let overflows = buffers.overflows();
+    for overflow in overflows.iter() {
+        if !reported_overflows
+            .iter()
+            .any(|o| o.buffer == overflow.buffer)
+        {
+            scene_diagnostics.report(PulseSceneDiagnosticEvent::BufferOverflow(*overflow));
+        }
+    }
+    *reported_overflows = overflows;
+    // Buffers that overflowed keep their old data and are only written again once their data changes, which is when
+    // the bind group is recreated.
+    if !reported_overflows.is_empty() {
+        bind_group.0 = None;
+        return;
+    }
 
-    let instance_index_buffer = create_storage_buffer(
-        tlas.0.instance_indices.clone(),
-        Some("pulse_instance_index_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    if !reallocated && bind_group.0.is_some() {
+        return;
+    }
 
-    let instance_buffer = create_storage_buffer(
-        instances.0.clone(),
-        Some("pulse_instance_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    buffer_usage.publish(PulseSceneBufferSizes {
+        uniform: buffers.uniform.buffer().map(|b| b.size()).unwrap_or(0),
+        primitives: buffers.primitives.byte_size(),
+        triangle_data: buffers.triangle_data.byte_size(),
+        triangle_indices: buffers.triangle_indices.byte_size(),
+        blas_nodes: buffers.blas_nodes.byte_size(),
+        tlas_nodes: buffers.tlas_nodes.byte_size(),
+        instance_indices: buffers.instance_indices.byte_size(),
+        instances: buffers.instances.byte_size(),
+        materials: buffers.materials.byte_size(),
+        light_emission_strength_cdf: buffers.light_emission_strength_cdf.byte_size(),
+        light_triangle_area_cdfs: buffers.light_triangle_area_cdfs.byte_size(),
+        light_mesh_areas: buffers.light_mesh_areas.byte_size(),
+        light_indices: buffers.light_indices.byte_size(),
+        vertex_uvs: buffers.vertex_uvs.byte_size(),
+        vertex_indices: buffers.vertex_indices.byte_size(),
+        texture_atlas: atlas.byte_size(),
+    });
+
+    let primitive_pages = buffers.primitives.bindings();
+    let triangle_data_pages = buffers.triangle_data.bindings();
+    let blas_node_pages = buffers.blas_nodes.bindings();
+    let mut entries = vec![
+        BindGroupEntry {
+            binding: 0,
+            resource: buffers.uniform.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 1,
+            resource: primitive_pages[0].clone(),
+        },
+        BindGroupEntry {
+            binding: 2,
+            resource: triangle_data_pages[0].clone(),
+        },
+        BindGroupEntry {
+            binding: 3,
+            resource: buffers.triangle_indices.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 4,
+            resource: blas_node_pages[0].clone(),
+        },
+        BindGroupEntry {
+            binding: 5,
+            resource: buffers.tlas_nodes.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 6,
+            resource: buffers.instance_indices.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 7,
+            resource: buffers.instances.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 8,
+            resource: buffers.materials.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 9,
+            resource: buffers.light_emission_strength_cdf.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 10,
+            resource: buffers.light_triangle_area_cdfs.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 11,
+            resource: buffers.light_mesh_areas.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 12,
+            resource: buffers.light_indices.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 13,
+            resource: buffers.vertex_uvs.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: 14,
+            resource: buffers.vertex_indices.binding().unwrap(),
+        },
+        BindGroupEntry {
+            binding: PULSE_TEXTURE_ATLAS_BINDING,
+            resource: BindingResource::TextureView(&atlas.view),
+        },
+        BindGroupEntry {
+            binding: PULSE_TEXTURE_ATLAS_SAMPLER_BINDING,
+            resource: BindingResource::Sampler(&atlas.sampler),
+        },
+        BindGroupEntry {
+            binding: PULSE_ENERGY_TABLE_BINDING,
+            resource: BindingResource::TextureView(&energy_table.view),
+        },
+    ];
+    for (buffer, pages) in [primitive_pages, triangle_data_pages, blas_node_pages]
+        .iter()
+        .enumerate()
+    {
+        for (page, resource) in pages.iter().enumerate().skip(1) {
+            entries.push(BindGroupEntry {
+                binding: page_binding(buffer as u32, page as u32),
+                resource: resource.clone(),
+            });
+        }
+    }
 
-    let material_buffer = create_storage_buffer(
-        material_data.0.clone(),
-        Some("pulse_material_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    bind_group.0 =
+        Some(render_device.create_bind_group(Some("pulse_scene_bind_group"), &layout.0, &entries));
+}
 
-    let light_emission_strength_cdf_buffer = create_storage_buffer(
-        light_data.emission_strength_cdf.clone(),
-        Some("pulse_light_strength_cdf_buffer"),
-        &render_device,
-        &render_queue,
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let light_triangle_area_cdf_buffer = create_storage_buffer(
-        light_data.triangle_cdfs.clone(),
-        Some("pulse_light_area_cdf_buffer"),
-        &render_device,
-        &render_queue,
-    );
+    #[test]
+    fn removed_instances_leave_tombstones() {
+        let [a, b, c, d] = [0, 1, 2, 3].map(Entity::from_raw);
 
-    let light_mesh_area_buffer = create_storage_buffer(
-        light_data.light_mesh_areas.clone(),
-        Some("pulse_light_area_buffer"),
-        &render_device,
-        &render_queue,
-    );
+        let order = stable_instance_order(&[], &[a, b, c]);
+        assert_eq!(order, [Some(a), Some(b), Some(c)]);
 
-    let light_index_buffer = create_storage_buffer(
-        light_data.light_data_indices.clone(),
-        Some("pulse_light_index_buffer"),
-        &render_device,
-        &render_queue,
-    );
+        // Removing an entity doesn't move the ones after it.
+        let order = stable_instance_order(&order, &[a, c]);
+        assert_eq!(order, [Some(a), None, Some(c)]);
 
-    // info!(
-    //     " AAAAAAAAAAAAAAAAAAAAAAAAAA {:?} BBBBBBBBBBBBBBBBBBBB",
-    //     instances.0
-    // );
-    // info!(
-    //     " CCCCCCCCCCCCCCCC {:?} DDDDDDDDDD ",
-    //     light_data.light_mesh_areas,
-    // );
+        // New entities take the first free slot.
+        let order = stable_instance_order(&order, &[d, c, a]);
+        assert_eq!(order, [Some(a), Some(d), Some(c)]);
 
-    bind_group.0 = Some(render_device.create_bind_group(
-        Some("pulse_scene_bind_group"),
-        &layout.0,
-        &[
-            BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: primitive_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: triangle_data_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 3,
-                resource: triangle_index_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 4,
-                resource: blas_node_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 5,
-                resource: tlas_node_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 6,
-                resource: instance_index_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 7,
-                resource: instance_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 8,
-                resource: material_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 9,
-                resource: light_emission_strength_cdf_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 10,
-                resource: light_triangle_area_cdf_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 11,
-                resource: light_mesh_area_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 12,
-                resource: light_index_buffer.binding().unwrap(),
-            },
-        ],
-    ));
+        // Free slots at the end are dropped.
+        let order = stable_instance_order(&order, &[a, d]);
+        assert_eq!(order, [Some(a), Some(d)]);
+        assert_eq!(stable_instance_order(&order, &[]), []);
+    }
 }
@@ -0,0 +1,626 @@
+use super::{
+    blas::*, build_mesh, build_primitives, cache::*, read_mesh_vertex_data, PulseBvhBuildSettings,
+    PulseBvhSettings, PulseIndexedVertices, PulseMesh, PulsePrimitive, PulseTriangleData,
+};
+use bevy::{
+    asset::{
+        io::{Reader as AssetReader, Writer},
+        processor::LoadTransformAndSave,
+        saver::{AssetSaver, SavedAsset},
+        transformer::{AssetTransformer, TransformedAsset},
+        AssetLoader, AsyncReadExt, AsyncWriteExt, LoadContext,
+    },
+    gltf::{Gltf, GltfLoader},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        Extract,
+    },
+    utils::{BoxedFuture, HashMap},
+};
+use std::{convert::Infallible, fmt, io, sync::Arc};
+
+// Meshes with prebuilt BLASes, so shipped games don't have to build them at runtime.
+//
+// Register `PulseGltfMeshProcessor` as the processor of a glTF file, eg. with
+// `app.set_default_asset_processor::<PulseGltfMeshProcessor>("glb")` after adding `PulsePlugin`, and load it in
+// `AssetMode::Processed`. The processed file only keeps the meshes, with their positions, normals, tangents and UVs, as
+// labeled assets under their glTF labels like "Mesh0/Primitive0". Everything else in the glTF, like materials and
+// scenes, is dropped.
+//
+// Prebuilt BLASes are only used while `PulseBvhSettings::blas` and the mesh's `PulseBlasBuilder` match the defaults
+// they were built with, and are rebuilt otherwise.
+//
+// File layout (little endian):
+//   magic: [u8; 8], version: u32, mesh count: u32, payload checksum: u64,
+//   payload per mesh: label length: u32, label: [u8], settings key: u64, triangle count: u32, node count: u32,
+//     tri index count: u32, primitives, triangle data, indices, nodes, tri indices
+
+const PULSEMESH_MAGIC: [u8; 8] = *b"PULSEMSH";
+const PULSEMESH_VERSION: u32 = 2;
+const PULSEMESH_EXTENSION: &str = "pulsemesh";
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8;
+const MESH_HEADER_SIZE: usize = 8 + 4 + 4 + 4;
+
+const PRIMITIVE_SIZE: usize = 3 * 3 * 4;
+const INDEX_SIZE: usize = 4;
+
+pub type PulseGltfMeshProcessor =
+    LoadTransformAndSave<GltfLoader, PulseGltfMeshTransformer, PulseMeshSaver>;
+
+#[derive(Asset, TypePath)]
+pub struct PulseMeshFile {
+    pub meshes: Vec<PulsePrebuiltMesh>,
+}
+
+#[derive(Clone)]
+pub struct PulsePrebuiltMesh {
+    pub label: String,
+    pub mesh: Handle<Mesh>,
+    // `blas_settings_key` of the builder and settings `data.bvh` was built with.
+    pub settings_key: u64,
+    pub data: Arc<PulseMesh>,
+}
+
+#[derive(Debug)]
+pub enum PulseMeshLoaderError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for PulseMeshLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read pulsemesh file: {}", e),
+            Self::Invalid(reason) => write!(f, "invalid pulsemesh file: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PulseMeshLoaderError {}
+
+impl From<io::Error> for PulseMeshLoaderError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Default)]
+pub struct PulseMeshLoader;
+
+impl AssetLoader for PulseMeshLoader {
+    type Asset = PulseMeshFile;
+    type Settings = ();
+    type Error = PulseMeshLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut AssetReader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<PulseMeshFile, PulseMeshLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).await?;
+            let decoded = decode_pulsemesh(&bytes).map_err(PulseMeshLoaderError::Invalid)?;
+
+            let mut meshes = vec![];
+            for (label, settings_key, mut data) in decoded {
+                let mesh = mesh_from_pulse_mesh(&mut data);
+                meshes.push(PulsePrebuiltMesh {
+                    mesh: load_context.add_labeled_asset(label.clone(), mesh),
+                    label,
+                    settings_key,
+                    data: Arc::new(data),
+                });
+            }
+            Ok(PulseMeshFile { meshes })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[PULSEMESH_EXTENSION]
+    }
+}
+
+// Rebuilds the Bevy mesh the BLAS was built for. Vertices with conflicting attributes are split, so `data.indices` is
+// replaced to match the new mesh.
+fn mesh_from_pulse_mesh(data: &mut PulseMesh) -> Mesh {
+    let mut vertices = PulseIndexedVertices::default();
+    vertices.append(data);
+
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    );
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vertices
+            .positions
+            .chunks_exact(3)
+            .map(|p| [p[0], p[1], p[2]])
+            .collect::<Vec<[f32; 3]>>(),
+    );
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        (0..vertices.vertex_count())
+            .map(|v| vertices.normal(v).to_array())
+            .collect::<Vec<[f32; 3]>>(),
+    );
+    // Meshes without tangents keep not having any, instead of getting zero tangents.
+    let tangents = (0..vertices.vertex_count())
+        .map(|v| vertices.tangent(v).to_array())
+        .collect::<Vec<[f32; 4]>>();
+    if tangents.iter().any(|t| t[3] != 0.0) {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, tangents);
+    }
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_UV_0,
+        vertices
+            .uvs
+            .iter()
+            .map(|uv| uv.to_array())
+            .collect::<Vec<[f32; 2]>>(),
+    );
+    mesh.insert_indices(Indices::U32(vertices.indices.clone()));
+    data.indices = vertices.indices;
+    mesh
+}
+
+#[derive(Default)]
+pub struct PulseMeshSaver;
+
+impl AssetSaver for PulseMeshSaver {
+    type Asset = PulseMeshFile;
+    type Settings = ();
+    type OutputLoader = PulseMeshLoader;
+    type Error = io::Error;
+
+    fn save<'a>(
+        &'a self,
+        writer: &'a mut Writer,
+        asset: SavedAsset<'a, PulseMeshFile>,
+        _settings: &'a (),
+    ) -> BoxedFuture<'a, Result<(), io::Error>> {
+        Box::pin(async move {
+            writer.write_all(&encode_pulsemesh(asset.get())).await?;
+            Ok(())
+        })
+    }
+}
+
+// Builds a BLAS for every mesh primitive in a glTF file, with the default `PulseBvhSettings` and `PulseBlasBuilder`.
+#[derive(Default)]
+pub struct PulseGltfMeshTransformer;
+
+impl AssetTransformer for PulseGltfMeshTransformer {
+    type AssetInput = Gltf;
+    type AssetOutput = PulseMeshFile;
+    type Settings = ();
+    type Error = Infallible;
+
+    fn transform<'a>(
+        &'a self,
+        asset: TransformedAsset<Gltf>,
+        _settings: &'a (),
+    ) -> BoxedFuture<'a, Result<TransformedAsset<PulseMeshFile>, Infallible>> {
+        Box::pin(async move {
+            let builder = PulseBlasBuilder::default();
+            let settings = PulseBvhSettings::default().blas;
+            let settings_key = blas_settings_key(builder, &settings);
+
+            // Sorted so the output doesn't depend on hash map order.
+            let mut labels = asset
+                .iter_labels()
+                .map(String::from)
+                .collect::<Vec<String>>();
+            labels.sort();
+
+            let mut meshes = vec![];
+            for label in labels {
+                let Some(mesh) = asset
+                    .get_erased_labeled(label.as_str())
+                    .and_then(|loaded| loaded.get::<Mesh>())
+                else {
+                    continue;
+                };
+                let Some(handle) = asset.get_handle::<_, Mesh>(label.as_str()) else {
+                    continue;
+                };
+                let data = match read_mesh_vertex_data(mesh) {
+                    Ok(data) => data,
+                    Err(reason) => {
+                        warn!("Skipping mesh {} in prebuilt mesh file: {}.", label, reason);
+                        continue;
+                    }
+                };
+                meshes.push(PulsePrebuiltMesh {
+                    label,
+                    mesh: handle,
+                    settings_key,
+                    data: Arc::new(build_mesh(data, builder, &settings, None)),
+                });
+            }
+
+            Ok(asset.replace_asset(PulseMeshFile { meshes }))
+        })
+    }
+}
+
+fn encode_pulsemesh(file: &PulseMeshFile) -> Vec<u8> {
+    let mut payload = vec![];
+    let write_vec3 = |payload: &mut Vec<u8>, v: Vec3| {
+        payload.extend_from_slice(&v.x.to_le_bytes());
+        payload.extend_from_slice(&v.y.to_le_bytes());
+        payload.extend_from_slice(&v.z.to_le_bytes());
+    };
+    for prebuilt in file.meshes.iter() {
+        let mesh = &prebuilt.data;
+        payload.extend_from_slice(&(prebuilt.label.len() as u32).to_le_bytes());
+        payload.extend_from_slice(prebuilt.label.as_bytes());
+        payload.extend_from_slice(&prebuilt.settings_key.to_le_bytes());
+        payload.extend_from_slice(&(mesh.primitives.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&(mesh.bvh.nodes.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&(mesh.bvh.tri_indices.len() as u32).to_le_bytes());
+
+        for primitive in mesh.primitives.iter() {
+            for p in primitive.positions.iter() {
+                write_vec3(&mut payload, *p);
+            }
+        }
+        for t in mesh.triangle_data.iter() {
+            for n in t.normals.iter() {
+                write_vec3(&mut payload, *n);
+            }
+            for tangent in t.tangents.iter() {
+                write_vec3(&mut payload, tangent.xyz());
+                payload.extend_from_slice(&tangent.w.to_le_bytes());
+            }
+            for uv in t.uvs.iter() {
+                payload.extend_from_slice(&uv.x.to_le_bytes());
+                payload.extend_from_slice(&uv.y.to_le_bytes());
+            }
+        }
+        for i in mesh.indices.iter() {
+            payload.extend_from_slice(&i.to_le_bytes());
+        }
+        for node in mesh.bvh.nodes.iter() {
+            write_vec3(&mut payload, node.aabb_min);
+            payload.extend_from_slice(&node.a_or_first_tri.to_le_bytes());
+            write_vec3(&mut payload, node.aabb_max);
+            payload.extend_from_slice(&node.tri_count.to_le_bytes());
+        }
+        for i in mesh.bvh.tri_indices.iter() {
+            payload.extend_from_slice(&i.to_le_bytes());
+        }
+    }
+
+    let mut checksum = ContentHasher::new();
+    checksum.write(&payload);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&PULSEMESH_MAGIC);
+    bytes.extend_from_slice(&PULSEMESH_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(file.meshes.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum.finish().to_le_bytes());
+    bytes.append(&mut payload);
+    bytes
+}
+
+// Returns the label, settings key and mesh of every entry, or the reason the file was rejected.
+fn decode_pulsemesh(bytes: &[u8]) -> Result<Vec<(String, u64, PulseMesh)>, String> {
+    if bytes.len() < HEADER_SIZE {
+        return Err("truncated header".into());
+    }
+
+    let mut reader = Reader::new(bytes);
+    if reader.take::<8>() != PULSEMESH_MAGIC {
+        return Err("bad magic".into());
+    }
+    let version = reader.u32();
+    if version != PULSEMESH_VERSION {
+        return Err(format!(
+            "version {} != {}, the file has to be processed again",
+            version, PULSEMESH_VERSION
+        ));
+    }
+    let mesh_count = reader.u32() as usize;
+    let checksum = reader.u64();
+
+    let mut hasher = ContentHasher::new();
+    hasher.write(&bytes[HEADER_SIZE..]);
+    if hasher.finish() != checksum {
+        return Err("checksum mismatch".into());
+    }
+
+    let mut meshes = vec![];
+    for _ in 0..mesh_count {
+        if reader.remaining() < 4 {
+            return Err("truncated mesh".into());
+        }
+        let label_len = reader.u32() as usize;
+        if reader.remaining() < label_len + MESH_HEADER_SIZE {
+            return Err("truncated mesh".into());
+        }
+        let mut label = vec![];
+        for _ in 0..label_len {
+            label.push(reader.take::<1>()[0]);
+        }
+        let label = String::from_utf8(label).map_err(|_| "label is not utf-8".to_string())?;
+        let settings_key = reader.u64();
+        let triangle_count = reader.u32() as usize;
+        let node_count = reader.u32() as usize;
+        let tri_index_count = reader.u32() as usize;
+
+        let size = triangle_count * (PRIMITIVE_SIZE + TRIANGLE_DATA_SIZE + 3 * INDEX_SIZE)
+            + node_count * NODE_SIZE
+            + tri_index_count * TRI_INDEX_SIZE;
+        if reader.remaining() < size {
+            return Err(format!("truncated mesh {:?}", label));
+        }
+
+        let mut primitives = Vec::with_capacity(triangle_count);
+        for _ in 0..triangle_count {
+            primitives.push(PulsePrimitive {
+                positions: [reader.vec3(), reader.vec3(), reader.vec3()],
+            });
+        }
+        let mut triangle_data = Vec::with_capacity(triangle_count);
+        for _ in 0..triangle_count {
+            triangle_data.push(PulseTriangleData {
+                normals: [reader.vec3(), reader.vec3(), reader.vec3()],
+                tangents: [reader.vec4(), reader.vec4(), reader.vec4()],
+                uvs: [reader.vec2(), reader.vec2(), reader.vec2()],
+            });
+        }
+        let mut indices = Vec::with_capacity(3 * triangle_count);
+        for _ in 0..(3 * triangle_count) {
+            indices.push(reader.u32());
+        }
+        let mut nodes = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            nodes.push(PulseBLASNode {
+                aabb_min: reader.vec3(),
+                a_or_first_tri: reader.u32(),
+                aabb_max: reader.vec3(),
+                tri_count: reader.u32(),
+            });
+        }
+        let mut tri_indices = Vec::with_capacity(tri_index_count);
+        for _ in 0..tri_index_count {
+            tri_indices.push(reader.u32());
+        }
+
+        let bvh = Blas { nodes, tri_indices };
+        validate_blas(&bvh, triangle_count)
+            .map_err(|reason| format!("{} in {:?}", reason, label))?;
+        // Vertices are rebuilt from the index buffer, which can't reference more vertices than corners.
+        if indices.iter().any(|i| *i as usize >= indices.len()) {
+            return Err(format!("vertex index out of bounds in {:?}", label));
+        }
+
+        meshes.push((
+            label,
+            settings_key,
+            PulseMesh {
+                primitives,
+                triangle_data,
+                built_sah_cost: bvh.sah_cost(),
+                bvh,
+                indices,
+            },
+        ));
+    }
+
+    if reader.remaining() != 0 {
+        return Err("size mismatch".into());
+    }
+    Ok(meshes)
+}
+
+// Prebuilt meshes of all loaded `PulseMeshFile`s in the render world.
+#[derive(Resource, Default)]
+pub struct PulsePrebuiltMeshes {
+    pub meshes: HashMap<AssetId<Mesh>, PulsePrebuiltMesh>,
+    files: HashMap<AssetId<PulseMeshFile>, Vec<AssetId<Mesh>>>,
+}
+
+impl PulsePrebuiltMeshes {
+    fn insert_file(&mut self, id: AssetId<PulseMeshFile>, file: &PulseMeshFile) {
+        let mut mesh_ids = vec![];
+        for prebuilt in file.meshes.iter() {
+            mesh_ids.push(prebuilt.mesh.id());
+            self.meshes.insert(prebuilt.mesh.id(), prebuilt.clone());
+        }
+        self.files.insert(id, mesh_ids);
+    }
+
+    fn remove_file(&mut self, id: &AssetId<PulseMeshFile>) {
+        for mesh_id in self.files.remove(id).unwrap_or_default() {
+            self.meshes.remove(&mesh_id);
+        }
+    }
+
+    // Returns the prebuilt mesh for `id` if it was built with the given settings and matches the mesh's vertices.
+    pub fn get(
+        &self,
+        id: &AssetId<Mesh>,
+        positions: &[Vec3],
+        indices: &[u32],
+        builder: PulseBlasBuilder,
+        settings: &PulseBvhBuildSettings,
+    ) -> Option<PulseMesh> {
+        let prebuilt = self.meshes.get(id)?;
+        if prebuilt.settings_key != blas_settings_key(builder, settings)
+            || prebuilt.data.indices != indices
+        {
+            return None;
+        }
+        let primitives = build_primitives(positions, indices);
+        let unchanged = primitives
+            .iter()
+            .zip(prebuilt.data.primitives.iter())
+            .all(|(a, b)| a.positions == b.positions);
+        unchanged.then(|| (*prebuilt.data).clone())
+    }
+}
+
+pub fn extract_prebuilt_meshes(
+    mut file_asset_events: Extract<EventReader<AssetEvent<PulseMeshFile>>>,
+    file_assets: Extract<Res<Assets<PulseMeshFile>>>,
+    mut prebuilt: ResMut<PulsePrebuiltMeshes>,
+) {
+    for event in file_asset_events.read() {
+        match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                prebuilt.remove_file(id);
+                if let Some(file) = file_assets.get(*id) {
+                    prebuilt.insert_file(*id, file);
+                }
+            }
+            AssetEvent::Removed { id } => {
+                prebuilt.remove_file(id);
+            }
+            AssetEvent::Unused { .. } => {}
+            AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_mesh(offset: f32) -> PulseMesh {
+        let positions = (0..16)
+            .map(|i| Vec3::new((i % 4) as f32, (i / 4) as f32, offset))
+            .collect::<Vec<Vec3>>();
+        let mut indices = vec![];
+        for y in 0..3 {
+            for x in 0..3 {
+                let i = y * 4 + x;
+                indices.extend_from_slice(&[i, i + 1, i + 4, i + 1, i + 5, i + 4]);
+            }
+        }
+        let primitives = build_primitives(&positions, &indices);
+        let triangle_data = (0..primitives.len())
+            .map(|i| PulseTriangleData {
+                normals: [Vec3::Z; 3],
+                tangents: [Vec4::new(1.0, 0.0, 0.0, 1.0); 3],
+                uvs: [Vec2::splat(i as f32), Vec2::X, Vec2::Y],
+            })
+            .collect();
+        let bvh = build_blas(&primitives, &PulseBvhSettings::default().blas);
+        PulseMesh {
+            primitives,
+            triangle_data,
+            built_sah_cost: bvh.sah_cost(),
+            bvh,
+            indices,
+        }
+    }
+
+    fn test_file() -> PulseMeshFile {
+        PulseMeshFile {
+            meshes: ["Mesh0/Primitive0", "Mesh1/Primitive0"]
+                .iter()
+                .enumerate()
+                .map(|(i, label)| PulsePrebuiltMesh {
+                    label: label.to_string(),
+                    mesh: Handle::default(),
+                    settings_key: i as u64 + 10,
+                    data: Arc::new(test_mesh(i as f32)),
+                })
+                .collect(),
+        }
+    }
+
+    // Updates the checksum after `bytes` was modified, so the checks behind it are reached.
+    fn fix_checksum(bytes: &mut [u8]) {
+        let mut hasher = ContentHasher::new();
+        hasher.write(&bytes[HEADER_SIZE..]);
+        bytes[16..24].copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let file = test_file();
+        let decoded = decode_pulsemesh(&encode_pulsemesh(&file)).unwrap();
+        assert_eq!(decoded.len(), file.meshes.len());
+        for ((label, settings_key, mesh), prebuilt) in decoded.iter().zip(file.meshes.iter()) {
+            assert_eq!(*label, prebuilt.label);
+            assert_eq!(*settings_key, prebuilt.settings_key);
+            assert_eq!(mesh.indices, prebuilt.data.indices);
+            assert_eq!(mesh.built_sah_cost, prebuilt.data.built_sah_cost);
+            assert!(mesh.bvh.nodes == prebuilt.data.bvh.nodes);
+            assert_eq!(mesh.bvh.tri_indices, prebuilt.data.bvh.tri_indices);
+            for (a, b) in mesh.primitives.iter().zip(prebuilt.data.primitives.iter()) {
+                assert_eq!(a.positions, b.positions);
+            }
+            for (a, b) in mesh
+                .triangle_data
+                .iter()
+                .zip(prebuilt.data.triangle_data.iter())
+            {
+                assert_eq!(a.normals, b.normals);
+                assert_eq!(a.tangents, b.tangents);
+                assert_eq!(a.uvs, b.uvs);
+            }
+        }
+    }
+
+    #[test]
+    fn version_mismatch_is_rejected() {
+        let mut bytes = encode_pulsemesh(&test_file());
+        bytes[8..12].copy_from_slice(&(PULSEMESH_VERSION + 1).to_le_bytes());
+        let reason = decode_pulsemesh(&bytes).err().unwrap();
+        assert!(reason.starts_with("version"), "{}", reason);
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rejected() {
+        let mut bytes = encode_pulsemesh(&test_file());
+        bytes[HEADER_SIZE + 4] ^= 1;
+        assert_eq!(decode_pulsemesh(&bytes).err().unwrap(), "checksum mismatch");
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let bytes = encode_pulsemesh(&test_file());
+        assert_eq!(
+            decode_pulsemesh(&bytes[..HEADER_SIZE - 1]).err().unwrap(),
+            "truncated header"
+        );
+        for len in [
+            HEADER_SIZE + 2,
+            HEADER_SIZE + 20,
+            bytes.len() / 2,
+            bytes.len() - 1,
+        ] {
+            let mut truncated = bytes[..len].to_vec();
+            fix_checksum(&mut truncated);
+            let reason = decode_pulsemesh(&truncated).err().unwrap();
+            assert!(reason.starts_with("truncated mesh"), "{}", reason);
+        }
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        fix_checksum(&mut extended);
+        assert_eq!(decode_pulsemesh(&extended).err().unwrap(), "size mismatch");
+    }
+
+    #[test]
+    fn cyclic_blas_is_rejected() {
+        let mut file = test_file();
+        let mut mesh = test_mesh(0.0);
+        let interior = (1..mesh.bvh.nodes.len())
+            .find(|i| mesh.bvh.nodes[*i].tri_count == 0)
+            .unwrap();
+        // Points back at itself, which would loop forever in traversal.
+        mesh.bvh.nodes[interior].a_or_first_tri = interior as u32;
+        file.meshes[1].data = Arc::new(mesh);
+        let reason = decode_pulsemesh(&encode_pulsemesh(&file)).err().unwrap();
+        assert!(reason.starts_with("node index out of bounds"), "{}", reason);
+    }
+}
@@ -0,0 +1,534 @@
+use super::{
+    async_blas::PulsePendingBlasBuilds, blas::Blas, tlas::PulseTLAS,
+    ExtractedMeshMaterialInstances, PulseDeformedInstances, PulseDeformedMeshIndices, PulseMesh,
+    PulseMeshInstances, PulseMeshes, PulsePrimitive, PulseSceneTLAS,
+};
+use bevy::{
+    prelude::*,
+    tasks::{ComputeTaskPool, ParallelSlice, TaskPool},
+    utils::HashMap,
+};
+use std::sync::{Arc, RwLock};
+
+// Rays per task for batched queries.
+const BATCH_CHUNK_SIZE: usize = 64;
+
+// CPU ray queries against the same acceleration structures Pulse renders with. The render world publishes a new
+// snapshot whenever the scene changes, so with pipelined rendering results can lag a frame or two behind the main world.
+// Meshes whose BLAS is still being built can't be hit, even while they are traced as a bounding box placeholder.
+#[derive(Resource, Clone, Default)]
+pub struct PulseRaycast(Arc<RwLock<Arc<PulseRaycastScene>>>);
+
+impl PulseRaycast {
+    // The scene as of the last update. Hold on to it to run many queries against a consistent state.
+    pub fn scene(&self) -> Arc<PulseRaycastScene> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn cast_ray(&self, ray: Ray3d, max_distance: f32) -> Option<PulseRayHit> {
+        self.scene().cast_ray(ray, max_distance)
+    }
+
+    pub fn any_hit(&self, ray: Ray3d, max_distance: f32) -> bool {
+        self.scene().any_hit(ray, max_distance)
+    }
+
+    pub fn cast_rays(&self, rays: &[Ray3d], max_distance: f32) -> Vec<Option<PulseRayHit>> {
+        self.scene().cast_rays(rays, max_distance)
+    }
+
+    fn publish(&self, scene: PulseRaycastScene) {
+        *self.0.write().unwrap() = Arc::new(scene);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PulseRayHit {
+    pub entity: Entity,
+    // Index into `PulseMeshInstances::instances`, and the `instance_index` of GPU ray hits. Never a tombstone.
+    pub instance_index: u32,
+    // Index of the triangle in the mesh.
+    pub triangle_index: u32,
+    // Weights of the triangle's first, second and third vertex.
+    pub barycentrics: Vec3,
+    pub distance: f32,
+    pub position: Vec3,
+    // World space interpolated vertex normal.
+    pub normal: Vec3,
+}
+
+pub struct PulseRaycastInstance {
+    pub entity: Entity,
+    pub object_world: Mat4,
+    pub world_object: Mat4,
+    // The same mesh the render world prepared, not a copy.
+    pub mesh: Arc<PulseMesh>,
+}
+
+#[derive(Default)]
+pub struct PulseRaycastScene {
+    pub instances: Vec<PulseRaycastInstance>,
+    pub tlas: PulseTLAS,
+}
+
+struct Hit {
+    instance_index: u32,
+    triangle_index: u32,
+    t: f32,
+    u: f32,
+    v: f32,
+}
+
+impl PulseRaycastScene {
+    pub fn cast_ray(&self, ray: Ray3d, max_distance: f32) -> Option<PulseRayHit> {
+        self.traverse_tlas(ray, max_distance, false)
+            .map(|hit| self.resolve_hit(ray, hit))
+    }
+
+    // Cheaper than `cast_ray` since it stops at the first triangle found, eg. for line of sight checks.
+    pub fn any_hit(&self, ray: Ray3d, max_distance: f32) -> bool {
+        self.traverse_tlas(ray, max_distance, true).is_some()
+    }
+
+    // Runs `cast_ray` for every ray on the `ComputeTaskPool`. Results are in the same order as `rays`.
+    pub fn cast_rays(&self, rays: &[Ray3d], max_distance: f32) -> Vec<Option<PulseRayHit>> {
+        let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        rays.par_chunk_map(pool, BATCH_CHUNK_SIZE, |chunk| {
+            chunk
+                .iter()
+                .map(|ray| self.cast_ray(*ray, max_distance))
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn traverse_tlas(&self, ray: Ray3d, max_distance: f32, any_hit: bool) -> Option<Hit> {
+        if self.instances.is_empty() || self.tlas.nodes.is_empty() {
+            return None;
+        }
+
+        let origin = ray.origin;
+        let dir = *ray.direction;
+        let inv_dir = dir.recip();
+        let mut closest: Option<Hit> = None;
+        let mut t_max = max_distance;
+        let mut stack = vec![0usize];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.tlas.nodes[node_index];
+            if ray_aabb_intersect(origin, inv_dir, node.aabb_min, node.aabb_max, t_max).is_none() {
+                continue;
+            }
+            if node.instance_count == 0 {
+                stack.push(node.a_or_first_instance as usize);
+                stack.push(node.a_or_first_instance as usize + 1);
+                continue;
+            }
+
+            let first = node.a_or_first_instance as usize;
+            for i in first..(first + node.instance_count as usize) {
+                let instance_index = self.tlas.instance_indices[i];
+                let instance = &self.instances[instance_index as usize];
+                // The direction isn't normalized so that t is the same in object and world space.
+                let origin_object = instance.world_object.transform_point3(origin);
+                let dir_object = instance.world_object.transform_vector3(dir);
+                if let Some((triangle_index, t, u, v)) =
+                    traverse_blas(&instance.mesh, origin_object, dir_object, t_max, any_hit)
+                {
+                    t_max = t;
+                    closest = Some(Hit {
+                        instance_index,
+                        triangle_index,
+                        t,
+                        u,
+                        v,
+                    });
+                    if any_hit {
+                        return closest;
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    fn resolve_hit(&self, ray: Ray3d, hit: Hit) -> PulseRayHit {
+        let instance = &self.instances[hit.instance_index as usize];
+        let barycentrics = Vec3::new(1.0 - hit.u - hit.v, hit.u, hit.v);
+        let normals = instance.mesh.triangle_data[hit.triangle_index as usize].normals;
+        let normal_object =
+            barycentrics.x * normals[0] + barycentrics.y * normals[1] + barycentrics.z * normals[2];
+        let normal = instance
+            .world_object
+            .transpose()
+            .transform_vector3(normal_object)
+            .normalize_or_zero();
+
+        PulseRayHit {
+            entity: instance.entity,
+            instance_index: hit.instance_index,
+            triangle_index: hit.triangle_index,
+            barycentrics,
+            distance: hit.t,
+            position: ray.get_point(hit.t),
+            normal,
+        }
+    }
+}
+
+// Returns (triangle index, t, u, v) of the closest hit closer than `t_max`, or of any hit if `any_hit` is set.
+fn traverse_blas(
+    mesh: &PulseMesh,
+    origin: Vec3,
+    dir: Vec3,
+    mut t_max: f32,
+    any_hit: bool,
+) -> Option<(u32, f32, f32, f32)> {
+    let bvh = &mesh.bvh;
+    let inv_dir = dir.recip();
+    let mut closest = None;
+    let mut stack = vec![0usize];
+    while let Some(node_index) = stack.pop() {
+        let node = &bvh.nodes[node_index];
+        if ray_aabb_intersect(origin, inv_dir, node.aabb_min, node.aabb_max, t_max).is_none() {
+            continue;
+        }
+        if node.tri_count == 0 && bvh.nodes.len() > 1 {
+            stack.push(node.a_or_first_tri as usize);
+            stack.push(node.a_or_first_tri as usize + 1);
+            continue;
+        }
+
+        let first = node.a_or_first_tri as usize;
+        for i in first..(first + node.tri_count as usize) {
+            let triangle_index = bvh.tri_indices[i];
+            let prim = &mesh.primitives[triangle_index as usize];
+            if let Some((t, u, v)) = ray_triangle_intersect(origin, dir, prim, t_max) {
+                t_max = t;
+                closest = Some((triangle_index, t, u, v));
+                if any_hit {
+                    return closest;
+                }
+            }
+        }
+    }
+    closest
+}
+
+fn ray_aabb_intersect(
+    origin: Vec3,
+    inv_dir: Vec3,
+    aabb_min: Vec3,
+    aabb_max: Vec3,
+    t_max: f32,
+) -> Option<f32> {
+    let t_1 = (aabb_min - origin) * inv_dir;
+    let t_2 = (aabb_max - origin) * inv_dir;
+    let t_near = t_1.min(t_2).max_element();
+    let t_far = t_1.max(t_2).min_element();
+    if t_far >= t_near && t_near <= t_max && t_far >= 0.0 {
+        Some(t_near)
+    } else {
+        None
+    }
+}
+
+// Moeller-Trumbore, same as `ray_triangle_intersect` in utilities.wgsl. Returns (t, u, v).
+fn ray_triangle_intersect(
+    origin: Vec3,
+    dir: Vec3,
+    prim: &PulsePrimitive,
+    t_max: f32,
+) -> Option<(f32, f32, f32)> {
+    let edge_1 = prim.p1() - prim.p0();
+    let edge_2 = prim.p2() - prim.p0();
+    let h = dir.cross(edge_2);
+    let a = edge_1.dot(h);
+    if a.abs() < 1e-8 {
+        // Ray parallel to triangle.
+        return None;
+    }
+    let f = 1.0 / a;
+    let s = origin - prim.p0();
+    let u = f * s.dot(h);
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge_1);
+    let v = f * dir.dot(q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = f * edge_2.dot(q);
+    if t > 0.0 && t < t_max {
+        Some((t, u, v))
+    } else {
+        None
+    }
+}
+
+// Publishes a new snapshot of the scene to `PulseRaycast` when instances, meshes or the TLAS changed. Meshes are shared
+// with `PulseMeshes` and `PulseDeformedInstances`, so this only copies the instances and the TLAS.
+pub fn update_raycast_scene(
+    raycast: Res<PulseRaycast>,
+    extracted: Res<ExtractedMeshMaterialInstances>,
+    meshes: Res<PulseMeshes>,
+    (deformed_instances, deformed_mesh_indices): (
+        Res<PulseDeformedInstances>,
+        Res<PulseDeformedMeshIndices>,
+    ),
+    mesh_instances: Res<PulseMeshInstances>,
+    tlas: Res<PulseSceneTLAS>,
+    pending: Res<PulsePendingBlasBuilds>,
+) {
+    if !mesh_instances.is_changed()
+        && !tlas.is_changed()
+        && !meshes.is_changed()
+        && !deformed_instances.is_changed()
+    {
+        return;
+    }
+
+    // Resolve which mesh each instance was prepared from, the same way `prepare_mesh_instances` does.
+    let mesh_sources = extracted
+        .0
+        .iter()
+        .map(|(entity, mesh, _, _, deformation, _)| {
+            let deformed = deformation.is_some() && deformed_mesh_indices.0.contains_key(entity);
+            (*entity, (mesh.id(), deformed))
+        })
+        .collect::<HashMap<Entity, (AssetId<Mesh>, bool)>>();
+
+    let empty_mesh = Arc::new(PulseMesh {
+        primitives: vec![],
+        triangle_data: vec![],
+        bvh: Blas {
+            nodes: vec![default()],
+            tri_indices: vec![],
+        },
+        indices: vec![],
+        built_sah_cost: 0.0,
+    });
+    let mut instances = vec![];
+    for (entity, mesh_instance) in mesh_instances
+        .entities
+        .iter()
+        .zip(mesh_instances.instances.iter())
+    {
+        let mesh = match entity.and_then(|entity| Some((entity, mesh_sources.get(&entity)?))) {
+            Some((entity, (_, true))) => deformed_instances.0.get(&entity).map(|i| &i.mesh),
+            Some((_, (mesh_id, false))) => meshes.0.get(mesh_id).filter(|_| {
+                !pending
+                    .0
+                    .get(mesh_id)
+                    .is_some_and(|build| build.has_placeholder)
+            }),
+            None => None,
+        };
+        // Tombstones and placeholders are kept as empty instances so indices stay the same as in `PulseMeshInstances`.
+        let mesh = mesh.unwrap_or(&empty_mesh).clone();
+        instances.push(PulseRaycastInstance {
+            entity: entity.unwrap_or(Entity::PLACEHOLDER),
+            object_world: mesh_instance.transform,
+            world_object: mesh_instance.transform_inv,
+            mesh,
+        });
+    }
+
+    raycast.publish(PulseRaycastScene {
+        instances,
+        tlas: tlas.0.clone(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        blas::build_blas, test_meshes::*, tlas::build_tlas, PulseBvhSettings,
+        PulsePrimitiveMeshInstance, PulseTriangleData,
+    };
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn test_mesh() -> Arc<PulseMesh> {
+        let (_, primitives) = load_glb_primitives("monkey_flat.glb").remove(0);
+        let triangle_data = primitives
+            .iter()
+            .map(|prim| {
+                let normal = (prim.p1() - prim.p0())
+                    .cross(prim.p2() - prim.p0())
+                    .normalize();
+                PulseTriangleData {
+                    normals: [normal; 3],
+                    ..default()
+                }
+            })
+            .collect();
+        let bvh = build_blas(&primitives, &PulseBvhSettings::default().blas);
+        Arc::new(PulseMesh {
+            primitives,
+            triangle_data,
+            built_sah_cost: bvh.sah_cost(),
+            bvh,
+            indices: vec![],
+        })
+    }
+
+    // A few transformed instances of the same mesh, and a tombstone that must never be hit.
+    fn test_scene() -> PulseRaycastScene {
+        let mesh = test_mesh();
+        let transforms = [
+            Transform::IDENTITY,
+            Transform::from_xyz(3.0, 0.5, -1.0).with_scale(Vec3::splat(0.5)),
+            Transform::from_xyz(-2.0, 0.0, 2.0).with_rotation(Quat::from_rotation_y(1.0)),
+        ];
+        let mut instances = vec![];
+        let mut tlas_instances = vec![];
+        for (i, transform) in transforms.iter().enumerate() {
+            let object_world = transform.compute_matrix();
+            let root = &mesh.bvh.nodes[0];
+            let mut bounds_min = Vec3::MAX;
+            let mut bounds_max = Vec3::MIN;
+            for corner in 0..8 {
+                let p = Vec3::select(
+                    BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                    root.aabb_max,
+                    root.aabb_min,
+                );
+                let p = object_world.transform_point3(p);
+                bounds_min = bounds_min.min(p);
+                bounds_max = bounds_max.max(p);
+            }
+            tlas_instances.push(PulsePrimitiveMeshInstance {
+                bounds_min,
+                bounds_max,
+                center: (bounds_min + bounds_max) * 0.5,
+            });
+            instances.push(PulseRaycastInstance {
+                entity: Entity::from_raw(i as u32),
+                object_world,
+                world_object: object_world.inverse(),
+                mesh: mesh.clone(),
+            });
+        }
+        tlas_instances.push(PulsePrimitiveMeshInstance::tombstone());
+        instances.push(PulseRaycastInstance {
+            entity: Entity::PLACEHOLDER,
+            object_world: Mat4::IDENTITY,
+            world_object: Mat4::IDENTITY,
+            mesh: mesh.clone(),
+        });
+
+        PulseRaycastScene {
+            instances,
+            tlas: build_tlas(&tlas_instances, &PulseBvhSettings::default().tlas),
+        }
+    }
+
+    // Closest hit found by testing every triangle of every live instance.
+    fn brute_force(
+        scene: &PulseRaycastScene,
+        ray: Ray3d,
+        max_distance: f32,
+    ) -> Option<(u32, u32, f32)> {
+        let mut closest = None;
+        let mut t_max = max_distance;
+        for (instance_index, instance) in scene.instances.iter().enumerate() {
+            if instance.entity == Entity::PLACEHOLDER {
+                continue;
+            }
+            let origin = instance.world_object.transform_point3(ray.origin);
+            let dir = instance.world_object.transform_vector3(*ray.direction);
+            for (triangle_index, prim) in instance.mesh.primitives.iter().enumerate() {
+                if let Some((t, _, _)) = ray_triangle_intersect(origin, dir, prim, t_max) {
+                    t_max = t;
+                    closest = Some((instance_index as u32, triangle_index as u32, t));
+                }
+            }
+        }
+        closest
+    }
+
+    fn random_rays(count: usize) -> Vec<Ray3d> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count)
+            .map(|_| {
+                let origin = Vec3::new(
+                    rng.gen_range(-8.0..8.0),
+                    rng.gen_range(-4.0..4.0),
+                    rng.gen_range(-8.0..8.0),
+                );
+                let target = Vec3::new(
+                    rng.gen_range(-3.0..4.0),
+                    rng.gen_range(-1.0..1.5),
+                    rng.gen_range(-2.0..3.0),
+                );
+                Ray3d::new(origin, target - origin)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn closest_hits_match_brute_force() {
+        let scene = test_scene();
+        let rays = random_rays(2000);
+        let mut hit_count = 0;
+        for ray in rays.iter() {
+            let hit = scene.cast_ray(*ray, 100.0);
+            let expected = brute_force(&scene, *ray, 100.0);
+            assert_eq!(
+                hit.as_ref()
+                    .map(|hit| (hit.instance_index, hit.triangle_index)),
+                expected.map(|(instance, triangle, _)| (instance, triangle)),
+                "{:?}",
+                ray
+            );
+            let Some(hit) = hit else {
+                continue;
+            };
+            hit_count += 1;
+            assert_eq!(hit.distance, expected.unwrap().2);
+            assert_eq!(hit.entity, Entity::from_raw(hit.instance_index));
+            assert!((hit.barycentrics.dot(Vec3::ONE) - 1.0).abs() < 1e-5);
+            assert!(hit.barycentrics.cmpge(Vec3::splat(-1e-6)).all());
+            assert!(ray.get_point(hit.distance).distance(hit.position) < 1e-5);
+            assert!((hit.normal.length() - 1.0).abs() < 1e-4);
+
+            // Nothing is closer than the closest hit.
+            assert!(!scene.any_hit(*ray, hit.distance * 0.999));
+        }
+        // Otherwise the rays don't test much.
+        assert!(hit_count > rays.len() / 4, "{} hits", hit_count);
+
+        let batched = scene.cast_rays(&rays, 100.0);
+        for (ray, hit) in rays.iter().zip(batched.iter()) {
+            assert_eq!(
+                hit.as_ref().map(|hit| hit.distance),
+                scene.cast_ray(*ray, 100.0).map(|hit| hit.distance)
+            );
+        }
+    }
+
+    #[test]
+    fn any_hit_matches_closest_hit() {
+        let scene = test_scene();
+        for ray in random_rays(2000) {
+            let expected = brute_force(&scene, ray, 100.0);
+            assert_eq!(scene.any_hit(ray, 100.0), expected.is_some(), "{:?}", ray);
+            if let Some((_, _, t)) = expected {
+                // Hits beyond `max_distance` don't count.
+                assert!(!scene.any_hit(ray, t * 0.999));
+                assert!(scene.cast_ray(ray, t * 0.999).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn empty_scene_has_no_hits() {
+        let scene = PulseRaycastScene::default();
+        let ray = Ray3d::new(Vec3::ZERO, Vec3::X);
+        assert!(scene.cast_ray(ray, f32::MAX).is_none());
+        assert!(!scene.any_hit(ray, f32::MAX));
+    }
+}
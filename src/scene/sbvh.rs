@@ -0,0 +1,542 @@
+use super::{blas::*, PulseBvhBuildSettings, PulsePrimitive};
+use bevy::prelude::*;
+
+// Spatial split BVH (SBVH) builder, based on "Spatial Splits in Bounding Volume Hierarchies" by Stich et al.
+// Besides the usual object splits on centroids it evaluates spatial splits, where triangles straddling the split plane
+// are clipped and referenced from both children. The result uses the same `Blas` layout as the binned builder, but
+// `tri_indices` can contain the same triangle more than once.
+
+// Spatial splits are only evaluated when the children of the best object split overlap by more than this fraction of
+// the root's surface area. Keeps the build fast for meshes that gain nothing from it.
+const SPATIAL_SPLIT_OVERLAP_THRESHOLD: f32 = 1e-5;
+
+#[derive(Copy, Clone)]
+struct Reference {
+    tri_index: usize,
+    // Bounds of the part of the triangle that this reference covers.
+    bounds: AABB,
+}
+
+impl Reference {
+    fn centroid(&self) -> Vec3 {
+        self.bounds.min + 0.5 * (self.bounds.max - self.bounds.min)
+    }
+}
+
+struct Split {
+    cost: f32,
+    axis: usize,
+    position: f32,
+    is_spatial: bool,
+    // Surface area of the overlap between the two children. Only set for object splits.
+    overlap_area: f32,
+}
+
+struct SbvhBuilder<'a> {
+    prims: &'a Vec<PulsePrimitive>,
+    settings: &'a PulseBvhBuildSettings,
+    nodes: Vec<PulseBLASNode>,
+    tri_indices: Vec<u32>,
+    root_area: f32,
+    // Number of references that may still be added by spatial splits.
+    duplication_budget: usize,
+}
+
+// `max_duplication` limits the number of extra triangle references as a fraction of the triangle count,
+// eg. 0.3 allows `tri_indices` to grow to 1.3 times the triangle count.
+pub fn build_sbvh(
+    prims: &Vec<PulsePrimitive>,
+    max_duplication: f32,
+    settings: &PulseBvhBuildSettings,
+) -> Blas {
+    let mut references = Vec::with_capacity(prims.len());
+    for (i, prim) in prims.iter().enumerate() {
+        let mut bounds = AABB::empty();
+        bounds.grow_position(prim.positions[0]);
+        bounds.grow_position(prim.positions[1]);
+        bounds.grow_position(prim.positions[2]);
+        references.push(Reference {
+            tri_index: i,
+            bounds,
+        });
+    }
+
+    let root_bounds = bounds_of(&references);
+    let mut builder = SbvhBuilder {
+        prims,
+        settings,
+        nodes: vec![PulseBLASNode::default()],
+        tri_indices: Vec::with_capacity(prims.len()),
+        root_area: root_bounds.area(),
+        duplication_budget: (prims.len() as f32 * max_duplication.max(0.0)) as usize,
+    };
+    builder.subdivide(0, references, root_bounds);
+
+    Blas {
+        nodes: builder.nodes,
+        tri_indices: builder.tri_indices,
+    }
+}
+
+impl<'a> SbvhBuilder<'a> {
+    fn subdivide(&mut self, node_idx: usize, references: Vec<Reference>, bounds: AABB) {
+        self.nodes[node_idx].aabb_min = bounds.min;
+        self.nodes[node_idx].aabb_max = bounds.max;
+
+        if references.len() <= self.settings.max_leaf_size as usize {
+            self.make_leaf(node_idx, references);
+            return;
+        }
+
+        let bin_count = self.settings.bin_count.max(2);
+        let Some(object_split) = find_object_split(&references, bin_count) else {
+            self.make_leaf(node_idx, references);
+            return;
+        };
+
+        let mut split = object_split;
+        if self.duplication_budget > 0
+            && split.overlap_area / self.root_area > SPATIAL_SPLIT_OVERLAP_THRESHOLD
+        {
+            if let Some(spatial_split) =
+                find_spatial_split(self.prims, &references, bounds, bin_count)
+            {
+                if spatial_split.cost < split.cost {
+                    split = spatial_split;
+                }
+            }
+        }
+
+        if !self
+            .settings
+            .is_split_worth_it(bounds.area(), references.len() as u32, split.cost)
+        {
+            self.make_leaf(node_idx, references);
+            return;
+        }
+
+        let (left, right) = if split.is_spatial {
+            self.partition_spatial(references, &split)
+        } else {
+            partition_object(references, &split)
+        };
+
+        // Don't split the node if either one of it's children contain no primitives.
+        if left.is_empty() || right.is_empty() {
+            let mut references = left;
+            references.extend(right);
+            self.make_leaf(node_idx, references);
+            return;
+        }
+
+        let child_a_index = self.nodes.len();
+        self.nodes.push(PulseBLASNode::default());
+        self.nodes.push(PulseBLASNode::default());
+        self.nodes[node_idx].a_or_first_tri = child_a_index as u32;
+        // Parent node is not a leaf, so set prim count to 0.
+        self.nodes[node_idx].tri_count = 0;
+
+        let left_bounds = bounds_of(&left);
+        let right_bounds = bounds_of(&right);
+        self.subdivide(child_a_index, left, left_bounds);
+        self.subdivide(child_a_index + 1, right, right_bounds);
+    }
+
+    fn make_leaf(&mut self, node_idx: usize, references: Vec<Reference>) {
+        self.nodes[node_idx].a_or_first_tri = self.tri_indices.len() as u32;
+        self.nodes[node_idx].tri_count = references.len() as u32;
+        for reference in references.iter() {
+            self.tri_indices.push(reference.tri_index as u32);
+        }
+    }
+
+    fn partition_spatial(
+        &mut self,
+        references: Vec<Reference>,
+        split: &Split,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left = vec![];
+        let mut right = vec![];
+        for reference in references.into_iter() {
+            if reference.bounds.max[split.axis] <= split.position {
+                left.push(reference);
+            } else if reference.bounds.min[split.axis] >= split.position {
+                right.push(reference);
+            } else if self.duplication_budget > 0 {
+                let (left_bounds, right_bounds) =
+                    split_reference(&self.prims[reference.tri_index], &reference, split);
+                left.push(Reference {
+                    tri_index: reference.tri_index,
+                    bounds: left_bounds,
+                });
+                right.push(Reference {
+                    tri_index: reference.tri_index,
+                    bounds: right_bounds,
+                });
+                self.duplication_budget -= 1;
+            } else if reference.centroid()[split.axis] < split.position {
+                // Out of budget. Keep the reference whole on the side of its centroid.
+                left.push(reference);
+            } else {
+                right.push(reference);
+            }
+        }
+        (left, right)
+    }
+}
+
+fn bounds_of(references: &[Reference]) -> AABB {
+    let mut bounds = AABB::empty();
+    for reference in references.iter() {
+        bounds.grow_aabb(reference.bounds);
+    }
+    bounds
+}
+
+#[derive(Copy, Clone)]
+struct ObjectBin {
+    bounds: AABB,
+    count: u32,
+}
+
+// Binned SAH over reference centroids. Same approach as `find_best_split_plane` in blas.rs.
+fn find_object_split(references: &[Reference], bin_count: usize) -> Option<Split> {
+    let mut centroid_bounds = AABB::empty();
+    for reference in references.iter() {
+        centroid_bounds.grow_position(reference.centroid());
+    }
+
+    let mut best: Option<Split> = None;
+    for axis in 0..3 {
+        let bounds_min = centroid_bounds.min[axis];
+        let bounds_max = centroid_bounds.max[axis];
+        if bounds_min == bounds_max {
+            continue;
+        }
+
+        let mut bins = vec![
+            ObjectBin {
+                bounds: AABB::empty(),
+                count: 0,
+            };
+            bin_count
+        ];
+        let bin_size_inv = bin_count as f32 / (bounds_max - bounds_min);
+        for reference in references.iter() {
+            let bin_idx = (bin_count - 1)
+                .min(((reference.centroid()[axis] - bounds_min) * bin_size_inv) as usize);
+            bins[bin_idx].count += 1;
+            bins[bin_idx].bounds.grow_aabb(reference.bounds);
+        }
+
+        let mut boxes_b = vec![AABB::empty(); bin_count - 1];
+        let mut counts_b = vec![0u32; bin_count - 1];
+        let mut box_b = AABB::empty();
+        let mut sum_b = 0;
+        for i in (1..bin_count).rev() {
+            sum_b += bins[i].count;
+            box_b.grow_aabb(bins[i].bounds);
+            counts_b[i - 1] = sum_b;
+            boxes_b[i - 1] = box_b;
+        }
+
+        let bin_size = (bounds_max - bounds_min) / bin_count as f32;
+        let mut box_a = AABB::empty();
+        let mut sum_a = 0;
+        for i in 0..(bin_count - 1) {
+            sum_a += bins[i].count;
+            box_a.grow_aabb(bins[i].bounds);
+            if sum_a == 0 || counts_b[i] == 0 {
+                continue;
+            }
+
+            let cost = sum_a as f32 * box_a.area() + counts_b[i] as f32 * boxes_b[i].area();
+            if best.as_ref().is_none_or(|b| cost < b.cost) {
+                best = Some(Split {
+                    cost,
+                    axis,
+                    position: bounds_min + bin_size * (i + 1) as f32,
+                    is_spatial: false,
+                    overlap_area: box_a.intersection(&boxes_b[i]).area(),
+                });
+            }
+        }
+    }
+
+    best
+}
+
+fn partition_object(references: Vec<Reference>, split: &Split) -> (Vec<Reference>, Vec<Reference>) {
+    references
+        .into_iter()
+        .partition(|reference| reference.centroid()[split.axis] < split.position)
+}
+
+#[derive(Copy, Clone)]
+struct SpatialBin {
+    bounds: AABB,
+    // Number of references starting/ending in this bin.
+    enter: u32,
+    exit: u32,
+}
+
+fn find_spatial_split(
+    prims: &[PulsePrimitive],
+    references: &[Reference],
+    node_bounds: AABB,
+    bin_count: usize,
+) -> Option<Split> {
+    let mut best: Option<Split> = None;
+    for axis in 0..3 {
+        let bounds_min = node_bounds.min[axis];
+        let bounds_max = node_bounds.max[axis];
+        if bounds_min == bounds_max {
+            continue;
+        }
+
+        let mut bins = vec![
+            SpatialBin {
+                bounds: AABB::empty(),
+                enter: 0,
+                exit: 0,
+            };
+            bin_count
+        ];
+        let bin_size = (bounds_max - bounds_min) / bin_count as f32;
+        let bin_size_inv = 1.0 / bin_size;
+        let bin_of = |p: f32| -> usize {
+            (((p - bounds_min) * bin_size_inv).max(0.0) as usize).min(bin_count - 1)
+        };
+
+        // Chop every reference into the bins it overlaps.
+        for reference in references.iter() {
+            let first_bin = bin_of(reference.bounds.min[axis]);
+            let last_bin = bin_of(reference.bounds.max[axis]);
+            bins[first_bin].enter += 1;
+            bins[last_bin].exit += 1;
+
+            let prim = &prims[reference.tri_index];
+            for (bin_idx, bin) in (first_bin..).zip(&mut bins[first_bin..=last_bin]) {
+                let slab_min = bounds_min + bin_size * bin_idx as f32;
+                let slab_max = if bin_idx == bin_count - 1 {
+                    bounds_max
+                } else {
+                    slab_min + bin_size
+                };
+                let clipped = clip_triangle_to_slab(prim, axis, slab_min, slab_max)
+                    .intersection(&reference.bounds);
+                if clipped.is_valid() {
+                    bin.bounds.grow_aabb(clipped);
+                }
+            }
+        }
+
+        let mut boxes_b = vec![AABB::empty(); bin_count - 1];
+        let mut counts_b = vec![0u32; bin_count - 1];
+        let mut box_b = AABB::empty();
+        let mut sum_b = 0;
+        for i in (1..bin_count).rev() {
+            sum_b += bins[i].exit;
+            box_b.grow_aabb(bins[i].bounds);
+            counts_b[i - 1] = sum_b;
+            boxes_b[i - 1] = box_b;
+        }
+
+        let mut box_a = AABB::empty();
+        let mut sum_a = 0;
+        for i in 0..(bin_count - 1) {
+            sum_a += bins[i].enter;
+            box_a.grow_aabb(bins[i].bounds);
+            if sum_a == 0 || counts_b[i] == 0 {
+                continue;
+            }
+
+            let cost = sum_a as f32 * box_a.area() + counts_b[i] as f32 * boxes_b[i].area();
+            if best.as_ref().is_none_or(|b| cost < b.cost) {
+                best = Some(Split {
+                    cost,
+                    axis,
+                    position: bounds_min + bin_size * (i + 1) as f32,
+                    is_spatial: true,
+                    overlap_area: 0.0,
+                });
+            }
+        }
+    }
+
+    best
+}
+
+// Returns (left bounds, right bounds) of a reference straddling the split plane.
+fn split_reference(prim: &PulsePrimitive, reference: &Reference, split: &Split) -> (AABB, AABB) {
+    let left = clip_triangle_to_slab(prim, split.axis, f32::MIN, split.position)
+        .intersection(&reference.bounds);
+    let right = clip_triangle_to_slab(prim, split.axis, split.position, f32::MAX)
+        .intersection(&reference.bounds);
+    (left, right)
+}
+
+// Bounds of the part of the triangle between `slab_min` and `slab_max` on `axis`.
+fn clip_triangle_to_slab(prim: &PulsePrimitive, axis: usize, slab_min: f32, slab_max: f32) -> AABB {
+    let mut bounds = AABB::empty();
+    for i in 0..3 {
+        let v0 = prim.positions[i];
+        let v1 = prim.positions[(i + 1) % 3];
+        let p0 = v0[axis];
+        let p1 = v1[axis];
+
+        if p0 >= slab_min && p0 <= slab_max {
+            bounds.grow_position(v0);
+        }
+
+        // Add intersections of the edge with the slab planes.
+        for plane in [slab_min, slab_max] {
+            if (p0 < plane && p1 > plane) || (p0 > plane && p1 < plane) {
+                let t = ((plane - p0) / (p1 - p0)).clamp(0.0, 1.0);
+                let mut p = v0.lerp(v1, t);
+                p[axis] = plane;
+                bounds.grow_position(p);
+            }
+        }
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{cache::validate_blas, test_meshes::*, PulseBvhSettings};
+    use super::*;
+
+    const MAX_DUPLICATION: f32 = 0.3;
+
+    fn contains(min: Vec3, max: Vec3, p: Vec3, epsilon: f32) -> bool {
+        p.cmpge(min - epsilon).all() && p.cmple(max + epsilon).all()
+    }
+
+    // Checks that every point of every triangle is inside a leaf that references it, so traversal can't miss it.
+    fn assert_leaves_cover_triangles(name: &str, prims: &[PulsePrimitive], bvh: &Blas) {
+        let root = &bvh.nodes[0];
+        let epsilon = (root.aabb_max - root.aabb_min).max_element() * 1e-5;
+        let mut leaves_of_triangle = vec![vec![]; prims.len()];
+        for node in bvh.nodes.iter().filter(|node| node.tri_count > 0) {
+            let first = node.a_or_first_tri as usize;
+            for tri_index in &bvh.tri_indices[first..first + node.tri_count as usize] {
+                // The clipped part of the triangle in this leaf can't be empty.
+                let prim = &prims[*tri_index as usize];
+                let tri_min = prim.p0().min(prim.p1()).min(prim.p2());
+                let tri_max = prim.p0().max(prim.p1()).max(prim.p2());
+                assert!(
+                    tri_min.cmple(node.aabb_max + epsilon).all()
+                        && tri_max.cmpge(node.aabb_min - epsilon).all(),
+                    "{}: triangle {} is referenced by a leaf it doesn't overlap",
+                    name,
+                    tri_index
+                );
+                leaves_of_triangle[*tri_index as usize].push(node);
+            }
+        }
+
+        let barycentrics = [
+            Vec3::X,
+            Vec3::Y,
+            Vec3::Z,
+            Vec3::new(0.5, 0.5, 0.0),
+            Vec3::new(0.0, 0.5, 0.5),
+            Vec3::new(0.5, 0.0, 0.5),
+            Vec3::splat(1.0 / 3.0),
+            Vec3::new(0.7, 0.2, 0.1),
+            Vec3::new(0.1, 0.15, 0.75),
+        ];
+        for (tri_index, prim) in prims.iter().enumerate() {
+            let leaves = &leaves_of_triangle[tri_index];
+            assert!(
+                !leaves.is_empty(),
+                "{}: triangle {} is missing",
+                name,
+                tri_index
+            );
+            for b in barycentrics {
+                let p = b.x * prim.p0() + b.y * prim.p1() + b.z * prim.p2();
+                assert!(
+                    leaves
+                        .iter()
+                        .any(|leaf| contains(leaf.aabb_min, leaf.aabb_max, p, epsilon)),
+                    "{}: {} on triangle {} is outside all of its leaves",
+                    name,
+                    p,
+                    tri_index
+                );
+            }
+        }
+
+        for node in bvh.nodes.iter().filter(|node| node.tri_count == 0) {
+            for child in &bvh.nodes[node.a_or_first_tri as usize..node.a_or_first_tri as usize + 2]
+            {
+                assert!(contains(
+                    node.aabb_min,
+                    node.aabb_max,
+                    child.aabb_min,
+                    epsilon
+                ));
+                assert!(contains(
+                    node.aabb_min,
+                    node.aabb_max,
+                    child.aabb_max,
+                    epsilon
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn spatial_splits_reference_every_triangle() {
+        let settings = PulseBvhSettings::default().blas;
+        for file in BUNDLED_GLB_FILES {
+            for (name, prims) in load_glb_primitives(file) {
+                let sbvh = build_sbvh(&prims, MAX_DUPLICATION, &settings);
+                assert!(validate_blas(&sbvh, prims.len()).is_ok(), "{}", name);
+                assert!(
+                    sbvh.tri_indices.len() as f32 <= prims.len() as f32 * (1.0 + MAX_DUPLICATION),
+                    "{}: {} references to {} triangles",
+                    name,
+                    sbvh.tri_indices.len(),
+                    prims.len()
+                );
+                assert_leaves_cover_triangles(&name, &prims, &sbvh);
+
+                let binned = build_blas(&prims, &settings);
+                assert!(
+                    sbvh.sah_cost() <= binned.sah_cost() * 1.0001,
+                    "{}: SAH cost {} > {}",
+                    name,
+                    sbvh.sah_cost(),
+                    binned.sah_cost()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spatial_splits_clip_thin_triangles() {
+        // Long diagonal slivers, whose bounds all overlap so object splits can't separate them.
+        let prims = (0..256)
+            .map(|i| {
+                let offset = Vec3::new(0.0, (i % 16) as f32, (i / 16) as f32) * 0.5;
+                PulsePrimitive {
+                    positions: [
+                        offset,
+                        offset + Vec3::new(20.0, 20.0, 0.0),
+                        offset + Vec3::new(20.0, 20.05, 0.05),
+                    ],
+                }
+            })
+            .collect::<Vec<_>>();
+        let settings = PulseBvhSettings::default().blas;
+        let sbvh = build_sbvh(&prims, MAX_DUPLICATION, &settings);
+        assert!(validate_blas(&sbvh, prims.len()).is_ok());
+        assert!(sbvh.tri_indices.len() > prims.len());
+        assert!(sbvh.tri_indices.len() as f32 <= prims.len() as f32 * (1.0 + MAX_DUPLICATION));
+        assert_leaves_cover_triangles("slivers", &prims, &sbvh);
+        assert!(sbvh.sah_cost() < build_blas(&prims, &settings).sah_cost());
+    }
+}
@@ -0,0 +1,426 @@
+use super::{
+    blas::*, build_primitives, build_triangle_data, generate_missing_tangents,
+    read_mesh_vertex_data, ExtractedMeshAssets, ExtractedMeshMaterialInstances, MeshVertexData,
+    PulseBlasBuilderSettings, PulseBvhBuildSettings, PulseBvhSettings, PulseMesh, PulseMeshIndex,
+    PulsePrimitive, PulseTriangleData,
+};
+use bevy::{
+    prelude::*,
+    reflect::Struct,
+    render::{
+        mesh::{
+            morph::MeshMorphWeights,
+            skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+            VertexAttributeValues,
+        },
+        Extract,
+    },
+    utils::{HashMap, HashSet},
+};
+use std::sync::Arc;
+
+// CPU skinning and morph targets. Every deformed entity gets its own copy of the mesh with a BLAS that is refit
+// (or rebuilt, see `PulseBvhBuildSettings::refit_rebuild_ratio`) whenever its pose changes.
+
+const MORPH_COMPONENT_COUNT: usize = 9;
+
+// Pose of a single entity, extracted every frame for entities with a `SkinnedMesh` or `MeshMorphWeights`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PulseDeformation {
+    // World space joint transforms multiplied by their inverse bind poses. Empty if the entity isn't skinned.
+    pub joint_matrices: Vec<Mat4>,
+    pub morph_weights: Vec<f32>,
+}
+
+impl PulseDeformation {
+    pub fn is_skinned(&self) -> bool {
+        !self.joint_matrices.is_empty()
+    }
+}
+
+// Returns `None` if the entity isn't deformed or its skin isn't ready yet, in which case it is rendered in its rest pose.
+pub fn extract_deformation(
+    skinned_mesh: Option<&SkinnedMesh>,
+    morph_weights: Option<&MeshMorphWeights>,
+    joints: &Query<&GlobalTransform>,
+    inverse_bindposes: &Assets<SkinnedMeshInverseBindposes>,
+) -> Option<PulseDeformation> {
+    let mut deformation = PulseDeformation::default();
+    if let Some(skinned_mesh) = skinned_mesh {
+        let bindposes = inverse_bindposes.get(&skinned_mesh.inverse_bindposes)?;
+        for (joint, bindpose) in skinned_mesh.joints.iter().zip(bindposes.iter()) {
+            let joint_transform = joints.get(*joint).ok()?;
+            deformation
+                .joint_matrices
+                .push(joint_transform.compute_matrix() * *bindpose);
+        }
+    }
+    if let Some(morph_weights) = morph_weights {
+        deformation.morph_weights = morph_weights.weights().to_vec();
+    }
+
+    if deformation.joint_matrices.is_empty() && deformation.morph_weights.is_empty() {
+        None
+    } else {
+        Some(deformation)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ExtractedMorphTargetImages(pub HashMap<AssetId<Mesh>, Image>);
+
+// `Mesh` has no getter for its morph target image, but the field is reflected.
+fn morph_target_handle(mesh: &Mesh) -> Option<&Handle<Image>> {
+    mesh.field("morph_targets")?
+        .downcast_ref::<Option<Handle<Image>>>()?
+        .as_ref()
+}
+
+pub fn extract_morph_target_images(
+    mut mesh_asset_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    mesh_assets: Extract<Res<Assets<Mesh>>>,
+    image_assets: Extract<Res<Assets<Image>>>,
+    mut extracted: ResMut<ExtractedMorphTargetImages>,
+) {
+    extracted.0.clear();
+    for event in mesh_asset_events.read() {
+        let (AssetEvent::Added { id } | AssetEvent::Modified { id }) = event else {
+            continue;
+        };
+        let Some(handle) = mesh_assets.get(*id).and_then(morph_target_handle) else {
+            continue;
+        };
+        match image_assets.get(handle) {
+            Some(image) => {
+                extracted.0.insert(*id, image.clone());
+            }
+            None => warn!(
+                "Morph targets of mesh {:?} aren't loaded. It will be ray traced without them.",
+                id
+            ),
+        }
+    }
+}
+
+// Rest pose vertex data of a mesh that can be skinned or morphed.
+pub struct PulseDeformableMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub tangents: Vec<Vec4>,
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<u32>,
+    pub joint_indices: Vec<[u16; 4]>,
+    pub joint_weights: Vec<Vec4>,
+    // Position, normal and tangent offsets of every vertex, per morph target.
+    pub morph_targets: Vec<Vec<(Vec3, Vec3, Vec3)>>,
+}
+
+impl PulseDeformableMesh {
+    pub fn is_skinned(&self) -> bool {
+        !self.joint_indices.is_empty()
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct PulseDeformableMeshes(pub HashMap<AssetId<Mesh>, PulseDeformableMesh>);
+
+pub fn prepare_deformable_mesh_assets(
+    extracted: Res<ExtractedMeshAssets>,
+    morph_target_images: Res<ExtractedMorphTargetImages>,
+    mut deformable_meshes: ResMut<PulseDeformableMeshes>,
+) {
+    if extracted.empty() {
+        return;
+    }
+
+    for (id, mesh) in extracted.new_or_modified.iter() {
+        let joint_indices = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX) {
+            Some(VertexAttributeValues::Uint16x4(values)) => values.clone(),
+            _ => vec![],
+        };
+        let joint_weights = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT) {
+            Some(VertexAttributeValues::Float32x4(values)) => {
+                values.iter().map(|w| Vec4::from_array(*w)).collect()
+            }
+            _ => vec![],
+        };
+        let morph_target_image = morph_target_images.0.get(id);
+        if (joint_indices.is_empty() || joint_weights.is_empty()) && morph_target_image.is_none() {
+            remove_deformable_mesh(&mut deformable_meshes, id);
+            continue;
+        }
+
+        // Skipped meshes are reported by `prepare_extracted_mesh_assets`.
+        let Ok(mut data) = read_mesh_vertex_data(mesh) else {
+            remove_deformable_mesh(&mut deformable_meshes, id);
+            continue;
+        };
+        // Generated once for the rest pose and deformed along with the normals.
+        generate_missing_tangents(&mut data);
+        let MeshVertexData {
+            positions,
+            normals,
+            tangents,
+            uvs,
+            indices,
+        } = data;
+
+        let morph_targets = morph_target_image
+            .map(|image| decode_morph_targets(image, positions.len()))
+            .unwrap_or_default();
+        let (joint_indices, joint_weights) =
+            if joint_indices.len() == positions.len() && joint_weights.len() == positions.len() {
+                (joint_indices, joint_weights)
+            } else {
+                (vec![], vec![])
+            };
+
+        deformable_meshes.0.insert(
+            *id,
+            PulseDeformableMesh {
+                positions,
+                normals,
+                tangents,
+                uvs,
+                indices,
+                joint_indices,
+                joint_weights,
+                morph_targets,
+            },
+        );
+    }
+
+    for id in extracted.removed.iter() {
+        remove_deformable_mesh(&mut deformable_meshes, id);
+    }
+}
+
+// Only marks `PulseDeformableMeshes` as changed if the mesh was in it, since that rebuilds all deformed instances.
+fn remove_deformable_mesh(
+    deformable_meshes: &mut ResMut<PulseDeformableMeshes>,
+    id: &AssetId<Mesh>,
+) {
+    if deformable_meshes
+        .bypass_change_detection()
+        .0
+        .contains_key(id)
+    {
+        deformable_meshes.0.remove(id);
+    }
+}
+
+// See `bevy::render::mesh::morph::MorphTargetImage` for the layout. Every target is one layer of the image
+// where each vertex has 9 floats: position, normal and tangent offsets.
+fn decode_morph_targets(image: &Image, vertex_count: usize) -> Vec<Vec<(Vec3, Vec3, Vec3)>> {
+    let size = image.texture_descriptor.size;
+    let layer_size = (size.width * size.height) as usize;
+    let target_count = size.depth_or_array_layers as usize;
+    let components = image
+        .data
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect::<Vec<f32>>();
+    if layer_size < vertex_count * MORPH_COMPONENT_COUNT
+        || components.len() < layer_size * target_count
+    {
+        warn!("Morph target image doesn't match its mesh. Ignoring morph targets.");
+        return vec![];
+    }
+
+    let mut targets = vec![];
+    for target in 0..target_count {
+        let layer = &components[(target * layer_size)..((target + 1) * layer_size)];
+        let mut offsets = Vec::with_capacity(vertex_count);
+        for vertex in 0..vertex_count {
+            let c = &layer[(vertex * MORPH_COMPONENT_COUNT)..];
+            offsets.push((
+                Vec3::new(c[0], c[1], c[2]),
+                Vec3::new(c[3], c[4], c[5]),
+                Vec3::new(c[6], c[7], c[8]),
+            ));
+        }
+        targets.push(offsets);
+    }
+    targets
+}
+
+// Skinned vertices end up in world space, everything else stays in mesh space.
+pub fn deform_mesh(
+    mesh: &PulseDeformableMesh,
+    deformation: &PulseDeformation,
+) -> (Vec<PulsePrimitive>, Vec<PulseTriangleData>) {
+    let mut positions = mesh.positions.clone();
+    let mut normals = mesh.normals.clone();
+    let mut tangents = mesh.tangents.clone();
+
+    for (offsets, weight) in mesh
+        .morph_targets
+        .iter()
+        .zip(deformation.morph_weights.iter())
+    {
+        if *weight == 0.0 {
+            continue;
+        }
+        for (v, (position_offset, normal_offset, tangent_offset)) in offsets.iter().enumerate() {
+            positions[v] += *weight * *position_offset;
+            if let Some(normal) = normals.get_mut(v) {
+                *normal += *weight * *normal_offset;
+            }
+            if let Some(tangent) = tangents.get_mut(v) {
+                *tangent += (*weight * *tangent_offset).extend(0.0);
+            }
+        }
+    }
+
+    if deformation.is_skinned() && mesh.is_skinned() {
+        for (v, position) in positions.iter_mut().enumerate() {
+            let model = skin_model(
+                &deformation.joint_matrices,
+                mesh.joint_indices[v],
+                mesh.joint_weights[v],
+            );
+            *position = model.transform_point3(*position);
+            let model = Mat3::from_mat4(model);
+            if let Some(normal) = normals.get_mut(v) {
+                *normal = model.inverse().transpose() * *normal;
+            }
+            // Mirroring joints flip the handedness, the same as `mesh_tangent_local_to_world` in Bevy.
+            if let Some(tangent) = tangents.get_mut(v) {
+                let handedness = tangent.w * model.determinant().signum();
+                *tangent = (model * tangent.xyz()).extend(handedness);
+            }
+        }
+    }
+
+    for n in normals.iter_mut() {
+        *n = n.normalize_or_zero();
+    }
+    for t in tangents.iter_mut() {
+        *t = t.xyz().normalize_or_zero().extend(t.w);
+    }
+
+    (
+        build_primitives(&positions, &mesh.indices),
+        build_triangle_data(&positions, &normals, &tangents, &mesh.uvs, &mesh.indices),
+    )
+}
+
+// Same as `skin_model` in bevy_pbr's skinning.wgsl.
+fn skin_model(joint_matrices: &[Mat4], indices: [u16; 4], weights: Vec4) -> Mat4 {
+    let mut model = Mat4::ZERO;
+    for i in 0..4 {
+        let joint = joint_matrices
+            .get(indices[i] as usize)
+            .copied()
+            .unwrap_or(Mat4::IDENTITY);
+        model += joint * weights[i];
+    }
+    model
+}
+
+pub struct PulseDeformedInstance {
+    pub mesh_id: AssetId<Mesh>,
+    pub deformation: PulseDeformation,
+    // Shared with the snapshots of `PulseRaycast`, like `PulseMeshes`.
+    pub mesh: Arc<PulseMesh>,
+    // Skinned vertices are already in world space so the instance transform must not be applied again.
+    pub world_space: bool,
+}
+
+#[derive(Resource, Default)]
+pub struct PulseDeformedInstances(pub HashMap<Entity, PulseDeformedInstance>);
+
+// Like `PulseMeshIndices` but for the per entity meshes in `PulseDeformedInstances`.
+#[derive(Resource, Default)]
+pub struct PulseDeformedMeshIndices(pub HashMap<Entity, PulseMeshIndex>);
+
+pub fn prepare_deformed_instances(
+    extracted: Res<ExtractedMeshMaterialInstances>,
+    deformable_meshes: Res<PulseDeformableMeshes>,
+    builder_settings: Res<PulseBlasBuilderSettings>,
+    bvh_settings: Res<PulseBvhSettings>,
+    mut instances: ResMut<PulseDeformedInstances>,
+    mut built_with: Local<Option<PulseBvhBuildSettings>>,
+) {
+    // Same as in `prepare_extracted_mesh_assets`, only the BLAS build settings affect the trees.
+    let blas_settings_changed = *built_with != Some(bvh_settings.blas);
+    *built_with = Some(bvh_settings.blas);
+    let rebuild_all =
+        deformable_meshes.is_changed() || builder_settings.is_changed() || blas_settings_changed;
+    let mut changed = false;
+    let mut alive = HashSet::new();
+
+    // Only mark the resource as changed if a mesh actually moved so mesh data isn't reuploaded for nothing.
+    let deformed = instances.bypass_change_detection();
+    for (entity, mesh_handle, _, _, deformation, _) in extracted.0.iter() {
+        let Some(deformation) = deformation else {
+            continue;
+        };
+        let mesh_id = mesh_handle.id();
+        let Some(deformable_mesh) = deformable_meshes.0.get(&mesh_id) else {
+            continue;
+        };
+        alive.insert(*entity);
+
+        let existing = deformed
+            .0
+            .get_mut(entity)
+            .filter(|instance| instance.mesh_id == mesh_id);
+        if let Some(instance) = &existing {
+            if !rebuild_all && instance.deformation == *deformation {
+                continue;
+            }
+        }
+
+        changed = true;
+        let (primitives, triangle_data) = deform_mesh(deformable_mesh, deformation);
+        let builder = builder_settings.builder_for(&mesh_id);
+        let world_space = deformation.is_skinned() && deformable_mesh.is_skinned();
+        match existing {
+            Some(instance) if !rebuild_all => {
+                instance.deformation = deformation.clone();
+                instance.world_space = world_space;
+                // A new mesh rather than modifying the old one, which raycast snapshots might still use.
+                let mut mesh = PulseMesh {
+                    primitives,
+                    triangle_data,
+                    bvh: instance.mesh.bvh.clone(),
+                    indices: instance.mesh.indices.clone(),
+                    built_sah_cost: instance.mesh.built_sah_cost,
+                };
+                refit_blas(&mut mesh.bvh, &mesh.primitives);
+                if mesh.bvh.sah_cost() > mesh.built_sah_cost * bvh_settings.blas.refit_rebuild_ratio
+                {
+                    mesh.bvh =
+                        build_blas_with_builder(&mesh.primitives, builder, &bvh_settings.blas);
+                    mesh.built_sah_cost = mesh.bvh.sah_cost();
+                }
+                instance.mesh = Arc::new(mesh);
+            }
+            _ => {
+                let bvh = build_blas_with_builder(&primitives, builder, &bvh_settings.blas);
+                deformed.0.insert(
+                    *entity,
+                    PulseDeformedInstance {
+                        mesh_id,
+                        deformation: deformation.clone(),
+                        mesh: Arc::new(PulseMesh {
+                            primitives,
+                            triangle_data,
+                            built_sah_cost: bvh.sah_cost(),
+                            bvh,
+                            indices: deformable_mesh.indices.clone(),
+                        }),
+                        world_space,
+                    },
+                );
+            }
+        }
+    }
+
+    let instance_count = deformed.0.len();
+    deformed.0.retain(|entity, _| alive.contains(entity));
+    if changed || deformed.0.len() != instance_count {
+        instances.set_changed();
+    }
+}
@@ -0,0 +1,58 @@
+// Meshes for tests, loaded with the `gltf` crate since Bevy's loader needs a running app.
+use super::PulsePrimitive;
+use bevy::prelude::*;
+
+pub const BUNDLED_GLB_FILES: [&str; 9] = [
+    "cornell.glb",
+    "cornell_no_light.glb",
+    "cornell_statue.glb",
+    "monkey_blue.glb",
+    "monkey_flat.glb",
+    "monkey_orange.glb",
+    "monkey_smooth.glb",
+    "particle_test.glb",
+    "statue.glb",
+];
+
+// Triangles of every mesh primitive in a glb file from the assets folder, one entry per primitive.
+pub fn load_glb_primitives(file: &str) -> Vec<(String, Vec<PulsePrimitive>)> {
+    let path = format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), file);
+    let bytes = std::fs::read(&path).unwrap();
+    let gltf = gltf::Gltf::from_slice(&bytes).unwrap();
+    let mut primitives = vec![];
+    for mesh in gltf.meshes() {
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+            let reader = primitive.reader(|_| gltf.blob.as_deref());
+            let positions = reader
+                .read_positions()
+                .unwrap()
+                .map(Vec3::from_array)
+                .collect::<Vec<Vec3>>();
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<u32>>(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            let name = format!(
+                "{} mesh {} primitive {}",
+                file,
+                mesh.index(),
+                primitive.index()
+            );
+            let prims = indices
+                .chunks_exact(3)
+                .map(|tri| PulsePrimitive {
+                    positions: [
+                        positions[tri[0] as usize],
+                        positions[tri[1] as usize],
+                        positions[tri[2] as usize],
+                    ],
+                })
+                .collect();
+            primitives.push((name, prims));
+        }
+    }
+    primitives
+}
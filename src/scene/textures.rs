@@ -0,0 +1,472 @@
+use super::{diagnostics::*, PulseMaterials};
+use crate::bsdf::PulseEnergyTable;
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::{HashMap, HashSet},
+};
+
+// Bound after the last extra page of the paged scene buffers, see `page_binding`.
+pub const PULSE_TEXTURE_ATLAS_BINDING: u32 = 24;
+pub const PULSE_TEXTURE_ATLAS_SAMPLER_BINDING: u32 = 25;
+pub const PULSE_ENERGY_TABLE_BINDING: u32 = 26;
+
+// Material textures are copied into the layers of a single texture array, so any of them can be sampled at a ray hit
+// without bindless textures. Textures larger than a layer are downscaled until they fit.
+#[derive(Resource, ExtractResource, Clone, Debug)]
+pub struct PulseTextureAtlasSettings {
+    // Width and height of a layer, clamped to the device limit.
+    pub layer_size: u32,
+}
+
+impl Default for PulseTextureAtlasSettings {
+    fn default() -> Self {
+        Self { layer_size: 4096 }
+    }
+}
+
+// Where a texture is in the atlas. `rect` is the offset and scale from texture to atlas UVs, inset by half a texel so
+// bilinear filtering doesn't bleed into neighbouring textures.
+#[derive(ShaderType, Clone, Copy, Debug)]
+pub struct PulseMaterialTexture {
+    pub rect: Vec4,
+    pub layer: u32,
+}
+
+impl PulseMaterialTexture {
+    // `layer` of a material without the texture, matches `NO_TEXTURE` in utilities.wgsl.
+    pub const NONE: Self = Self {
+        rect: Vec4::ZERO,
+        layer: u32::MAX,
+    };
+}
+
+// Images sampled by a `StandardMaterial`.
+#[derive(Clone, Default)]
+pub struct PulseMaterialTextureIds {
+    pub base_color: Option<AssetId<Image>>,
+    pub emissive: Option<AssetId<Image>>,
+    pub metallic_roughness: Option<AssetId<Image>>,
+    pub occlusion: Option<AssetId<Image>>,
+    pub normal_map: Option<AssetId<Image>>,
+}
+
+impl PulseMaterialTextureIds {
+    pub fn new(material: &StandardMaterial) -> Self {
+        let id = |handle: &Option<Handle<Image>>| handle.as_ref().map(|h| h.id());
+        Self {
+            base_color: id(&material.base_color_texture),
+            emissive: id(&material.emissive_texture),
+            metallic_roughness: id(&material.metallic_roughness_texture),
+            occlusion: id(&material.occlusion_texture),
+            normal_map: id(&material.normal_map_texture),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = AssetId<Image>> {
+        [
+            self.base_color,
+            self.emissive,
+            self.metallic_roughness,
+            self.occlusion,
+            self.normal_map,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ExtractedTextureImages {
+    pub new_or_modified: Vec<(AssetId<Image>, Image)>,
+    pub removed: Vec<AssetId<Image>>,
+}
+
+// Images are copied to the render world once a material uses them, since `RenderAssets<Image>` only has the GPU
+// textures.
+pub fn extract_texture_images(
+    mut image_events: Extract<EventReader<AssetEvent<Image>>>,
+    mut material_events: Extract<EventReader<AssetEvent<StandardMaterial>>>,
+    image_assets: Extract<Res<Assets<Image>>>,
+    material_assets: Extract<Res<Assets<StandardMaterial>>>,
+    mut extracted: ResMut<ExtractedTextureImages>,
+    mut copied: Local<HashSet<AssetId<Image>>>,
+) {
+    let mut new_or_modified = vec![];
+    let mut removed = vec![];
+    let mut modified = HashSet::new();
+    for event in image_events.read() {
+        match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                modified.insert(*id);
+            }
+            AssetEvent::Removed { id } => {
+                if copied.remove(id) {
+                    removed.push(*id);
+                }
+            }
+            AssetEvent::Unused { .. } => {}
+            AssetEvent::LoadedWithDependencies { .. } => {}
+        }
+    }
+    let materials_changed = material_events.read().count() > 0;
+
+    if materials_changed || !modified.is_empty() {
+        let mut used = HashSet::new();
+        for (_, material) in material_assets.iter() {
+            for id in PulseMaterialTextureIds::new(material).iter() {
+                if !used.insert(id) || (copied.contains(&id) && !modified.contains(&id)) {
+                    continue;
+                }
+                if let Some(image) = image_assets.get(id) {
+                    new_or_modified.push((id, image.clone()));
+                    copied.insert(id);
+                }
+            }
+        }
+    }
+
+    extracted.new_or_modified = new_or_modified;
+    extracted.removed = removed;
+}
+
+// CPU copy of a material image, converted to RGBA8 without changing its color space.
+pub struct PulseTextureImage {
+    pub size: UVec2,
+    pub data: Vec<u8>,
+    // Normal maps with only red and green channels have their blue channel reconstructed in the shader.
+    pub two_component: bool,
+}
+
+#[derive(Resource, Default)]
+pub struct PulseTextureImages(pub HashMap<AssetId<Image>, PulseTextureImage>);
+
+pub fn prepare_texture_images(
+    extracted: Res<ExtractedTextureImages>,
+    diagnostics: Res<PulseSceneDiagnostics>,
+    mut images: ResMut<PulseTextureImages>,
+) {
+    if extracted.new_or_modified.is_empty() && extracted.removed.is_empty() {
+        return;
+    }
+
+    for (id, image) in extracted.new_or_modified.iter() {
+        let format = image.texture_descriptor.format;
+        let Some(data) = rgba8_pixels(image) else {
+            images.0.remove(id);
+            diagnostics.report(PulseSceneDiagnosticEvent::TextureSkipped {
+                image: *id,
+                reason: PulseTextureSkipReason::UnsupportedFormat(format),
+            });
+            continue;
+        };
+        images.0.insert(
+            *id,
+            PulseTextureImage {
+                size: image.size(),
+                data,
+                two_component: format == TextureFormat::Rg8Unorm,
+            },
+        );
+    }
+
+    for id in extracted.removed.iter() {
+        images.0.remove(id);
+    }
+}
+
+// First mip level of the first layer as RGBA8, or `None` for formats that can't be read on the CPU.
+fn rgba8_pixels(image: &Image) -> Option<Vec<u8>> {
+    let pixel_count = (image.width() * image.height()) as usize;
+    let data = &image.data;
+    let pixels = match image.texture_descriptor.format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            data.get(..4 * pixel_count)?.to_vec()
+        }
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => data
+            .get(..4 * pixel_count)?
+            .chunks_exact(4)
+            .flat_map(|p| [p[2], p[1], p[0], p[3]])
+            .collect(),
+        TextureFormat::Rg8Unorm => data
+            .get(..2 * pixel_count)?
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[1], 0, 255])
+            .collect(),
+        TextureFormat::R8Unorm => data
+            .get(..pixel_count)?
+            .iter()
+            .flat_map(|r| [*r, *r, *r, 255])
+            .collect(),
+        _ => return None,
+    };
+    Some(pixels)
+}
+
+// Halves the size of an RGBA8 image with a box filter. Odd rows and columns at the end are dropped.
+fn downsample(size: UVec2, data: &[u8]) -> (UVec2, Vec<u8>) {
+    let new_size = (size / 2).max(UVec2::ONE);
+    let mut new_data = Vec::with_capacity((4 * new_size.x * new_size.y) as usize);
+    for y in 0..new_size.y {
+        for x in 0..new_size.x {
+            for c in 0..4 {
+                let mut sum = 0u32;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (2 * x + dx).min(size.x - 1);
+                    let sy = (2 * y + dy).min(size.y - 1);
+                    sum += data[(4 * (sy * size.x + sx) + c) as usize] as u32;
+                }
+                new_data.push(((sum + 2) / 4) as u8);
+            }
+        }
+    }
+    (new_size, new_data)
+}
+
+// Places every texture on a shelf, tallest textures first. Returns the layer and position of every texture, or `None`
+// if it didn't fit into `max_layers` layers.
+fn pack_shelves(sizes: &[UVec2], layer_size: u32, max_layers: u32) -> Vec<Option<(u32, UVec2)>> {
+    let mut order = (0..sizes.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|i| std::cmp::Reverse((sizes[*i].y, sizes[*i].x)));
+
+    let mut placements = vec![None; sizes.len()];
+    let (mut layer, mut cursor, mut shelf_height) = (0, UVec2::ZERO, 0);
+    for i in order {
+        let size = sizes[i];
+        if cursor.x + size.x > layer_size {
+            cursor = UVec2::new(0, cursor.y + shelf_height);
+            shelf_height = 0;
+        }
+        if cursor.y + size.y > layer_size {
+            layer += 1;
+            cursor = UVec2::ZERO;
+            shelf_height = 0;
+        }
+        if layer >= max_layers {
+            continue;
+        }
+        placements[i] = Some((layer, cursor));
+        cursor.x += size.x;
+        shelf_height = shelf_height.max(size.y);
+    }
+    placements
+}
+
+#[derive(Clone, Copy)]
+pub struct PulseAtlasEntry {
+    pub texture: PulseMaterialTexture,
+    pub two_component: bool,
+}
+
+#[derive(Resource)]
+pub struct PulseTextureAtlas {
+    pub texture: Texture,
+    pub view: TextureView,
+    pub sampler: Sampler,
+    entries: HashMap<AssetId<Image>, PulseAtlasEntry>,
+}
+
+impl FromWorld for PulseTextureAtlas {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let queue = world.resource::<RenderQueue>();
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("pulse_texture_atlas_sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+        // Bound even when no material has textures.
+        let (texture, view) = create_atlas_texture(device, queue, UVec2::ONE, 1, &[255; 4]);
+        Self {
+            texture,
+            view,
+            sampler,
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl PulseTextureAtlas {
+    pub fn get(&self, id: Option<AssetId<Image>>) -> Option<PulseAtlasEntry> {
+        id.and_then(|id| self.entries.get(&id).copied())
+    }
+
+    pub fn byte_size(&self) -> u64 {
+        let size = self.texture.size();
+        4 * (size.width * size.height * size.depth_or_array_layers) as u64
+    }
+}
+
+// `PulseEnergyTable` of the layered BSDF, computed once on startup.
+#[derive(Resource)]
+pub struct PulseEnergyTableTexture {
+    pub texture: Texture,
+    pub view: TextureView,
+}
+
+impl FromWorld for PulseEnergyTableTexture {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let queue = world.resource::<RenderQueue>();
+        let table = PulseEnergyTable::new();
+        let extent = PulseEnergyTable::extent();
+        let texture = device.create_texture_with_data(
+            queue,
+            &TextureDescriptor {
+                label: Some("pulse_energy_table"),
+                size: Extent3d {
+                    width: extent.x,
+                    height: extent.y,
+                    depth_or_array_layers: extent.z,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D3,
+                // Interpolated in the shader, since 32 bit floats aren't filterable everywhere.
+                format: TextureFormat::Rgba32Float,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            },
+            TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(&table.0),
+        );
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Self { texture, view }
+    }
+}
+
+fn create_atlas_texture(
+    device: &RenderDevice,
+    queue: &RenderQueue,
+    size: UVec2,
+    layers: u32,
+    data: &[u8],
+) -> (Texture, TextureView) {
+    let texture = device.create_texture_with_data(
+        queue,
+        &TextureDescriptor {
+            label: Some("pulse_texture_atlas"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            // Color textures stay sRGB encoded and are decoded in the shader, so all of them can share one format.
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        TextureDataOrder::LayerMajor,
+        data,
+    );
+    // An explicit array view, since a texture with a single layer would otherwise be viewed as a plain 2D texture.
+    let view = texture.create_view(&TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+    (texture, view)
+}
+
+// Repacks the whole atlas whenever the images or the set of images used by materials change.
+pub fn prepare_texture_atlas(
+    images: Res<PulseTextureImages>,
+    materials: Res<PulseMaterials>,
+    settings: Res<PulseTextureAtlasSettings>,
+    diagnostics: Res<PulseSceneDiagnostics>,
+    (device, queue): (Res<RenderDevice>, Res<RenderQueue>),
+    mut atlas: ResMut<PulseTextureAtlas>,
+    mut used: Local<Vec<AssetId<Image>>>,
+) {
+    let mut ids = materials
+        .0
+        .values()
+        .flat_map(|(_, textures)| textures.iter())
+        .filter(|id| images.0.contains_key(id))
+        .collect::<HashSet<AssetId<Image>>>()
+        .into_iter()
+        .collect::<Vec<AssetId<Image>>>();
+    ids.sort();
+    if !images.is_changed() && !settings.is_changed() && ids == *used {
+        return;
+    }
+    *used = ids.clone();
+
+    let limits = device.limits();
+    let layer_size = settings
+        .layer_size
+        .clamp(1, limits.max_texture_dimension_2d);
+    let scaled = ids
+        .iter()
+        .map(|id| {
+            let image = &images.0[id];
+            let (mut size, mut data) = (image.size, image.data.clone());
+            while size.x > layer_size || size.y > layer_size {
+                (size, data) = downsample(size, &data);
+            }
+            (size, data)
+        })
+        .collect::<Vec<_>>();
+    let sizes = scaled.iter().map(|(size, _)| *size).collect::<Vec<_>>();
+    let placements = pack_shelves(&sizes, layer_size, limits.max_texture_array_layers);
+
+    // Layers are only as large as the textures in them need.
+    let mut atlas_size = UVec2::ONE;
+    let mut layer_count = 1;
+    for (size, placement) in sizes.iter().zip(placements.iter()) {
+        if let Some((layer, position)) = placement {
+            atlas_size = atlas_size.max(*position + *size);
+            layer_count = layer_count.max(layer + 1);
+        }
+    }
+
+    let layer_bytes = (4 * atlas_size.x * atlas_size.y) as usize;
+    let mut data = vec![255; layer_bytes * layer_count as usize];
+    let mut entries = HashMap::new();
+    for (i, id) in ids.iter().enumerate() {
+        let Some((layer, position)) = placements[i] else {
+            diagnostics.report(PulseSceneDiagnosticEvent::TextureSkipped {
+                image: *id,
+                reason: PulseTextureSkipReason::AtlasFull,
+            });
+            continue;
+        };
+        let (size, pixels) = &scaled[i];
+        for row in 0..size.y {
+            let src = (4 * row * size.x) as usize;
+            let dst = layer as usize * layer_bytes
+                + (4 * ((position.y + row) * atlas_size.x + position.x)) as usize;
+            data[dst..dst + 4 * size.x as usize]
+                .copy_from_slice(&pixels[src..src + 4 * size.x as usize]);
+        }
+        let atlas_size = atlas_size.as_vec2();
+        entries.insert(
+            *id,
+            PulseAtlasEntry {
+                texture: PulseMaterialTexture {
+                    rect: Vec4::new(
+                        (position.x as f32 + 0.5) / atlas_size.x,
+                        (position.y as f32 + 0.5) / atlas_size.y,
+                        (size.x - 1) as f32 / atlas_size.x,
+                        (size.y - 1) as f32 / atlas_size.y,
+                    ),
+                    layer,
+                },
+                two_component: images.0[id].two_component,
+            },
+        );
+    }
+
+    let (texture, view) = create_atlas_texture(&device, &queue, atlas_size, layer_count, &data);
+    atlas.texture = texture;
+    atlas.view = view;
+    atlas.entries = entries;
+}
@@ -1,7 +1,7 @@
-use super::*;
+use super::{blas::AABB, *};
 use bevy::render::render_resource::ShaderType;
 
-#[derive(Default, ShaderType, Clone, Debug)]
+#[derive(Default, ShaderType, Clone, Debug, PartialEq)]
 pub struct PulseTLASNode {
     pub aabb_min: Vec3,
     pub a_or_first_instance: u32,
@@ -20,34 +20,40 @@ impl PulseTLASNode {
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct PulseTLAS {
     pub nodes: Vec<PulseTLASNode>,
     pub instance_indices: Vec<u32>,
 }
 
-pub fn build_tlas(instances: &Vec<PulsePrimitiveMeshInstance>) -> PulseTLAS {
-    if instances.len() == 0 {
+pub fn build_tlas(
+    instances: &Vec<PulsePrimitiveMeshInstance>,
+    settings: &PulseBvhBuildSettings,
+) -> PulseTLAS {
+    // Tombstones are left out, so no leaf points to them.
+    let mut instance_indices = vec![];
+    for (i, instance) in instances.iter().enumerate() {
+        if !instance.is_tombstone() {
+            instance_indices.push(i);
+        }
+    }
+
+    if instance_indices.is_empty() {
         return PulseTLAS {
             nodes: vec![PulseTLASNode::invalid()],
             instance_indices: vec![],
         };
     }
 
-    let mut instance_indices = vec![];
-    for i in 0..instances.len() {
-        instance_indices.push(i);
-    }
-
     let mut nodes: Vec<PulseTLASNode> = vec![];
     let mut root = PulseTLASNode::default();
     root.a_or_first_instance = 0;
     root.instance_count = 0;
-    root.instance_count = instances.len() as u32;
+    root.instance_count = instance_indices.len() as u32;
     calculate_node_aabb(&mut root, instances, &instance_indices);
     nodes.push(root);
 
-    subdivide(0, &mut nodes, instances, &mut instance_indices);
+    subdivide(0, &mut nodes, instances, &mut instance_indices, settings);
 
     let instance_indices = instance_indices
         .iter()
@@ -64,28 +70,87 @@ pub fn build_tlas(instances: &Vec<PulsePrimitiveMeshInstance>) -> PulseTLAS {
     }
 }
 
+// Recomputes node bounds for moved instances while keeping the tree topology.
+// Only valid if `instances` are the same, in the same order, as the tree was built with.
+pub fn refit_tlas(tlas: &mut PulseTLAS, instances: &Vec<PulsePrimitiveMeshInstance>) {
+    if tlas.instance_indices.is_empty() {
+        return;
+    }
+
+    // Children are always stored after their parent, so walking backwards visits them first.
+    for node_idx in (0..tlas.nodes.len()).rev() {
+        let node = &tlas.nodes[node_idx];
+        let mut aabb = AABB::empty();
+        if node.instance_count > 0 {
+            for i in node.a_or_first_instance..(node.a_or_first_instance + node.instance_count) {
+                let instance = &instances[tlas.instance_indices[i as usize] as usize];
+                aabb.grow_position(instance.bounds_min);
+                aabb.grow_position(instance.bounds_max);
+            }
+        } else {
+            let a = &tlas.nodes[node.a_or_first_instance as usize];
+            let b = &tlas.nodes[node.a_or_first_instance as usize + 1];
+            aabb.grow_aabb(AABB {
+                min: a.aabb_min,
+                max: a.aabb_max,
+            });
+            aabb.grow_aabb(AABB {
+                min: b.aabb_min,
+                max: b.aabb_max,
+            });
+        }
+        let node = &mut tlas.nodes[node_idx];
+        node.aabb_min = aabb.min;
+        node.aabb_max = aabb.max;
+    }
+}
+
+impl PulseTLAS {
+    // Same as `Blas::sah_cost`, relative to the area of the root node.
+    pub fn sah_cost(&self) -> f32 {
+        if self.nodes.is_empty() || self.instance_indices.is_empty() {
+            return 0.0;
+        }
+
+        let root_area = calculate_node_area(&self.nodes[0]);
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+
+        let mut cost = 0.0;
+        for node in self.nodes.iter() {
+            let relative_area = calculate_node_area(node) / root_area;
+            if node.instance_count > 0 {
+                cost += relative_area * node.instance_count as f32;
+            } else {
+                cost += relative_area * 2.0;
+            }
+        }
+        cost
+    }
+}
+
 pub fn subdivide(
     node_idx: usize,
     nodes: &mut Vec<PulseTLASNode>,
     instances: &Vec<PulsePrimitiveMeshInstance>,
     instance_indices: &mut Vec<usize>,
+    settings: &PulseBvhBuildSettings,
 ) {
-    if nodes[node_idx].instance_count <= 1 {
+    if nodes[node_idx].instance_count <= settings.max_leaf_size {
         return;
     }
 
-    let (axis, split_position, _split_cost) =
-        find_best_split_plane(&nodes[node_idx], instances, instance_indices);
-
-    // let no_split_cost = calculate_node_cost(&nodes[node_idx]);
-    // if split_cost >= no_split_cost {
-    //     // warn!(
-    //     //     "aborting. split: {}, no_split: {}, ",
-    //     //     split_cost,
-    //     //     no_split_cost
-    //     // );
-    //     return;
-    // }
+    let (axis, split_position, split_cost) =
+        find_best_split_plane(&nodes[node_idx], instances, instance_indices, settings);
+
+    if !settings.is_split_worth_it(
+        calculate_node_area(&nodes[node_idx]),
+        nodes[node_idx].instance_count,
+        split_cost,
+    ) {
+        return;
+    }
 
     let mut i = nodes[node_idx].a_or_first_instance;
     let mut j = i + nodes[node_idx].instance_count - 1;
@@ -126,38 +191,17 @@ pub fn subdivide(
         nodes,
         instances,
         instance_indices,
+        settings,
     );
     subdivide(
         nodes[node_idx].a_or_first_instance as usize + 1,
         nodes,
         instances,
         instance_indices,
+        settings,
     );
 }
 
-#[derive(Default, Copy, Clone)]
-struct AABB {
-    pub min: Vec3,
-    pub max: Vec3,
-}
-
-impl AABB {
-    pub fn grow_position(&mut self, p: Vec3) {
-        self.min = self.min.min(p);
-        self.max = self.max.max(p);
-    }
-
-    pub fn grow_aabb(&mut self, aabb: AABB) {
-        self.grow_position(aabb.min);
-        self.grow_position(aabb.max);
-    }
-
-    pub fn area(&self) -> f32 {
-        let e = self.max - self.min;
-        e.x * e.y + e.y * e.z + e.z * e.x
-    }
-}
-
 #[derive(Default, Copy, Clone)]
 struct Bin {
     bounds: AABB,
@@ -169,6 +213,7 @@ fn find_best_split_plane(
     node: &PulseTLASNode,
     instances: &Vec<PulsePrimitiveMeshInstance>,
     instance_indices: &Vec<usize>,
+    settings: &PulseBvhBuildSettings,
 ) -> (usize, f32, f32) {
     let mut best_axis = 0;
     let mut best_position = 0.0;
@@ -189,41 +234,41 @@ fn find_best_split_plane(
         }
 
         // Create bins
-        const BIN_COUNT: usize = 2;
-        let mut bins: [Bin; BIN_COUNT] = [Bin::default(); BIN_COUNT];
-        let bin_size_inv = BIN_COUNT as f32 / (bounds_max - bounds_min);
+        let bin_count = settings.bin_count.max(2);
+        let mut bins = vec![Bin::default(); bin_count];
+        let bin_size_inv = bin_count as f32 / (bounds_max - bounds_min);
         for i in 0..node.instance_count {
             let instance = &instances[instance_indices[(node.a_or_first_instance + i) as usize]];
             let bin_idx =
-                (BIN_COUNT - 1).min(((instance.center[axis] - bounds_min) * bin_size_inv) as usize);
+                (bin_count - 1).min(((instance.center[axis] - bounds_min) * bin_size_inv) as usize);
             bins[bin_idx].instance_count += 1;
             bins[bin_idx].bounds.grow_position(instance.bounds_min);
             bins[bin_idx].bounds.grow_position(instance.bounds_max);
         }
 
         // Calculate bin data
-        let mut area_a = [0.0; BIN_COUNT - 1];
-        let mut area_b = [0.0; BIN_COUNT - 1];
-        let mut count_a = [0u32; BIN_COUNT - 1];
-        let mut count_b = [0u32; BIN_COUNT - 1];
+        let mut area_a = vec![0.0; bin_count - 1];
+        let mut area_b = vec![0.0; bin_count - 1];
+        let mut count_a = vec![0u32; bin_count - 1];
+        let mut count_b = vec![0u32; bin_count - 1];
         let mut box_a = AABB::default();
         let mut box_b = AABB::default();
         let mut sum_a = 0;
         let mut sum_b = 0;
-        for i in 0..(BIN_COUNT - 1) {
+        for i in 0..(bin_count - 1) {
             sum_a += bins[i].instance_count;
             count_a[i] = sum_a;
             box_a.grow_aabb(bins[i].bounds);
             area_a[i] = box_a.area();
 
-            sum_b += bins[BIN_COUNT - 1 - i].instance_count;
-            count_b[BIN_COUNT - 2 - i] = sum_b;
-            box_b.grow_aabb(bins[BIN_COUNT - 1 - i].bounds);
-            area_b[BIN_COUNT - 2 - i] = box_b.area();
+            sum_b += bins[bin_count - 1 - i].instance_count;
+            count_b[bin_count - 2 - i] = sum_b;
+            box_b.grow_aabb(bins[bin_count - 1 - i].bounds);
+            area_b[bin_count - 2 - i] = box_b.area();
         }
 
-        let bin_size = (bounds_max - bounds_min) / BIN_COUNT as f32;
-        for i in 0..(BIN_COUNT - 1) {
+        let bin_size = (bounds_max - bounds_min) / bin_count as f32;
+        for i in 0..(bin_count - 1) {
             let plane_cost = count_a[i] as f32 * area_a[i] + count_b[i] as f32 * area_b[i];
             if plane_cost < best_cost {
                 best_axis = axis;
@@ -261,3 +306,102 @@ fn calculate_node_aabb(
         node.aabb_max = node.aabb_max.max(instance.bounds_max);
     }
 }
+
+fn calculate_node_area(node: &PulseTLASNode) -> f32 {
+    let e = node.aabb_max - node.aabb_min;
+    e.x * e.y + e.y * e.z + e.z * e.x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn box_instance(center: Vec3, half_size: Vec3) -> PulsePrimitiveMeshInstance {
+        PulsePrimitiveMeshInstance {
+            bounds_min: center - half_size,
+            bounds_max: center + half_size,
+            center,
+        }
+    }
+
+    // Random boxes, with every fifth one a tombstone.
+    fn random_instances(rng: &mut StdRng) -> Vec<PulsePrimitiveMeshInstance> {
+        (0..500)
+            .map(|i| {
+                if i % 5 == 4 {
+                    return PulsePrimitiveMeshInstance::tombstone();
+                }
+                let center = Vec3::new(
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-5.0..5.0),
+                    rng.gen_range(-50.0..50.0),
+                );
+                box_instance(center, Vec3::splat(rng.gen_range(0.1..2.0)))
+            })
+            .collect()
+    }
+
+    // Every leaf has to bound its instances exactly and every interior node its children.
+    fn assert_tight_bounds(tlas: &PulseTLAS, instances: &[PulsePrimitiveMeshInstance]) {
+        for node in tlas.nodes.iter() {
+            let mut aabb = AABB::empty();
+            let first = node.a_or_first_instance as usize;
+            if node.instance_count > 0 {
+                for i in &tlas.instance_indices[first..first + node.instance_count as usize] {
+                    let instance = &instances[*i as usize];
+                    assert!(!instance.is_tombstone());
+                    aabb.grow_position(instance.bounds_min);
+                    aabb.grow_position(instance.bounds_max);
+                }
+            } else {
+                for child in &tlas.nodes[first..first + 2] {
+                    aabb.grow_position(child.aabb_min);
+                    aabb.grow_position(child.aabb_max);
+                }
+            }
+            assert_eq!(node.aabb_min, aabb.min);
+            assert_eq!(node.aabb_max, aabb.max);
+        }
+    }
+
+    #[test]
+    fn refit_matches_moved_instances() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut instances = random_instances(&mut rng);
+        let mut tlas = build_tlas(&instances, &PulseBvhSettings::default().tlas);
+        let live_count = instances.iter().filter(|i| !i.is_tombstone()).count();
+        assert_eq!(tlas.instance_indices.len(), live_count);
+        assert_tight_bounds(&tlas, &instances);
+
+        // Nothing moved, so nothing changes.
+        let built = tlas.nodes.clone();
+        refit_tlas(&mut tlas, &instances);
+        assert!(tlas.nodes == built);
+
+        for instance in instances.iter_mut().filter(|i| !i.is_tombstone()) {
+            let offset = Vec3::new(rng.gen_range(-10.0..10.0), 0.0, rng.gen_range(-10.0..10.0));
+            let half_size = (instance.bounds_max - instance.bounds_min) * 0.5;
+            *instance = box_instance(instance.center + offset, half_size);
+        }
+        let instance_indices = tlas.instance_indices.clone();
+        refit_tlas(&mut tlas, &instances);
+        assert_eq!(tlas.instance_indices, instance_indices);
+        for (node, built) in tlas.nodes.iter().zip(built.iter()) {
+            assert_eq!(node.a_or_first_instance, built.a_or_first_instance);
+            assert_eq!(node.instance_count, built.instance_count);
+        }
+        assert_tight_bounds(&tlas, &instances);
+    }
+
+    #[test]
+    fn refit_keeps_empty_tlas_invalid() {
+        let instances = (0..3)
+            .map(|_| PulsePrimitiveMeshInstance::tombstone())
+            .collect();
+        let mut tlas = build_tlas(&instances, &PulseBvhSettings::default().tlas);
+        refit_tlas(&mut tlas, &instances);
+        assert!(tlas.nodes == vec![PulseTLASNode::invalid()]);
+        assert!(tlas.instance_indices.is_empty());
+    }
+}
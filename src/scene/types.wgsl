@@ -3,6 +3,10 @@
 struct SceneUniform {
     instance_count: u32,
     light_count: u32,
+    // Elements per page of the paged bindings, see `PulsePagedSceneBuffer`.
+    primitive_page_size: u32,
+    triangle_data_page_size: u32,
+    blas_node_page_size: u32,
 }
 
 struct Ray {
@@ -30,17 +34,52 @@ struct TriangleData {
     n_first: vec3<f32>,
     n_second: vec3<f32>,
     n_third: vec3<f32>,
+    // Zero if the mesh has no tangents.
+    t_first: vec4<f32>,
+    t_second: vec4<f32>,
+    t_third: vec4<f32>,
     uv_first: vec2<f32>,
     uv_second: vec2<f32>,
     uv_third: vec2<f32>,
 }
 
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+// `TriangleData` with octahedral encoded normals and tangents and half float UVs, see `PulseVertexEncoding`.
+struct PackedTriangleData {
+    n_first: u32,
+    n_second: u32,
+    n_third: u32,
+    t_first: u32,
+    t_second: u32,
+    t_third: u32,
+    uv_first: u32,
+    uv_second: u32,
+    uv_third: u32,
+}
+#endif
+
+#ifdef PULSE_WIDE_BVH
+// Compressed wide node, see wide_bvh.rs. Per-child bytes are packed four to a word.
+struct BLASNode {
+    origin: vec3<f32>,
+    exponents_and_imask: u32,
+    children: array<u32, #{PULSE_BVH_WIDTH}>,
+    tri_counts: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+    lo_x: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+    lo_y: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+    lo_z: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+    hi_x: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+    hi_y: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+    hi_z: array<u32, #{PULSE_BVH_WIDTH_WORDS}>,
+}
+#else
 struct BLASNode {
     aabb_min: vec3<f32>,
     a_or_first_tri: u32,
     aabb_max: vec3<f32>,
     tri_count: u32,
 }
+#endif
 
 struct TLASNode {
     aabb_min: vec3<f32>,
@@ -57,6 +96,15 @@ struct MeshInstance {
     index_offset: u32,
     node_offset: u32,
     material_index: u32,
+    render_layers: u32,
+    visibility_flags: u32,
+}
+
+// Place of a texture in the material texture atlas, `layer` is `NO_TEXTURE` for materials without it.
+struct MaterialTexture {
+    // Offset and scale from texture to atlas UVs.
+    rect: vec4f,
+    layer: u32,
 }
 
 struct Material {
@@ -65,6 +113,26 @@ struct Material {
     perceptual_roughness: f32,
     reflectance: f32,
     metallic: f32,
+    flags: u32,
+    specular_transmission: f32,
+    diffuse_transmission: f32,
+    ior: f32,
+    // Zero for thin-walled materials.
+    thickness: f32,
+    attenuation_color: vec3f,
+    attenuation_distance: f32,
+    clearcoat: f32,
+    clearcoat_perceptual_roughness: f32,
+    anisotropy_strength: f32,
+    // Radians counterclockwise from the tangent.
+    anisotropy_rotation: f32,
+    sheen_color: vec3f,
+    sheen_perceptual_roughness: f32,
+    base_color_texture: MaterialTexture,
+    emissive_texture: MaterialTexture,
+    metallic_roughness_texture: MaterialTexture,
+    occlusion_texture: MaterialTexture,
+    normal_map_texture: MaterialTexture,
 }
 
 struct LightDataIndex {
@@ -0,0 +1,455 @@
+use super::{PulseMesh, PulseTriangleData};
+use bevy::{
+    prelude::*,
+    render::render_resource::{ShaderDefVal, ShaderType},
+};
+
+// How triangle vertices are stored on the GPU.
+//
+// `PerTriangle` stores three positions in `primitives` and three normals, tangents and UVs in `triangle_data` for every
+// triangle. `Indexed` stores every vertex once, with positions in `primitives`, normals and tangents in `triangle_data`
+// and UVs in `vertex_uvs`, and three indices per triangle in `vertex_indices`, which usually takes about a third of the
+// memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PulseVertexLayout {
+    #[default]
+    PerTriangle,
+    Indexed,
+}
+
+impl PulseVertexLayout {
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        match self {
+            Self::PerTriangle => vec![],
+            Self::Indexed => vec!["PULSE_INDEXED_VERTICES".into()],
+        }
+    }
+}
+
+// Vertex buffers of the `Indexed` layout. Positions are tightly packed `f32`s, three per vertex.
+#[derive(Default)]
+pub struct PulseIndexedVertices {
+    pub positions: Vec<f32>,
+    // The normal followed by the tangent of every vertex, seven tightly packed `f32`s.
+    pub tangent_frames: Vec<f32>,
+    pub uvs: Vec<Vec2>,
+    // Replace `tangent_frames` and `uvs` after `pack`, with the normal and tangent of a vertex in two u32s.
+    pub packed_tangent_frames: Vec<u32>,
+    pub packed_uvs: Vec<u32>,
+    // Three per triangle, in the same order as `PulseMesh::primitives`. Already offset to index the whole buffer.
+    pub indices: Vec<u32>,
+}
+
+impl PulseIndexedVertices {
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len() / 3
+    }
+
+    // Encodes normals, tangents and UVs for `PulseVertexEncoding::Compact`. Nothing can be appended afterwards.
+    pub fn pack(&mut self) {
+        self.packed_tangent_frames = self
+            .tangent_frames
+            .chunks_exact(7)
+            .flat_map(|f| {
+                [
+                    encode_octahedral_normal(Vec3::from_slice(f)),
+                    encode_octahedral_tangent(Vec4::from_slice(&f[3..])),
+                ]
+            })
+            .collect();
+        self.packed_uvs = self.uvs.iter().map(|uv| pack_half2(*uv)).collect();
+        self.tangent_frames = vec![];
+        self.uvs = vec![];
+    }
+
+    pub fn normal(&self, index: usize) -> Vec3 {
+        Vec3::from_slice(&self.tangent_frames[7 * index..])
+    }
+
+    pub fn tangent(&self, index: usize) -> Vec4 {
+        Vec4::from_slice(&self.tangent_frames[7 * index + 3..])
+    }
+
+    fn push(&mut self, position: Vec3, normal: Vec3, tangent: Vec4, uv: Vec2) {
+        self.positions.extend(position.to_array());
+        self.tangent_frames.extend(normal.to_array());
+        self.tangent_frames.extend(tangent.to_array());
+        self.uvs.push(uv);
+    }
+
+    fn vertex(&self, index: usize) -> (Vec3, Vec3, Vec4, Vec2) {
+        (
+            Vec3::from_slice(&self.positions[3 * index..]),
+            self.normal(index),
+            self.tangent(index),
+            self.uvs[index],
+        )
+    }
+
+    // Vertices are recovered from the per-triangle data through the mesh's index buffer. Corners that disagree with
+    // what is already stored for their vertex, like flat normals generated for meshes without any, get a vertex of
+    // their own.
+    pub fn append(&mut self, mesh: &PulseMesh) {
+        let first_vertex = self.vertex_count();
+        let vertex_count = mesh.indices.iter().max().map_or(0, |i| *i as usize + 1);
+        for _ in 0..vertex_count {
+            self.push(Vec3::ZERO, Vec3::ZERO, Vec4::ZERO, Vec2::ZERO);
+        }
+        let mut written = vec![false; vertex_count];
+
+        for (triangle, (primitive, data)) in mesh
+            .primitives
+            .iter()
+            .zip(mesh.triangle_data.iter())
+            .enumerate()
+        {
+            for corner in 0..3 {
+                let vertex = mesh.indices[3 * triangle + corner] as usize;
+                let attributes = (
+                    primitive.positions[corner],
+                    data.normals[corner],
+                    data.tangents[corner],
+                    data.uvs[corner],
+                );
+                let index = if !written[vertex] {
+                    written[vertex] = true;
+                    let i = first_vertex + vertex;
+                    self.positions[3 * i..3 * i + 3].copy_from_slice(&attributes.0.to_array());
+                    self.tangent_frames[7 * i..7 * i + 3].copy_from_slice(&attributes.1.to_array());
+                    self.tangent_frames[7 * i + 3..7 * i + 7]
+                        .copy_from_slice(&attributes.2.to_array());
+                    self.uvs[i] = attributes.3;
+                    i
+                } else if self.vertex(first_vertex + vertex) == attributes {
+                    first_vertex + vertex
+                } else {
+                    self.push(attributes.0, attributes.1, attributes.2, attributes.3);
+                    self.vertex_count() - 1
+                };
+                self.indices.push(index as u32);
+            }
+        }
+    }
+}
+
+// How vertex normals, tangents and UVs are stored on the GPU, in either layout.
+//
+// `Compact` stores normals and tangents octahedral encoded as two 16 bit snorms and UVs as two half floats, each packed
+// into a single u32. Decoded normals are within `OCTAHEDRAL_NORMAL_MAX_ERROR` radians of the original. Tangents give up
+// the lowest bit of each snorm for their handedness and whether the mesh has tangents at all, which loosens their bound
+// to `OCTAHEDRAL_TANGENT_MAX_ERROR`. UVs keep 11 significant bits, so textures that repeat many times over a mesh lose
+// precision far from the origin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PulseVertexEncoding {
+    #[default]
+    Full,
+    Compact,
+}
+
+impl PulseVertexEncoding {
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        match self {
+            Self::Full => vec![],
+            Self::Compact => vec!["PULSE_COMPACT_VERTEX_ATTRIBUTES".into()],
+        }
+    }
+}
+
+// Upper bound of the angle between a unit normal and its decoded octahedral encoding.
+pub const OCTAHEDRAL_NORMAL_MAX_ERROR: f32 = 1e-4;
+// Same for tangents, which lose a bit of precision in each snorm to `TANGENT_HANDEDNESS_BIT` and `TANGENT_PRESENT_BIT`.
+pub const OCTAHEDRAL_TANGENT_MAX_ERROR: f32 = 2.5e-4;
+
+// `PulseTriangleData` with `PulseVertexEncoding::Compact`, matches `PackedTriangleData` in types.wgsl.
+#[derive(Default, ShaderType, Clone, Debug)]
+pub struct PulsePackedTriangleData {
+    pub normals: [u32; 3],
+    pub tangents: [u32; 3],
+    pub uvs: [u32; 3],
+}
+
+impl From<&PulseTriangleData> for PulsePackedTriangleData {
+    fn from(data: &PulseTriangleData) -> Self {
+        Self {
+            normals: data.normals.map(encode_octahedral_normal),
+            tangents: data.tangents.map(encode_octahedral_tangent),
+            uvs: data.uvs.map(pack_half2),
+        }
+    }
+}
+
+// Same as `pack2x16snorm(octahedral_encode(n))` in WGSL. Zero length normals decode to +Z.
+pub fn encode_octahedral_normal(n: Vec3) -> u32 {
+    let l1_norm = n.x.abs() + n.y.abs() + n.z.abs();
+    if l1_norm == 0.0 {
+        return 0;
+    }
+    let n = n / l1_norm;
+    let p = if n.z >= 0.0 {
+        n.xy()
+    } else {
+        (1.0 - n.yx().abs()) * Vec2::select(n.xy().cmpge(Vec2::ZERO), Vec2::ONE, -Vec2::ONE)
+    };
+    let snorm = |v: f32| (v.clamp(-1.0, 1.0) * 32767.0).round() as i16 as u16 as u32;
+    snorm(p.x) | (snorm(p.y) << 16)
+}
+
+// CPU version of `decode_octahedral_normal` in utilities.wgsl.
+pub fn decode_octahedral_normal(packed: u32) -> Vec3 {
+    let snorm = |v: u32| (v as u16 as i16 as f32 / 32767.0).max(-1.0);
+    let p = Vec2::new(snorm(packed & 0xffff), snorm(packed >> 16));
+    let mut n = Vec3::new(p.x, p.y, 1.0 - p.x.abs() - p.y.abs());
+    let t = (-n.z).max(0.0);
+    n.x += if n.x >= 0.0 { -t } else { t };
+    n.y += if n.y >= 0.0 { -t } else { t };
+    n.normalize()
+}
+
+// Bits of an encoded tangent that replace the lowest bit of its x and y snorms.
+pub const TANGENT_HANDEDNESS_BIT: u32 = 1;
+pub const TANGENT_PRESENT_BIT: u32 = 1 << 16;
+
+// Missing tangents, which are zero, encode to zero.
+pub fn encode_octahedral_tangent(t: Vec4) -> u32 {
+    if t.w == 0.0 {
+        return 0;
+    }
+    let handedness = if t.w < 0.0 { TANGENT_HANDEDNESS_BIT } else { 0 };
+    (encode_octahedral_normal(t.xyz()) & !(TANGENT_HANDEDNESS_BIT | TANGENT_PRESENT_BIT))
+        | TANGENT_PRESENT_BIT
+        | handedness
+}
+
+// CPU version of `decode_octahedral_tangent` in utilities.wgsl.
+pub fn decode_octahedral_tangent(packed: u32) -> Vec4 {
+    if packed & TANGENT_PRESENT_BIT == 0 {
+        return Vec4::ZERO;
+    }
+    let handedness = if packed & TANGENT_HANDEDNESS_BIT != 0 {
+        -1.0
+    } else {
+        1.0
+    };
+    decode_octahedral_normal(packed).extend(handedness)
+}
+
+// Same as `pack2x16float` in WGSL, rounding to nearest with ties to even.
+pub fn pack_half2(v: Vec2) -> u32 {
+    f32_to_f16(v.x) as u32 | ((f32_to_f16(v.y) as u32) << 16)
+}
+
+pub fn unpack_half2(packed: u32) -> Vec2 {
+    Vec2::new(f16_to_f32(packed as u16), f16_to_f32((packed >> 16) as u16))
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    // Infinity and NaN.
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        // Subnormal, or too small and flushed to zero.
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        return sign | (half + round_up(half, mantissa, shift)) as u16;
+    }
+
+    // A carry out of the mantissa correctly bumps the exponent, and rounds up to infinity past the largest half.
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    sign | (half + round_up(half, mantissa, 13)) as u16
+}
+
+// 1 if `half`, the bits of `mantissa` kept after shifting it right by `shift`, has to be rounded up. Ties round to the
+// even neighbour.
+fn round_up(half: u32, mantissa: u32, shift: u32) -> u32 {
+    let remainder = mantissa & ((1 << shift) - 1);
+    let midpoint = 1 << (shift - 1);
+    (remainder > midpoint || (remainder == midpoint && half & 1 == 1)) as u32
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    let bits = match exponent {
+        0 => {
+            let value = mantissa as f32 * (2.0f32).powi(-24);
+            return if sign != 0 { -value } else { value };
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_unit_vectors(count: usize) -> Vec<Vec3> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut vectors = vec![
+            Vec3::X,
+            Vec3::NEG_X,
+            Vec3::Y,
+            Vec3::NEG_Y,
+            Vec3::Z,
+            Vec3::NEG_Z,
+            Vec3::ONE.normalize(),
+            -Vec3::ONE.normalize(),
+        ];
+        while vectors.len() < count {
+            let v = Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            );
+            if v.length_squared() > 0.01 && v.length_squared() <= 1.0 {
+                vectors.push(v.normalize());
+            }
+        }
+        vectors
+    }
+
+    // In double precision, since an f32 `acos` can't resolve angles this small.
+    fn angle_between(a: Vec3, b: Vec3) -> f32 {
+        let (a, b) = (a.as_dvec3().normalize(), b.as_dvec3().normalize());
+        a.cross(b).length().atan2(a.dot(b)) as f32
+    }
+
+    #[test]
+    fn octahedral_normals_round_trip() {
+        for n in random_unit_vectors(200_000) {
+            let decoded = decode_octahedral_normal(encode_octahedral_normal(n));
+            let error = angle_between(n, decoded);
+            assert!(
+                error <= OCTAHEDRAL_NORMAL_MAX_ERROR,
+                "{n} decoded to {decoded}, {error} radians off"
+            );
+        }
+        assert_eq!(
+            decode_octahedral_normal(encode_octahedral_normal(Vec3::ZERO)),
+            Vec3::Z
+        );
+    }
+
+    #[test]
+    fn octahedral_tangents_round_trip() {
+        for t in random_unit_vectors(200_000) {
+            for handedness in [1.0, -1.0] {
+                let packed = encode_octahedral_tangent(t.extend(handedness));
+                assert_ne!(packed & TANGENT_PRESENT_BIT, 0);
+
+                let decoded = decode_octahedral_tangent(packed);
+                assert_eq!(decoded.w, handedness);
+                let error = angle_between(t, decoded.xyz());
+                assert!(
+                    error <= OCTAHEDRAL_TANGENT_MAX_ERROR,
+                    "{t} decoded to {decoded}, {error} radians off"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn missing_tangents_round_trip() {
+        assert_eq!(encode_octahedral_tangent(Vec4::ZERO), 0);
+        assert_eq!(decode_octahedral_tangent(0), Vec4::ZERO);
+        // +Z encodes both snorms as zero, so only the present bit tells it apart from a missing tangent.
+        let packed = encode_octahedral_tangent(Vec4::new(0.0, 0.0, 1.0, 1.0));
+        assert_eq!(packed, TANGENT_PRESENT_BIT);
+        let decoded = decode_octahedral_tangent(packed);
+        assert_eq!(decoded.w, 1.0);
+        assert!(angle_between(decoded.xyz(), Vec3::Z) <= OCTAHEDRAL_TANGENT_MAX_ERROR);
+    }
+
+    #[test]
+    fn half_floats_round_trip() {
+        let halves = [
+            (0.0, 0x0000),
+            (-0.0, 0x8000),
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (0.5, 0x3800),
+            (1.0 + 2.0f32.powi(-10), 0x3c01),
+            (65504.0, 0x7bff),
+            // Smallest normal, and subnormals down to the smallest one.
+            (2.0f32.powi(-14), 0x0400),
+            (2.0f32.powi(-15), 0x0200),
+            (3.0 * 2.0f32.powi(-24), 0x0003),
+            (-(2.0f32.powi(-24)), 0x8001),
+        ];
+        for (value, half) in halves {
+            let packed = pack_half2(Vec2::new(value, -value));
+            assert_eq!(packed & 0xffff, half, "{value} packed to {packed:#x}");
+            assert_eq!(
+                packed >> 16,
+                half ^ 0x8000,
+                "{} packed to {packed:#x}",
+                -value
+            );
+            let unpacked = unpack_half2(packed);
+            assert_eq!(unpacked.x.to_bits(), value.to_bits());
+            assert_eq!(unpacked.y.to_bits(), (-value).to_bits());
+        }
+
+        // Rounded to the nearest half, ties to even.
+        assert_eq!(
+            pack_half2(Vec2::new(0.1, 1.0 + 2.0f32.powi(-12))),
+            0x3c00_2e66
+        );
+        assert_eq!(
+            pack_half2(Vec2::new(1.0 + 3.0 * 2.0f32.powi(-12), 0.0)),
+            0x3c01
+        );
+
+        for (value, half) in [
+            (1.0 + 2.0f32.powi(-11), 0x3c00),
+            (1.0 + 3.0 * 2.0f32.powi(-11), 0x3c02),
+            (65504.0 + 16.0, 0x7c00),
+            (2.0f32.powi(-25), 0x0000),
+            (2.0f32.powi(-25) + 2.0f32.powi(-40), 0x0001),
+            (3.0 * 2.0f32.powi(-25), 0x0002),
+            (5.0 * 2.0f32.powi(-25), 0x0002),
+            (2.0f32.powi(-14) - 2.0f32.powi(-25), 0x0400),
+        ] {
+            assert_eq!(pack_half2(Vec2::new(value, 0.0)), half, "{value}");
+        }
+
+        // Too small, flushed to zero.
+        assert_eq!(
+            pack_half2(Vec2::new(2.0f32.powi(-26), -(2.0f32.powi(-26)))),
+            0x8000_0000
+        );
+
+        // Overflow to infinity.
+        for value in [65520.0, 1e6, f32::MAX, f32::INFINITY] {
+            assert_eq!(pack_half2(Vec2::new(value, -value)), 0xfc00_7c00, "{value}");
+            assert_eq!(
+                unpack_half2(0xfc00_7c00),
+                Vec2::new(f32::INFINITY, f32::NEG_INFINITY)
+            );
+        }
+
+        let packed = pack_half2(Vec2::new(f32::NAN, 1.0));
+        assert_eq!(packed & 0x7c00, 0x7c00);
+        assert_ne!(packed & 0x3ff, 0);
+        assert!(unpack_half2(packed).x.is_nan());
+        assert_eq!(unpack_half2(packed).y, 1.0);
+    }
+}
@@ -0,0 +1,435 @@
+use super::blas::*;
+use bevy::{prelude::*, render::render_resource::ShaderDefVal};
+
+// Optional compressed GPU layout for BLASes. The binary BVH is built as usual and then collapsed into a 4 or 8 wide
+// tree where child bounds are stored quantized to 8 bits per axis, relative to the bounds of the parent node.
+//
+// Node layout, in u32 words (matches `BLASNode` in types.wgsl when `PULSE_WIDE_BVH` is defined):
+//   origin: vec3<f32>
+//   exponents_and_imask: biased exponent of the quantization grid for x, y, z and a mask of interior children, 8 bits each
+//   children: [u32; width], child node index for interior children and index of the first triangle for leaf children
+//   tri_counts: [u32; width / 4], triangle count of leaf children, 8 bits per child
+//   lo_x, lo_y, lo_z, hi_x, hi_y, hi_z: [u32; width / 4] each, quantized child bounds, 8 bits per child
+// padded to a multiple of 16 bytes.
+
+const MAX_LEAF_TRI_COUNT: u32 = 255;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PulseBlasLayout {
+    // Two 32 byte nodes per level, as built.
+    #[default]
+    Binary,
+    Wide4,
+    Wide8,
+}
+
+impl PulseBlasLayout {
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            Self::Binary => None,
+            Self::Wide4 => Some(4),
+            Self::Wide8 => Some(8),
+        }
+    }
+
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let Some(width) = self.width() else {
+            return vec![];
+        };
+        vec![
+            "PULSE_WIDE_BVH".into(),
+            ShaderDefVal::UInt("PULSE_BVH_WIDTH".into(), width as u32),
+            ShaderDefVal::UInt("PULSE_BVH_WIDTH_WORDS".into(), width as u32 / 4),
+        ]
+    }
+}
+
+// Size of a single wide node in u32 words.
+pub fn wide_node_words(width: usize) -> usize {
+    let words = 4 + width + 7 * (width / 4);
+    words.div_ceil(4) * 4
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    // Node of the binary BVH.
+    Node(usize),
+    // Leaf with too many triangles to fit in a single child slot. Split into several slots with the same bounds.
+    LeafRange {
+        first_tri: u32,
+        tri_count: u32,
+        bounds: AABB,
+    },
+}
+
+enum Slot {
+    Interior {
+        source: Source,
+        bounds: AABB,
+    },
+    Leaf {
+        first_tri: u32,
+        tri_count: u32,
+        bounds: AABB,
+    },
+}
+
+impl Slot {
+    fn bounds(&self) -> AABB {
+        match self {
+            Slot::Interior { bounds, .. } | Slot::Leaf { bounds, .. } => *bounds,
+        }
+    }
+}
+
+// Collapses `bvh` into wide nodes, returned as raw words ready to be uploaded. `width` must be 4 or 8.
+pub fn collapse_blas(bvh: &Blas, width: usize) -> Vec<u32> {
+    let node_words = wide_node_words(width);
+    let mut words = vec![0u32; node_words];
+    let mut queue = vec![(Source::Node(0), 0usize)];
+
+    while let Some((source, wide_index)) = queue.pop() {
+        let slots = open_slots(bvh, source, width);
+
+        let mut children = vec![0u32; width];
+        let mut tri_counts = vec![0u32; width];
+        let mut imask = 0u32;
+        for (i, slot) in slots.iter().enumerate() {
+            match slot {
+                Slot::Interior { source, .. } => {
+                    let child_index = words.len() / node_words;
+                    words.resize(words.len() + node_words, 0);
+                    queue.push((*source, child_index));
+                    children[i] = child_index as u32;
+                    imask |= 1 << i;
+                }
+                Slot::Leaf {
+                    first_tri,
+                    tri_count,
+                    ..
+                } => {
+                    children[i] = *first_tri;
+                    tri_counts[i] = *tri_count;
+                }
+            }
+        }
+
+        let node = &mut words[(wide_index * node_words)..((wide_index + 1) * node_words)];
+        encode_node(node, &slots, &children, &tri_counts, imask, width);
+    }
+
+    words
+}
+
+fn node_bounds(node: &PulseBLASNode) -> AABB {
+    AABB {
+        min: node.aabb_min,
+        max: node.aabb_max,
+    }
+}
+
+fn leaf_slot(first_tri: u32, tri_count: u32, bounds: AABB) -> Slot {
+    if tri_count <= MAX_LEAF_TRI_COUNT {
+        Slot::Leaf {
+            first_tri,
+            tri_count,
+            bounds,
+        }
+    } else {
+        Slot::Interior {
+            source: Source::LeafRange {
+                first_tri,
+                tri_count,
+                bounds,
+            },
+            bounds,
+        }
+    }
+}
+
+// Returns the children of the wide node made from `source`.
+fn open_slots(bvh: &Blas, source: Source, width: usize) -> Vec<Slot> {
+    match source {
+        Source::Node(index) => {
+            let node = &bvh.nodes[index];
+            if node.tri_count > 0 || bvh.nodes.len() == 1 {
+                return vec![leaf_slot(
+                    node.a_or_first_tri,
+                    node.tri_count,
+                    node_bounds(node),
+                )];
+            }
+
+            // Greedily open the interior child with the largest surface area until the node is full.
+            let mut open = vec![
+                node.a_or_first_tri as usize,
+                node.a_or_first_tri as usize + 1,
+            ];
+            while open.len() < width {
+                let largest = open
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, i)| bvh.nodes[**i].tri_count == 0)
+                    .max_by(|(_, a), (_, b)| {
+                        let area_a = node_bounds(&bvh.nodes[**a]).area();
+                        let area_b = node_bounds(&bvh.nodes[**b]).area();
+                        area_a.total_cmp(&area_b)
+                    })
+                    .map(|(slot, _)| slot);
+                let Some(slot) = largest else {
+                    break;
+                };
+                let child_a = bvh.nodes[open[slot]].a_or_first_tri as usize;
+                open[slot] = child_a;
+                open.push(child_a + 1);
+            }
+
+            open.iter()
+                .map(|i| {
+                    let node = &bvh.nodes[*i];
+                    if node.tri_count > 0 {
+                        leaf_slot(node.a_or_first_tri, node.tri_count, node_bounds(node))
+                    } else {
+                        Slot::Interior {
+                            source: Source::Node(*i),
+                            bounds: node_bounds(node),
+                        }
+                    }
+                })
+                .collect()
+        }
+        Source::LeafRange {
+            first_tri,
+            tri_count,
+            bounds,
+        } => {
+            // The bounds of the chunks aren't known without the triangles, so use the bounds of the whole leaf.
+            let chunk_size = tri_count.div_ceil(width as u32);
+            let mut slots = vec![];
+            let mut first = first_tri;
+            while first < first_tri + tri_count {
+                let count = chunk_size.min(first_tri + tri_count - first);
+                slots.push(leaf_slot(first, count, bounds));
+                first += count;
+            }
+            slots
+        }
+    }
+}
+
+fn encode_node(
+    node: &mut [u32],
+    slots: &[Slot],
+    children: &[u32],
+    tri_counts: &[u32],
+    imask: u32,
+    width: usize,
+) {
+    let mut bounds = AABB::empty();
+    for slot in slots.iter() {
+        bounds.grow_aabb(slot.bounds());
+    }
+    let origin = if bounds.is_valid() {
+        bounds.min
+    } else {
+        Vec3::ZERO
+    };
+
+    // Smallest power of two grid that covers the node with 255 steps on every axis.
+    let mut exponents = [0i32; 3];
+    for axis in 0..3 {
+        let max = if bounds.is_valid() {
+            bounds.max[axis]
+        } else {
+            origin[axis]
+        };
+        let extent = max - origin[axis];
+        let mut e = (extent / 255.0).max(f32::MIN_POSITIVE).log2().ceil() as i32;
+        e = e.clamp(-126, 127);
+        // Checked the way shaders dequantize, since adding the origin rounds.
+        while e < 127 && origin[axis] + 2f32.powi(e) * 255.0 < max {
+            e += 1;
+        }
+        exponents[axis] = e;
+    }
+
+    // Stored per axis, the same way they are packed.
+    let mut lo: [Vec<u32>; 3] = std::array::from_fn(|_| vec![255; width]);
+    let mut hi: [Vec<u32>; 3] = std::array::from_fn(|_| vec![0; width]);
+    for (i, slot) in slots.iter().enumerate() {
+        let child = slot.bounds();
+        if !child.is_valid() {
+            // Left as an empty box that rays never hit.
+            continue;
+        }
+        for axis in 0..3 {
+            let scale = 2f32.powi(exponents[axis]);
+            let mut child_lo = ((child.min[axis] - origin[axis]) / scale)
+                .floor()
+                .clamp(0.0, 255.0) as u32;
+            let mut child_hi = ((child.max[axis] - origin[axis]) / scale)
+                .ceil()
+                .clamp(0.0, 255.0) as u32;
+            // Adding the origin when dequantizing rounds, which can move the bounds inwards by a step.
+            while child_lo > 0 && origin[axis] + child_lo as f32 * scale > child.min[axis] {
+                child_lo -= 1;
+            }
+            while child_hi < 255 && origin[axis] + child_hi as f32 * scale < child.max[axis] {
+                child_hi += 1;
+            }
+            lo[axis][i] = child_lo;
+            hi[axis][i] = child_hi;
+        }
+    }
+
+    let width_words = width / 4;
+    node[0] = origin.x.to_bits();
+    node[1] = origin.y.to_bits();
+    node[2] = origin.z.to_bits();
+    node[3] = (exponents[0] + 127) as u32
+        | ((exponents[1] + 127) as u32) << 8
+        | ((exponents[2] + 127) as u32) << 16
+        | imask << 24;
+    node[4..(4 + width)].copy_from_slice(children);
+
+    let mut offset = 4 + width;
+    let mut write_bytes = |bytes: &dyn Fn(usize) -> u32| {
+        for word in 0..width_words {
+            let mut packed = 0;
+            for byte in 0..4 {
+                packed |= bytes(word * 4 + byte) << (8 * byte);
+            }
+            node[offset + word] = packed;
+        }
+        offset += width_words;
+    };
+    write_bytes(&|i| tri_counts[i]);
+    for bytes in lo.iter().chain(&hi) {
+        write_bytes(&|i| bytes[i]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_meshes::*, PulseBvhSettings, PulsePrimitive};
+    use super::*;
+
+    // Child bounds of a wide node, dequantized with the same f32 math as `intersect_blas` in utilities.wgsl.
+    fn child_bounds(node: &[u32], width: usize, child: usize) -> Option<AABB> {
+        let origin = Vec3::new(
+            f32::from_bits(node[0]),
+            f32::from_bits(node[1]),
+            f32::from_bits(node[2]),
+        );
+        let scale = Vec3::new(
+            f32::from_bits((node[3] & 0xff) << 23),
+            f32::from_bits(((node[3] >> 8) & 0xff) << 23),
+            f32::from_bits(((node[3] >> 16) & 0xff) << 23),
+        );
+        let byte = |array: usize, axis: usize| {
+            let word = node[4 + width + (1 + array * 3 + axis) * (width / 4) + child / 4];
+            ((word >> (8 * (child % 4))) & 0xff) as f32
+        };
+        let lo = Vec3::new(byte(0, 0), byte(0, 1), byte(0, 2));
+        let hi = Vec3::new(byte(1, 0), byte(1, 1), byte(1, 2));
+        if lo.cmpgt(hi).any() {
+            return None;
+        }
+        Some(AABB {
+            min: origin + lo * scale,
+            max: origin + hi * scale,
+        })
+    }
+
+    fn contains(outer: &AABB, inner: &AABB) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    // Walks the wide node at `index` and returns the bounds of all triangles below it. Checks that every child's
+    // dequantized bounds contain the triangles below that child, and marks the triangle indices that were reached.
+    fn check_node(
+        words: &[u32],
+        width: usize,
+        index: usize,
+        bvh: &Blas,
+        prims: &[PulsePrimitive],
+        reached: &mut [u32],
+    ) -> AABB {
+        let node_words = wide_node_words(width);
+        let node = &words[index * node_words..(index + 1) * node_words];
+        let imask = node[3] >> 24;
+        let mut node_bounds = AABB::empty();
+        for child in 0..width {
+            let Some(bounds) = child_bounds(node, width, child) else {
+                continue;
+            };
+            let target = node[4 + child] as usize;
+            let below = if imask & (1 << child) != 0 {
+                // Children come after their parent, so traversal can't loop.
+                assert!(target > index);
+                check_node(words, width, target, bvh, prims, reached)
+            } else {
+                let tri_count = (node[4 + width + child / 4] >> (8 * (child % 4))) & 0xff;
+                let mut below = AABB::empty();
+                for i in target..target + tri_count as usize {
+                    reached[i] += 1;
+                    for p in prims[bvh.tri_indices[i] as usize].positions {
+                        below.grow_position(p);
+                    }
+                }
+                below
+            };
+            if below.is_valid() {
+                assert!(
+                    contains(&bounds, &below),
+                    "child {} of node {}: {:?}..{:?} doesn't contain {:?}..{:?}",
+                    child,
+                    index,
+                    bounds.min,
+                    bounds.max,
+                    below.min,
+                    below.max
+                );
+            }
+            node_bounds.grow_aabb(below);
+        }
+        node_bounds
+    }
+
+    fn assert_collapse_is_conservative(name: &str, bvh: &Blas, prims: &[PulsePrimitive]) {
+        for width in [4, 8] {
+            let words = collapse_blas(bvh, width);
+            assert_eq!(words.len() % wide_node_words(width), 0);
+            let mut reached = vec![0; bvh.tri_indices.len()];
+            check_node(&words, width, 0, bvh, prims, &mut reached);
+            assert!(
+                reached.iter().all(|count| *count == 1),
+                "{}: every triangle reference has to be reached exactly once with width {}",
+                name,
+                width
+            );
+        }
+    }
+
+    #[test]
+    fn collapsed_bounds_are_conservative() {
+        let settings = PulseBvhSettings::default().blas;
+        for file in BUNDLED_GLB_FILES {
+            for (name, prims) in load_glb_primitives(file) {
+                let bvh = build_blas(&prims, &settings);
+                assert_collapse_is_conservative(&name, &bvh, &prims);
+            }
+        }
+    }
+
+    #[test]
+    fn large_leaves_are_split_across_slots() {
+        let (name, prims) = load_glb_primitives("monkey_smooth.glb").remove(0);
+        let mut settings = PulseBvhSettings::default().blas;
+        settings.max_leaf_size = u32::MAX;
+        let bvh = build_blas(&prims, &settings);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert!(bvh.nodes[0].tri_count > MAX_LEAF_TRI_COUNT);
+        assert_collapse_is_conservative(&name, &bvh, &prims);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::scene::buffers::PULSE_PIPELINE_STORAGE_BUFFERS;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{TextureFormat, TextureUsages, WgpuFeatures},
+        renderer::{RenderAdapter, RenderDevice},
+    },
+};
+use wgpu::{DownlevelFlags, TextureFormatFeatureFlags};
+
+// Smallest storage buffer binding Pulse runs with. Larger scenes are paged across several bindings, see
+// `PulseBufferLimits`.
+pub const PULSE_MIN_STORAGE_BUFFER_BINDING_SIZE: u64 = 128 << 20;
+// The GI and path tracer targets are bound as read-write storage textures.
+const RENDER_TARGET_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+const STORAGE_TEXTURES: u32 = 2;
+
+// Whether the render device can run Pulse. Checked once in the render app, and copied into the main world by
+// `PulsePlugin`.
+//
+// When something is missing, Pulse's plugins don't add their pipelines and render graph nodes, so cameras keep
+// rendering with Bevy's rasterizer.
+#[derive(Resource, Clone, Debug)]
+pub struct PulseSupport {
+    // Everything the device lacks, empty if Pulse is supported.
+    pub missing: Vec<String>,
+}
+
+impl PulseSupport {
+    pub fn is_supported(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+impl FromWorld for PulseSupport {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let adapter = world.resource::<RenderAdapter>();
+        let limits = device.limits();
+        let mut missing = vec![];
+
+        if !adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(DownlevelFlags::COMPUTE_SHADERS)
+        {
+            missing.push("compute shaders".to_string());
+        }
+
+        // Read-write access is an adapter specific format feature, which has to be enabled on the device.
+        let format_features = adapter.get_texture_format_features(RENDER_TARGET_FORMAT);
+        if !device
+            .features()
+            .contains(WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+            || !format_features
+                .allowed_usages
+                .contains(TextureUsages::STORAGE_BINDING)
+            || !format_features
+                .flags
+                .contains(TextureFormatFeatureFlags::STORAGE_READ_WRITE)
+        {
+            missing.push(format!(
+                "read-write storage textures with format {:?}",
+                RENDER_TARGET_FORMAT
+            ));
+        }
+
+        if limits.max_storage_textures_per_shader_stage < STORAGE_TEXTURES {
+            missing.push(format!(
+                "{} storage textures per shader stage, the device supports {}",
+                STORAGE_TEXTURES, limits.max_storage_textures_per_shader_stage
+            ));
+        }
+        // Counted for a whole pipeline, the scene bindings aren't the only ones.
+        if limits.max_storage_buffers_per_shader_stage < PULSE_PIPELINE_STORAGE_BUFFERS {
+            missing.push(format!(
+                "{} storage buffers per shader stage, the device supports {}",
+                PULSE_PIPELINE_STORAGE_BUFFERS, limits.max_storage_buffers_per_shader_stage
+            ));
+        }
+        if (limits.max_storage_buffer_binding_size as u64) < PULSE_MIN_STORAGE_BUFFER_BINDING_SIZE {
+            missing.push(format!(
+                "storage buffer bindings of {} bytes, the device supports {}",
+                PULSE_MIN_STORAGE_BUFFER_BINDING_SIZE, limits.max_storage_buffer_binding_size
+            ));
+        }
+        if limits.max_buffer_size < PULSE_MIN_STORAGE_BUFFER_BINDING_SIZE {
+            missing.push(format!(
+                "buffers of {} bytes, the device supports {}",
+                PULSE_MIN_STORAGE_BUFFER_BINDING_SIZE, limits.max_buffer_size
+            ));
+        }
+
+        if !missing.is_empty() {
+            error!(
+                "Pulse is not supported by this device and is disabled, falling back to Bevy's renderer. Missing: {}.",
+                missing.join(", ")
+            );
+        }
+
+        Self { missing }
+    }
+}
+
+// Checks support the first time it's called, for plugins to skip adding anything to the render app that can't run.
+pub fn pulse_support(render_app: &mut App) -> PulseSupport {
+    render_app.init_resource::<PulseSupport>();
+    render_app.world.resource::<PulseSupport>().clone()
+}
+
+// Run condition for render systems that use Pulse's GPU resources.
+pub fn pulse_supported(support: Option<Res<PulseSupport>>) -> bool {
+    support.is_some_and(|support| support.is_supported())
+}
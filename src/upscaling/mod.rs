@@ -1,3 +1,4 @@
+use crate::support::pulse_support;
 use bevy::{
     asset::load_internal_asset,
     prelude::*,
@@ -27,6 +28,9 @@ impl Plugin for PulseUpscalingPlugin {
 
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
+        if !pulse_support(render_app).is_supported() {
+            return;
+        }
 
         render_app
             .init_resource::<PulseUpscalingLayout>()
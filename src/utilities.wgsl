@@ -9,15 +9,13 @@
         MeshInstance,
         SceneUniform,
         Material,
+        MaterialTexture,
         Ray,
         RayHitRecord,
     }, 
     bindings::{
         scene_uniform,
-        primitives,
-        triangle_data,
         triangle_indices,
-        blas_nodes,
         tlas_nodes,
         instance_indices,
         instances,
@@ -26,14 +24,45 @@
         light_indices,
         light_mesh_areas,
         light_emission_strength_cdf,
+        load_primitive_element,
+        load_triangle_data_element,
+        load_blas_node,
+        texture_atlas,
+        texture_atlas_sampler,
+        energy_table,
     }
 }
+#ifdef PULSE_INDEXED_VERTICES
+#import pulse::scene::bindings::{vertex_uvs, vertex_indices}
+#endif
 
 const PI: f32 = 3.14159265358;
 const HALF_PI: f32 = 1.57079632679;
 const TWO_PI: f32 = 6.28318530718;
 const INV_PI: f32 = 0.31830988618;
 
+// Ray types and instance visibility flags, matches the `PULSE_VISIBLE_TO_*` constants in scene/mod.rs.
+const RAY_CAMERA: u32 = 1u;
+const RAY_GI: u32 = 2u;
+const RAY_SHADOW: u32 = 4u;
+const INSTANCE_RECEIVES_SHADOWS: u32 = 8u;
+
+// Material flags, matches the `PULSE_MATERIAL_*` constants in scene/mod.rs.
+const MATERIAL_TWO_COMPONENT_NORMAL_MAP: u32 = 1u;
+const MATERIAL_FLIP_NORMAL_MAP_Y: u32 = 2u;
+// `MaterialTexture::layer` of textures a material doesn't have.
+const NO_TEXTURE: u32 = 0xffffffffu;
+// Bits of a compact tangent, matches the `TANGENT_*_BIT` constants in vertices.rs.
+const TANGENT_HANDEDNESS_BIT: u32 = 1u;
+const TANGENT_PRESENT_BIT: u32 = 0x10000u;
+// Layered BSDF, matches the constants in bsdf.rs.
+const ENERGY_TABLE_COS_THETA_SIZE: u32 = 16u;
+const ENERGY_TABLE_AZIMUTH_SIZE: u32 = 5u;
+const ENERGY_TABLE_ROUGHNESS_SIZE: u32 = 16u;
+const MIN_GGX_ALPHA: f32 = 1e-3;
+const MIN_SHEEN_ALPHA: f32 = 0.05;
+const CLEARCOAT_F0: f32 = 0.04;
+
 //------------
 // BEGIN: MISC
 
@@ -94,23 +123,142 @@ fn rand_f_pair(state: ptr<function, u32>) -> vec2<f32> {
 
 fn get_blas_node(index: u32, instance_index: u32) -> BLASNode {
     let instance = instances[instance_index];
-    return blas_nodes[index + instance.node_offset];
+    return load_blas_node(index + instance.node_offset);
 }
 
 fn get_primitive(index: u32, instance_index: u32) -> Primitive {
     let instance = instances[instance_index];
     let triangle_index = triangle_indices[index + instance.index_offset];
-    return primitives[triangle_index + instance.triangle_offset];
+    return load_primitive(triangle_index + instance.triangle_offset);
 }
 
 fn get_triangle_data(index: u32, instance_index: u32) -> TriangleData {
     let instance = instances[instance_index];
     let triangle_index = triangle_indices[index + instance.index_offset];
-    return triangle_data[triangle_index + instance.triangle_offset];
+    return load_triangle_data(triangle_index + instance.triangle_offset);
+}
+
+// `triangle_index` is the index of the triangle in the whole scene, ie offset by `MeshInstance::triangle_offset`.
+fn load_primitive(triangle_index: u32) -> Primitive {
+#ifdef PULSE_INDEXED_VERTICES
+    let i = 3u * triangle_index;
+    return Primitive(
+        load_vertex_position(vertex_indices[i]),
+        load_vertex_position(vertex_indices[i + 1u]),
+        load_vertex_position(vertex_indices[i + 2u]),
+    );
+#else
+    return load_primitive_element(triangle_index);
+#endif
+}
+
+fn load_triangle_data(triangle_index: u32) -> TriangleData {
+#ifdef PULSE_INDEXED_VERTICES
+    let i = 3u * triangle_index;
+    let v0 = vertex_indices[i];
+    let v1 = vertex_indices[i + 1u];
+    let v2 = vertex_indices[i + 2u];
+    return TriangleData(
+        load_vertex_normal(v0),
+        load_vertex_normal(v1),
+        load_vertex_normal(v2),
+        load_vertex_tangent(v0),
+        load_vertex_tangent(v1),
+        load_vertex_tangent(v2),
+        load_vertex_uv(v0),
+        load_vertex_uv(v1),
+        load_vertex_uv(v2),
+    );
+#else
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+    let t = load_triangle_data_element(triangle_index);
+    return TriangleData(
+        decode_octahedral_normal(t.n_first),
+        decode_octahedral_normal(t.n_second),
+        decode_octahedral_normal(t.n_third),
+        decode_octahedral_tangent(t.t_first),
+        decode_octahedral_tangent(t.t_second),
+        decode_octahedral_tangent(t.t_third),
+        unpack2x16float(t.uv_first),
+        unpack2x16float(t.uv_second),
+        unpack2x16float(t.uv_third),
+    );
+#else
+    return load_triangle_data_element(triangle_index);
+#endif
+#endif
+}
+
+// Inverse of `encode_octahedral_normal` in vertices.rs.
+fn decode_octahedral_normal(packed: u32) -> vec3f {
+    let p = unpack2x16snorm(packed);
+    var n = vec3f(p.x, p.y, 1.0 - abs(p.x) - abs(p.y));
+    let t = max(-n.z, 0.0);
+    n.x += select(t, -t, n.x >= 0.0);
+    n.y += select(t, -t, n.y >= 0.0);
+    return normalize(n);
+}
+
+// Inverse of `encode_octahedral_tangent` in vertices.rs.
+fn decode_octahedral_tangent(packed: u32) -> vec4f {
+    if (packed & TANGENT_PRESENT_BIT) == 0u {
+        return vec4f(0.0);
+    }
+    let handedness = select(1.0, -1.0, (packed & TANGENT_HANDEDNESS_BIT) != 0u);
+    return vec4f(decode_octahedral_normal(packed), handedness);
+}
+
+#ifdef PULSE_INDEXED_VERTICES
+fn load_vertex_position(vertex: u32) -> vec3f {
+    let i = 3u * vertex;
+    return vec3f(
+        load_primitive_element(i),
+        load_primitive_element(i + 1u),
+        load_primitive_element(i + 2u),
+    );
+}
+
+// Normals and tangents are interleaved, see `PulseIndexedVertices::tangent_frames`.
+fn load_vertex_normal(vertex: u32) -> vec3f {
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+    return decode_octahedral_normal(load_triangle_data_element(2u * vertex));
+#else
+    let i = 7u * vertex;
+    return vec3f(
+        load_triangle_data_element(i),
+        load_triangle_data_element(i + 1u),
+        load_triangle_data_element(i + 2u),
+    );
+#endif
+}
+
+fn load_vertex_tangent(vertex: u32) -> vec4f {
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+    return decode_octahedral_tangent(load_triangle_data_element(2u * vertex + 1u));
+#else
+    let i = 7u * vertex + 3u;
+    return vec4f(
+        load_triangle_data_element(i),
+        load_triangle_data_element(i + 1u),
+        load_triangle_data_element(i + 2u),
+        load_triangle_data_element(i + 3u),
+    );
+#endif
 }
 
-fn trace_ray(ray: ptr<function, Ray>) {
-    traverse_tlas(ray);
+fn load_vertex_uv(vertex: u32) -> vec2f {
+#ifdef PULSE_COMPACT_VERTEX_ATTRIBUTES
+    return unpack2x16float(vertex_uvs[vertex]);
+#else
+    return vertex_uvs[vertex];
+#endif
+}
+#endif
+
+// `ray_type` is one of `RAY_CAMERA`, `RAY_GI` or `RAY_SHADOW`. `render_layers` are the layers of the camera and are
+// ignored for shadow rays.
+fn trace_ray(ray: ptr<function, Ray>, ray_type: u32, render_layers: u32) {
+    traverse_tlas(ray, ray_type, render_layers);
 }
 
 // Returns whether or not the ray hit anything within `distance_threshold`
@@ -118,13 +266,28 @@ fn trace_shadow_ray(ray: ptr<function, Ray>, distance_threshold: f32) -> bool {
     return traverse_tlas_for_shadow_ray(ray, distance_threshold);
 }
 
-fn trace_ray_blas_only(ray: ptr<function, Ray>)  {
+fn trace_ray_blas_only(ray: ptr<function, Ray>, ray_type: u32, render_layers: u32)  {
     for (var i = 0u; i < scene_uniform.instance_count; i += 1u) {
-        traverse_blas(ray, i);
+        if is_instance_visible(i, ray_type, render_layers) {
+            traverse_blas(ray, i);
+        }
+    }
+}
+
+fn is_instance_visible(instance_index: u32, ray_type: u32, render_layers: u32) -> bool {
+    let instance = instances[instance_index];
+    if (instance.visibility_flags & ray_type) == 0u {
+        return false;
     }
+    // Shadow rays ignore render layers, same as Bevy's shadow maps.
+    return ray_type == RAY_SHADOW || (instance.render_layers & render_layers) != 0u;
 }
 
-fn traverse_tlas(ray: ptr<function, Ray>) {
+fn instance_receives_shadows(instance_index: u32) -> bool {
+    return (instances[instance_index].visibility_flags & INSTANCE_RECEIVES_SHADOWS) != 0u;
+}
+
+fn traverse_tlas(ray: ptr<function, Ray>, ray_type: u32, render_layers: u32) {
     // Abort on empty/invalid root node.
     if tlas_nodes[0].a_or_first_instance == 0u && tlas_nodes[0].instance_count == 0u {
         return;
@@ -142,7 +305,9 @@ fn traverse_tlas(ray: ptr<function, Ray>) {
         if node.instance_count > 0u { // Is leaf node.
             for (var i: u32 = 0u; i < node.instance_count; i += 1u) {
                 let instance_index = instance_indices[node.a_or_first_instance + i];
-                traverse_blas(ray, instance_index);
+                if is_instance_visible(instance_index, ray_type, render_layers) {
+                    traverse_blas(ray, instance_index);
+                }
             }
             if stack_ptr == 0 {
                 break;
@@ -206,6 +371,9 @@ fn traverse_tlas_for_shadow_ray(ray: ptr<function, Ray>, distance_threshold: f32
         if node.instance_count > 0u { // Is leaf node.
             for (var i: u32 = 0u; i < node.instance_count; i += 1u) {
                 let instance_index = instance_indices[node.a_or_first_instance + i];
+                if !is_instance_visible(instance_index, RAY_SHADOW, 0u) {
+                    continue;
+                }
                 if traverse_blas_for_shadow_ray(ray, instance_index, distance_threshold) {
                     return true;
                 }
@@ -255,15 +423,70 @@ fn traverse_tlas_for_shadow_ray(ray: ptr<function, Ray>, distance_threshold: f32
     return false;
 }
 
-// Takes world space ray.
-fn traverse_blas(ray: ptr<function, Ray>, instance_index: u32) {
-    // Transform ray to object/blas space.
-    let instance = instances[instance_index];
-    var ray_object = Ray();
-    ray_object.origin = transform_position(instance.world_object, (*ray).origin);
-    ray_object.dir = normalize(transform_direction(instance.world_object, (*ray).dir));
-    ray_object.record = RayHitRecord(1e30, 0u, 0u, 0.0, 0.0);
+#ifdef PULSE_WIDE_BVH
+fn wide_child_byte(word: u32, child: u32) -> u32 {
+    return (word >> (8u * (child % 4u))) & 0xffu;
+}
 
+// Takes object space ray.
+fn intersect_blas(ray_object: ptr<function, Ray>, instance_index: u32) {
+    var node_index = 0u;
+    var stack: array<u32, 64>;
+    var stack_ptr = 0;
+    var iteration = 0;
+    let max_iterations = 100000;
+    while iteration < max_iterations {
+        iteration += 1;
+        var node = get_blas_node(node_index, instance_index);
+        let scale = vec3(
+            bitcast<f32>((node.exponents_and_imask & 0xffu) << 23u),
+            bitcast<f32>(((node.exponents_and_imask >> 8u) & 0xffu) << 23u),
+            bitcast<f32>(((node.exponents_and_imask >> 16u) & 0xffu) << 23u),
+        );
+        let imask = node.exponents_and_imask >> 24u;
+        for (var child = 0u; child < #{PULSE_BVH_WIDTH}u; child += 1u) {
+            let word = child / 4u;
+            let lo = vec3(
+                f32(wide_child_byte(node.lo_x[word], child)),
+                f32(wide_child_byte(node.lo_y[word], child)),
+                f32(wide_child_byte(node.lo_z[word], child)),
+            );
+            let hi = vec3(
+                f32(wide_child_byte(node.hi_x[word], child)),
+                f32(wide_child_byte(node.hi_y[word], child)),
+                f32(wide_child_byte(node.hi_z[word], child)),
+            );
+            // Unused slots are stored as inverted boxes.
+            if any(lo > hi) {
+                continue;
+            }
+            let dist = ray_aabb_intersect(ray_object, node.origin + lo * scale, node.origin + hi * scale);
+            if dist == 1e30f {
+                continue;
+            }
+            if (imask & (1u << child)) != 0u {
+                if stack_ptr < 64 {
+                    stack[stack_ptr] = node.children[child];
+                    stack_ptr += 1;
+                }
+            } else {
+                let tri_count = wide_child_byte(node.tri_counts[word], child);
+                for (var i: u32 = 0u; i < tri_count; i += 1u) {
+                    ray_triangle_intersect(ray_object, node.children[child] + i, instance_index);
+                }
+            }
+        }
+
+        if stack_ptr == 0 {
+            break;
+        }
+        stack_ptr -= 1;
+        node_index = stack[stack_ptr];
+    }
+}
+#else
+// Takes object space ray.
+fn intersect_blas(ray_object: ptr<function, Ray>, instance_index: u32) {
     var node_index = 0u;
     var stack: array<u32, 32>;
     var stack_ptr = 0;
@@ -274,7 +497,7 @@ fn traverse_blas(ray: ptr<function, Ray>, instance_index: u32) {
         let node = get_blas_node(node_index, instance_index);
         if node.tri_count > 0u {
             for (var i: u32 = 0u; i < node.tri_count; i += 1u) {
-                ray_triangle_intersect(&ray_object, node.a_or_first_tri + i, instance_index);
+                ray_triangle_intersect(ray_object, node.a_or_first_tri + i, instance_index);
             }
             if stack_ptr == 0 {
                 break;
@@ -289,8 +512,8 @@ fn traverse_blas(ray: ptr<function, Ray>, instance_index: u32) {
         var child_b_index = child_a_index + 1u;
         let child_a = get_blas_node(child_a_index, instance_index);
         let child_b = get_blas_node(child_b_index, instance_index);
-        var dist_a = ray_aabb_intersect(&ray_object, child_a.aabb_min, child_a.aabb_max);
-        var dist_b = ray_aabb_intersect(&ray_object, child_b.aabb_min, child_b.aabb_max);
+        var dist_a = ray_aabb_intersect(ray_object, child_a.aabb_min, child_a.aabb_max);
+        var dist_b = ray_aabb_intersect(ray_object, child_b.aabb_min, child_b.aabb_max);
         if dist_a > dist_b {
             let d = dist_a;
             dist_a = dist_b;
@@ -314,6 +537,19 @@ fn traverse_blas(ray: ptr<function, Ray>, instance_index: u32) {
             }
         }
     }
+}
+#endif
+
+// Takes world space ray.
+fn traverse_blas(ray: ptr<function, Ray>, instance_index: u32) {
+    // Transform ray to object/blas space.
+    let instance = instances[instance_index];
+    var ray_object = Ray();
+    ray_object.origin = transform_position(instance.world_object, (*ray).origin);
+    ray_object.dir = normalize(transform_direction(instance.world_object, (*ray).dir));
+    ray_object.record = RayHitRecord(1e30, 0u, 0u, 0.0, 0.0);
+
+    intersect_blas(&ray_object, instance_index);
 
     let hit_position_object = ray_object.origin + ray_object.record.t * ray_object.dir;
     let hit_position_world = transform_position(instance.object_world, hit_position_object);
@@ -335,56 +571,7 @@ fn traverse_blas_for_shadow_ray(ray: ptr<function, Ray>, instance_index: u32, di
     ray_object.dir = normalize(transform_direction(instance.world_object, (*ray).dir));
     ray_object.record = RayHitRecord(1e30, 0u, 0u, 0.0, 0.0);
 
-    var node_index = 0u;
-    var stack: array<u32, 32>;
-    var stack_ptr = 0;
-    var iteration = 0;
-    let max_iterations = 100000;
-    while iteration < max_iterations {
-        iteration += 1;
-        let node = get_blas_node(node_index, instance_index);
-        if node.tri_count > 0u {
-            for (var i: u32 = 0u; i < node.tri_count; i += 1u) {
-                ray_triangle_intersect(&ray_object, node.a_or_first_tri + i, instance_index);
-            }
-            if stack_ptr == 0 {
-                break;
-            } else {
-                stack_ptr -= 1;
-                node_index = stack[stack_ptr];
-            }
-            continue;
-        }
-
-        var child_a_index = node.a_or_first_tri;
-        var child_b_index = child_a_index + 1u;
-        let child_a = get_blas_node(child_a_index, instance_index);
-        let child_b = get_blas_node(child_b_index, instance_index);
-        var dist_a = ray_aabb_intersect(&ray_object, child_a.aabb_min, child_a.aabb_max);
-        var dist_b = ray_aabb_intersect(&ray_object, child_b.aabb_min, child_b.aabb_max);
-        if dist_a > dist_b {
-            let d = dist_a;
-            dist_a = dist_b;
-            dist_b = d;
-            let c = child_a_index;
-            child_a_index = child_b_index;
-            child_b_index = c;
-        }
-        if dist_a == 1e30f {
-            if stack_ptr == 0 {
-                break;
-            } else  {
-                stack_ptr -= 1;
-                node_index = stack[stack_ptr];
-            }
-        } else {
-            node_index = child_a_index;
-            if dist_b != 1e30f {
-                stack[stack_ptr] = child_b_index;
-                stack_ptr += 1;
-            }
-        }
-    }
+    intersect_blas(&ray_object, instance_index);
 
     let hit_position_object = ray_object.origin + ray_object.record.t * ray_object.dir;
     let hit_position_world = transform_position(instance.object_world, hit_position_object);
@@ -462,6 +649,166 @@ fn ray_triangle_intersect(ray: ptr<function, Ray>, triangle_index: u32, instance
 // END: RAY ACCELERATION/INTERSECTION
 //-----------------------------------
 
+//-----------------
+// BEGIN: MATERIALS
+
+fn srgb_to_linear(c: vec3f) -> vec3f {
+    let low = c / 12.92;
+    let high = pow((c + 0.055) / 1.055, vec3f(2.4));
+    return select(high, low, c <= vec3f(0.04045));
+}
+
+// Textures repeat, but aren't filtered across their edges.
+fn sample_material_texture(texture: MaterialTexture, uv: vec2f) -> vec4f {
+    let atlas_uv = texture.rect.xy + fract(uv) * texture.rect.zw;
+    return textureSampleLevel(texture_atlas, texture_atlas_sampler, atlas_uv, texture.layer, 0.0);
+}
+
+// `material` with its color, emissive and metallic-roughness textures applied at `uv`.
+fn textured_material(material: Material, uv: vec2f) -> Material {
+    var m = material;
+    if m.base_color_texture.layer != NO_TEXTURE {
+        let texel = sample_material_texture(m.base_color_texture, uv);
+        m.base_color *= vec4f(srgb_to_linear(texel.rgb), texel.a);
+    }
+    if m.emissive_texture.layer != NO_TEXTURE {
+        let texel = sample_material_texture(m.emissive_texture, uv);
+        m.emissive = vec4f(m.emissive.rgb * srgb_to_linear(texel.rgb), m.emissive.a);
+    }
+    if m.metallic_roughness_texture.layer != NO_TEXTURE {
+        // Roughness is stored in green and metallic in blue, the same as in glTF.
+        let texel = sample_material_texture(m.metallic_roughness_texture, uv);
+        m.perceptual_roughness *= texel.g;
+        m.metallic *= texel.b;
+    }
+    return m;
+}
+
+fn material_occlusion(material: Material, uv: vec2f) -> f32 {
+    if material.occlusion_texture.layer == NO_TEXTURE {
+        return 1.0;
+    }
+    return sample_material_texture(material.occlusion_texture, uv).r;
+}
+
+// Perturbs the object space `normal` with the material's normal map, the same as Bevy's `apply_normal_mapping`.
+// `tangent` is the interpolated vertex tangent. Meshes without tangents, where it's zero, use a tangent frame derived
+// from the texture coordinates of the triangle instead, which is constant across each triangle.
+fn apply_normal_map(
+    material: Material,
+    normal: vec3f,
+    tangent: vec4f,
+    p: Primitive,
+    t: TriangleData,
+    uv: vec2f,
+) -> vec3f {
+    // Like MikkTSpace expects, the interpolated tangent is neither normalized nor orthogonalized.
+    var tangent_dir = tangent.xyz;
+    var bitangent = tangent.w * cross(normal, tangent.xyz);
+    if tangent.w == 0.0 {
+        let dp1 = p.p_second - p.p_first;
+        let dp2 = p.p_third - p.p_first;
+        let duv1 = t.uv_second - t.uv_first;
+        let duv2 = t.uv_third - t.uv_first;
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        if abs(det) < 1e-12 {
+            return normal;
+        }
+        let uv_tangent = (dp1 * duv2.y - dp2 * duv1.y) / det;
+        let uv_bitangent = (dp2 * duv1.x - dp1 * duv2.x) / det;
+        tangent_dir = normalize(uv_tangent - normal * dot(normal, uv_tangent));
+        let handedness = select(1.0, -1.0, dot(cross(normal, tangent_dir), uv_bitangent) < 0.0);
+        bitangent = handedness * cross(normal, tangent_dir);
+    }
+
+    var nt = sample_material_texture(material.normal_map_texture, uv).rgb;
+    if (material.flags & MATERIAL_TWO_COMPONENT_NORMAL_MAP) != 0u {
+        nt = vec3f(nt.rg * 2.0 - 1.0, 0.0);
+        nt.z = sqrt(max(1.0 - dot(nt.xy, nt.xy), 0.0));
+    } else {
+        nt = nt * 2.0 - 1.0;
+    }
+    if (material.flags & MATERIAL_FLIP_NORMAL_MAP_Y) != 0u {
+        nt.y = -nt.y;
+    }
+    return normalize(nt.x * tangent_dir + nt.y * bitangent + nt.z * normal);
+}
+
+// Closest hit of a ray, with the textures of its material applied.
+struct SurfaceHit {
+    position: vec3f,
+    // World space shading normal, including the normal map.
+    normal: vec3f,
+    // World space direction of anisotropy, orthogonal to `normal`. Follows the vertex tangents when the mesh has them.
+    tangent: vec3f,
+    material: Material,
+    // Only meant for indirect light, the same as in Bevy's rasterizer.
+    occlusion: f32,
+    // Whether the ray hit the side the vertex normals point to, ie. entered rather than left a closed mesh.
+    front_face: bool,
+}
+
+fn load_surface_hit(ray: Ray) -> SurfaceHit {
+    let instance = instances[ray.record.instance_index];
+    let triangle_index = instance.triangle_offset + triangle_indices[instance.index_offset + ray.record.triangle_index];
+    let t = load_triangle_data(triangle_index);
+    let w = 1.0 - (ray.record.u + ray.record.v);
+    let uv = w * t.uv_first + ray.record.u * t.uv_second + ray.record.v * t.uv_third;
+    let material = materials[instance.material_index];
+
+    var normal = normalize(w * t.n_first + ray.record.u * t.n_second + ray.record.v * t.n_third);
+    let tangent = w * t.t_first + ray.record.u * t.t_second + ray.record.v * t.t_third;
+    // Decided before normal mapping, which can tilt the normal past the ray.
+    let front_face = dot(transform_direction(instance.object_world, normal), ray.dir) < 0.0;
+    if material.normal_map_texture.layer != NO_TEXTURE {
+        normal = apply_normal_map(material, normal, tangent, load_primitive(triangle_index), t, uv);
+    }
+
+    var hit: SurfaceHit;
+    hit.position = ray.origin + ray.record.t * ray.dir;
+    hit.normal = normalize(transform_direction(instance.object_world, normal));
+    hit.tangent = anisotropy_direction(hit.normal, transform_direction(instance.object_world, tangent.xyz), material);
+    hit.material = textured_material(material, uv);
+    hit.occlusion = material_occlusion(material, uv);
+    hit.front_face = front_face;
+    return hit;
+}
+
+// Orthogonalizes the world space vertex `tangent` against `normal` and rotates it by the material's anisotropy rotation.
+// Meshes without tangents get an arbitrary one, which only matters for anisotropic materials.
+fn anisotropy_direction(normal: vec3f, tangent: vec3f, material: Material) -> vec3f {
+    var t = tangent - normal * dot(normal, tangent);
+    if length_sq(t) < 1e-12 {
+        t = orthonormal_from_normal(normal).e_one;
+    }
+    t = normalize(t);
+    return cos(material.anisotropy_rotation) * t + sin(material.anisotropy_rotation) * cross(normal, t);
+}
+
+// Beer-Lambert absorption along `distance` inside the volume of `material`. Thin-walled materials don't absorb.
+fn volume_transmittance(material: Material, distance: f32) -> vec3f {
+    if material.thickness <= 0.0 {
+        return vec3f(1.0);
+    }
+    return exp(log(max(material.attenuation_color, vec3f(1e-6))) * distance / material.attenuation_distance);
+}
+
+// Emission of a light at `barycentrics` on `triangle_index`, which is counted from `MeshInstance::triangle_offset`.
+fn light_emission(instance: MeshInstance, triangle_index: u32, barycentrics: vec2f) -> vec3f {
+    let material = materials[instance.material_index];
+    if material.emissive_texture.layer == NO_TEXTURE {
+        return material.emissive.rgb;
+    }
+    let t = load_triangle_data(instance.triangle_offset + triangle_index);
+    let w = 1.0 - (barycentrics.x + barycentrics.y);
+    let uv = w * t.uv_first + barycentrics.x * t.uv_second + barycentrics.y * t.uv_third;
+    let texel = sample_material_texture(material.emissive_texture, uv);
+    return material.emissive.rgb * srgb_to_linear(texel.rgb);
+}
+
+// END: MATERIALS
+//---------------
+
 //----------------
 // BEGIN: SAMPLING
 
@@ -497,7 +844,8 @@ fn sample_hemisphere_rejection(normal: vec3f, state: ptr<function, u32>) -> vec3
 // BEGIN: DIRECT LIGHT SAMPLING
 
 // `p0`/`n0`/`base_color` are position/normal/color of point from where to sample
-fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, rng_state: ptr<function, u32>) -> vec3f {
+// Lights are never occluded if `receives_shadows` is false.
+fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, receives_shadows: bool, rng_state: ptr<function, u32>) -> vec3f {
     // let light_index = sample_light_emission_strength_cdf(rand_f(rng_state));
     let light_index = rand_range_u(scene_uniform.light_count, rng_state);
     let light_data_index = light_indices[light_index];
@@ -508,17 +856,20 @@ fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, rng_state: ptr<f
     let e1 = rand_f(rng_state);
     let e2 = rand_f(rng_state);
     let primitive_index = sample_light_triangle_area_cdf(e0, light_data_index.cdf_offset, mesh_instance.triangle_count);
-    let primitive = primitives[mesh_instance.triangle_offset + primitive_index];
+    let primitive = load_primitive(mesh_instance.triangle_offset + primitive_index);
 
     let pl_obj = sample_triangle_uniformly(e1, e2, primitive.p_first, primitive.p_second, primitive.p_third);
     let pl = transform_position(mesh_instance.object_world, pl_obj);
+    let emission = light_emission(mesh_instance, primitive_index, sample_triangle_barycentrics(e1, e2));
 
     let to_light = pl - p0;
     var shadow_ray = Ray();
     shadow_ray.origin = p0 + 0.001 * n0;
     shadow_ray.dir = normalize(to_light);
     shadow_ray.record = RayHitRecord(1e30, 0u, 0u, 0.0, 0.0);
-    trace_ray(&shadow_ray);
+    if receives_shadows {
+        trace_ray(&shadow_ray, RAY_SHADOW, 0u);
+    }
 
     if shadow_ray.record.t < length(pl - shadow_ray.origin) - 0.005 {
         return vec3f(0.0);
@@ -552,14 +903,15 @@ fn sample_direct_light(p0: vec3f, n0: vec3f, base_color: vec3f, rng_state: ptr<f
     let cos_theta_receiver = dot(shadow_ray.dir, n0);
     let cos_theta_emitter = dot(-shadow_ray.dir, nl);
     let brdf = base_color * INV_PI;
-    let light_material = materials[mesh_instance.material_index];
-    let direct_light = brdf * light_material.emissive.xyz * cos_theta_receiver * cos_theta_emitter / pdf / distance_sq(n0, nl);
+    let direct_light = brdf * emission * cos_theta_receiver * cos_theta_emitter / pdf / distance_sq(n0, nl);
     return max(direct_light, vec3f(0.0));
 }
 
-// p0/n0/material are position/normal/material of point from where to sample
+// `hit` is the point from where to sample, with the layered BSDF of its material.
 // wo is the view direction from the sample point, ie the output direction of the light via the sample point
-fn sample_direct_light_ggx(p0: vec3f, n0: vec3f, material: Material, wo: vec3f, rng_state: ptr<function, u32>) -> vec3f {
+fn sample_direct_light_ggx(hit: SurfaceHit, wo: vec3f, receives_shadows: bool, rng_state: ptr<function, u32>) -> vec3f {
+    let p0 = hit.position;
+    let n0 = select(-hit.normal, hit.normal, dot(hit.normal, wo) >= 0.0);
     // let light_index = sample_light_emission_strength_cdf(rand_f(rng_state));
     let light_index = rand_range_u(scene_uniform.light_count, rng_state);
     let light_data_index = light_indices[light_index];
@@ -570,17 +922,20 @@ fn sample_direct_light_ggx(p0: vec3f, n0: vec3f, material: Material, wo: vec3f,
     let e1 = rand_f(rng_state);
     let e2 = rand_f(rng_state);
     let primitive_index = sample_light_triangle_area_cdf(e0, light_data_index.cdf_offset, mesh_instance.triangle_count);
-    let primitive = primitives[mesh_instance.triangle_offset + primitive_index];
+    let primitive = load_primitive(mesh_instance.triangle_offset + primitive_index);
 
     let pl_obj = sample_triangle_uniformly(e1, e2, primitive.p_first, primitive.p_second, primitive.p_third);
     let pl = transform_position(mesh_instance.object_world, pl_obj);
+    let emission = light_emission(mesh_instance, primitive_index, sample_triangle_barycentrics(e1, e2));
 
     let to_light = pl - p0;
     var shadow_ray = Ray();
     shadow_ray.origin = p0 + 0.001 * n0;
     shadow_ray.dir = normalize(to_light);
     shadow_ray.record = RayHitRecord(1e30, 0u, 0u, 0.0, 0.0);
-    trace_ray(&shadow_ray);
+    if receives_shadows {
+        trace_ray(&shadow_ray, RAY_SHADOW, 0u);
+    }
 
     if shadow_ray.record.t < length(pl - shadow_ray.origin) - 0.005 {
         // Light source is occluded; no direct light contribution
@@ -605,52 +960,11 @@ fn sample_direct_light_ggx(p0: vec3f, n0: vec3f, material: Material, wo: vec3f,
     var triangle_pdf = 1.0 / light_mesh_areas[light_index];
     let pdf = light_pdf * triangle_pdf;
 
-    // Evaluate direct light contribution
+    // Evaluate direct light contribution, the BSDF already includes the cosine at the receiver.
     // https://www.youtube.com/watch?v=FU1dbi827LY at 4:24
-    let cos_theta_receiver = dot(shadow_ray.dir, n0);
     let cos_theta_emitter = dot(-shadow_ray.dir, nl);
-
-
-
-    //
-    // Calculate GGX brdf
-    // 
-    var f0 = vec3f(0.16 * material.reflectance * material.reflectance);
-    f0 = f0 * (1.0 - material.metallic) + material.base_color.rgb * material.metallic;
-
-    let NdotO = dot(n0, wo);
-    let F0 = fresnel_schlick(NdotO, f0);
-    let F0_max = max(max(F0.r, F0.g), F0.b);
-    
-    // Sample specular
-    let a = material.perceptual_roughness * material.perceptual_roughness;
-    let a2 = a * a;
-    let theta = acos(sqrt((1.0 - e1) / (e1 * (a2 - 1.0) + 1.0)));
-    let phi = e2 * TWO_PI;
-
-    // // Create tangent space basis vectors.
-    // let on = orthonormal_from_normal(n0);
-    // // Microsurface normal or half-vector in world space.
-    // let wm = spherical_to_cartesian_in_on(theta, phi, on);
-    // // Incident direction in world space.
-    // wi = reflect(wo, wm);
-
-    // Incident direction in world space.
-    let wi = to_light;
-    // Microsurface normal or half-vector in world space.
-    let wm = normalize(0.5 * wi + 0.5 * wo);
-
-    let NdotM = dot(n0, wm);
-    let NdotI = dot(n0, wi);
-    let OdotM = dot(wo, wm);
-
-    let F = fresnel_schlick(OdotM, f0);
-    let D = D_GGX(NdotM, material.perceptual_roughness);
-    let G = G_smith(NdotO, NdotI, material.perceptual_roughness);
-    let brdf = (F * D * G) / (4.0 * NdotO * NdotI);
-    
-    let light_material = materials[mesh_instance.material_index];
-    let direct_light = brdf * light_material.emissive.xyz * cos_theta_receiver * cos_theta_emitter / pdf / distance_sq(n0, nl);
+    let bsdf = eval_layered_bsdf(hit, wo, shadow_ray.dir);
+    let direct_light = bsdf * emission * cos_theta_emitter / pdf / distance_sq(n0, nl);
     return max(direct_light, vec3f(0.0));
 }
 
@@ -692,6 +1006,15 @@ fn sample_light_triangle_area_cdf(e: f32, cdf_offset: u32, count: u32) -> u32 {
 
 // Parallelogram method
 // https://extremelearning.com.au/evenly-distributing-points-in-a-triangle/
+// Barycentric coordinates of the point `sample_triangle_uniformly` returns for the same `e0` and `e1`.
+fn sample_triangle_barycentrics(e0: f32, e1: f32) -> vec2f {
+    if e0 + e1 < 1.0 {
+        return vec2f(e0, e1);
+    } else {
+        return vec2f(1.0 - e0, 1.0 - e1);
+    }
+}
+
 fn sample_triangle_uniformly(e0: f32, e1: f32, p0: vec3f, p1: vec3f, p2: vec3f) -> vec3f {
     let a = p1 - p0;
     let b = p2 - p0;
@@ -774,16 +1097,6 @@ fn reflect(a: vec3f, b: vec3f) -> vec3f {
 //-----------
 // BEGIN: GGX
 
-// from https://agraphicsguynotes.com/posts/sample_microfacet_brdf/
-fn pdf_D_GGX(view: vec3f, half: vec3f, theta: f32, phi: f32, roughness: f32) -> f32 {
-    let a = roughness * roughness;
-    let cos_theta = cos(theta);
-    let denominator = 2.0 * a * cos_theta * sin(theta);
-    let d = (a - 1.0) * cos_theta + 1.0;
-    let numerator = 4.0 * dot(view, half) * d * d;
-    return denominator / numerator;
-}
-
 fn fresnel_schlick(cos_theta: f32, f0: vec3f) -> vec3f {
     return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
 }
@@ -813,65 +1126,370 @@ struct ImportanceSamplingResult {
     reflectance: vec3f,
 }
 
-fn importance_sample_ggx_d(n: vec3f, wo: vec3f, material: Material, rng_state: ptr<function, u32>) -> ImportanceSamplingResult {
-    var f0 = vec3f(0.16 * material.reflectance * material.reflectance);
-    f0 = f0 * (1.0 - material.metallic) + material.base_color.rgb * material.metallic;
+// Samples the base layer in the shading frame of `layered_frame`, GGX specular over Lambertian diffuse with the
+// probability of the energy each of them reflects. Same as `sample_base` in bsdf.rs.
+fn importance_sample_ggx_vndf(material: Material, layers: LayerAlbedos, wo: vec3f, rng_state: ptr<function, u32>) -> ImportanceSamplingResult {
+    let specular = max(max(layers.specular.r, layers.specular.g), layers.specular.b);
+    let diffuse = max(max(layers.diffuse.r, layers.diffuse.g), layers.diffuse.b);
+    let specular_probability = specular / max(specular + diffuse, 1e-6);
+
+    let e0 = rand_f(rng_state);
+    let e1 = rand_f(rng_state);
+    let e2 = rand_f(rng_state);
+    if e0 < specular_probability {
+        let alpha = ggx_alpha(material.perceptual_roughness, material.anisotropy_strength);
+        let m = sample_ggx_vndf(wo, alpha, vec2f(e1, e2));
+        let wi = reflect(wo, m);
+        if wi.z <= 0.0 {
+            return ImportanceSamplingResult(wi, vec3f(0.0));
+        }
+        let F = fresnel_schlick(dot(wo, m), layers.specular_f0);
+        let weight = F * ggx_reflection_weight(wo, wi, alpha) * layers.specular_compensation / specular_probability;
+        return ImportanceSamplingResult(wi, weight);
+    }
+
+    let wi = sample_cosine_hemisphere(vec3f(0.0, 0.0, 1.0), e1, e2);
+    return ImportanceSamplingResult(wi, layers.diffuse / (1.0 - specular_probability));
+}
+
+// Samples the whole BSDF at `hit`. Transmission is split off the same way as in Bevy's rasterizer: metals don't transmit,
+// and diffuse transmission only gets what's left after specular transmission. Everything else is handled by
+// `importance_sample_layered_bsdf`.
+fn importance_sample_bsdf(hit: SurfaceHit, wo: vec3f, rng_state: ptr<function, u32>) -> ImportanceSamplingResult {
+    let material = hit.material;
+    let specular_transmission = material.specular_transmission * (1.0 - material.metallic);
+    let diffuse_transmission = material.diffuse_transmission * (1.0 - material.metallic) * (1.0 - material.specular_transmission);
+
+    let e = rand_f(rng_state);
+    if e < specular_transmission {
+        return importance_sample_dielectric(hit, wo, rng_state);
+    }
+    if e < specular_transmission + diffuse_transmission {
+        // Lambertian, into the hemisphere on the other side of the surface.
+        let n = select(hit.normal, -hit.normal, dot(hit.normal, wo) > 0.0);
+        let wi = sample_cosine_hemisphere(n, rand_f(rng_state), rand_f(rng_state));
+        return ImportanceSamplingResult(wi, material.base_color.rgb);
+    }
+    return importance_sample_layered_bsdf(hit, wo, rng_state);
+}
+
+// Unpolarized Fresnel reflectance of a dielectric boundary. `eta` is the IOR on the incident side over the IOR on the
+// other side. Returns 1 on total internal reflection.
+fn fresnel_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let sin2_theta_t = eta * eta * max(1.0 - cos_theta_i * cos_theta_i, 0.0);
+    if sin2_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = sqrt(1.0 - sin2_theta_t);
+    let r_s = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_p = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    return 0.5 * (r_s * r_s + r_p * r_p);
+}
 
-    let NdotO = dot(n, wo);
-    let F0 = fresnel_schlick(NdotO, f0);
-    let F0_max = max(max(F0.r, F0.g), F0.b);
+// Rough dielectric from Walter et al. 2007, "Microfacet Models for Refraction through Rough Surfaces". A microfacet
+// normal visible from `wo` is sampled from GGX, then either reflected off or refracted through with the Fresnel
+// reflectance as probability. Refracted light is tinted by the base color, like Bevy's specular transmission.
+//
+// Thin-walled materials, with zero `thickness`, let light pass straight through instead of bending it, and include the
+// reflections between both of their sides in the Fresnel term.
+fn importance_sample_dielectric(hit: SurfaceHit, wo: vec3f, rng_state: ptr<function, u32>) -> ImportanceSamplingResult {
+    let material = hit.material;
+    let thin = material.thickness <= 0.0;
+    // Oriented to the side of `wo`, so rays leaving a volume see the inverse ratio.
+    let n = select(-hit.normal, hit.normal, hit.front_face);
+    let eta = select(material.ior, 1.0 / material.ior, hit.front_face || thin);
+    let frame = ShadingFrame(hit.tangent, cross(n, hit.tangent), n);
+    let wo_local = to_shading_frame(frame, wo);
+    if wo_local.z <= 0.0 {
+        return ImportanceSamplingResult(wo, vec3f(0.0));
+    }
 
     let e0 = rand_f(rng_state);
     let e1 = rand_f(rng_state);
     let e2 = rand_f(rng_state);
+    let alpha = ggx_alpha(material.perceptual_roughness, 0.0);
+    let m = sample_ggx_vndf(wo_local, alpha, vec2f(e1, e2));
+    let OdotM = max(dot(wo_local, m), 0.0);
 
-    var brdf: vec3f;
-    var pdf_s: f32;
-    var pdf_d: f32;
-    var wi: vec3f;
-    if e0 <= F0_max {
-        // Sample specular
-        let a = material.perceptual_roughness * material.perceptual_roughness;
-        let a2 = a * a;
-        let theta = acos(sqrt((1.0 - e1) / (e1 * (a2 - 1.0) + 1.0)));
-        let phi = e2 * TWO_PI;
-
-        // Create tangent space basis vectors.
-        let on = orthonormal_from_normal(n);
-        // Microsurface normal or half-vector in world space.
-        let wm = spherical_to_cartesian_in_on(theta, phi, on);
-        // Incident direction in world space.
-        wi = reflect(wo, wm);
-
-        let NdotM = dot(n, wm);
-        let NdotI = dot(n, wi);
-        let OdotM = dot(wo, wm);
-
-        let F = fresnel_schlick(OdotM, f0);
-        let D = D_GGX(NdotM, material.perceptual_roughness);
-        let G = G_smith(NdotO, NdotI, material.perceptual_roughness);
-
-        brdf = (F * D * G) / (4.0 * NdotO * NdotI);
-        pdf_s = D * NdotM / (4.0 * OdotM);
-    } else {
-        // Sample diffuse
-        wi = sample_cosine_hemisphere(n, e1, e2);
-        let NdotI = dot(n, wi);
-        brdf = material.base_color.rgb * PI * NdotI;
-        pdf_d = PI * NdotI;
+    var F = fresnel_dielectric(OdotM, eta);
+    if thin {
+        F = 2.0 * F / (1.0 + F);
+    }
+
+    let reflected = e0 < F;
+    var wi_local = reflect(wo_local, m);
+    var tint = vec3f(1.0);
+    if !reflected {
+        tint = material.base_color.rgb;
+        if thin {
+            wi_local.z = -wi_local.z;
+        } else {
+            // Can't be total internal reflection, `F` would have been 1.
+            wi_local = refract(-wo_local, m, eta);
+        }
     }
 
-    var pdf = ((1.0 - F0_max) * pdf_d) + (F0_max * pdf_s);
-    // Do this to get rid of division by zero and thus NaN reflectance => black pixels at grazing angles
-    if pdf < 0.0001 {
-        pdf = 1.0;
+    // Reflections have to stay on the side of `wo`, refractions have to cross over.
+    let wi = from_shading_frame(frame, wi_local);
+    if reflected != (wi_local.z > 0.0) {
+        return ImportanceSamplingResult(wi, vec3f(0.0));
     }
-    let reflectance = brdf / pdf;
 
-    return ImportanceSamplingResult(wi, reflectance);
+    // F cancels out with the probability of the lobe, and D with the pdf of the visible normal, for both reflection and
+    // refraction, leaving `G2 / G1(wo)`. The radiance scaling by the squared IOR ratio is left out since it cancels once
+    // the path leaves the volume again.
+    return ImportanceSamplingResult(wi, tint * ggx_reflection_weight(wo_local, wi_local, alpha));
 }
 
 // END: GGX
 //---------
 
+//--------------------
+// BEGIN: LAYERED BSDF
+
+// Clearcoat over sheen over anisotropic GGX specular and Lambertian diffuse, with the energy lost by single scattering
+// GGX added back. The CPU reference in bsdf.rs explains the model and checks that it conserves energy. Directions are
+// in the shading frame, with the anisotropy direction along x and the normal along z.
+
+// Shading frame at a hit, with the normal flipped to the side of `wo`.
+struct ShadingFrame {
+    tangent: vec3f,
+    bitangent: vec3f,
+    normal: vec3f,
+}
+
+fn shading_frame(hit: SurfaceHit, wo: vec3f) -> ShadingFrame {
+    let normal = select(-hit.normal, hit.normal, dot(hit.normal, wo) >= 0.0);
+    return ShadingFrame(hit.tangent, cross(normal, hit.tangent), normal);
+}
+
+fn to_shading_frame(frame: ShadingFrame, v: vec3f) -> vec3f {
+    return vec3f(dot(v, frame.tangent), dot(v, frame.bitangent), dot(v, frame.normal));
+}
+
+fn from_shading_frame(frame: ShadingFrame, v: vec3f) -> vec3f {
+    return v.x * frame.tangent + v.y * frame.bitangent + v.z * frame.normal;
+}
+
+// Roughness along the anisotropy direction and across it, the same mapping as `KHR_materials_anisotropy`.
+fn ggx_alpha(perceptual_roughness: f32, anisotropy_strength: f32) -> vec2f {
+    let alpha = max(perceptual_roughness * perceptual_roughness, MIN_GGX_ALPHA);
+    let strength = clamp(anisotropy_strength, 0.0, 1.0);
+    return vec2f(alpha + (1.0 - alpha) * strength * strength, alpha);
+}
+
+fn ggx_d(m: vec3f, alpha: vec2f) -> f32 {
+    let s = dot(m.xy / alpha, m.xy / alpha) + m.z * m.z;
+    return INV_PI / (alpha.x * alpha.y * s * s);
+}
+
+// Smith's Λ, so that G1 = 1 / (1 + Λ).
+fn ggx_lambda(w: vec3f, alpha: vec2f) -> f32 {
+    let tan2_theta = dot(alpha * w.xy, alpha * w.xy) / (w.z * w.z);
+    return 0.5 * (sqrt(1.0 + tan2_theta) - 1.0);
+}
+
+// Height correlated masking and shadowing.
+fn ggx_g2(wo: vec3f, wi: vec3f, alpha: vec2f) -> f32 {
+    return 1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+}
+
+// Microfacet normal visible from `wo`, with a pdf of `G1(wo) * D(m) * dot(wo, m) / wo.z`. Samples a spherical cap in
+// the configuration stretched to unit roughness, from Dupuy and Benyoub 2023, "Sampling Visible GGX Normals with
+// Spherical Caps".
+fn sample_ggx_vndf(wo: vec3f, alpha: vec2f, u: vec2f) -> vec3f {
+    let wo_std = normalize(vec3f(wo.xy * alpha, wo.z));
+    let phi = TWO_PI * u.x;
+    let z = (1.0 - u.y) * (1.0 + wo_std.z) - wo_std.z;
+    let sin_theta = sqrt(clamp(1.0 - z * z, 0.0, 1.0));
+    let m_std = vec3f(sin_theta * cos(phi), sin_theta * sin(phi), z) + wo_std;
+    return normalize(vec3f(m_std.xy * alpha, m_std.z));
+}
+
+// Density of `wi` reflected about a microfacet normal from `sample_ggx_vndf`, over solid angle.
+fn ggx_vndf_pdf(wo: vec3f, wi: vec3f, alpha: vec2f) -> f32 {
+    let h = wo + wi;
+    if dot(h, h) == 0.0 {
+        return 0.0;
+    }
+    let m = normalize(h);
+    if m.z <= 0.0 || dot(wo, m) <= 0.0 {
+        return 0.0;
+    }
+    // Visible normal density, times the Jacobian of the reflection `1 / (4 * dot(wo, m))`.
+    return ggx_d(m, alpha) / (4.0 * wo.z * (1.0 + ggx_lambda(wo, alpha)));
+}
+
+// Single scattering GGX reflection over its pdf times the cosine, without Fresnel, for `wi` sampled by reflecting `wo`
+// about a normal from `sample_ggx_vndf`. D cancels out, leaving `G2 / G1(wo)`.
+fn ggx_reflection_weight(wo: vec3f, wi: vec3f, alpha: vec2f) -> f32 {
+    return (1.0 + ggx_lambda(wo, alpha)) * ggx_g2(wo, wi, alpha);
+}
+
+fn sheen_alpha(perceptual_roughness: f32) -> f32 {
+    return max(perceptual_roughness * perceptual_roughness, MIN_SHEEN_ALPHA);
+}
+
+fn sheen_d(m: vec3f, alpha: f32) -> f32 {
+    let sin_theta = sqrt(max(1.0 - m.z * m.z, 0.0));
+    return (2.0 + 1.0 / alpha) * pow(sin_theta, 1.0 / alpha) / TWO_PI;
+}
+
+fn sheen_v(wo: vec3f, wi: vec3f) -> f32 {
+    return 1.0 / (4.0 * (wi.z + wo.z - wi.z * wo.z));
+}
+
+// Sheen with a white color over the pdf of a cosine weighted `wi`, times the cosine.
+fn sheen_reflection_weight(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+    let h = normalize(wo + wi);
+    return sheen_d(h, alpha) * sheen_v(wo, wi) * PI;
+}
+
+// Index of the entry of `energy_table` before `v`, between zero and one, and how far `v` is towards the next one.
+fn energy_table_coordinate(v: f32, size: u32) -> vec2f {
+    let p = clamp(v, 0.0, 1.0) * f32(size - 1u);
+    let i = min(floor(p), f32(size - 2u));
+    return vec2f(i, p - i);
+}
+
+// Albedo of GGX with the roughnesses `alpha` seen from `wo`, as `F0 * x + y`. Same as `PulseEnergyTable::ggx`.
+fn load_ggx_albedo(wo: vec3f, alpha: vec2f) -> vec2f {
+    let cos_theta = energy_table_coordinate(sqrt(max(wo.z, 0.0)), ENERGY_TABLE_COS_THETA_SIZE);
+    let azimuth = energy_table_coordinate(atan2(abs(wo.y), abs(wo.x)) / HALF_PI, ENERGY_TABLE_AZIMUTH_SIZE);
+    let roughness_x = energy_table_coordinate(sqrt(alpha.x), ENERGY_TABLE_ROUGHNESS_SIZE);
+    let roughness_y = energy_table_coordinate(sqrt(alpha.y), ENERGY_TABLE_ROUGHNESS_SIZE);
+    let first = vec4u(vec4f(cos_theta.x, azimuth.x, roughness_x.x, roughness_y.x));
+    let t = vec4f(cos_theta.y, azimuth.y, roughness_x.y, roughness_y.y);
+
+    // Linear along all four dimensions.
+    var albedo = vec2f(0.0);
+    for (var corner = 0u; corner < 16u; corner += 1u) {
+        let next = (vec4u(corner) >> vec4u(0u, 1u, 2u, 3u)) & vec4u(1u);
+        let w = select(1.0 - t, t, next == vec4u(1u));
+        let i = first + next;
+        let entry = textureLoad(energy_table, vec3u(i.x, i.z, i.y * ENERGY_TABLE_ROUGHNESS_SIZE + i.w), 0);
+        albedo += w.x * w.y * w.z * w.w * entry.xy;
+    }
+    return albedo;
+}
+
+// Albedo of sheen with a white color seen from `cos_theta`. Same as `PulseEnergyTable::sheen`.
+fn load_sheen_albedo(cos_theta: f32, perceptual_roughness: f32) -> f32 {
+    let c = energy_table_coordinate(sqrt(max(cos_theta, 0.0)), ENERGY_TABLE_COS_THETA_SIZE);
+    let x = energy_table_coordinate(perceptual_roughness, ENERGY_TABLE_ROUGHNESS_SIZE);
+    let i = vec2u(u32(c.x), u32(x.x));
+    let bottom = mix(textureLoad(energy_table, vec3u(i.x, i.y, 0u), 0).z, textureLoad(energy_table, vec3u(i.x + 1u, i.y, 0u), 0).z, c.y);
+    let top = mix(textureLoad(energy_table, vec3u(i.x, i.y + 1u, 0u), 0).z, textureLoad(energy_table, vec3u(i.x + 1u, i.y + 1u, 0u), 0).z, c.y);
+    return mix(bottom, top, x.y);
+}
+
+// How much of the energy arriving from `wo` each layer reflects. `coat` and `sheen` are the probabilities of sampling
+// those layers, which is also what they take away from the layers below.
+struct LayerAlbedos {
+    coat: f32,
+    coat_albedo: f32,
+    coat_compensation: f32,
+    sheen: f32,
+    specular_f0: vec3f,
+    specular: vec3f,
+    specular_compensation: vec3f,
+    diffuse: vec3f,
+}
+
+// Same as `layer_albedos` in bsdf.rs.
+fn layer_albedos(material: Material, wo: vec3f) -> LayerAlbedos {
+    var layers: LayerAlbedos;
+
+    let coat_table = load_ggx_albedo(wo, ggx_alpha(material.clearcoat_perceptual_roughness, 0.0));
+    let coat_single = coat_table.x + coat_table.y;
+    layers.coat_compensation = 1.0 + CLEARCOAT_F0 * (1.0 - coat_single) / max(coat_single, 1e-4);
+    layers.coat_albedo = (CLEARCOAT_F0 * coat_table.x + coat_table.y) * layers.coat_compensation;
+    layers.coat = clamp(material.clearcoat, 0.0, 1.0) * layers.coat_albedo;
+
+    let sheen_color = material.sheen_color;
+    layers.sheen = clamp(max(max(sheen_color.r, sheen_color.g), sheen_color.b), 0.0, 1.0)
+        * load_sheen_albedo(wo.z, material.sheen_perceptual_roughness);
+
+    let alpha = ggx_alpha(material.perceptual_roughness, material.anisotropy_strength);
+    let base_color = material.base_color.rgb;
+    let dielectric_f0 = vec3f(0.16 * material.reflectance * material.reflectance);
+    layers.specular_f0 = mix(dielectric_f0, base_color, material.metallic);
+    let specular_table = load_ggx_albedo(wo, alpha);
+    let specular_single = specular_table.x + specular_table.y;
+    layers.specular_compensation = 1.0 + layers.specular_f0 * (1.0 - specular_single) / max(specular_single, 1e-4);
+    layers.specular = (layers.specular_f0 * specular_table.x + specular_table.y) * layers.specular_compensation;
+    layers.diffuse = (1.0 - layers.specular) * base_color * (1.0 - material.metallic);
+    return layers;
+}
+
+// Picks a layer with the probability of the energy it reflects, then samples only that layer. Same as
+// `sample_layered_bsdf` in bsdf.rs.
+fn importance_sample_layered_bsdf(hit: SurfaceHit, wo_world: vec3f, rng_state: ptr<function, u32>) -> ImportanceSamplingResult {
+    let material = hit.material;
+    let frame = shading_frame(hit, wo_world);
+    let wo = to_shading_frame(frame, wo_world);
+    if wo.z <= 0.0 {
+        return ImportanceSamplingResult(wo_world, vec3f(0.0));
+    }
+    let layers = layer_albedos(material, wo);
+
+    var sample: ImportanceSamplingResult;
+    if rand_f(rng_state) < layers.coat {
+        let alpha = ggx_alpha(material.clearcoat_perceptual_roughness, 0.0);
+        let m = sample_ggx_vndf(wo, alpha, rand_f_pair(rng_state));
+        let wi = reflect(wo, m);
+        let F = fresnel_schlick(dot(wo, m), vec3f(CLEARCOAT_F0));
+        let weight = F * ggx_reflection_weight(wo, wi, alpha) * layers.coat_compensation / layers.coat_albedo;
+        sample = ImportanceSamplingResult(wi, select(vec3f(0.0), weight, wi.z > 0.0));
+    } else if rand_f(rng_state) < layers.sheen {
+        let e = rand_f_pair(rng_state);
+        let wi = sample_cosine_hemisphere(vec3f(0.0, 0.0, 1.0), e.x, e.y);
+        let alpha = sheen_alpha(material.sheen_perceptual_roughness);
+        sample = ImportanceSamplingResult(wi, material.sheen_color * sheen_reflection_weight(wo, wi, alpha) / layers.sheen);
+    } else {
+        sample = importance_sample_ggx_vndf(material, layers, wo, rng_state);
+    }
+    return ImportanceSamplingResult(from_shading_frame(frame, sample.wi), sample.reflectance);
+}
+
+// The BSDF times the cosine of `wi`. Same as `eval_layered_bsdf` in bsdf.rs.
+fn eval_layered_bsdf(hit: SurfaceHit, wo_world: vec3f, wi_world: vec3f) -> vec3f {
+    let material = hit.material;
+    let frame = shading_frame(hit, wo_world);
+    let wo = to_shading_frame(frame, wo_world);
+    let wi = to_shading_frame(frame, wi_world);
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return vec3f(0.0);
+    }
+    let layers = layer_albedos(material, wo);
+    let h = normalize(wo + wi);
+
+    let coat_alpha = ggx_alpha(material.clearcoat_perceptual_roughness, 0.0);
+    let coat = fresnel_schlick(dot(wo, h), vec3f(CLEARCOAT_F0))
+        * ggx_d(h, coat_alpha)
+        * ggx_g2(wo, wi, coat_alpha)
+        / (4.0 * wo.z)
+        * layers.coat_compensation;
+
+    let sheen = material.sheen_color
+        * sheen_d(h, sheen_alpha(material.sheen_perceptual_roughness))
+        * sheen_v(wo, wi)
+        * wi.z;
+
+    let alpha = ggx_alpha(material.perceptual_roughness, material.anisotropy_strength);
+    let specular = fresnel_schlick(dot(wo, h), layers.specular_f0)
+        * ggx_d(h, alpha)
+        * ggx_g2(wo, wi, alpha)
+        / (4.0 * wo.z)
+        * layers.specular_compensation;
+    let diffuse = layers.diffuse * wi.z * INV_PI;
+
+    let below_coat = 1.0 - layers.coat;
+    let below_sheen = 1.0 - layers.sheen;
+    return clamp(material.clearcoat, 0.0, 1.0) * coat + below_coat * (sheen + below_sheen * (specular + diffuse));
+}
+
+// END: LAYERED BSDF
+//------------------
+
 